@@ -0,0 +1,214 @@
+use chrono::{Duration, TimeZone, Utc};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::retention::RetentionPolicy;
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+use pulsesense_backend::ws::HubEvent;
+
+const N: usize = 100_000;
+const PATIENTS: usize = 50;
+const WRITERS: usize = 4;
+const READERS: usize = 4;
+const WRITES_PER_WRITER: usize = 2_000;
+const READS_PER_READER: usize = 2_000;
+// Live dashboards: every reading is broadcast to these clients
+const WS_CLIENTS: usize = 20;
+const SCANNERS: usize = 2;
+const SCAN_WRITES: usize = 500;
+
+fn reading(i: usize) -> SensorReading {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    SensorReading {
+        device_id: format!("device-{}", i % PATIENTS),
+        patient_id: format!("patient-{}", i % PATIENTS),
+        code: SignalCode::HeartRate,
+        value: 70.0,
+        unit: "bpm".into(),
+        ts: start + Duration::seconds(i as i64),
+    }
+}
+
+fn populated() -> (AppState, Vec<mpsc::UnboundedReceiver<HubEvent>>) {
+    let s = AppState::with_limits(N, RetentionPolicy::default());
+    for i in 0..N {
+        s.add_reading(reading(i));
+    }
+    let clients = (0..WS_CLIENTS)
+        .map(|_| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            s.ws_hub.add_client(tx);
+            rx
+        })
+        .collect();
+    (s, clients)
+}
+
+fn drain(clients: &mut [mpsc::UnboundedReceiver<HubEvent>]) {
+    for rx in clients {
+        while rx.try_recv().is_ok() {}
+    }
+}
+
+// Wall time for writers and readers to finish a fixed amount of work
+// side by side: per-patient dashboard queries interleaved with ingest.
+fn run(write: impl Fn(usize) + Sync, read: impl Fn(usize) + Sync) -> std::time::Duration {
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for r in 0..READERS {
+            let read = &read;
+            scope.spawn(move || {
+                for i in 0..READS_PER_READER {
+                    read(r + i);
+                }
+            });
+        }
+        for w in 0..WRITERS {
+            let write = &write;
+            scope.spawn(move || {
+                for i in 0..WRITES_PER_WRITER {
+                    write(N + w * WRITES_PER_WRITER + i);
+                }
+            });
+        }
+    });
+    started.elapsed()
+}
+
+// Wall time for one writer to finish while readers scan the whole store in
+// a loop, as exports and long aggregates do.
+fn run_during_scans(write: impl Fn(usize) + Sync, scan: impl Fn() + Sync) -> std::time::Duration {
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..SCANNERS {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    scan();
+                }
+            });
+        }
+        let started = Instant::now();
+        for i in 0..SCAN_WRITES {
+            write(N + i);
+        }
+        let elapsed = started.elapsed();
+        done.store(true, Ordering::Relaxed);
+        elapsed
+    })
+}
+
+fn dashboard(i: usize) -> ObsFilter {
+    ObsFilter {
+        patient_id: Some(format!("patient-{}", i % PATIENTS)),
+        code: Some(SignalCode::HeartRate),
+        ..Default::default()
+    }
+}
+
+fn bench_ingest_under_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ingest_8k_read_8k");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(
+        (WRITERS * WRITES_PER_WRITER + READERS * READS_PER_READER) as u64,
+    ));
+
+    // The previous design: every request, broadcast included, holds one lock
+    let (store, mut clients) = populated();
+    let global = Mutex::new(store);
+    group.bench_function("global_mutex", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| {
+                    let elapsed = run(
+                        |i| {
+                            global.lock().unwrap().add_reading(reading(i));
+                        },
+                        |i| {
+                            global.lock().unwrap().query(&dashboard(i), 200);
+                        },
+                    );
+                    drain(&mut clients);
+                    elapsed
+                })
+                .sum()
+        })
+    });
+
+    let (sharded, mut clients) = populated();
+    group.bench_function("sharded_rwlock", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| {
+                    let elapsed = run(
+                        |i| {
+                            sharded.add_reading(reading(i));
+                        },
+                        |i| {
+                            sharded.query(&dashboard(i), 200);
+                        },
+                    );
+                    drain(&mut clients);
+                    elapsed
+                })
+                .sum()
+        })
+    });
+    group.finish();
+}
+
+fn bench_ingest_during_scans(c: &mut Criterion) {
+    let everything = ObsFilter::default();
+    let mut group = c.benchmark_group("ingest_500_during_scans");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SCAN_WRITES as u64));
+
+    let (store, mut clients) = populated();
+    let global = Mutex::new(store);
+    group.bench_function("global_mutex", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| {
+                    let elapsed = run_during_scans(
+                        |i| {
+                            global.lock().unwrap().add_reading(reading(i));
+                        },
+                        || {
+                            global.lock().unwrap().range(&everything);
+                        },
+                    );
+                    drain(&mut clients);
+                    elapsed
+                })
+                .sum()
+        })
+    });
+
+    let (sharded, mut clients) = populated();
+    group.bench_function("sharded_rwlock", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| {
+                    let elapsed = run_during_scans(
+                        |i| {
+                            sharded.add_reading(reading(i));
+                        },
+                        || {
+                            sharded.range(&everything);
+                        },
+                    );
+                    drain(&mut clients);
+                    elapsed
+                })
+                .sum()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_ingest_under_reads, bench_ingest_during_scans);
+criterion_main!(benches);
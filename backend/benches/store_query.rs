@@ -0,0 +1,99 @@
+use chrono::{Duration, TimeZone, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode, StoredObservation};
+use pulsesense_backend::domain::retention::RetentionPolicy;
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+
+const N: usize = 100_000;
+const PATIENTS: usize = 50;
+
+fn readings() -> Vec<SensorReading> {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let codes = [
+        SignalCode::HeartRate,
+        SignalCode::BodyTemperature,
+        SignalCode::StepsPerMinute,
+    ];
+    let mut out: Vec<SensorReading> = (0..N)
+        .map(|i| {
+            let code = codes[i % codes.len()];
+            SensorReading {
+                device_id: format!("device-{}", i % PATIENTS),
+                patient_id: format!("patient-{}", i % PATIENTS),
+                code,
+                value: 70.0,
+                unit: "bpm".into(),
+                ts: start + Duration::seconds(i as i64),
+            }
+        })
+        .collect();
+
+    // Simulate out-of-order arrival
+    out.shuffle(&mut StdRng::seed_from_u64(7));
+    out
+}
+
+// The previous store: one arrival-ordered deque, filtered and sorted per query.
+fn old_query(
+    observations: &VecDeque<StoredObservation>,
+    code: Option<SignalCode>,
+    limit: usize,
+) -> Vec<StoredObservation> {
+    let mut out: Vec<StoredObservation> = observations
+        .iter()
+        .filter(|o| {
+            code.map(|c| o.reading.code as u8 == c as u8)
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect();
+
+    out.sort_by_key(|o| o.reading.ts);
+    out.into_iter()
+        .rev()
+        .take(limit)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect()
+}
+
+fn bench_query(c: &mut Criterion) {
+    let readings = readings();
+
+    let old: VecDeque<StoredObservation> = readings
+        .iter()
+        .cloned()
+        .map(|reading| StoredObservation {
+            id: Uuid::new_v4(),
+            reading,
+        })
+        .collect();
+
+    let new = AppState::with_limits(N, RetentionPolicy::default());
+    for r in readings {
+        new.add_reading(r);
+    }
+
+    let filter = ObsFilter {
+        code: Some(SignalCode::HeartRate),
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("query_100k");
+    group.bench_function("old_clone_and_sort", |b| {
+        b.iter(|| old_query(black_box(&old), Some(SignalCode::HeartRate), 200))
+    });
+    group.bench_function("series_index", |b| {
+        b.iter(|| black_box(&new).query(&filter, 200))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_query);
+criterion_main!(benches);
@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors = protox::compile(["pulsesense.proto"], ["proto"])?;
+    tonic_build::configure().compile_fds(descriptors)?;
+    Ok(())
+}
@@ -0,0 +1,861 @@
+use crate::errors::AppError;
+use crate::metrics::METRICS;
+use crate::settings::Settings;
+use crate::telemetry::RequestId;
+use crate::tls::ClientIdentity;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+/// `prev_hash` of the first record.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+pub const HASH_EXTENSION: &str = "http://pulsesense.io/fhir/StructureDefinition/audit-hash";
+pub const PREV_HASH_EXTENSION: &str =
+    "http://pulsesense.io/fhir/StructureDefinition/audit-prev-hash";
+pub const REQUEST_ID_EXTENSION: &str =
+    "http://pulsesense.io/fhir/StructureDefinition/audit-request-id";
+const OPERATION_SYSTEM: &str = "http://pulsesense.io/fhir/CodeSystem/audit-operation";
+const ENTITY_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/audit-entity-type";
+const OBJECT_ROLE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/object-role";
+// Probes and scrapes touch no health data
+const UNAUDITED: [&str; 4] = ["/healthz", "/livez", "/readyz", "/metrics"];
+const DEFAULT_COUNT: usize = 100;
+const MAX_COUNT: usize = 1_000;
+// Lines a search reads per visit to the index
+const READ_BATCH: usize = 256;
+/// How often `spawn_syncer` forces appended records to disk.
+pub const SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// FHIR `AuditEvent.action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    #[serde(rename = "C")]
+    Create,
+    #[serde(rename = "R")]
+    Read,
+    #[serde(rename = "U")]
+    Update,
+    #[serde(rename = "D")]
+    Delete,
+    #[serde(rename = "E")]
+    Execute,
+}
+
+impl Action {
+    pub fn code(self) -> &'static str {
+        match self {
+            Action::Create => "C",
+            Action::Read => "R",
+            Action::Update => "U",
+            Action::Delete => "D",
+            Action::Execute => "E",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [
+            Action::Create,
+            Action::Read,
+            Action::Update,
+            Action::Delete,
+            Action::Execute,
+        ]
+        .into_iter()
+        .find(|a| a.code() == s)
+    }
+
+    // Operations (`$export`, `$status`, …) are executions, whatever their method
+    fn of_http(method: &Method, route: &str) -> Self {
+        if route.contains('$') {
+            return Action::Execute;
+        }
+        match *method {
+            Method::GET | Method::HEAD => Action::Read,
+            Method::POST => Action::Create,
+            Method::PUT | Method::PATCH => Action::Update,
+            Method::DELETE => Action::Delete,
+            _ => Action::Execute,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    /// Refused or invalid (a 4xx)
+    Failure,
+    /// Failed on our side (a 5xx)
+    Error,
+}
+
+impl Outcome {
+    pub fn of_status(status: StatusCode) -> Self {
+        if status.is_server_error() {
+            Outcome::Error
+        } else if status.is_client_error() {
+            Outcome::Failure
+        } else {
+            Outcome::Success
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Error => "error",
+        }
+    }
+
+    /// FHIR `AuditEvent.outcome`: success, minor failure, serious failure.
+    pub fn code(self) -> &'static str {
+        match self {
+            Outcome::Success => "0",
+            Outcome::Failure => "4",
+            Outcome::Error => "8",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        [Outcome::Success, Outcome::Failure, Outcome::Error]
+            .into_iter()
+            .find(|o| o.code() == code)
+    }
+}
+
+/// One access to the API, as seen by the endpoint that served it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub action: Action,
+    /// e.g. `GET /fhir/Observation` (the route, not the path) or `grpc Ingest`
+    pub operation: String,
+    /// `admin` or `ingest` for the bearer token presented, `cert:<name>` for
+    /// a client certificate, the gateway (`mqtt`, `coap`), or `anonymous`
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Path and query, which show a read's scope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Patients whose data was read or written (or would have been)
+    pub patients: Vec<String>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// A line of the audit trail. `hash` seals the record together with
+/// `prev_hash`, the hash of the record before it, so editing, removing or
+/// reordering a record breaks every hash after it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub seq: u64,
+    pub recorded: DateTime<Utc>,
+    #[serde(flatten)]
+    pub entry: Entry,
+    pub prev_hash: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl Record {
+    /// SHA-256 (hex) of the record's JSON without its `hash`.
+    pub fn compute_hash(&self) -> String {
+        let unsealed = Record {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unsealed).unwrap_or_default();
+        ring::digest::digest(&ring::digest::SHA256, &json)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Result of checking the trail's hash chain.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Verification {
+    pub valid: bool,
+    pub records: u64,
+    /// Hash of the last record. The chain cannot show records cut off its
+    /// end, so keep a copy of this elsewhere to compare against.
+    pub head: String,
+    /// Line of the first record that does not fit the chain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Where each complete line of the trail starts, so reads go straight to
+/// the records they need. Only lists lines already written in full.
+///
+/// It is not bounded: it takes about 16 bytes per record plus 8 for each
+/// patient a record names (up to twice that while its vectors grow), and is
+/// rebuilt by reading the whole file on startup, which the chain check does
+/// anyway. A million records cost some 30 MB. Rotate the trail before it
+/// gets much larger: stop the server, move the file aside (it still verifies
+/// on its own) and start a new chain.
+#[derive(Debug, Default)]
+struct Index {
+    // seq (0 if unreadable) and starting offset of each line, in file order
+    lines: Vec<(u64, u64)>,
+    // Positions in `lines` of the records naming each patient
+    by_patient: HashMap<String, Vec<usize>>,
+    // Bytes up to the end of the last indexed line
+    len: u64,
+}
+
+impl Index {
+    fn push(&mut self, record: Option<&Record>, line_len: u64) {
+        let pos = self.lines.len();
+        self.lines.push((record.map_or(0, |r| r.seq), self.len));
+        for patient in record
+            .map(|r| r.entry.patients.as_slice())
+            .unwrap_or_default()
+        {
+            self.by_patient
+                .entry(patient.clone())
+                .or_default()
+                .push(pos);
+        }
+        self.len += line_len;
+    }
+
+    // Byte range of the line at `pos`
+    fn span(&self, pos: usize) -> Span {
+        let stop = self
+            .lines
+            .get(pos + 1)
+            .map_or(self.len, |(_, start)| *start);
+        Span {
+            pos,
+            start: self.lines[pos].1,
+            stop,
+        }
+    }
+
+    // Up to READ_BATCH lines that `query` may match, before position
+    // `before`, newest first
+    fn batch(&self, query: &AuditQuery, before: usize) -> Vec<Span> {
+        match &query.patient {
+            Some(patient) => {
+                let positions = self
+                    .by_patient
+                    .get(patient)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                let end = positions.partition_point(|&pos| pos < before);
+                positions[..end]
+                    .iter()
+                    .rev()
+                    .take(READ_BATCH)
+                    .map(|&pos| self.span(pos))
+                    .collect()
+            }
+            None => (0..before.min(self.lines.len()))
+                .rev()
+                .take(READ_BATCH)
+                .map(|pos| self.span(pos))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    pos: usize,
+    start: u64,
+    stop: u64,
+}
+
+// The records on the lines of `spans` (newest first, as from
+// `Index::batch`), None for unreadable ones. Runs of adjacent lines are
+// read at once.
+fn read_spans(file: &mut File, spans: &[Span]) -> std::io::Result<Vec<Option<Record>>> {
+    let mut out = Vec::with_capacity(spans.len());
+    let mut rest = spans;
+    while let Some(newest) = rest.first() {
+        let run = 1 + rest
+            .windows(2)
+            .take_while(|w| w[1].pos + 1 == w[0].pos)
+            .count();
+        let start = rest[run - 1].start;
+        let mut buf = vec![0; (newest.stop - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buf)?;
+        for span in &rest[..run] {
+            let line = &buf[(span.start - start) as usize..(span.stop - start) as usize];
+            out.push(serde_json::from_slice::<Record>(line).ok());
+        }
+        rest = &rest[run..];
+    }
+    Ok(out)
+}
+
+// One pass over the trail
+struct Scan {
+    verification: Verification,
+    last: Option<Record>,
+    // Bytes up to the end of the last complete line
+    complete_len: u64,
+    torn: bool,
+}
+
+// Also fills `index`, if given, with the complete lines
+fn scan(mut reader: impl BufRead, mut index: Option<&mut Index>) -> std::io::Result<Scan> {
+    let mut verification = Verification {
+        valid: true,
+        records: 0,
+        head: GENESIS_HASH.to_string(),
+        broken_at: None,
+        reason: None,
+    };
+    let mut last: Option<Record> = None;
+    let (mut complete_len, mut torn) = (0, false);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        if line.last() != Some(&b'\n') {
+            torn = true;
+            break;
+        }
+        complete_len += n as u64;
+        verification.records += 1;
+        let lineno = verification.records;
+
+        let parsed = serde_json::from_slice::<Record>(&line);
+        if let Some(index) = index.as_deref_mut() {
+            index.push(parsed.as_ref().ok(), n as u64);
+        }
+        let problem = match parsed {
+            Err(e) => Some(format!("unreadable record: {}", e)),
+            Ok(record) => {
+                let (expected_seq, expected_prev) = match &last {
+                    Some(prev) => (prev.seq + 1, prev.hash.as_str()),
+                    None => (1, GENESIS_HASH),
+                };
+                let problem = if record.seq != expected_seq {
+                    Some(format!(
+                        "seq {} where {} was expected",
+                        record.seq, expected_seq
+                    ))
+                } else if record.prev_hash != expected_prev {
+                    Some(format!(
+                        "record {} does not follow the record before it",
+                        record.seq
+                    ))
+                } else if record.hash != record.compute_hash() {
+                    Some(format!("record {} does not match its hash", record.seq))
+                } else {
+                    None
+                };
+                verification.head = record.hash.clone();
+                last = Some(record);
+                problem
+            }
+        };
+        if let (Some(problem), true) = (problem, verification.valid) {
+            verification.valid = false;
+            verification.broken_at = Some(lineno);
+            verification.reason = Some(problem);
+        }
+    }
+    Ok(Scan {
+        verification,
+        last,
+        complete_len,
+        torn,
+    })
+}
+
+/// The audit trail: an append-only NDJSON file of hash-chained records.
+/// Appends are serialized by a lock that reads never take: they go through
+/// a file handle of their own, reading only the lines the index lists.
+pub struct AuditLog {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+    index: RwLock<Index>,
+    // A second handle on the trail, so syncing does not hold up appends
+    sync_file: File,
+    // Records appended since the last sync
+    unsynced: AtomicBool,
+}
+
+struct Inner {
+    file: File,
+    seq: u64,
+    head: String,
+    // Bytes of complete records
+    len: u64,
+}
+
+impl AuditLog {
+    /// Open the trail at `path`, creating it if needed, and check its chain.
+    /// A broken chain is logged but does not stop the server: new records
+    /// follow the last one, and `verify` keeps reporting the break. A torn
+    /// last line (a write cut short by a crash) is cut off.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut index = Index::default();
+        let scan = scan(BufReader::new(&file), Some(&mut index))?;
+        if scan.torn {
+            tracing::warn!(path = %path.display(), "cutting off a torn audit record");
+            file.set_len(scan.complete_len)?;
+        }
+        let v = &scan.verification;
+        if v.valid {
+            tracing::info!(path = %path.display(), records = v.records, head = %v.head, "audit trail verified");
+        } else {
+            tracing::error!(
+                path = %path.display(),
+                line = v.broken_at,
+                "audit trail hash chain is broken: {}",
+                v.reason.as_deref().unwrap_or_default()
+            );
+        }
+        let (seq, head) = match scan.last {
+            Some(last) => (last.seq, last.hash),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            sync_file: file.try_clone()?,
+            inner: Mutex::new(Inner {
+                file,
+                seq,
+                head,
+                len: scan.complete_len,
+            }),
+            index: RwLock::new(index),
+            unsynced: AtomicBool::new(false),
+        })
+    }
+
+    /// Seal `entry` onto the end of the trail. The record is in the OS's
+    /// hands once this returns, so it survives the process crashing, but
+    /// only reaches the disk with the next `sync`: a power loss or kernel
+    /// crash can take the records of the last `SYNC_INTERVAL`.
+    pub fn append(&self, entry: Entry, now: DateTime<Utc>) -> std::io::Result<Record> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut record = Record {
+            seq: inner.seq + 1,
+            recorded: now,
+            entry,
+            prev_hash: inner.head.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        // One write per line on an O_APPEND file. Cut off what a failed
+        // write left, so the next record does not land after half a line.
+        if let Err(e) = inner.file.write_all(&line) {
+            let _ = inner.file.set_len(inner.len);
+            return Err(e);
+        }
+        inner.len += line.len() as u64;
+        self.unsynced.store(true, Ordering::SeqCst);
+        self.index
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Some(&record), line.len() as u64);
+        inner.seq = record.seq;
+        inner.head = record.hash.clone();
+        METRICS
+            .audit_records_total
+            .with_label_values(&[record.entry.outcome.as_str()])
+            .inc();
+        Ok(record)
+    }
+
+    /// `append` at the current time. The access has already happened by
+    /// now, so a failed write is logged and counted rather than returned.
+    pub fn record(&self, entry: Entry) {
+        if let Err(e) = self.append(entry, Utc::now()) {
+            METRICS.audit_write_errors_total.inc();
+            tracing::error!(path = %self.path.display(), "writing audit record failed: {}", e);
+        }
+    }
+
+    /// Force the records appended since the last sync to disk.
+    pub fn sync(&self) -> std::io::Result<()> {
+        if self.unsynced.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.sync_file.sync_data() {
+                self.unsynced.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self) -> std::sync::RwLockReadGuard<'_, Index> {
+        self.index.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Re-read the whole trail and check its hash chain. Records appended
+    /// meanwhile are left for the next check.
+    pub fn verify(&self) -> std::io::Result<Verification> {
+        let len = self.index().len;
+        let file = File::open(&self.path)?;
+        scan(BufReader::new(file.take(len)), None).map(|s| s.verification)
+    }
+
+    /// Records matching `query`, newest first, reading back from the end
+    /// of the trail until `count` are found (only the patient's records,
+    /// when searching by patient). Unreadable lines are skipped here;
+    /// `verify` reports them.
+    pub fn search(&self, query: &AuditQuery) -> std::io::Result<Vec<Record>> {
+        let mut file = File::open(&self.path)?;
+        let mut out = Vec::new();
+        let mut before = usize::MAX;
+        while out.len() < query.count {
+            // The index is only locked while picking the next lines
+            let spans = self.index().batch(query, before);
+            let Some(oldest) = spans.last() else {
+                break;
+            };
+            before = oldest.pos;
+            let records = read_spans(&mut file, &spans)?;
+            out.extend(records.into_iter().flatten().filter(|r| query.matches(r)));
+        }
+        out.truncate(query.count);
+        Ok(out)
+    }
+
+    pub fn get(&self, seq: u64) -> std::io::Result<Option<Record>> {
+        let span = {
+            let index = self.index();
+            // Record n is on line n unless the chain is broken
+            let guess = usize::try_from(seq).ok().and_then(|n| n.checked_sub(1));
+            let pos = guess
+                .filter(|&pos| index.lines.get(pos).is_some_and(|(s, _)| *s == seq))
+                .or_else(|| index.lines.iter().rposition(|(s, _)| *s == seq));
+            match pos {
+                Some(pos) => index.span(pos),
+                None => return Ok(None),
+            }
+        };
+        let record = read_spans(&mut File::open(&self.path)?, &[span])?
+            .pop()
+            .flatten();
+        Ok(record.filter(|r| r.seq == seq))
+    }
+}
+
+/// AuditEvent search parameters.
+#[derive(Debug, Clone)]
+pub struct AuditQuery {
+    pub patient: Option<String>,
+    pub from: Bound<DateTime<Utc>>,
+    pub to: Bound<DateTime<Utc>>,
+    pub action: Option<Action>,
+    pub outcome: Option<Outcome>,
+    pub actor: Option<String>,
+    pub address: Option<String>,
+    pub count: usize,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        Self {
+            patient: None,
+            from: Bound::Unbounded,
+            to: Bound::Unbounded,
+            action: None,
+            outcome: None,
+            actor: None,
+            address: None,
+            count: DEFAULT_COUNT,
+        }
+    }
+}
+
+impl AuditQuery {
+    /// Parse `patient`, `date` (`ge`/`gt`/`le`/`lt` prefixed, repeatable),
+    /// `action`, `outcome`, `agent-name`, `address` and `_count`.
+    pub fn parse(params: &[(String, String)]) -> Result<Self, AppError> {
+        let mut q = AuditQuery::default();
+        for (name, value) in params {
+            match name.as_str() {
+                "patient" => {
+                    q.patient = Some(value.strip_prefix("Patient/").unwrap_or(value).to_string())
+                }
+                "date" => {
+                    let invalid = || {
+                        AppError::Validation(
+                            "date must be ge|gt|le|lt followed by an RFC3339 timestamp".into(),
+                        )
+                    };
+                    let (prefix, ts) = value.split_at_checked(2).ok_or_else(invalid)?;
+                    let ts = DateTime::parse_from_rfc3339(ts)
+                        .map_err(|_| invalid())?
+                        .with_timezone(&Utc);
+                    match prefix {
+                        "ge" => q.from = Bound::Included(ts),
+                        "gt" => q.from = Bound::Excluded(ts),
+                        "le" => q.to = Bound::Included(ts),
+                        "lt" => q.to = Bound::Excluded(ts),
+                        _ => return Err(invalid()),
+                    }
+                }
+                "action" => {
+                    let action = Action::parse(value).ok_or_else(|| {
+                        AppError::Validation("action must be C, R, U, D or E".into())
+                    })?;
+                    q.action = Some(action);
+                }
+                "outcome" => {
+                    let outcome = Outcome::parse(value)
+                        .ok_or_else(|| AppError::Validation("outcome must be 0, 4 or 8".into()))?;
+                    q.outcome = Some(outcome);
+                }
+                "agent-name" => q.actor = Some(value.clone()),
+                "address" => q.address = Some(value.clone()),
+                "_count" => {
+                    let count: usize = value
+                        .parse()
+                        .map_err(|_| AppError::Validation("_count must be a number".into()))?;
+                    q.count = count.min(MAX_COUNT);
+                }
+                other => {
+                    return Err(AppError::Validation(format!(
+                        "unsupported search parameter '{}'",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(q)
+    }
+
+    pub fn matches(&self, r: &Record) -> bool {
+        let e = &r.entry;
+        self.patient.as_ref().is_none_or(|p| e.patients.contains(p))
+            && (self.from, self.to).contains(&r.recorded)
+            && self.action.is_none_or(|a| a == e.action)
+            && self.outcome.is_none_or(|o| o == e.outcome)
+            && self.actor.as_ref().is_none_or(|a| *a == e.actor)
+            && self
+                .address
+                .as_ref()
+                .is_none_or(|a| e.client_ip.as_ref() == Some(a))
+    }
+}
+
+/// The record as a FHIR R4 AuditEvent, carrying its hashes as extensions.
+pub fn to_audit_event(r: &Record) -> serde_json::Value {
+    let e = &r.entry;
+    let mut extension = vec![
+        serde_json::json!({ "url": PREV_HASH_EXTENSION, "valueString": r.prev_hash }),
+        serde_json::json!({ "url": HASH_EXTENSION, "valueString": r.hash }),
+    ];
+    if let Some(id) = &e.request_id {
+        extension.push(serde_json::json!({ "url": REQUEST_ID_EXTENSION, "valueString": id }));
+    }
+
+    let mut agent =
+        serde_json::json!({ "who": { "display": e.actor }, "name": e.actor, "requestor": true });
+    if let Some(ip) = &e.client_ip {
+        // Type 2: IP address
+        agent["network"] = serde_json::json!({ "address": ip, "type": "2" });
+    }
+
+    let mut entity: Vec<serde_json::Value> = e
+        .patients
+        .iter()
+        .map(|p| {
+            serde_json::json!({
+                "what": { "reference": format!("Patient/{}", p) },
+                "type": { "system": ENTITY_TYPE_SYSTEM, "code": "1", "display": "Person" },
+                "role": { "system": OBJECT_ROLE_SYSTEM, "code": "1", "display": "Patient" },
+            })
+        })
+        .collect();
+    if let Some(query) = &e.query {
+        entity.push(serde_json::json!({
+            "type": { "system": ENTITY_TYPE_SYSTEM, "code": "2", "display": "System Object" },
+            "role": { "system": OBJECT_ROLE_SYSTEM, "code": "24", "display": "Query" },
+            "query": base64::engine::general_purpose::STANDARD.encode(query),
+        }));
+    }
+
+    let mut event = serde_json::json!({
+        "resourceType": "AuditEvent",
+        "id": r.seq.to_string(),
+        "extension": extension,
+        "type": {
+            "system": "http://terminology.hl7.org/CodeSystem/audit-event-type",
+            "code": "rest",
+            "display": "RESTful Operation",
+        },
+        "subtype": [{ "system": OPERATION_SYSTEM, "code": e.operation }],
+        "action": e.action.code(),
+        "recorded": r.recorded,
+        "outcome": e.outcome.code(),
+        "agent": [agent],
+        "source": { "observer": { "display": "pulsesense-backend" } },
+    });
+    if let Some(detail) = &e.detail {
+        event["outcomeDesc"] = detail.clone().into();
+    }
+    // FHIR forbids empty arrays
+    if !entity.is_empty() {
+        event["entity"] = entity.into();
+    }
+    event
+}
+
+/// Sync the trail every `every` on a blocking thread, so records reach the
+/// disk in batches rather than one fsync per access.
+pub fn spawn_syncer(audit: Arc<AuditLog>, every: std::time::Duration) {
+    actix_rt::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop {
+            tick.tick().await;
+            let audit = audit.clone();
+            match web::block(move || audit.sync()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    METRICS.audit_write_errors_total.inc();
+                    tracing::error!("syncing the audit trail failed: {}", e);
+                }
+                Err(e) => tracing::error!("syncing the audit trail failed: {}", e),
+            }
+        }
+    });
+}
+
+// Patients touched by the current request, noted by its handler
+#[derive(Debug, Default)]
+struct Touched(BTreeSet<String>);
+
+/// Whether requests are being audited, for callers that would have to do
+/// work to find the patients to `touch`.
+pub fn is_audited(req: &HttpRequest) -> bool {
+    req.app_data::<web::Data<AuditLog>>().is_some()
+}
+
+/// Note patients whose data the current request reads or writes, for its
+/// audit record.
+pub fn touch<'a>(req: &HttpRequest, patients: impl IntoIterator<Item = &'a str>) {
+    let mut extensions = req.extensions_mut();
+    if !extensions.contains::<Touched>() {
+        extensions.insert(Touched::default());
+    }
+    if let Some(touched) = extensions.get_mut::<Touched>() {
+        touched.0.extend(
+            patients
+                .into_iter()
+                .filter(|p| !p.is_empty())
+                .map(str::to_string),
+        );
+    }
+}
+
+/// Who is behind a request: the client certificate's name, else the bearer
+/// token it presented (`admin`, `ingest`), else `anonymous`.
+pub fn actor(req: &HttpRequest) -> String {
+    if let Some(name) = req
+        .conn_data::<ClientIdentity>()
+        .and_then(|id| id.names.first())
+    {
+        return format!("cert:{}", name);
+    }
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let auth = req.app_data::<web::Data<Settings>>().map(|s| &s.auth);
+    match (auth, bearer) {
+        (Some(auth), Some(token)) if auth.admin_token() == Some(token) => "admin".to_string(),
+        (Some(auth), Some(token)) if auth.ingest_token() == Some(token) => "ingest".to_string(),
+        _ => "anonymous".to_string(),
+    }
+}
+
+// Everything known before the request runs; patients and outcome come after
+fn http_entry(req: &HttpRequest, route: Option<&str>) -> Entry {
+    let route = route.unwrap_or(req.path());
+    Entry {
+        action: Action::of_http(req.method(), route),
+        operation: format!("{} {}", req.method(), route),
+        actor: actor(req),
+        client_ip: req.peer_addr().map(|a| a.ip().to_string()),
+        request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        query: req.uri().path_and_query().map(|pq| pq.to_string()),
+        patients: Vec::new(),
+        outcome: Outcome::Success,
+        detail: None,
+    }
+}
+
+/// Middleware writing an audit record for every request once it has been
+/// answered, when an `AuditLog` is registered. Probes, `/metrics` and CORS
+/// preflights are left out.
+pub async fn record_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let route = req.match_pattern();
+    let audit = req
+        .app_data::<web::Data<AuditLog>>()
+        .filter(|_| {
+            req.method() != Method::OPTIONS
+                && !route.as_deref().is_some_and(|r| UNAUDITED.contains(&r))
+        })
+        .cloned();
+    let Some(audit) = audit else {
+        return next.call(req).await;
+    };
+
+    // Not a clone of the request: routing needs it unshared
+    let mut entry = http_entry(req.request(), route.as_deref());
+    let result = next.call(req).await;
+    let status = match &result {
+        Ok(res) => {
+            if let Some(touched) = res.request().extensions().get::<Touched>() {
+                entry.patients = touched.0.iter().cloned().collect();
+            }
+            res.status()
+        }
+        Err(e) => e.as_response_error().status_code(),
+    };
+    entry.outcome = Outcome::of_status(status);
+    entry.detail = Some(format!("HTTP {}", status.as_u16()));
+    // Off the worker, like the reads; `record` logs its own failures
+    if let Err(e) = web::block(move || audit.record(entry)).await {
+        METRICS.audit_write_errors_total.inc();
+        tracing::error!("writing audit record failed: {}", e);
+    }
+    result
+}
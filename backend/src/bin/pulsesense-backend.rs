@@ -1,35 +1,291 @@
-use actix_web::{middleware, web, App, HttpServer};
-use std::sync::{Arc, Mutex};
+use actix_web::{middleware, web, App, HttpMessage, HttpServer};
+use std::sync::Arc;
+use std::time::Duration;
 
+use pulsesense_backend::audit::{self, AuditLog};
+use pulsesense_backend::bulk::{self, BulkExports};
+use pulsesense_backend::coap::{CoapIngest, CoapServer};
+use pulsesense_backend::domain::retention::spawn_compactor;
+use pulsesense_backend::domain::snapshot;
 use pulsesense_backend::domain::store::AppState;
-use pulsesense_backend::routes;
-use pulsesense_backend::telemetry::init_tracing;
+use pulsesense_backend::grpc::{GrpcServer, GrpcService};
+use pulsesense_backend::hl7::Hl7Forwarder;
+use pulsesense_backend::lifecycle::{self, Lifecycle};
+use pulsesense_backend::mqtt::MqttBridge;
+use pulsesense_backend::pipeline::Pipeline;
+use pulsesense_backend::ratelimit::{self, RateLimiter};
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::subscriptions::Subscriptions;
+use pulsesense_backend::telemetry::{self, init_tracing};
+use pulsesense_backend::upstream::UpstreamForwarder;
+use pulsesense_backend::webhooks::Webhooks;
+use pulsesense_backend::{cors, metrics, routes, tls};
+
+// How often finished bulk exports are checked against their TTL
+const BULK_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    init_tracing();
+    let telemetry = init_tracing();
+
+    let settings = Settings::load().map_err(|e| {
+        tracing::error!("{}", e);
+        std::io::Error::other(e)
+    })?;
+    let bind_addr = settings.bind_addr();
+    let tls_config = if settings.tls.enabled {
+        Some(tls::server_config(&settings.tls).map_err(|e| {
+            tracing::error!("TLS setup failed: {:#}", e);
+            std::io::Error::other(e)
+        })?)
+    } else {
+        None
+    };
 
-    // Docker-friendly default; you can override locally with HOST=127.0.0.1
-    let host = std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+    let store = AppState::from_settings(&settings);
+    if let Some(path) = &settings.store.snapshot_path {
+        let loaded = snapshot::load(&store, path).map_err(|e| {
+            tracing::error!(path = %path.display(), "loading store snapshot failed: {}", e);
+            e
+        })?;
+        tracing::info!(path = %path.display(), loaded, "store snapshot loaded");
+    }
+    let state = web::Data::new(store);
+    spawn_compactor(
+        state.clone().into_inner(),
+        Duration::from_secs(settings.store.compaction_interval_secs),
+    );
 
-    let port: u16 = std::env::var("PORT")
-        .ok()
-        .and_then(|v| v.parse::<u16>().ok())
-        .unwrap_or(8080);
+    let audit_log = if settings.audit.enabled {
+        let log = AuditLog::open(&settings.audit.path).map_err(|e| {
+            tracing::error!(path = %settings.audit.path.display(), "opening the audit trail failed: {}", e);
+            e
+        })?;
+        let log = Arc::new(log);
+        audit::spawn_syncer(log.clone(), audit::SYNC_INTERVAL);
+        Some(log)
+    } else {
+        None
+    };
+    let audit_data = audit_log.clone().map(web::Data::from);
 
-    let bind_addr = format!("{}:{}", host, port);
+    let bulk = BulkExports::new(
+        settings.store.bulk_export_dir.clone(),
+        Duration::from_secs(settings.store.bulk_export_ttl_secs),
+    );
+    match bulk.remove_stale() {
+        Ok(0) => {}
+        Ok(removed) => tracing::info!(removed, "removed bulk exports left by an earlier run"),
+        Err(e) => {
+            tracing::warn!(dir = %bulk.dir().display(), "removing stale bulk exports failed: {}", e)
+        }
+    }
+    bulk::spawn_expiry(bulk.clone(), BULK_EXPIRY_INTERVAL);
+    let bulk = web::Data::new(bulk);
+    let limiter = web::Data::new(RateLimiter::new(settings.rate_limit.clone()));
+    let lifecycle = web::Data::new(Lifecycle::new());
+    let upstream = if settings.upstream.enabled {
+        let forwarder = UpstreamForwarder::start(&settings.upstream).map_err(|e| {
+            tracing::error!(dir = %settings.upstream.outbox_dir.display(), "opening the FHIR upstream outbox failed: {}", e);
+            e
+        })?;
+        tracing::info!(base_url = %settings.upstream.base_url, "forwarding observations to upstream FHIR server");
+        Some(forwarder)
+    } else {
+        None
+    };
+    let upstream_status = upstream.as_ref().map(|f| web::Data::from(f.upstream()));
+    // Before the pipeline, whose persist stage journals into the outbox
+    let pipeline = web::Data::new(Pipeline::start_with_upstream(
+        state.clone().into_inner(),
+        &settings.pipeline,
+        upstream.as_ref().map(UpstreamForwarder::upstream),
+    ));
+    let mut mqtt = if settings.mqtt.enabled {
+        tracing::info!(host = %settings.mqtt.host, port = settings.mqtt.port, tls = settings.mqtt.tls, "starting MQTT bridge");
+        Some(MqttBridge::start(
+            state.clone().into_inner(),
+            Some(pipeline.clone().into_inner()),
+            audit_log.clone(),
+            &settings.mqtt,
+        )?)
+    } else {
+        None
+    };
+    let coap = if settings.coap.enabled {
+        let ingest = CoapIngest::new(
+            state.clone().into_inner(),
+            Some(pipeline.clone().into_inner()),
+            audit_log.clone(),
+            Some(limiter.clone().into_inner()),
+            &settings,
+        );
+        let server = CoapServer::bind(&settings.coap.bind_addr(), ingest).await?;
+        tracing::info!(addr = %server.local_addr(), "CoAP listener started");
+        Some(server)
+    } else {
+        None
+    };
+    let grpc = if settings.grpc.enabled {
+        let service = GrpcService::new(
+            state.clone().into_inner(),
+            Some(pipeline.clone().into_inner()),
+            audit_log.clone(),
+            Some(limiter.clone().into_inner()),
+            &settings,
+        );
+        let server = GrpcServer::bind(&settings.grpc.bind_addr(), service).await?;
+        tracing::info!(addr = %server.local_addr(), "gRPC listener started");
+        Some(server)
+    } else {
+        None
+    };
+    let hl7 = settings.hl7.enabled.then(|| {
+        tracing::info!(addr = %settings.hl7.addr(), "forwarding HL7 v2 over MLLP");
+        Hl7Forwarder::start(state.clone().into_inner(), &settings.hl7)
+    });
+    let webhooks = if settings.webhooks.enabled {
+        let webhooks = Webhooks::start(state.clone().into_inner(), &settings.webhooks).map_err(|e| {
+            tracing::error!(path = %settings.webhooks.path.display(), "loading webhook subscriptions failed: {}", e);
+            e
+        })?;
+        Some(webhooks)
+    } else {
+        None
+    };
+    let webhooks_data = webhooks.clone().map(web::Data::new);
+    let subscriptions = if settings.subscriptions.enabled {
+        let path = &settings.subscriptions.path;
+        let subscriptions = Subscriptions::start(
+            state.clone().into_inner(),
+            &settings.subscriptions,
+            &settings.webhooks,
+        )
+        .map_err(|e| {
+            tracing::error!(path = %path.display(), "loading FHIR subscriptions failed: {}", e);
+            e
+        })?;
+        Some(subscriptions)
+    } else {
+        None
+    };
+    let subscriptions_data = subscriptions.clone().map(web::Data::new);
+    let settings = web::Data::new(settings);
 
-    let state = web::Data::new(Arc::new(Mutex::new(AppState::new_demo())));
+    // Kept outside the app factory for the shutdown sequence below
+    let shutdown = (
+        lifecycle.clone(),
+        state.clone(),
+        settings.clone(),
+        pipeline.clone(),
+    );
+    let shutdown_timeout = settings.server.shutdown_timeout_secs;
 
-    tracing::info!(%bind_addr, "starting backend");
+    tracing::info!(%bind_addr, tls = tls_config.is_some(), "starting backend");
 
-    HttpServer::new(move || {
-        App::new()
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
             .app_data(state.clone())
-            .wrap(middleware::Logger::default())
-            .configure(routes::configure)
+            .app_data(bulk.clone())
+            .app_data(settings.clone())
+            .app_data(limiter.clone())
+            .app_data(lifecycle.clone())
+            .app_data(pipeline.clone());
+        if let Some(upstream) = &upstream_status {
+            app = app.app_data(upstream.clone());
+        }
+        if let Some(webhooks) = &webhooks_data {
+            app = app.app_data(webhooks.clone());
+        }
+        if let Some(subscriptions) = &subscriptions_data {
+            app = app.app_data(subscriptions.clone());
+        }
+        if let Some(audit) = &audit_data {
+            app = app.app_data(audit.clone());
+        }
+        app.wrap(
+            middleware::Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{request_id}xi"#,
+            )
+            .custom_request_replace("request_id", |req| {
+                req.extensions()
+                    .get::<telemetry::RequestId>()
+                    .map(|id| id.0.clone())
+                    .unwrap_or_default()
+            }),
+        )
+        .wrap(middleware::from_fn(ratelimit::limit_requests))
+        // Outside the limiter, so rejected requests are recorded too
+        .wrap(middleware::from_fn(audit::record_requests))
+        .wrap(cors::cors(&settings.cors))
+        .wrap(middleware::from_fn(metrics::track_latency))
+        .wrap(middleware::from_fn(telemetry::request_id))
+        .configure(routes::configure)
     })
-    .bind(bind_addr)?
-    .run()
-    .await
+    .on_connect(tls::on_connect)
+    .shutdown_timeout(shutdown_timeout)
+    .disable_signals();
+
+    let server = match tls_config {
+        Some(config) => server.bind_rustls_0_23(bind_addr, config)?,
+        None => server.bind(bind_addr)?,
+    };
+    let server = server.run();
+
+    let handle = server.handle();
+    let (lifecycle, state, settings, pipeline) = shutdown;
+    let drain_state = state.clone();
+    let drain_settings = settings.server.clone();
+    actix_rt::spawn(async move {
+        lifecycle::shutdown_signal().await;
+        lifecycle::drain(&lifecycle, &drain_state, handle, &drain_settings).await;
+    });
+    let result = server.await;
+
+    // Every worker has stopped; with MQTT ingest, CoAP and gRPC stopped too nothing new
+    // gets queued, and once the pipeline has drained no ingest can race the flush
+    if let Some(mqtt) = &mut mqtt {
+        mqtt.stop_ingest().await;
+    }
+    if let Some(coap) = coap {
+        coap.stop();
+    }
+    if let Some(grpc) = grpc {
+        grpc.stop().await;
+    }
+    pipeline.shutdown().await;
+    // Publishes the alerts raised while draining
+    if let Some(mqtt) = mqtt {
+        mqtt.stop().await;
+    }
+    // Sends the alerts raised while draining too
+    if let Some(hl7) = hl7 {
+        hl7.stop().await;
+    }
+    // These deliver what the pipeline broadcast while draining as well
+    if let Some(webhooks) = webhooks {
+        webhooks.stop().await;
+    }
+    if let Some(subscriptions) = subscriptions {
+        subscriptions.stop().await;
+    }
+    // After the pipeline, so the last observations are journaled
+    if let Some(upstream) = upstream {
+        upstream.stop().await;
+    }
+    if let Some(path) = &settings.store.snapshot_path {
+        match snapshot::save(&state, path) {
+            Ok(saved) => tracing::info!(path = %path.display(), saved, "store snapshot flushed"),
+            Err(e) => {
+                tracing::error!(path = %path.display(), "flushing store snapshot failed: {}", e)
+            }
+        }
+    }
+    if let Some(audit) = &audit_log {
+        if let Err(e) = audit.sync() {
+            tracing::error!("syncing the audit trail failed: {}", e);
+        }
+    }
+    telemetry.shutdown();
+    result
 }
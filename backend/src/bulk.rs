@@ -0,0 +1,428 @@
+use crate::domain::store::{AppState, ObsFilter};
+use crate::errors::AppError;
+use crate::fhir;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Observations read from the store per lock, so ingest keeps flowing.
+const PAGE_SIZE: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum ResourceType {
+    Observation,
+    Patient,
+    Device,
+}
+
+impl ResourceType {
+    pub const ALL: [ResourceType; 3] = [
+        ResourceType::Observation,
+        ResourceType::Patient,
+        ResourceType::Device,
+    ];
+
+    pub fn parse(s: &str) -> Option<ResourceType> {
+        match s {
+            "Observation" => Some(ResourceType::Observation),
+            "Patient" => Some(ResourceType::Patient),
+            "Device" => Some(ResourceType::Device),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceType::Observation => "Observation",
+            ResourceType::Patient => "Patient",
+            ResourceType::Device => "Device",
+        }
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}.ndjson", self.as_str())
+    }
+}
+
+/// One entry of the completion manifest's `output` array.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputFile {
+    #[serde(rename = "type")]
+    pub resource_type: ResourceType,
+    pub url: String,
+    pub count: usize,
+}
+
+/// Body returned by the status endpoint once a job has completed.
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub transactionTime: DateTime<Utc>,
+    pub request: String,
+    pub requiresAccessToken: bool,
+    pub output: Vec<OutputFile>,
+    pub error: Vec<OutputFile>,
+}
+
+#[derive(Debug, Clone)]
+pub enum JobStatus {
+    InProgress { progress: String },
+    Complete(Manifest),
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct Job {
+    status: JobStatus,
+    cancelled: Arc<AtomicBool>,
+    // When it completed or failed, for expiry
+    finished: Option<Instant>,
+}
+
+/// What to export; `since` filters on when the store took readings in, so
+/// late readings measured before it are still exported.
+#[derive(Debug, Clone)]
+pub struct ExportRequest {
+    pub types: Vec<ResourceType>,
+    pub since: Option<DateTime<Utc>>,
+    // original kick-off URL, echoed in the manifest
+    pub request_url: String,
+    // base for download URLs, e.g. "http://host:8080/fhir"
+    pub base_url: String,
+}
+
+/// Registry of FHIR Bulk Data `$export` jobs and their NDJSON output directory.
+/// Finished jobs are kept for `ttl`; see `expire`.
+#[derive(Debug, Clone)]
+pub struct BulkExports {
+    dir: PathBuf,
+    ttl: Duration,
+    jobs: Arc<Mutex<HashMap<Uuid, Job>>>,
+}
+
+impl BulkExports {
+    pub fn new(dir: impl Into<PathBuf>, ttl: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            ttl,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Remove the job directories an earlier run left behind: its jobs went
+    /// with it, so nothing would ever serve or delete them. Returns how many
+    /// were removed.
+    pub fn remove_stale(&self) -> std::io::Result<usize> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            entries => entries?,
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            // Only directories named like the ones `run` creates
+            let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| Uuid::parse_str(n).ok())
+            else {
+                continue;
+            };
+            if !entry.file_type()?.is_dir() || self.jobs.lock().unwrap().contains_key(&id) {
+                continue;
+            }
+            std::fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Forget jobs that finished more than the TTL before `now`, removing
+    /// their files. Returns how many expired.
+    pub fn expire(&self, now: Instant) -> usize {
+        let mut expired = Vec::new();
+        self.jobs.lock().unwrap().retain(|id, job| {
+            let keep = job
+                .finished
+                .is_none_or(|at| now.saturating_duration_since(at) < self.ttl);
+            if !keep {
+                expired.push(*id);
+            }
+            keep
+        });
+        // Off the lock: a large export takes a while to delete
+        for id in &expired {
+            let _ = std::fs::remove_dir_all(self.job_dir(*id));
+        }
+        expired.len()
+    }
+
+    /// Register a job and run it on the blocking pool.
+    pub fn start(&self, state: Arc<AppState>, req: ExportRequest) -> Uuid {
+        let id = Uuid::new_v4();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.jobs.lock().unwrap().insert(
+            id,
+            Job {
+                status: JobStatus::InProgress {
+                    progress: "queued".into(),
+                },
+                cancelled: cancelled.clone(),
+                finished: None,
+            },
+        );
+
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let status = match this.run(id, &state, &req, &cancelled) {
+                Ok(manifest) => JobStatus::Complete(manifest),
+                Err(e) => {
+                    tracing::error!(job = %id, error = %e, "bulk export failed");
+                    JobStatus::Failed(e.to_string())
+                }
+            };
+            if cancelled.load(Ordering::Relaxed) {
+                let _ = std::fs::remove_dir_all(this.job_dir(id));
+                return;
+            }
+            this.set_status(id, status);
+        });
+
+        id
+    }
+
+    /// Jobs still running.
+    pub fn in_progress(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|j| matches!(j.status, JobStatus::InProgress { .. }))
+            .count()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn status(&self, id: Uuid) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().get(&id).map(|j| j.status.clone())
+    }
+
+    /// Cancel a running job or delete a finished one, along with its files.
+    pub fn delete(&self, id: Uuid) -> Result<(), AppError> {
+        let job = self
+            .jobs
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or(AppError::NotFound)?;
+        job.cancelled.store(true, Ordering::Relaxed);
+        if !matches!(job.status, JobStatus::InProgress { .. }) {
+            let _ = std::fs::remove_dir_all(self.job_dir(id));
+        }
+        Ok(())
+    }
+
+    /// Path of an output file, only if it is listed in the job's manifest.
+    pub fn file_path(&self, id: Uuid, name: &str) -> Result<PathBuf, AppError> {
+        let Some(JobStatus::Complete(manifest)) = self.status(id) else {
+            return Err(AppError::NotFound);
+        };
+        manifest
+            .output
+            .iter()
+            .find(|o| o.resource_type.file_name() == name)
+            .map(|o| self.job_dir(id).join(o.resource_type.file_name()))
+            .ok_or(AppError::NotFound)
+    }
+
+    fn job_dir(&self, id: Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn set_status(&self, id: Uuid, status: JobStatus) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            if !matches!(status, JobStatus::InProgress { .. }) {
+                job.finished = Some(Instant::now());
+            }
+            job.status = status;
+        }
+    }
+
+    fn run(
+        &self,
+        id: Uuid,
+        state: &AppState,
+        req: &ExportRequest,
+        cancelled: &AtomicBool,
+    ) -> std::io::Result<Manifest> {
+        let transaction_time = Utc::now();
+        let dir = self.job_dir(id);
+        std::fs::create_dir_all(&dir)?;
+
+        let wants = |t: ResourceType| req.types.contains(&t);
+        let mut observations = if wants(ResourceType::Observation) {
+            Some(NdjsonFile::create(&dir, ResourceType::Observation)?)
+        } else {
+            None
+        };
+
+        // Patients and devices are derived from the observations they appear in
+        let mut patients: BTreeSet<String> = BTreeSet::new();
+        let mut devices: BTreeMap<String, String> = BTreeMap::new();
+
+        let filter = ObsFilter {
+            to: Some(transaction_time),
+            ..Default::default()
+        };
+        // Only arrivals record when they were taken in. Restored readings
+        // came in before the store started, so a `since` before then takes
+        // everything, in time order; a later one goes by arrival
+        let since = req.since.filter(|since| *since > state.created_at());
+        let until = state.last_arrival();
+        let mut after = None;
+        let mut arrived = 0;
+        let mut next_page = || match since {
+            Some(since) => {
+                let page: Vec<_> = state
+                    .ingested_since(&filter, since, arrived, PAGE_SIZE)
+                    .into_iter()
+                    .take_while(|(n, _)| *n <= until)
+                    .collect();
+                if let Some((n, _)) = page.last() {
+                    arrived = *n;
+                }
+                page.into_iter().map(|(_, o)| o).collect()
+            }
+            None => {
+                let page = state.page(&filter, after, PAGE_SIZE);
+                if let Some(last) = page.last() {
+                    after = Some((last.reading.ts, last.id));
+                }
+                page
+            }
+        };
+        let mut seen = 0usize;
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(std::io::Error::other("cancelled"));
+            }
+            let page = next_page();
+            if page.is_empty() {
+                break;
+            }
+
+            for o in &page {
+                if let Some(f) = observations.as_mut() {
+                    let resource = fhir::to_fhir_observation(o).map_err(std::io::Error::other)?;
+                    f.write(&resource)?;
+                }
+                patients.insert(o.reading.patient_id.clone());
+                devices.insert(o.reading.device_id.clone(), o.reading.patient_id.clone());
+            }
+
+            seen += page.len();
+            self.set_status(
+                id,
+                JobStatus::InProgress {
+                    progress: format!("{} observations scanned", seen),
+                },
+            );
+        }
+
+        let mut files = Vec::new();
+        files.extend(observations);
+        if wants(ResourceType::Patient) {
+            let mut f = NdjsonFile::create(&dir, ResourceType::Patient)?;
+            for p in &patients {
+                f.write(&fhir::to_fhir_patient(p))?;
+            }
+            files.push(f);
+        }
+        if wants(ResourceType::Device) {
+            let mut f = NdjsonFile::create(&dir, ResourceType::Device)?;
+            for (d, p) in &devices {
+                f.write(&fhir::to_fhir_device(d, p))?;
+            }
+            files.push(f);
+        }
+
+        let mut output = Vec::new();
+        for f in files {
+            let (resource_type, count) = f.finish()?;
+            if count == 0 {
+                let _ = std::fs::remove_file(dir.join(resource_type.file_name()));
+                continue;
+            }
+            output.push(OutputFile {
+                resource_type,
+                url: format!(
+                    "{}/$export-files/{}/{}",
+                    req.base_url,
+                    id,
+                    resource_type.file_name()
+                ),
+                count,
+            });
+        }
+
+        Ok(Manifest {
+            transactionTime: transaction_time,
+            request: req.request_url.clone(),
+            // Files are served only with the admin token
+            requiresAccessToken: true,
+            output,
+            error: Vec::new(),
+        })
+    }
+}
+
+/// Periodically expire finished bulk export jobs.
+pub fn spawn_expiry(bulk: BulkExports, every: Duration) {
+    actix_rt::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop {
+            tick.tick().await;
+            let expired = bulk.expire(Instant::now());
+            if expired > 0 {
+                tracing::debug!(expired, "bulk export jobs expired");
+            }
+        }
+    });
+}
+
+struct NdjsonFile {
+    resource_type: ResourceType,
+    out: BufWriter<File>,
+    count: usize,
+}
+
+impl NdjsonFile {
+    fn create(dir: &Path, resource_type: ResourceType) -> std::io::Result<Self> {
+        Ok(Self {
+            resource_type,
+            out: BufWriter::new(File::create(dir.join(resource_type.file_name()))?),
+            count: 0,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, resource: &T) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.out, resource)?;
+        self.out.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<(ResourceType, usize)> {
+        self.out.flush()?;
+        Ok((self.resource_type, self.count))
+    }
+}
@@ -0,0 +1,359 @@
+use crate::audit::{Action, AuditLog, Entry, Outcome};
+use crate::domain::models::SensorReading;
+use crate::domain::store::AppState;
+use crate::errors::AppError;
+use crate::metrics::METRICS;
+use crate::pipeline::{self, Pipeline};
+use crate::ratelimit::{KeyKind, RateLimiter};
+use crate::settings::{Settings, TlsSettings};
+use crate::tls;
+use coap_lite::{
+    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType, ResponseType,
+};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+// RFC 7252 EXCHANGE_LIFETIME: how long a retransmitted confirmable message
+// may still arrive
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+// Bound on remembered exchanges; the oldest is dropped to make room
+const MAX_EXCHANGES: usize = 4_096;
+// Large enough for any reading; CoAP messages should fit one datagram anyway
+const MAX_DATAGRAM: usize = 1_152;
+
+/// UDP listener serving `POST /ingest` over CoAP for devices too constrained
+/// for HTTP and TLS.
+pub struct CoapServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl CoapServer {
+    pub async fn bind(addr: &str, ingest: CoapIngest) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let addr = socket.local_addr()?;
+        let task = actix_rt::spawn(serve(socket, ingest));
+        Ok(Self { addr, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop receiving. Every datagram is answered before the next is read,
+    /// so nothing is left half done.
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+/// What a CoAP reading goes through: the same token, device identity and
+/// rate limit checks as `POST /ingest`, then its validation and pipeline.
+pub struct CoapIngest {
+    state: Arc<AppState>,
+    pipeline: Option<Arc<Pipeline>>,
+    audit: Option<Arc<AuditLog>>,
+    limiter: Option<Arc<RateLimiter>>,
+    ingest_token: Option<String>,
+    tls: TlsSettings,
+}
+
+impl CoapIngest {
+    pub fn new(
+        state: Arc<AppState>,
+        pipeline: Option<Arc<Pipeline>>,
+        audit: Option<Arc<AuditLog>>,
+        limiter: Option<Arc<RateLimiter>>,
+        settings: &Settings,
+    ) -> Self {
+        Self {
+            state,
+            pipeline,
+            audit,
+            limiter,
+            ingest_token: settings.auth.ingest_token().map(str::to_string),
+            tls: settings.tls.clone(),
+        }
+    }
+
+    /// Answer one CoAP message. A confirmable request gets its result in a
+    /// piggybacked ACK: 2.01 once the reading is accepted, 4.01 without the
+    /// ingest token, 4.00 when it fails validation, 4.29 or 5.03 with Max-Age
+    /// while the device is over its rate limit or the ingest queue is full.
+    /// ACKs and resets need no answer; an empty confirmable message (a ping)
+    /// gets a reset.
+    pub fn respond(&self, request: &Packet) -> Option<Packet> {
+        let reply_type = match (request.header.get_type(), request.header.code) {
+            (MessageType::Confirmable, MessageClass::Empty) => {
+                let mut reset = Packet::new();
+                reset.header.set_type(MessageType::Reset);
+                reset.header.message_id = request.header.message_id;
+                return Some(reset);
+            }
+            (MessageType::Confirmable, MessageClass::Request(_)) => MessageType::Acknowledgement,
+            (MessageType::NonConfirmable, MessageClass::Request(_)) => MessageType::NonConfirmable,
+            _ => return None,
+        };
+
+        let (code, payload, max_age) = match self.handle(request) {
+            Ok(id) => (ResponseType::Created, id, None),
+            Err(rejection) => (rejection.code, rejection.message, rejection.max_age),
+        };
+        METRICS
+            .coap_requests_total
+            .with_label_values(&[&MessageClass::Response(code).to_string()])
+            .inc();
+
+        let mut response = Packet::new();
+        response.header.set_type(reply_type);
+        // Clients match replies by token; the request's id is unique per peer
+        response.header.message_id = request.header.message_id;
+        response.header.code = MessageClass::Response(code);
+        response.set_token(request.get_token().to_vec());
+        if let Some(secs) = max_age {
+            // A uint option: big-endian without leading zero bytes
+            let secs = u32::try_from(secs).unwrap_or(u32::MAX).to_be_bytes();
+            response.add_option(
+                CoapOption::MaxAge,
+                secs.into_iter().skip_while(|b| *b == 0).collect(),
+            );
+        }
+        response.set_content_format(ContentFormat::TextPlain);
+        response.payload = payload.into_bytes();
+        Some(response)
+    }
+
+    fn handle(&self, request: &Packet) -> Result<String, Rejection> {
+        let path: Vec<&[u8]> = request
+            .get_option(CoapOption::UriPath)
+            .map(|segments| segments.iter().map(|s| s.as_slice()).collect())
+            .unwrap_or_default();
+        if path != [b"ingest".as_slice()] {
+            return Err(Rejection::new(ResponseType::NotFound, "no such resource"));
+        }
+        if request.header.code != MessageClass::Request(RequestType::Post) {
+            return Err(Rejection::new(
+                ResponseType::MethodNotAllowed,
+                "POST a reading to /ingest",
+            ));
+        }
+        if !self.authorized(request) {
+            return Err(Rejection::new(
+                ResponseType::Unauthorized,
+                "missing or invalid token query option",
+            ));
+        }
+
+        let reading = decode(request)?;
+        // Always refused when client certificates are required: CoAP has none
+        tls::check_device_identity(None, &reading.device_id, &self.tls)?;
+        if let Some(limiter) = &self.limiter {
+            limiter.check(
+                "/ingest",
+                &[(KeyKind::Device, &reading.device_id)],
+                Instant::now(),
+            )?;
+        }
+        let obs = pipeline::admit(&self.state, reading)?;
+        let id = obs.id.to_string();
+        match &self.pipeline {
+            Some(pipeline) => pipeline.submit(obs)?,
+            None => {
+                pipeline::process(&self.state, &obs);
+            }
+        }
+        Ok(id)
+    }
+
+    // Same token as POST /ingest; CoAP has no headers, so devices send it as
+    // a `token=<token>` Uri-Query option (`coap://host/ingest?token=...`)
+    fn authorized(&self, request: &Packet) -> bool {
+        let Some(token) = &self.ingest_token else {
+            return true;
+        };
+        request
+            .get_option(CoapOption::UriQuery)
+            .is_some_and(|queries| {
+                queries
+                    .iter()
+                    .any(|query| query.strip_prefix(b"token=".as_slice()) == Some(token.as_bytes()))
+            })
+    }
+}
+
+// Responses by (peer, message id), so a retransmitted reading is answered
+// again instead of stored twice. Entries are kept in arrival order, so the
+// oldest is dropped first once the cache is full.
+#[derive(Default)]
+struct Exchanges {
+    replies: HashMap<(SocketAddr, u16), (Instant, Vec<u8>)>,
+    order: VecDeque<((SocketAddr, u16), Instant)>,
+}
+
+impl Exchanges {
+    fn get(&self, key: &(SocketAddr, u16), now: Instant) -> Option<&Vec<u8>> {
+        match self.replies.get(key) {
+            Some((at, bytes)) if now.duration_since(*at) < EXCHANGE_LIFETIME => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: (SocketAddr, u16), now: Instant, bytes: Vec<u8>) {
+        while let Some(&(oldest, at)) = self.order.front() {
+            if self.order.len() < MAX_EXCHANGES && now.duration_since(at) < EXCHANGE_LIFETIME {
+                break;
+            }
+            self.order.pop_front();
+            // Unless the same exchange id was answered again since
+            if self
+                .replies
+                .get(&oldest)
+                .is_some_and(|(current, _)| *current == at)
+            {
+                self.replies.remove(&oldest);
+            }
+        }
+        self.replies.insert(key, (now, bytes));
+        self.order.push_back((key, now));
+    }
+}
+
+async fn serve(socket: UdpSocket, ingest: CoapIngest) {
+    let mut exchanges = Exchanges::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                tracing::warn!("CoAP receive failed: {}", e);
+                continue;
+            }
+        };
+        let Ok(request) = Packet::from_bytes(&buf[..len]) else {
+            // Malformed messages are silently ignored (RFC 7252 4.2)
+            continue;
+        };
+
+        let now = Instant::now();
+        let key = (peer, request.header.message_id);
+        let confirmable = request.header.get_type() == MessageType::Confirmable;
+        let reply = match exchanges.get(&key, now) {
+            Some(bytes) if confirmable => Some(bytes.clone()),
+            _ => {
+                let span = tracing::info_span!("coap_ingest", %peer, message_id = request.header.message_id);
+                let response = span.in_scope(|| ingest.respond(&request));
+                if let (Some(audit), Some(response)) = (&ingest.audit, &response) {
+                    record(audit, peer, &request, response);
+                }
+                let bytes = response.and_then(|r| r.to_bytes().ok());
+                if let (Some(bytes), true) = (&bytes, confirmable) {
+                    exchanges.insert(key, now, bytes.clone());
+                }
+                bytes
+            }
+        };
+        if let Some(bytes) = reply {
+            if let Err(e) = socket.send_to(&bytes, peer).await {
+                tracing::warn!(%peer, "CoAP send failed: {}", e);
+            }
+        }
+    }
+}
+
+// A request answered with an error: its response code, a diagnostic payload
+// and, for temporary refusals, when to retry
+struct Rejection {
+    code: ResponseType,
+    message: String,
+    max_age: Option<u64>,
+}
+
+impl Rejection {
+    fn new(code: ResponseType, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            max_age: None,
+        }
+    }
+}
+
+impl From<AppError> for Rejection {
+    fn from(e: AppError) -> Self {
+        let (code, max_age) = match &e {
+            AppError::Validation(_) => (ResponseType::BadRequest, None),
+            AppError::Unauthorized => (ResponseType::Unauthorized, None),
+            AppError::Forbidden(_) => (ResponseType::Forbidden, None),
+            AppError::RateLimited { retry_after_secs } => {
+                (ResponseType::TooManyRequests, Some(*retry_after_secs))
+            }
+            AppError::Unavailable(_) => (ResponseType::ServiceUnavailable, Some(1)),
+            _ => (ResponseType::InternalServerError, None),
+        };
+        Self {
+            code,
+            message: e.to_string(),
+            max_age,
+        }
+    }
+}
+
+// Answered requests only; retransmissions and pings are not new accesses
+fn record(audit: &AuditLog, peer: SocketAddr, request: &Packet, response: &Packet) {
+    let MessageClass::Response(_) = response.header.code else {
+        return;
+    };
+    let code = response.header.code.to_string();
+    let outcome = match code.as_bytes().first() {
+        Some(b'2') => Outcome::Success,
+        Some(b'5') => Outcome::Error,
+        _ => Outcome::Failure,
+    };
+    audit.record(Entry {
+        action: Action::Create,
+        operation: "coap POST /ingest".to_string(),
+        actor: "coap".to_string(),
+        client_ip: Some(peer.ip().to_string()),
+        request_id: None,
+        query: None,
+        patients: decode(request).map(|r| r.patient_id).into_iter().collect(),
+        outcome,
+        detail: Some(format!("CoAP {}", code)),
+    });
+}
+
+fn decode(request: &Packet) -> Result<SensorReading, Rejection> {
+    let unsupported = || {
+        Rejection::new(
+            ResponseType::UnsupportedContentFormat,
+            "use CBOR (60) or JSON (50)",
+        )
+    };
+    let format = match request.get_content_format() {
+        Some(format) => format,
+        None if request.get_option(CoapOption::ContentFormat).is_none() => {
+            ContentFormat::ApplicationJSON
+        }
+        None => return Err(unsupported()),
+    };
+    match format {
+        ContentFormat::ApplicationCBOR => ciborium::from_reader(request.payload.as_slice())
+            .map_err(|e| {
+                Rejection::new(
+                    ResponseType::BadRequest,
+                    format!("invalid CBOR reading: {}", e),
+                )
+            }),
+        ContentFormat::ApplicationJSON => serde_json::from_slice(&request.payload).map_err(|e| {
+            Rejection::new(
+                ResponseType::BadRequest,
+                format!("invalid JSON reading: {}", e),
+            )
+        }),
+        _ => Err(unsupported()),
+    }
+}
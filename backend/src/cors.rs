@@ -0,0 +1,32 @@
+use crate::settings::CorsSettings;
+use actix_cors::Cors;
+
+/// Headers browsers may read from our responses (bulk export polling,
+/// downloads, rate limiting and request correlation).
+const EXPOSED_HEADERS: [&str; 5] = [
+    "content-location",
+    "content-disposition",
+    "retry-after",
+    "x-progress",
+    "x-request-id",
+];
+
+/// CORS middleware built from settings. Preflight requests are answered
+/// for every route, including `/ingest` and the FHIR endpoints; requests
+/// from origins not in the list are rejected.
+pub fn cors(settings: &CorsSettings) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(settings.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(settings.allowed_headers.iter().map(String::as_str))
+        .expose_headers(EXPOSED_HEADERS)
+        .max_age(settings.max_age_secs);
+
+    for origin in &settings.allowed_origins {
+        cors = if origin == "*" {
+            cors.allow_any_origin().send_wildcard()
+        } else {
+            cors.allowed_origin(origin)
+        };
+    }
+    cors
+}
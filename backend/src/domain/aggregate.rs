@@ -0,0 +1,154 @@
+use crate::domain::models::StoredObservation;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Summary of the readings that fall into one time bucket.
+#[derive(Debug, Clone, Serialize)]
+pub struct Bucket {
+    pub start: DateTime<Utc>,
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub last: f64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub percentiles: BTreeMap<String, f64>,
+}
+
+/// Running min/max/sum/count/last over one bucket. Rollups merge exactly,
+/// so coarser buckets can be built from finer ones and late readings can be
+/// folded in at any time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rollup {
+    pub start: DateTime<Utc>,
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub last: f64,
+    pub last_ts: DateTime<Utc>,
+}
+
+impl Rollup {
+    pub fn new(start: DateTime<Utc>, ts: DateTime<Utc>, v: f64) -> Self {
+        Self {
+            start,
+            count: 1,
+            min: v,
+            max: v,
+            sum: v,
+            last: v,
+            last_ts: ts,
+        }
+    }
+
+    pub fn add(&mut self, ts: DateTime<Utc>, v: f64) {
+        self.count += 1;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        self.sum += v;
+        if ts >= self.last_ts {
+            self.last = v;
+            self.last_ts = ts;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Rollup) {
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        if other.last_ts >= self.last_ts {
+            self.last = other.last;
+            self.last_ts = other.last_ts;
+        }
+    }
+
+    pub fn to_bucket(&self) -> Bucket {
+        Bucket {
+            start: self.start,
+            count: self.count,
+            min: self.min,
+            max: self.max,
+            mean: self.sum / self.count as f64,
+            last: self.last,
+            percentiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Start of the `width`-sized bucket containing `ts` (aligned to the Unix epoch).
+pub fn bucket_start(ts: DateTime<Utc>, width: Duration) -> DateTime<Utc> {
+    let w = width.num_milliseconds().max(1);
+    let ms = ts.timestamp_millis();
+    DateTime::from_timestamp_millis(ms - ms.rem_euclid(w)).unwrap_or(ts)
+}
+
+/// Group time-ordered readings into buckets of `width`, computing the
+/// requested percentiles (0..=100, nearest-rank) for each bucket.
+pub fn aggregate(
+    readings: &[&StoredObservation],
+    width: Duration,
+    percentiles: &[f64],
+) -> Vec<Bucket> {
+    let mut out: Vec<Bucket> = Vec::new();
+    let mut current: Option<Rollup> = None;
+    let mut values: Vec<f64> = Vec::new();
+
+    for o in readings {
+        let start = bucket_start(o.reading.ts, width);
+        let v = o.reading.value;
+
+        match current.as_mut() {
+            Some(r) if r.start == start => r.add(o.reading.ts, v),
+            _ => {
+                if let Some(r) = current.take() {
+                    out.push(finish(&r, &mut values, percentiles));
+                }
+                current = Some(Rollup::new(start, o.reading.ts, v));
+            }
+        }
+
+        if !percentiles.is_empty() {
+            values.push(v);
+        }
+    }
+
+    if let Some(r) = current {
+        out.push(finish(&r, &mut values, percentiles));
+    }
+    out
+}
+
+/// Re-bucket time-ordered rollups into buckets of `width`, which must be a
+/// multiple of the rollups' own width.
+pub fn merge_rollups(rollups: &[&Rollup], width: Duration) -> Vec<Bucket> {
+    let mut merged: Vec<Rollup> = Vec::new();
+    for r in rollups {
+        let start = bucket_start(r.start, width);
+        match merged.last_mut() {
+            Some(m) if m.start == start => m.merge(r),
+            _ => merged.push(Rollup {
+                start,
+                ..(*r).clone()
+            }),
+        }
+    }
+    merged.iter().map(Rollup::to_bucket).collect()
+}
+
+fn finish(r: &Rollup, values: &mut Vec<f64>, percentiles: &[f64]) -> Bucket {
+    let mut b = r.to_bucket();
+
+    if !percentiles.is_empty() {
+        values.sort_by(|a, b| a.total_cmp(b));
+        for p in percentiles {
+            let rank = ((p / 100.0) * values.len() as f64).ceil() as usize;
+            let v = values[rank.clamp(1, values.len()) - 1];
+            b.percentiles.insert(format!("p{}", p), v);
+        }
+        values.clear();
+    }
+    b
+}
@@ -0,0 +1,59 @@
+use crate::domain::models::{SignalCode, StoredObservation};
+use crate::settings::SignalRange;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertKind {
+    Low,
+    High,
+}
+
+impl AlertKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::Low => "low",
+            AlertKind::High => "high",
+        }
+    }
+}
+
+/// A stored reading that crossed one of its signal's alert thresholds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub id: Uuid,
+    pub observation_id: Uuid,
+    pub patient_id: String,
+    pub device_id: String,
+    pub code: SignalCode,
+    pub kind: AlertKind,
+    pub value: f64,
+    pub unit: String,
+    pub threshold: f64,
+    pub ts: DateTime<Utc>,
+}
+
+/// Check a reading against its thresholds; values exactly on a threshold
+/// do not alert.
+pub fn evaluate(range: &SignalRange, obs: &StoredObservation) -> Option<Alert> {
+    let r = &obs.reading;
+    let (kind, threshold) = match (range.alert_below, range.alert_above) {
+        (Some(t), _) if r.value < t => (AlertKind::Low, t),
+        (_, Some(t)) if r.value > t => (AlertKind::High, t),
+        _ => return None,
+    };
+    Some(Alert {
+        id: Uuid::new_v4(),
+        observation_id: obs.id,
+        patient_id: r.patient_id.clone(),
+        device_id: r.device_id.clone(),
+        code: r.code,
+        kind,
+        value: r.value,
+        unit: r.unit.clone(),
+        threshold,
+        ts: r.ts,
+    })
+}
@@ -1,2 +1,6 @@
+pub mod aggregate;
+pub mod alerts;
 pub mod models;
+pub mod retention;
+pub mod snapshot;
 pub mod store;
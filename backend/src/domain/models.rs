@@ -12,20 +12,41 @@ pub struct SensorReading {
     pub ts: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum SignalCode {
     HeartRate,
     BodyTemperature,
     StepsPerMinute,
+    /// Time between successive beats, in milliseconds
+    RrInterval,
 }
 
 impl SignalCode {
+    pub const ALL: [SignalCode; 4] = [
+        SignalCode::HeartRate,
+        SignalCode::BodyTemperature,
+        SignalCode::StepsPerMinute,
+        SignalCode::RrInterval,
+    ];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             SignalCode::HeartRate => "heart-rate",
             SignalCode::BodyTemperature => "body-temperature",
             SignalCode::StepsPerMinute => "steps-per-minute",
+            SignalCode::RrInterval => "rr-interval",
+        }
+    }
+
+    /// Inverse of `as_str`.
+    pub fn parse(code: &str) -> Option<Self> {
+        match code {
+            "heart-rate" => Some(SignalCode::HeartRate),
+            "body-temperature" => Some(SignalCode::BodyTemperature),
+            "steps-per-minute" => Some(SignalCode::StepsPerMinute),
+            "rr-interval" => Some(SignalCode::RrInterval),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,206 @@
+use crate::domain::aggregate::{bucket_start, Rollup};
+use crate::domain::models::StoredObservation;
+use crate::domain::store::{AppState, ObsFilter, SeriesKey};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// How long each resolution is kept; `None` keeps data until capacity evicts it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    pub raw: Option<Duration>,
+    pub minute: Option<Duration>,
+    pub hour: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn days(raw: i64, minute: i64, hour: i64) -> Self {
+        Self {
+            raw: Some(Duration::days(raw)),
+            minute: Some(Duration::days(minute)),
+            hour: Some(Duration::days(hour)),
+        }
+    }
+
+    /// Oldest timestamp still held at `res`, if that resolution expires at all.
+    pub fn horizon(&self, res: Resolution, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let keep = match res {
+            Resolution::Raw => self.raw,
+            Resolution::Minute => self.minute,
+            Resolution::Hour => self.hour,
+        };
+        keep.map(|k| now - k)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    pub fn width(&self) -> Duration {
+        match self {
+            Resolution::Raw => Duration::zero(),
+            Resolution::Minute => Duration::minutes(1),
+            Resolution::Hour => Duration::hours(1),
+        }
+    }
+
+    /// Resolution to serve `width`-sized buckets starting at `from`: the
+    /// coarsest one that divides the bucket exactly and still covers `from`,
+    /// falling back to whatever resolution still holds data that old.
+    /// Percentiles can only come from raw readings.
+    pub fn choose(
+        policy: &RetentionPolicy,
+        width: Duration,
+        from: Option<DateTime<Utc>>,
+        needs_raw: bool,
+        now: DateTime<Utc>,
+    ) -> Resolution {
+        if needs_raw {
+            return Resolution::Raw;
+        }
+        let covers = |res: Resolution| match (from, policy.horizon(res, now)) {
+            (Some(f), Some(h)) => f >= h,
+            _ => true,
+        };
+        let divides =
+            |res: Resolution| width.num_milliseconds() % res.width().num_milliseconds() == 0;
+
+        for res in [Resolution::Hour, Resolution::Minute] {
+            if divides(res) && covers(res) {
+                return res;
+            }
+        }
+        [Resolution::Raw, Resolution::Minute]
+            .into_iter()
+            .find(|r| covers(*r))
+            .unwrap_or(Resolution::Hour)
+    }
+
+    /// Smallest multiple of this resolution's width that is at least `width`.
+    pub fn fit(&self, width: Duration) -> Duration {
+        let step = self.width().num_milliseconds();
+        if step == 0 {
+            return width;
+        }
+        let ms = width.num_milliseconds();
+        Duration::milliseconds(((ms + step - 1) / step).max(1) * step)
+    }
+}
+
+/// Per-series 1-minute and 1-hour rollups, updated as readings arrive.
+#[derive(Debug, Default)]
+pub struct Rollups {
+    minute: HashMap<SeriesKey, BTreeMap<DateTime<Utc>, Rollup>>,
+    hour: HashMap<SeriesKey, BTreeMap<DateTime<Utc>, Rollup>>,
+}
+
+impl Rollups {
+    pub fn record(&mut self, obs: &StoredObservation) {
+        let key = SeriesKey::of(&obs.reading);
+        let (ts, v) = (obs.reading.ts, obs.reading.value);
+
+        for (res, index) in [
+            (Resolution::Minute, &mut self.minute),
+            (Resolution::Hour, &mut self.hour),
+        ] {
+            let start = bucket_start(ts, res.width());
+            index
+                .entry(key.clone())
+                .or_default()
+                .entry(start)
+                .and_modify(|r| r.add(ts, v))
+                .or_insert_with(|| Rollup::new(start, ts, v));
+        }
+    }
+
+    /// Every rollup with its resolution and series.
+    pub fn iter(&self) -> impl Iterator<Item = (Resolution, &SeriesKey, &Rollup)> {
+        [
+            (Resolution::Minute, &self.minute),
+            (Resolution::Hour, &self.hour),
+        ]
+        .into_iter()
+        .flat_map(|(res, index)| {
+            index
+                .iter()
+                .flat_map(move |(key, series)| series.values().map(move |r| (res, key, r)))
+        })
+    }
+
+    /// Put back a rollup taken from `iter`, replacing that bucket.
+    pub fn restore(&mut self, res: Resolution, key: SeriesKey, rollup: Rollup) {
+        let index = match res {
+            Resolution::Minute => &mut self.minute,
+            Resolution::Hour => &mut self.hour,
+            Resolution::Raw => return,
+        };
+        index.entry(key).or_default().insert(rollup.start, rollup);
+    }
+
+    /// Rollups at `res` matching `filter`, ordered by bucket start.
+    pub fn range(&self, res: Resolution, filter: &ObsFilter) -> Vec<&Rollup> {
+        let index = match res {
+            Resolution::Minute => &self.minute,
+            Resolution::Hour => &self.hour,
+            Resolution::Raw => return Vec::new(),
+        };
+        if filter.is_inverted() {
+            return Vec::new();
+        }
+        // A bucket overlaps the range if it starts before `to` and after `from`'s bucket
+        let from = filter
+            .from
+            .map(|f| bucket_start(f, res.width()))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let to = filter.to.unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+        let mut out: Vec<&Rollup> = index
+            .iter()
+            .filter(|(key, _)| filter.matches_series(key))
+            .flat_map(|(_, series)| series.range(from..=to).map(|(_, r)| r))
+            .collect();
+        out.sort_by_key(|r| r.start);
+        out
+    }
+
+    /// Drop rollups whose bucket started before each resolution's horizon.
+    pub fn expire(&mut self, policy: &RetentionPolicy, now: DateTime<Utc>) -> usize {
+        let mut expired = 0;
+        for (res, index) in [
+            (Resolution::Minute, &mut self.minute),
+            (Resolution::Hour, &mut self.hour),
+        ] {
+            let Some(horizon) = policy.horizon(res, now) else {
+                continue;
+            };
+            for series in index.values_mut() {
+                let keep = series.split_off(&horizon);
+                expired += series.len();
+                *series = keep;
+            }
+            index.retain(|_, s| !s.is_empty());
+        }
+        expired
+    }
+}
+
+/// Periodically expire raw readings and rollups past their retention.
+pub fn spawn_compactor(state: Arc<AppState>, every: std::time::Duration) {
+    actix_rt::spawn(async move {
+        let mut tick = tokio::time::interval(every);
+        loop {
+            tick.tick().await;
+            let expired = state.compact(Utc::now());
+            if expired > 0 {
+                tracing::debug!(expired, "retention compaction");
+            }
+        }
+    });
+}
@@ -0,0 +1,97 @@
+use crate::domain::aggregate::Rollup;
+use crate::domain::models::{SignalCode, StoredObservation};
+use crate::domain::retention::Resolution;
+use crate::domain::store::{AppState, ObsFilter, SeriesKey};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// A rollup bucket as written to the snapshot, ahead of the raw readings
+#[derive(Serialize, Deserialize)]
+struct RollupLine {
+    rollup: Resolution,
+    patient_id: String,
+    code: SignalCode,
+    #[serde(flatten)]
+    data: Rollup,
+}
+
+/// Write the minute and hour rollups, then every raw reading oldest first,
+/// to `path` as NDJSON. The file is written next to `path` and renamed
+/// into place, so a crash mid-write leaves the previous snapshot intact.
+/// Returns the number of raw readings written.
+pub fn save(state: &AppState, path: &Path) -> std::io::Result<usize> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    for (rollup, key, data) in state.rollups() {
+        let line = RollupLine {
+            rollup,
+            patient_id: key.patient_id,
+            code: key.code,
+            data,
+        };
+        serde_json::to_writer(&mut out, &line)?;
+        out.write_all(b"\n")?;
+    }
+    let readings = state.range(&ObsFilter::default());
+    for obs in &readings {
+        serde_json::to_writer(&mut out, obs)?;
+        out.write_all(b"\n")?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(readings.len())
+}
+
+/// Load a snapshot written by [`save`]. A missing file is an empty store.
+/// Snapshots without rollups (from older versions) have them rebuilt from
+/// the restored readings. Retention and capacity then apply as usual.
+/// Returns the number of raw readings loaded.
+pub fn load(state: &AppState, path: &Path) -> std::io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut loaded = 0;
+    let mut has_rollups = false;
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |e: serde_json::Error| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), n + 1, e),
+            )
+        };
+        let value: serde_json::Value = serde_json::from_str(&line).map_err(invalid)?;
+        if value.get("rollup").is_some() {
+            let line: RollupLine = serde_json::from_value(value).map_err(invalid)?;
+            let key = SeriesKey {
+                patient_id: line.patient_id,
+                code: line.code,
+            };
+            state.restore_rollup(line.rollup, key, line.data);
+            has_rollups = true;
+            continue;
+        }
+        let obs: StoredObservation = serde_json::from_value(value).map_err(invalid)?;
+        if has_rollups {
+            state.restore_reading(obs);
+        } else {
+            state.restore(obs);
+        }
+        loaded += 1;
+    }
+
+    state.compact(Utc::now());
+    Ok(loaded)
+}
@@ -1,16 +1,254 @@
+use crate::domain::aggregate::Rollup;
 use crate::domain::models::{SensorReading, SignalCode, StoredObservation};
+use crate::domain::retention::{Resolution, RetentionPolicy, Rollups};
 use crate::errors::AppError;
 use crate::fhir;
+use crate::metrics::METRICS;
+use crate::settings::{Settings, SignalRanges};
+use crate::ws::LiveEvent;
 use chrono::{DateTime, Utc};
 use serde::Serialize;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::ops::{Bound, Deref, DerefMut};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use uuid::Uuid;
 
 const MAX_BUFFER: usize = 2_000;
+// Series are spread over this many independently locked shards
+const SHARDS: usize = 16;
 
+/// One time series: a single signal for a single patient.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SeriesKey {
+    pub patient_id: String,
+    pub code: SignalCode,
+}
+
+impl SeriesKey {
+    pub fn of(r: &SensorReading) -> Self {
+        Self {
+            patient_id: r.patient_id.clone(),
+            code: r.code,
+        }
+    }
+}
+
+/// Which readings a query selects; `None` fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct ObsFilter {
+    pub patient_id: Option<String>,
+    pub code: Option<SignalCode>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl ObsFilter {
+    pub(crate) fn matches_series(&self, key: &SeriesKey) -> bool {
+        self.code.is_none_or(|c| c == key.code)
+            && self
+                .patient_id
+                .as_deref()
+                .is_none_or(|p| p == key.patient_id)
+    }
+
+    /// True when `from` is after `to`, so nothing can match.
+    pub fn is_inverted(&self) -> bool {
+        matches!((self.from, self.to), (Some(f), Some(t)) if f > t)
+    }
+
+    fn bounds(&self) -> (Bound<TimeKey>, Bound<TimeKey>) {
+        let lower = self
+            .from
+            .map(|f| Bound::Included((f, Uuid::nil())))
+            .unwrap_or(Bound::Unbounded);
+        let upper = self
+            .to
+            .map(|t| Bound::Included((t, Uuid::max())))
+            .unwrap_or(Bound::Unbounded);
+        (lower, upper)
+    }
+}
+
+/// Readings are ordered by measurement time; the id breaks ties between
+/// readings that share a timestamp. Also used as a paging cursor.
+pub type TimeKey = (DateTime<Utc>, Uuid);
+
+/// When a device was last heard from.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DeviceActivity {
+    /// When the backend accepted its latest reading
+    pub last_ingest: DateTime<Utc>,
+    /// Measurement time of that reading
+    pub last_reading_ts: DateTime<Utc>,
+}
+
+impl DeviceActivity {
+    /// How far the latest reading arrived behind its measurement time.
+    pub fn lag(&self) -> chrono::Duration {
+        self.last_ingest - self.last_reading_ts
+    }
+}
+
+/// One shard of the store: the series that hash to it, with their own
+/// time index, rollups and device activity.
+#[derive(Debug, Default)]
+struct Shard {
+    // Per-series indexes, each ordered by timestamp (late readings land in place)
+    series: HashMap<SeriesKey, BTreeMap<TimeKey, StoredObservation>>,
+    // Timestamp order across the shard's series, used for eviction, with
+    // each reading's arrival number if it has one
+    by_time: BTreeMap<TimeKey, (SeriesKey, Option<u64>)>,
+    // Arrival order of the readings inserted since startup, with when each
+    // was taken in
+    arrivals: BTreeMap<u64, (TimeKey, DateTime<Utc>)>,
+    // Where each reading sits in the time index, for lookups by id
+    ids: HashMap<Uuid, TimeKey>,
+    rollups: Rollups,
+    devices: HashMap<String, DeviceActivity>,
+}
+
+impl Shard {
+    // True if the reading was not stored yet. One stored again (e.g. a
+    // retried ingest) keeps its first arrival and is not counted twice in
+    // the rollups, which only fold it in when `rollup` is set.
+    fn insert(
+        &mut self,
+        obs: StoredObservation,
+        arrival: Option<(u64, DateTime<Utc>)>,
+        rollup: bool,
+    ) -> bool {
+        let key = SeriesKey::of(&obs.reading);
+        let tk = (obs.reading.ts, obs.id);
+        let previous = self.by_time.get(&tk).map(|(_, n)| *n);
+        let arrival = match previous.flatten() {
+            Some(n) => Some(n),
+            None => arrival.map(|(n, at)| {
+                self.arrivals.insert(n, (tk, at));
+                n
+            }),
+        };
+        if rollup && previous.is_none() {
+            self.rollups.record(&obs);
+        }
+        self.by_time.insert(tk, (key.clone(), arrival));
+        self.ids.insert(obs.id, tk);
+        self.series.entry(key).or_default().insert(tk, obs);
+        previous.is_none()
+    }
+
+    fn remove(&mut self, tk: &TimeKey) -> bool {
+        let Some((key, arrival)) = self.by_time.remove(tk) else {
+            return false;
+        };
+        if let Some(n) = arrival {
+            self.arrivals.remove(&n);
+        }
+        self.ids.remove(&tk.1);
+        if let Some(s) = self.series.get_mut(&key) {
+            s.remove(tk);
+            if s.is_empty() {
+                self.series.remove(&key);
+            }
+        }
+        true
+    }
+
+    fn oldest(&self) -> Option<TimeKey> {
+        self.by_time.first_key_value().map(|(tk, _)| *tk)
+    }
+
+    fn expire(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let mut expired = 0;
+        while let Some(tk) = self.oldest() {
+            if tk.0 >= cutoff {
+                break;
+            }
+            self.remove(&tk);
+            expired += 1;
+        }
+        expired
+    }
+}
+
+/// A shard plus the timestamp (µs) of its oldest reading, kept outside the
+/// lock so capacity eviction can find the globally oldest without locking
+/// every shard.
+#[derive(Debug)]
+struct Slot {
+    shard: RwLock<Shard>,
+    oldest: AtomicI64,
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            shard: RwLock::default(),
+            oldest: AtomicI64::new(i64::MAX),
+        }
+    }
+
+    // A panicking writer poisons its shard; keep serving, and let liveness
+    // report it (see `AppState::is_poisoned`).
+    fn read(&self) -> RwLockReadGuard<'_, Shard> {
+        self.shard.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> ShardMut<'_> {
+        ShardMut {
+            guard: self.shard.write().unwrap_or_else(PoisonError::into_inner),
+            oldest: &self.oldest,
+        }
+    }
+}
+
+/// Write guard that refreshes the slot's oldest timestamp on release.
+struct ShardMut<'a> {
+    guard: RwLockWriteGuard<'a, Shard>,
+    oldest: &'a AtomicI64,
+}
+
+impl Deref for ShardMut<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        &self.guard
+    }
+}
+
+impl DerefMut for ShardMut<'_> {
+    fn deref_mut(&mut self) -> &mut Shard {
+        &mut self.guard
+    }
+}
+
+impl Drop for ShardMut<'_> {
+    fn drop(&mut self) {
+        let oldest = self
+            .guard
+            .oldest()
+            .map_or(i64::MAX, |tk| tk.0.timestamp_micros());
+        self.oldest.store(oldest, Ordering::SeqCst);
+    }
+}
+
+/// The observation store. Series are sharded by (patient, code), each shard
+/// behind its own `RwLock`: ingest write-locks one shard, queries read-lock
+/// only the shards they touch, and nothing is held while broadcasting.
 #[derive(Debug)]
 pub struct AppState {
-    pub observations: VecDeque<StoredObservation>,
+    shards: Box<[Slot]>,
+    hasher: RandomState,
+    len: AtomicUsize,
+    // The last arrival number handed out. Held while the reading goes into
+    // its shard, so arrivals become visible in order across shards
+    arrivals: Mutex<u64>,
+    capacity: usize,
+    policy: RetentionPolicy,
+    signals: SignalRanges,
+    created_at: DateTime<Utc>,
+    last_compaction: Mutex<Option<DateTime<Utc>>>,
     pub ws_hub: crate::ws::Hub,
     pub demo_patient_id: String,
     pub demo_device_id: String,
@@ -18,86 +256,508 @@ pub struct AppState {
 
 impl AppState {
     pub fn new_demo() -> Self {
+        Self::with_limits(MAX_BUFFER, RetentionPolicy::days(7, 30, 365))
+    }
+
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut state = Self::with_limits(settings.store.capacity, settings.store.retention());
+        state.signals = settings.signals.clone();
+        state
+    }
+
+    /// Store holding at most `capacity` raw readings, with rollups and raw
+    /// data expiring according to `policy`.
+    pub fn with_limits(capacity: usize, policy: RetentionPolicy) -> Self {
         Self {
-            observations: VecDeque::with_capacity(MAX_BUFFER),
+            shards: (0..SHARDS).map(|_| Slot::new()).collect(),
+            hasher: RandomState::new(),
+            len: AtomicUsize::new(0),
+            arrivals: Mutex::new(0),
+            capacity,
+            policy,
+            signals: SignalRanges::default(),
+            created_at: Utc::now(),
+            last_compaction: Mutex::new(None),
             ws_hub: crate::ws::Hub::new(),
             demo_patient_id: "patient-001".to_string(),
             demo_device_id: "device-001".to_string(),
         }
     }
 
+    #[tracing::instrument(name = "validate", skip_all)]
     pub fn validate(&self, r: &SensorReading) -> Result<(), AppError> {
         if r.device_id.trim().is_empty() || r.patient_id.trim().is_empty() {
-            return Err(AppError::Validation("device_id and patient_id are required".into()));
+            return Err(AppError::Validation(
+                "device_id and patient_id are required".into(),
+            ));
         }
 
-        // Simple range validation (extend for higher grade)
-        match r.code {
-            SignalCode::HeartRate => {
-                if !(20.0..=240.0).contains(&r.value) {
-                    return Err(AppError::Validation("heart-rate out of range (20..240)".into()));
-                }
-                if r.unit != "beats/min" && r.unit != "bpm" {
-                    return Err(AppError::Validation("heart-rate unit should be 'beats/min' or 'bpm'".into()));
-                }
-            }
-            SignalCode::BodyTemperature => {
-                if !(30.0..=45.0).contains(&r.value) {
-                    return Err(AppError::Validation("body-temperature out of range (30..45)".into()));
-                }
-                if r.unit != "C" && r.unit != "°C" {
-                    return Err(AppError::Validation("body-temperature unit should be 'C' or '°C'".into()));
-                }
-            }
-            SignalCode::StepsPerMinute => {
-                if !(0.0..=400.0).contains(&r.value) {
-                    return Err(AppError::Validation("steps-per-minute out of range (0..400)".into()));
-                }
-                if r.unit != "steps/min" {
-                    return Err(AppError::Validation("steps-per-minute unit should be 'steps/min'".into()));
-                }
-            }
+        // Range and unit checks come from settings.signals
+        let code = r.code.as_str();
+        let range = self.signals.get(r.code);
+        if !(range.min..=range.max).contains(&r.value) {
+            return Err(AppError::Validation(format!(
+                "{} out of range ({}..{})",
+                code, range.min, range.max
+            )));
+        }
+        if !range.units.contains(&r.unit) {
+            let units: Vec<String> = range.units.iter().map(|u| format!("'{}'", u)).collect();
+            return Err(AppError::Validation(format!(
+                "{} unit should be {}",
+                code,
+                units.join(" or ")
+            )));
         }
         Ok(())
     }
 
-    pub fn add_reading(&mut self, reading: SensorReading) -> StoredObservation {
+    pub fn add_reading(&self, reading: SensorReading) -> StoredObservation {
         let obs = StoredObservation {
             id: Uuid::new_v4(),
             reading,
         };
+        self.insert(obs.clone());
+        self.broadcast(&obs);
+        obs
+    }
 
-        if self.observations.len() >= MAX_BUFFER {
-            self.observations.pop_front();
+    /// Store an already-identified reading, applying retention and capacity.
+    /// Does not notify subscribers; see `broadcast`.
+    pub fn insert(&self, obs: StoredObservation) {
+        tracing::info_span!("store_insert", observation_id = %obs.id).in_scope(|| {
+            let now = Utc::now();
+            {
+                let mut last = self.arrivals.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut shard = self.slot(&obs.reading.patient_id, obs.reading.code).write();
+                shard.devices.insert(
+                    obs.reading.device_id.clone(),
+                    DeviceActivity {
+                        last_ingest: now,
+                        last_reading_ts: obs.reading.ts,
+                    },
+                );
+                *last += 1;
+                if shard.insert(obs, Some((*last, now)), true) {
+                    self.len.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            let expired = self
+                .policy
+                .raw
+                .map(|keep| self.expire_raw(now - keep))
+                .unwrap_or(0);
+            let evicted = expired + self.evict_over_capacity();
+            METRICS.store_evictions_total.inc_by(evicted as u64);
+            METRICS.store_observations.set(self.len() as i64);
+        });
+    }
+
+    /// Send a stored reading to websocket subscribers as a FHIR Observation,
+    /// and publish it to in-process consumers as is. Must not be called
+    /// while holding a store lock.
+    pub fn broadcast(&self, obs: &StoredObservation) {
+        tracing::info_span!("broadcast").in_scope(|| {
+            if let Ok(fhir_obs) = fhir::to_fhir_observation(obs) {
+                self.ws_hub
+                    .broadcast_scoped(&fhir_obs, &obs.reading.patient_id, obs.reading.code);
+            }
+            self.ws_hub.publish(LiveEvent::Observation(obs.clone()));
+        });
+    }
+
+    /// Re-insert a previously stored reading (e.g. from a snapshot) without
+    /// broadcasting it, folding it into the rollups. Call `evict` once done.
+    pub fn restore(&self, obs: StoredObservation) {
+        let mut shard = self.slot(&obs.reading.patient_id, obs.reading.code).write();
+        if shard.insert(obs, None, true) {
+            self.len.fetch_add(1, Ordering::SeqCst);
         }
-        self.observations.push_back(obs.clone());
+    }
 
-        // Broadcast FHIR Observation to websocket subscribers
-        if let Ok(fhir_obs) = fhir::to_fhir_observation(&obs) {
-            self.ws_hub.broadcast_json(&fhir_obs);
+    /// Like `restore`, for readings whose rollups are restored separately
+    /// (see `restore_rollup`).
+    pub fn restore_reading(&self, obs: StoredObservation) {
+        if self
+            .slot(&obs.reading.patient_id, obs.reading.code)
+            .write()
+            .insert(obs, None, false)
+        {
+            self.len.fetch_add(1, Ordering::SeqCst);
         }
+    }
 
-        obs
+    /// Put back a rollup taken from `rollups`.
+    pub fn restore_rollup(&self, res: Resolution, key: SeriesKey, rollup: Rollup) {
+        self.slot(&key.patient_id, key.code)
+            .write()
+            .rollups
+            .restore(res, key, rollup);
     }
 
-    pub fn query(
+    /// Every minute and hour rollup with its series, e.g. for a snapshot.
+    pub fn rollups(&self) -> Vec<(Resolution, SeriesKey, Rollup)> {
+        let mut out = Vec::new();
+        for slot in self.shards.iter() {
+            out.extend(
+                slot.read()
+                    .rollups
+                    .iter()
+                    .map(|(res, key, r)| (res, key.clone(), r.clone())),
+            );
+        }
+        out
+    }
+
+    /// Drop readings past the retention window, then the oldest-timestamped
+    /// readings until the store is back within capacity.
+    pub fn evict(&self, now: DateTime<Utc>) -> usize {
+        let mut evicted = self
+            .policy
+            .raw
+            .map(|keep| self.expire_raw(now - keep))
+            .unwrap_or(0);
+        evicted += self.evict_over_capacity();
+
+        METRICS.store_evictions_total.inc_by(evicted as u64);
+        evicted
+    }
+
+    // Drop raw readings measured before `cutoff`, locking only the shards
+    // whose oldest reading is that old.
+    fn expire_raw(&self, cutoff: DateTime<Utc>) -> usize {
+        let cutoff_micros = cutoff.timestamp_micros();
+        let mut expired = 0;
+        for slot in self.shards.iter() {
+            if slot.oldest.load(Ordering::SeqCst) >= cutoff_micros {
+                continue;
+            }
+            let n = slot.write().expire(cutoff);
+            self.len.fetch_sub(n, Ordering::SeqCst);
+            expired += n;
+        }
+        expired
+    }
+
+    // Remove the globally oldest reading until within capacity. Each round
+    // locks the shard with the oldest reading, then claims one reading of
+    // the overflow from `len` before removing it: concurrent writers never
+    // evict more than the overflow, and `len` only drops for readings that
+    // were actually removed.
+    fn evict_over_capacity(&self) -> usize {
+        let mut evicted = 0;
+        while self.len() > self.capacity {
+            let Some(slot) = self
+                .shards
+                .iter()
+                .filter(|slot| slot.oldest.load(Ordering::SeqCst) != i64::MAX)
+                .min_by_key(|slot| slot.oldest.load(Ordering::SeqCst))
+            else {
+                break;
+            };
+            let mut shard = slot.write();
+            // Another writer may have emptied it meanwhile; then look again
+            let Some(tk) = shard.oldest() else {
+                continue;
+            };
+            let claimed = self
+                .len
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                    (len > self.capacity).then(|| len - 1)
+                });
+            if claimed.is_err() {
+                break;
+            }
+            shard.remove(&tk);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Expire raw readings and rollups past their retention.
+    pub fn compact(&self, now: DateTime<Utc>) -> usize {
+        let mut expired = self.evict(now);
+        for slot in self.shards.iter() {
+            expired += slot.write().rollups.expire(&self.policy, now);
+        }
+        METRICS.store_observations.set(self.len() as i64);
+        *self.last_compaction.lock().unwrap() = Some(now);
+        expired
+    }
+
+    /// When retention compaction last ran, if it has.
+    pub fn last_compaction(&self) -> Option<DateTime<Utc>> {
+        *self.last_compaction.lock().unwrap()
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Latest ingest per device since startup.
+    pub fn devices(&self) -> HashMap<String, DeviceActivity> {
+        let mut out: HashMap<String, DeviceActivity> = HashMap::new();
+        for slot in self.shards.iter() {
+            for (id, d) in &slot.read().devices {
+                out.entry(id.clone())
+                    .and_modify(|cur| {
+                        if d.last_ingest > cur.last_ingest {
+                            *cur = *d;
+                        }
+                    })
+                    .or_insert(*d);
+            }
+        }
+        out
+    }
+
+    /// True if a writer panicked while holding a shard lock, leaving that
+    /// shard possibly half-updated.
+    pub fn is_poisoned(&self) -> bool {
+        self.shards.iter().any(|s| s.shard.is_poisoned())
+    }
+
+    /// Panic while holding a shard's write lock, poisoning it. Only for
+    /// tests of the liveness failure path.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn poison_shard(&self) {
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = self.shards[0].shard.write();
+            panic!("poisoning a store shard");
+        }));
+    }
+
+    pub fn signals(&self) -> &SignalRanges {
+        &self.signals
+    }
+
+    pub fn policy(&self) -> &RetentionPolicy {
+        &self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Latest `limit` readings matching `filter`, oldest first.
+    pub fn query(&self, filter: &ObsFilter, limit: usize) -> Vec<StoredObservation> {
+        if filter.is_inverted() {
+            return Vec::new();
+        }
+        let bounds = filter.bounds();
+
+        // Each series is already sorted, so only its newest `limit` readings
+        // in range can make the cut; each shard clones at most `limit`.
+        let mut out: Vec<StoredObservation> = Vec::new();
+        for slot in self.shards_for(filter) {
+            let shard = slot.read();
+            let mut candidates: Vec<&StoredObservation> = Vec::new();
+            for (key, series) in &shard.series {
+                if !filter.matches_series(key) {
+                    continue;
+                }
+                candidates.extend(series.range(bounds).rev().take(limit).map(|(_, o)| o));
+            }
+            candidates.sort_by_key(|o| (o.reading.ts, o.id));
+            let skip = candidates.len().saturating_sub(limit);
+            out.extend(candidates.into_iter().skip(skip).cloned());
+        }
+
+        out.sort_by_key(|o| (o.reading.ts, o.id));
+        let skip = out.len().saturating_sub(limit);
+        out.drain(..skip);
+        out
+    }
+
+    /// Up to `limit` readings matching `filter` that come strictly after
+    /// `after`, oldest first. Lets callers walk large ranges page by page.
+    pub fn page(
         &self,
-        code: Option<SignalCode>,
+        filter: &ObsFilter,
+        after: Option<TimeKey>,
         limit: usize,
-        from: Option<DateTime<Utc>>,
-        to: Option<DateTime<Utc>>,
     ) -> Vec<StoredObservation> {
-        let mut out: Vec<StoredObservation> = self
-            .observations
-            .iter()
-            .filter(|o| code.map(|c| o.reading.code as u8 == c as u8).unwrap_or(true))
-            .filter(|o| from.map(|f| o.reading.ts >= f).unwrap_or(true))
-            .filter(|o| to.map(|t| o.reading.ts <= t).unwrap_or(true))
-            .cloned()
-            .collect();
-
-        out.sort_by_key(|o| o.reading.ts);
-        out.into_iter().rev().take(limit).collect::<Vec<_>>().into_iter().rev().collect()
+        if filter.is_inverted() {
+            return Vec::new();
+        }
+        let (lower, upper) = filter.bounds();
+        let lower = after.map(Bound::Excluded).unwrap_or(lower);
+
+        let mut out: Vec<StoredObservation> = Vec::new();
+        for slot in self.shards_for(filter) {
+            let shard = slot.read();
+            let mut candidates: Vec<&StoredObservation> = Vec::new();
+            for (key, series) in &shard.series {
+                if !filter.matches_series(key) {
+                    continue;
+                }
+                candidates.extend(series.range((lower, upper)).take(limit).map(|(_, o)| o));
+            }
+            candidates.sort_by_key(|o| (o.reading.ts, o.id));
+            out.extend(candidates.into_iter().take(limit).cloned());
+        }
+
+        out.sort_by_key(|o| (o.reading.ts, o.id));
+        out.truncate(limit);
+        out
+    }
+
+    /// Arrival number of a stored reading: its place among the readings
+    /// `insert`ed since startup, counting from 1. None for restored readings
+    /// and ones no longer stored.
+    pub fn arrival(&self, patient_id: &str, code: SignalCode, tk: TimeKey) -> Option<u64> {
+        self.slot(patient_id, code)
+            .read()
+            .by_time
+            .get(&tk)
+            .and_then(|(_, arrival)| *arrival)
+    }
+
+    /// The arrival number of the last reading `insert`ed, 0 before any.
+    pub fn last_arrival(&self) -> u64 {
+        *self.arrivals.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Up to `limit` stored readings matching the patient and code of
+    /// `filter` that arrived after arrival number `after`, in arrival order
+    /// with their numbers. Unlike `page`, this also finds late readings
+    /// measured before the last one seen. Arrivals show up in order: once
+    /// one is returned, no earlier one can appear later.
+    pub fn arrived_after(
+        &self,
+        filter: &ObsFilter,
+        after: u64,
+        limit: usize,
+    ) -> Vec<(u64, StoredObservation)> {
+        self.arrivals_after(filter, after, None, limit)
+    }
+
+    /// Like `arrived_after`, for readings taken in at or after `since`.
+    /// Restored readings were taken in before `created_at` and never match.
+    pub fn ingested_since(
+        &self,
+        filter: &ObsFilter,
+        since: DateTime<Utc>,
+        after: u64,
+        limit: usize,
+    ) -> Vec<(u64, StoredObservation)> {
+        self.arrivals_after(filter, after, Some(since), limit)
+    }
+
+    fn arrivals_after(
+        &self,
+        filter: &ObsFilter,
+        after: u64,
+        since: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> Vec<(u64, StoredObservation)> {
+        // Shards are read one at a time; what arrives meanwhile waits for
+        // the next call, so it cannot land behind something returned now
+        let last = self.last_arrival();
+        let mut out: Vec<(u64, StoredObservation)> = Vec::new();
+        if after >= last {
+            return out;
+        }
+        for slot in self.shards_for(filter) {
+            let shard = slot.read();
+            let matching = shard
+                .arrivals
+                .range(after + 1..=last)
+                .filter(|(_, (_, at))| since.is_none_or(|since| *at >= since))
+                .filter_map(|(n, (tk, _))| {
+                    let (key, _) = shard.by_time.get(tk)?;
+                    if !filter.matches_series(key) {
+                        return None;
+                    }
+                    Some((*n, shard.series.get(key)?.get(tk)?.clone()))
+                });
+            out.extend(matching.take(limit));
+        }
+
+        out.sort_by_key(|(n, _)| *n);
+        out.truncate(limit);
+        out
+    }
+
+    /// The stored reading with this id, if it is still held.
+    pub fn get(&self, id: Uuid) -> Option<StoredObservation> {
+        self.shards.iter().find_map(|slot| {
+            let shard = slot.read();
+            let tk = shard.ids.get(&id)?;
+            let (key, _) = shard.by_time.get(tk)?;
+            shard.series.get(key)?.get(tk).cloned()
+        })
+    }
+
+    /// Every reading matching `filter`, oldest first.
+    pub fn range(&self, filter: &ObsFilter) -> Vec<StoredObservation> {
+        if filter.is_inverted() {
+            return Vec::new();
+        }
+        let bounds = filter.bounds();
+        let mut out: Vec<StoredObservation> = Vec::new();
+        for slot in self.shards_for(filter) {
+            let shard = slot.read();
+            out.extend(
+                shard
+                    .series
+                    .iter()
+                    .filter(|(key, _)| filter.matches_series(key))
+                    .flat_map(|(_, series)| series.range(bounds).map(|(_, o)| o.clone())),
+            );
+        }
+
+        out.sort_by_key(|o| (o.reading.ts, o.id));
+        out
+    }
+
+    /// Patients with readings matching `filter`, sorted.
+    pub fn patients(&self, filter: &ObsFilter) -> Vec<String> {
+        if filter.is_inverted() {
+            return Vec::new();
+        }
+        let bounds = filter.bounds();
+        let mut out = BTreeSet::new();
+        for slot in self.shards_for(filter) {
+            let shard = slot.read();
+            for (key, series) in &shard.series {
+                if filter.matches_series(key) && series.range(bounds).next().is_some() {
+                    out.insert(key.patient_id.clone());
+                }
+            }
+        }
+        out.into_iter().collect()
+    }
+
+    /// Rollups at `res` matching `filter`, ordered by bucket start.
+    pub fn rollup_range(&self, res: Resolution, filter: &ObsFilter) -> Vec<Rollup> {
+        let mut out: Vec<Rollup> = Vec::new();
+        for slot in self.shards_for(filter) {
+            out.extend(slot.read().rollups.range(res, filter).into_iter().cloned());
+        }
+        out.sort_by_key(|r| r.start);
+        out
+    }
+
+    fn slot(&self, patient_id: &str, code: SignalCode) -> &Slot {
+        let i = self.hasher.hash_one((patient_id, code)) as usize % self.shards.len();
+        &self.shards[i]
+    }
+
+    // A filter naming both patient and code lives in exactly one shard
+    fn shards_for(&self, filter: &ObsFilter) -> Vec<&Slot> {
+        match (&filter.patient_id, filter.code) {
+            (Some(patient_id), Some(code)) => vec![self.slot(patient_id, code)],
+            _ => self.shards.iter().collect(),
+        }
     }
 }
 
@@ -10,10 +10,38 @@ pub enum AppError {
     #[error("unauthorized")]
     Unauthorized,
 
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("not found")]
+    NotFound,
+
+    #[error("rate limit exceeded, retry in {retry_after_secs}s")]
+    RateLimited { retry_after_secs: u64 },
+
+    /// Temporarily unable to take the request (queue full, shutting down)
+    #[error("service unavailable: {0}")]
+    Unavailable(String),
+
     #[error("internal error")]
     Internal,
 }
 
+impl AppError {
+    /// Variant name, used as a metrics label.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound => "not_found",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Unavailable(_) => "unavailable",
+            AppError::Internal => "internal",
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct ErrorBody {
     error: String,
@@ -24,12 +52,30 @@ impl ResponseError for AppError {
         match self {
             AppError::Validation(_) => StatusCode::BAD_REQUEST,
             AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
             AppError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(ErrorBody {
+        crate::metrics::METRICS
+            .errors_total
+            .with_label_values(&[self.variant()])
+            .inc();
+        let mut res = HttpResponse::build(self.status_code());
+        match self {
+            AppError::RateLimited { retry_after_secs } => {
+                res.insert_header(("retry-after", retry_after_secs.to_string()));
+            }
+            AppError::Unavailable(_) => {
+                res.insert_header(("retry-after", "1"));
+            }
+            _ => {}
+        }
+        res.json(ErrorBody {
             error: self.to_string(),
         })
     }
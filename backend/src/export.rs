@@ -0,0 +1,295 @@
+use crate::domain::models::StoredObservation;
+use crate::domain::store::{AppState, ObsFilter};
+use crate::errors::AppError;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use bytes::Bytes;
+use parquet::arrow::ArrowWriter;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// Rows read from the store per lock; also one Parquet row group.
+const PAGE_SIZE: usize = 5_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Patient,
+    Device,
+    Code,
+    Value,
+    Unit,
+    Ts,
+    Status,
+}
+
+impl Column {
+    pub const ALL: [Column; 7] = [
+        Column::Patient,
+        Column::Device,
+        Column::Code,
+        Column::Value,
+        Column::Unit,
+        Column::Ts,
+        Column::Status,
+    ];
+
+    pub fn parse(s: &str) -> Option<Column> {
+        match s {
+            "patient" => Some(Column::Patient),
+            "device" => Some(Column::Device),
+            "code" => Some(Column::Code),
+            "value" => Some(Column::Value),
+            "unit" => Some(Column::Unit),
+            "ts" => Some(Column::Ts),
+            "status" => Some(Column::Status),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Patient => "patient",
+            Column::Device => "device",
+            Column::Code => "code",
+            Column::Value => "value",
+            Column::Unit => "unit",
+            Column::Ts => "ts",
+            Column::Status => "status",
+        }
+    }
+
+    fn text(&self, o: &StoredObservation) -> String {
+        match self {
+            Column::Patient => o.reading.patient_id.clone(),
+            Column::Device => o.reading.device_id.clone(),
+            Column::Code => o.reading.code.as_str().to_string(),
+            Column::Value => o.reading.value.to_string(),
+            Column::Unit => o.reading.unit.clone(),
+            Column::Ts => o.reading.ts.to_rfc3339(),
+            // Stored observations are always final (see fhir::to_fhir_observation)
+            Column::Status => "final".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// Page through the store and encode matching rows on a background task.
+/// The channel is bounded, so a slow client holds back the producer instead
+/// of the export piling up in memory.
+pub fn stream(
+    state: Arc<AppState>,
+    filter: ObsFilter,
+    columns: Vec<Column>,
+    format: Format,
+) -> mpsc::Receiver<Result<Bytes, AppError>> {
+    let (tx, rx) = mpsc::channel(4);
+
+    actix_rt::spawn(async move {
+        let mut encoder = match Encoder::new(format, &columns) {
+            Ok(e) => e,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+        let mut after = None;
+
+        loop {
+            let page = state.page(&filter, after, PAGE_SIZE);
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some((last.reading.ts, last.id));
+
+            let chunk = encoder.write(&page);
+            let done = page.len() < PAGE_SIZE;
+            if tx.send(chunk).await.is_err() {
+                // client went away
+                return;
+            }
+            if done {
+                break;
+            }
+        }
+
+        let _ = tx.send(encoder.finish()).await;
+    });
+
+    rx
+}
+
+enum Encoder {
+    Csv {
+        columns: Vec<Column>,
+        header_written: bool,
+    },
+    Parquet {
+        columns: Vec<Column>,
+        schema: Arc<Schema>,
+        writer: Box<ArrowWriter<Vec<u8>>>,
+    },
+}
+
+impl Encoder {
+    fn new(format: Format, columns: &[Column]) -> Result<Self, AppError> {
+        let columns = columns.to_vec();
+        match format {
+            Format::Csv => Ok(Encoder::Csv {
+                columns,
+                header_written: false,
+            }),
+            Format::Parquet => {
+                let schema = Arc::new(parquet_schema(&columns));
+                let writer =
+                    ArrowWriter::try_new(Vec::new(), schema.clone(), None).map_err(|e| {
+                        tracing::error!(error = %e, "parquet writer setup failed");
+                        AppError::Internal
+                    })?;
+                Ok(Encoder::Parquet {
+                    columns,
+                    schema,
+                    writer: Box::new(writer),
+                })
+            }
+        }
+    }
+
+    fn write(&mut self, rows: &[StoredObservation]) -> Result<Bytes, AppError> {
+        match self {
+            Encoder::Csv {
+                columns,
+                header_written,
+            } => {
+                let mut w = csv::Writer::from_writer(Vec::new());
+                if !*header_written {
+                    write_record(&mut w, columns.iter().map(|c| c.name().to_string()))?;
+                    *header_written = true;
+                }
+                for o in rows {
+                    write_record(&mut w, columns.iter().map(|c| c.text(o)))?;
+                }
+                let buf = w.into_inner().map_err(|_| AppError::Internal)?;
+                Ok(Bytes::from(buf))
+            }
+            Encoder::Parquet {
+                columns,
+                schema,
+                writer,
+            } => {
+                let batch = record_batch(schema.clone(), columns, rows)?;
+                // One row group per page; hand over whatever has been encoded so far
+                writer
+                    .write(&batch)
+                    .and_then(|_| writer.flush())
+                    .map_err(|e| {
+                        tracing::error!(error = %e, "parquet encode failed");
+                        AppError::Internal
+                    })?;
+                Ok(Bytes::from(std::mem::take(writer.inner_mut())))
+            }
+        }
+    }
+
+    fn finish(self) -> Result<Bytes, AppError> {
+        match self {
+            Encoder::Csv {
+                columns,
+                header_written,
+            } => {
+                if header_written {
+                    return Ok(Bytes::new());
+                }
+                // Empty export: still emit the header
+                let mut w = csv::Writer::from_writer(Vec::new());
+                write_record(&mut w, columns.iter().map(|c| c.name().to_string()))?;
+                Ok(Bytes::from(w.into_inner().map_err(|_| AppError::Internal)?))
+            }
+            Encoder::Parquet { writer, .. } => {
+                // Closing writes the footer into the (already drained) buffer
+                let tail = writer.into_inner().map_err(|e| {
+                    tracing::error!(error = %e, "parquet finish failed");
+                    AppError::Internal
+                })?;
+                Ok(Bytes::from(tail))
+            }
+        }
+    }
+}
+
+fn write_record(
+    w: &mut csv::Writer<Vec<u8>>,
+    fields: impl Iterator<Item = String>,
+) -> Result<(), AppError> {
+    w.write_record(fields).map_err(|e| {
+        tracing::error!(error = %e, "csv encode failed");
+        AppError::Internal
+    })
+}
+
+fn parquet_schema(columns: &[Column]) -> Schema {
+    let fields: Vec<Field> = columns
+        .iter()
+        .map(|c| match c {
+            Column::Value => Field::new(c.name(), DataType::Float64, false),
+            Column::Ts => Field::new(
+                c.name(),
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                false,
+            ),
+            _ => Field::new(c.name(), DataType::Utf8, false),
+        })
+        .collect();
+    Schema::new(fields)
+}
+
+fn record_batch(
+    schema: Arc<Schema>,
+    columns: &[Column],
+    rows: &[StoredObservation],
+) -> Result<RecordBatch, AppError> {
+    let arrays: Vec<ArrayRef> = columns
+        .iter()
+        .map(|c| -> ArrayRef {
+            match c {
+                Column::Value => Arc::new(Float64Array::from_iter_values(
+                    rows.iter().map(|o| o.reading.value),
+                )),
+                Column::Ts => Arc::new(
+                    TimestampMillisecondArray::from_iter_values(
+                        rows.iter().map(|o| o.reading.ts.timestamp_millis()),
+                    )
+                    .with_timezone("UTC"),
+                ),
+                _ => Arc::new(StringArray::from_iter_values(
+                    rows.iter().map(|o| c.text(o)),
+                )),
+            }
+        })
+        .collect();
+
+    RecordBatch::try_new(schema, arrays).map_err(|e| {
+        tracing::error!(error = %e, "building record batch failed");
+        AppError::Internal
+    })
+}
@@ -1,10 +1,9 @@
-use crate::domain::models::{SignalCode, StoredObservation};
+use crate::domain::models::{SensorReading, SignalCode, StoredObservation};
 use crate::errors::AppError;
 use chrono::{DateTime, Utc};
-use serde::Serialize;
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FhirReference {
     pub reference: String,
 }
@@ -14,12 +13,14 @@ pub struct FhirCode {
     pub text: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FhirValueQuantity {
     pub value: f64,
     pub unit: String,
 }
 
+// Field names follow the FHIR JSON spelling
+#[allow(non_snake_case)]
 #[derive(Debug, Serialize)]
 pub struct FhirObservation {
     pub resourceType: &'static str,
@@ -32,11 +33,57 @@ pub struct FhirObservation {
     pub valueQuantity: FhirValueQuantity,
 }
 
+/// Observation as sent by devices and gateways that speak FHIR; only the
+/// fields a reading needs are read.
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct FhirObservationInput {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub code: FhirCodeInput,
+    pub subject: FhirReference,
+    #[serde(default)]
+    pub device: Option<FhirReference>,
+    pub effectiveDateTime: DateTime<Utc>,
+    pub valueQuantity: FhirValueQuantity,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FhirCodeInput {
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub coding: Vec<FhirCoding>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FhirCoding {
+    #[serde(default)]
+    pub system: Option<String>,
+    pub code: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct FhirPatient {
+    pub resourceType: &'static str,
+    pub id: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct FhirDevice {
+    pub resourceType: &'static str,
+    pub id: String,
+    pub patient: FhirReference,
+}
+
 #[derive(Debug, Serialize)]
 pub struct FhirBundleEntry<T> {
     pub resource: T,
 }
 
+#[allow(non_snake_case)]
 #[derive(Debug, Serialize)]
 pub struct FhirBundle<T> {
     pub resourceType: &'static str,
@@ -46,31 +93,148 @@ pub struct FhirBundle<T> {
     pub entry: Vec<FhirBundleEntry<T>>,
 }
 
-fn signal_name(code: SignalCode) -> &'static str {
+#[derive(Debug, Serialize)]
+pub struct FhirRequest {
+    pub method: &'static str,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FhirTransactionEntry<T> {
+    pub resource: T,
+    pub request: FhirRequest,
+}
+
+/// A `transaction` Bundle: the server applies every entry or none.
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct FhirTransaction<T> {
+    pub resourceType: &'static str,
+    #[serde(rename = "type")]
+    pub bundle_type: &'static str,
+    pub entry: Vec<FhirTransactionEntry<T>>,
+}
+
+/// Display name, also used as `code.text`.
+pub fn signal_name(code: SignalCode) -> &'static str {
     match code {
         SignalCode::HeartRate => "Heart Rate",
         SignalCode::BodyTemperature => "Body Temperature",
         SignalCode::StepsPerMinute => "Steps per Minute",
+        SignalCode::RrInterval => "RR Interval",
     }
 }
 
+pub const LOINC: &str = "http://loinc.org";
+
+/// LOINC code for a signal; RR intervals have none and go by name only.
+pub fn loinc_code(code: SignalCode) -> Option<&'static str> {
+    match code {
+        SignalCode::HeartRate => Some("8867-4"),
+        SignalCode::BodyTemperature => Some("8310-5"),
+        SignalCode::StepsPerMinute => Some("55423-8"),
+        SignalCode::RrInterval => None,
+    }
+}
+
+/// Inverse of `loinc_code`.
+pub fn signal_from_loinc(code: &str) -> Option<SignalCode> {
+    SignalCode::ALL
+        .into_iter()
+        .find(|c| loinc_code(*c) == Some(code))
+}
+
+/// Turn an inbound Observation into a reading. The signal comes from a LOINC
+/// coding or the same `code.text` we emit; `device_id` is used when the
+/// resource has no `device` reference.
+pub fn from_fhir_observation(
+    obs: FhirObservationInput,
+    device_id: Option<&str>,
+) -> Result<SensorReading, AppError> {
+    let code = obs
+        .code
+        .coding
+        .iter()
+        .filter(|c| c.system.as_deref().is_none_or(|s| s == LOINC))
+        .find_map(|c| signal_from_loinc(&c.code))
+        .or_else(|| {
+            let text = obs.code.text.as_deref()?;
+            SignalCode::ALL
+                .into_iter()
+                .find(|c| signal_name(*c).eq_ignore_ascii_case(text))
+        })
+        .ok_or_else(|| AppError::Validation("Observation.code is not a supported signal".into()))?;
+    let patient_id = obs
+        .subject
+        .reference
+        .strip_prefix("Patient/")
+        .ok_or_else(|| {
+            AppError::Validation("Observation.subject must reference a Patient".into())
+        })?;
+    let device_id = match &obs.device {
+        Some(device) => device.reference.strip_prefix("Device/").ok_or_else(|| {
+            AppError::Validation("Observation.device must reference a Device".into())
+        })?,
+        None => device_id
+            .ok_or_else(|| AppError::Validation("Observation.device is required".into()))?,
+    };
+    Ok(SensorReading {
+        device_id: device_id.to_string(),
+        patient_id: patient_id.to_string(),
+        code,
+        value: obs.valueQuantity.value,
+        unit: obs.valueQuantity.unit,
+        ts: obs.effectiveDateTime,
+    })
+}
+
 pub fn to_fhir_observation(obs: &StoredObservation) -> Result<FhirObservation, AppError> {
     Ok(FhirObservation {
         resourceType: "Observation",
         id: obs.id.to_string(),
         status: "final",
-        code: FhirCode { text: signal_name(obs.reading.code).to_string() },
-        subject: FhirReference { reference: format!("Patient/{}", obs.reading.patient_id) },
-        device: FhirReference { reference: format!("Device/{}", obs.reading.device_id) },
+        code: FhirCode {
+            text: signal_name(obs.reading.code).to_string(),
+        },
+        subject: FhirReference {
+            reference: format!("Patient/{}", obs.reading.patient_id),
+        },
+        device: FhirReference {
+            reference: format!("Device/{}", obs.reading.device_id),
+        },
         effectiveDateTime: obs.reading.ts,
-        valueQuantity: FhirValueQuantity { value: obs.reading.value, unit: obs.reading.unit.clone() },
+        valueQuantity: FhirValueQuantity {
+            value: obs.reading.value,
+            unit: obs.reading.unit.clone(),
+        },
     })
 }
 
-pub fn to_bundle(observations: &[StoredObservation]) -> Result<FhirBundle<FhirObservation>, AppError> {
+pub fn to_fhir_patient(patient_id: &str) -> FhirPatient {
+    FhirPatient {
+        resourceType: "Patient",
+        id: patient_id.to_string(),
+    }
+}
+
+pub fn to_fhir_device(device_id: &str, patient_id: &str) -> FhirDevice {
+    FhirDevice {
+        resourceType: "Device",
+        id: device_id.to_string(),
+        patient: FhirReference {
+            reference: format!("Patient/{}", patient_id),
+        },
+    }
+}
+
+pub fn to_bundle(
+    observations: &[StoredObservation],
+) -> Result<FhirBundle<FhirObservation>, AppError> {
     let mut entry = Vec::with_capacity(observations.len());
     for o in observations {
-        entry.push(FhirBundleEntry { resource: to_fhir_observation(o)? });
+        entry.push(FhirBundleEntry {
+            resource: to_fhir_observation(o)?,
+        });
     }
     Ok(FhirBundle {
         resourceType: "Bundle",
@@ -79,3 +243,25 @@ pub fn to_bundle(observations: &[StoredObservation]) -> Result<FhirBundle<FhirOb
         entry,
     })
 }
+
+/// Observations as a transaction of `PUT Observation/{id}`, so sending the
+/// same bundle twice leaves the server unchanged.
+pub fn to_transaction(
+    observations: &[StoredObservation],
+) -> Result<FhirTransaction<FhirObservation>, AppError> {
+    let mut entry = Vec::with_capacity(observations.len());
+    for o in observations {
+        entry.push(FhirTransactionEntry {
+            resource: to_fhir_observation(o)?,
+            request: FhirRequest {
+                method: "PUT",
+                url: format!("Observation/{}", o.id),
+            },
+        });
+    }
+    Ok(FhirTransaction {
+        resourceType: "Bundle",
+        bundle_type: "transaction",
+        entry,
+    })
+}
@@ -0,0 +1,336 @@
+use crate::domain::models::{SensorReading, SignalCode};
+use crate::errors::AppError;
+use base64::Engine;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Heart Rate Measurement characteristic
+pub const HEART_RATE_MEASUREMENT: u16 = 0x2A37;
+/// Temperature Measurement characteristic (Health Thermometer service)
+pub const TEMPERATURE_MEASUREMENT: u16 = 0x2A1C;
+/// Longest attribute value ATT allows (Core 5.4, Vol 3, Part F, 3.2.9)
+pub const MAX_PAYLOAD_BYTES: usize = 512;
+/// RR intervals accepted in one heart rate measurement. A notification
+/// covers about a second of beats, so this leaves plenty of room for a
+/// sensor that batches a few.
+pub const MAX_RR_INTERVALS: usize = 32;
+
+// Heart Rate Measurement flags (Heart Rate Service 1.0, 3.1.1.1)
+const HR_FORMAT_U16: u8 = 0x01;
+const HR_CONTACT_DETECTED: u8 = 0x02;
+const HR_CONTACT_SUPPORTED: u8 = 0x04;
+const HR_ENERGY_EXPENDED: u8 = 0x08;
+const HR_RR_INTERVALS: u8 = 0x10;
+
+// Temperature Measurement flags (Health Thermometer Service 1.0, 3.1.1)
+const TEMP_FAHRENHEIT: u8 = 0x01;
+const TEMP_TIMESTAMP: u8 = 0x02;
+const TEMP_TYPE: u8 = 0x04;
+
+/// A GATT characteristic value relayed by a phone, as posted to
+/// `/ingest/gatt`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GattUpload {
+    pub device_id: String,
+    pub patient_id: String,
+    /// UUID16 as hex: `2A37`, `0x2A37` or the full 128-bit Bluetooth base UUID
+    pub characteristic: String,
+    /// Characteristic value, standard base64
+    pub payload: String,
+    /// When the notification was received; defaults to now
+    #[serde(default)]
+    pub ts: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeartRateMeasurement {
+    pub heart_rate: u16,
+    /// None if the sensor cannot tell whether it has skin contact
+    pub sensor_contact: Option<bool>,
+    /// Cumulative energy since the last reset, in kilojoules
+    pub energy_expended_kj: Option<u16>,
+    /// Oldest first, in milliseconds
+    pub rr_intervals_ms: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TemperatureMeasurement {
+    /// In `unit`, as sent
+    pub value: f64,
+    pub unit: TemperatureUnit,
+    /// The thermometer's own clock, which has no time zone; taken as UTC
+    pub ts: Option<DateTime<Utc>>,
+    /// Body site code from the Temperature Type characteristic (2 = body)
+    pub temperature_type: Option<u8>,
+}
+
+impl TemperatureMeasurement {
+    pub fn celsius(&self) -> f64 {
+        match self.unit {
+            TemperatureUnit::Celsius => self.value,
+            TemperatureUnit::Fahrenheit => (self.value - 32.0) * 5.0 / 9.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "characteristic", rename_all = "kebab-case")]
+pub enum Measurement {
+    HeartRate(HeartRateMeasurement),
+    Temperature(TemperatureMeasurement),
+}
+
+/// Parse a characteristic UUID into its 16-bit form.
+pub fn parse_uuid(uuid: &str) -> Option<u16> {
+    let uuid = uuid.trim().to_ascii_lowercase();
+    let short = match uuid.strip_suffix("-0000-1000-8000-00805f9b34fb") {
+        Some(long) => long.strip_prefix("0000")?,
+        None => uuid.strip_prefix("0x").unwrap_or(&uuid),
+    };
+    if short.len() != 4 {
+        return None;
+    }
+    u16::from_str_radix(short, 16).ok()
+}
+
+/// Decode an IEEE-11073 32-bit FLOAT: a 24-bit signed mantissa and an
+/// 8-bit signed base-10 exponent, little-endian. NaN, NRes, ±infinity and
+/// the reserved value come back as None.
+pub fn decode_float(bytes: [u8; 4]) -> Option<f64> {
+    let raw = u32::from_le_bytes(bytes);
+    let mantissa = raw & 0x00FF_FFFF;
+    if (0x007F_FFFE..=0x0080_0002).contains(&mantissa) {
+        return None;
+    }
+    // Sign-extend 24 to 32 bits
+    let mantissa = ((mantissa << 8) as i32) >> 8;
+    let exponent = (raw >> 24) as u8 as i8;
+    Some(mantissa as f64 * 10f64.powi(exponent as i32))
+}
+
+// Reads little-endian fields off the front of a payload
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    what: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn take<const N: usize>(&mut self, field: &str) -> Result<[u8; N], AppError> {
+        if self.bytes.len() < N {
+            return Err(AppError::Validation(format!(
+                "{} payload is truncated at {}",
+                self.what, field
+            )));
+        }
+        let (head, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(head.try_into().expect("split at N"))
+    }
+
+    fn u8(&mut self, field: &str) -> Result<u8, AppError> {
+        Ok(self.take::<1>(field)?[0])
+    }
+
+    fn u16(&mut self, field: &str) -> Result<u16, AppError> {
+        Ok(u16::from_le_bytes(self.take(field)?))
+    }
+}
+
+/// Decode a Heart Rate Measurement (0x2A37) value.
+pub fn decode_heart_rate(bytes: &[u8]) -> Result<HeartRateMeasurement, AppError> {
+    let mut cur = Cursor {
+        bytes,
+        what: "heart rate measurement",
+    };
+    let flags = cur.u8("flags")?;
+    let heart_rate = if flags & HR_FORMAT_U16 != 0 {
+        cur.u16("heart rate")?
+    } else {
+        cur.u8("heart rate")? as u16
+    };
+    let sensor_contact =
+        (flags & HR_CONTACT_SUPPORTED != 0).then_some(flags & HR_CONTACT_DETECTED != 0);
+    let energy_expended_kj = if flags & HR_ENERGY_EXPENDED != 0 {
+        Some(cur.u16("energy expended")?)
+    } else {
+        None
+    };
+    let mut rr_intervals_ms = Vec::new();
+    if flags & HR_RR_INTERVALS != 0 {
+        // The rest of the payload, two bytes each
+        let intervals = cur.bytes.chunks_exact(2);
+        if cur.bytes.is_empty() || !intervals.remainder().is_empty() {
+            return Err(AppError::Validation(
+                "heart rate measurement has a partial RR interval".into(),
+            ));
+        }
+        if intervals.len() > MAX_RR_INTERVALS {
+            return Err(AppError::Validation(format!(
+                "heart rate measurement has {} RR intervals, more than {}",
+                intervals.len(),
+                MAX_RR_INTERVALS
+            )));
+        }
+        // Sent in units of 1/1024 s
+        rr_intervals_ms = intervals
+            .map(|rr| u16::from_le_bytes([rr[0], rr[1]]) as f64 * 1000.0 / 1024.0)
+            .collect();
+    }
+    Ok(HeartRateMeasurement {
+        heart_rate,
+        sensor_contact,
+        energy_expended_kj,
+        rr_intervals_ms,
+    })
+}
+
+/// Decode a Temperature Measurement (0x2A1C) value.
+pub fn decode_temperature(bytes: &[u8]) -> Result<TemperatureMeasurement, AppError> {
+    let mut cur = Cursor {
+        bytes,
+        what: "temperature measurement",
+    };
+    let flags = cur.u8("flags")?;
+    let value = decode_float(cur.take("temperature")?).ok_or_else(|| {
+        AppError::Validation("temperature measurement has no value (NaN or out of range)".into())
+    })?;
+    let unit = if flags & TEMP_FAHRENHEIT != 0 {
+        TemperatureUnit::Fahrenheit
+    } else {
+        TemperatureUnit::Celsius
+    };
+    let ts = if flags & TEMP_TIMESTAMP != 0 {
+        let year = cur.u16("time stamp")?;
+        let [month, day, hour, minute, second] = cur.take("time stamp")?;
+        let ts = NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)
+            .and_then(|d| d.and_hms_opt(hour as u32, minute as u32, second as u32))
+            .ok_or_else(|| {
+                AppError::Validation("temperature measurement has an invalid time stamp".into())
+            })?;
+        Some(ts.and_utc())
+    } else {
+        None
+    };
+    let temperature_type = if flags & TEMP_TYPE != 0 {
+        Some(cur.u8("temperature type")?)
+    } else {
+        None
+    };
+    Ok(TemperatureMeasurement {
+        value,
+        unit,
+        ts,
+        temperature_type,
+    })
+}
+
+/// Decode one characteristic value by its 16-bit UUID.
+pub fn decode(characteristic: u16, bytes: &[u8]) -> Result<Measurement, AppError> {
+    match characteristic {
+        HEART_RATE_MEASUREMENT => decode_heart_rate(bytes).map(Measurement::HeartRate),
+        TEMPERATURE_MEASUREMENT => decode_temperature(bytes).map(Measurement::Temperature),
+        other => Err(AppError::Validation(format!(
+            "unsupported characteristic 0x{:04X} (expected 2A37 or 2A1C)",
+            other
+        ))),
+    }
+}
+
+/// Turn a measurement into readings. A heart rate measurement gives the
+/// rate plus one `rr-interval` reading per interval, each stamped with the
+/// beat that ended it: the last at `ts`, earlier ones stepped back by the
+/// intervals after them. Temperatures are stored in Celsius, at the
+/// thermometer's time stamp if it sent one.
+pub fn to_readings(
+    device_id: &str,
+    patient_id: &str,
+    measurement: &Measurement,
+    ts: DateTime<Utc>,
+) -> Vec<SensorReading> {
+    let reading = |code, value, unit: &str, ts| SensorReading {
+        device_id: device_id.to_string(),
+        patient_id: patient_id.to_string(),
+        code,
+        value,
+        unit: unit.to_string(),
+        ts,
+    };
+    match measurement {
+        Measurement::HeartRate(hr) => {
+            let mut readings = vec![reading(
+                SignalCode::HeartRate,
+                hr.heart_rate as f64,
+                "bpm",
+                ts,
+            )];
+            let mut beat = ts;
+            let mut rr: Vec<SensorReading> = hr
+                .rr_intervals_ms
+                .iter()
+                .rev()
+                .map(|ms| {
+                    let r = reading(SignalCode::RrInterval, *ms, "ms", beat);
+                    beat -= Duration::microseconds((ms * 1000.0).round() as i64);
+                    r
+                })
+                .collect();
+            rr.reverse();
+            readings.extend(rr);
+            readings
+        }
+        Measurement::Temperature(t) => {
+            vec![reading(
+                SignalCode::BodyTemperature,
+                t.celsius(),
+                "C",
+                t.ts.unwrap_or(ts),
+            )]
+        }
+    }
+}
+
+/// Decode an upload into its measurement and readings; `now` stands in
+/// for a missing `ts`.
+pub fn decode_upload(
+    upload: &GattUpload,
+    now: DateTime<Utc>,
+) -> Result<(Measurement, Vec<SensorReading>), AppError> {
+    let characteristic = parse_uuid(&upload.characteristic).ok_or_else(|| {
+        AppError::Validation(format!(
+            "invalid characteristic UUID '{}'",
+            upload.characteristic
+        ))
+    })?;
+    let too_large = || {
+        AppError::Validation(format!(
+            "payload is larger than {} bytes",
+            MAX_PAYLOAD_BYTES
+        ))
+    };
+    // Checked before decoding too, so an oversized upload is never decoded
+    let encoded = upload.payload.trim();
+    if encoded.len() > MAX_PAYLOAD_BYTES.div_ceil(3) * 4 {
+        return Err(too_large());
+    }
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| AppError::Validation(format!("payload is not valid base64: {}", e)))?;
+    if bytes.len() > MAX_PAYLOAD_BYTES {
+        return Err(too_large());
+    }
+    let measurement = decode(characteristic, &bytes)?;
+    let readings = to_readings(
+        &upload.device_id,
+        &upload.patient_id,
+        &measurement,
+        upload.ts.unwrap_or(now),
+    );
+    Ok((measurement, readings))
+}
@@ -0,0 +1,480 @@
+use crate::audit::{Action, AuditLog, Entry, Outcome};
+use crate::domain::alerts::{Alert, AlertKind};
+use crate::domain::models::{SensorReading, SignalCode};
+use crate::domain::store::AppState;
+use crate::errors::AppError;
+use crate::pipeline::{self, Pipeline};
+use crate::ratelimit::{KeyKind, RateLimiter};
+use crate::settings::{Settings, TlsSettings};
+use crate::tls;
+use crate::ws::{Hub, HubEvent, LiveEvent, LiveEvents, LiveFilter};
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Code generated from `proto/pulsesense.proto`.
+pub mod proto {
+    tonic::include_proto!("pulsesense.v1");
+}
+
+use proto::ingest_ack::Status as AckStatus;
+use proto::pulse_sense_server::{PulseSense, PulseSenseServer};
+
+// How long `stop` waits for open Ingest streams to finish
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl From<SignalCode> for proto::SignalCode {
+    fn from(code: SignalCode) -> Self {
+        match code {
+            SignalCode::HeartRate => proto::SignalCode::HeartRate,
+            SignalCode::BodyTemperature => proto::SignalCode::BodyTemperature,
+            SignalCode::StepsPerMinute => proto::SignalCode::StepsPerMinute,
+            SignalCode::RrInterval => proto::SignalCode::RrInterval,
+        }
+    }
+}
+
+fn signal_code(code: i32) -> Option<SignalCode> {
+    match proto::SignalCode::try_from(code).ok()? {
+        proto::SignalCode::Unspecified => None,
+        proto::SignalCode::HeartRate => Some(SignalCode::HeartRate),
+        proto::SignalCode::BodyTemperature => Some(SignalCode::BodyTemperature),
+        proto::SignalCode::StepsPerMinute => Some(SignalCode::StepsPerMinute),
+        proto::SignalCode::RrInterval => Some(SignalCode::RrInterval),
+    }
+}
+
+fn timestamp(ts: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: ts.timestamp(),
+        nanos: ts.timestamp_subsec_nanos() as i32,
+    }
+}
+
+impl TryFrom<proto::SensorReading> for SensorReading {
+    type Error = AppError;
+
+    fn try_from(r: proto::SensorReading) -> Result<Self, AppError> {
+        let code =
+            signal_code(r.code).ok_or_else(|| AppError::Validation("code is required".into()))?;
+        let ts =
+            r.ts.and_then(|ts| DateTime::from_timestamp(ts.seconds, u32::try_from(ts.nanos).ok()?))
+                .ok_or_else(|| AppError::Validation("ts is required".into()))?;
+        Ok(SensorReading {
+            device_id: r.device_id,
+            patient_id: r.patient_id,
+            code,
+            value: r.value,
+            unit: r.unit,
+            ts,
+        })
+    }
+}
+
+impl From<SensorReading> for proto::SensorReading {
+    fn from(r: SensorReading) -> Self {
+        proto::SensorReading {
+            device_id: r.device_id,
+            patient_id: r.patient_id,
+            code: proto::SignalCode::from(r.code).into(),
+            value: r.value,
+            unit: r.unit,
+            ts: Some(timestamp(r.ts)),
+        }
+    }
+}
+
+impl From<Alert> for proto::Alert {
+    fn from(a: Alert) -> Self {
+        let kind = match a.kind {
+            AlertKind::Low => proto::alert::Kind::Low,
+            AlertKind::High => proto::alert::Kind::High,
+        };
+        proto::Alert {
+            id: a.id.to_string(),
+            observation_id: a.observation_id.to_string(),
+            patient_id: a.patient_id,
+            device_id: a.device_id,
+            code: proto::SignalCode::from(a.code).into(),
+            kind: kind.into(),
+            value: a.value,
+            unit: a.unit,
+            threshold: a.threshold,
+            ts: Some(timestamp(a.ts)),
+        }
+    }
+}
+
+/// The `PulseSense` service: streamed ingest through the same checks,
+/// validation and pipeline as `POST /ingest`, and the `/ws/live` feed as a
+/// server stream.
+#[derive(Clone)]
+pub struct GrpcService {
+    state: Arc<AppState>,
+    pipeline: Option<Arc<Pipeline>>,
+    audit: Option<Arc<AuditLog>>,
+    limiter: Option<Arc<RateLimiter>>,
+    ingest_token: Option<String>,
+    tls: TlsSettings,
+}
+
+impl GrpcService {
+    pub fn new(
+        state: Arc<AppState>,
+        pipeline: Option<Arc<Pipeline>>,
+        audit: Option<Arc<AuditLog>>,
+        limiter: Option<Arc<RateLimiter>>,
+        settings: &Settings,
+    ) -> Self {
+        Self {
+            state,
+            pipeline,
+            audit,
+            limiter,
+            ingest_token: settings.auth.ingest_token().map(str::to_string),
+            tls: settings.tls.clone(),
+        }
+    }
+
+    // An audit entry for a call, before its patients and outcome are known
+    fn audit_entry<T>(&self, request: &Request<T>, action: Action, operation: &str) -> Entry {
+        let actor = if self.ingest_token.is_some() && self.authorized(request) {
+            "ingest"
+        } else {
+            "anonymous"
+        };
+        Entry {
+            action,
+            operation: operation.to_string(),
+            actor: actor.to_string(),
+            client_ip: request.remote_addr().map(|a| a.ip().to_string()),
+            request_id: None,
+            query: None,
+            patients: Vec::new(),
+            outcome: Outcome::Success,
+            detail: None,
+        }
+    }
+
+    fn record(&self, entry: Entry, outcome: Outcome, detail: String) {
+        if let Some(audit) = &self.audit {
+            audit.record(Entry {
+                outcome,
+                detail: Some(detail),
+                ..entry
+            });
+        }
+    }
+
+    // Same bearer token as POST /ingest, sent as `authorization` metadata
+    fn authorized<T>(&self, request: &Request<T>) -> bool {
+        let Some(token) = &self.ingest_token else {
+            return true;
+        };
+        let header = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        header == format!("Bearer {}", token)
+    }
+
+    async fn ingest_one(&self, index: u64, reading: proto::SensorReading) -> proto::IngestAck {
+        let mut ack = proto::IngestAck {
+            index,
+            ..proto::IngestAck::default()
+        };
+        match self.accept(reading).await {
+            Ok(id) => {
+                ack.set_status(AckStatus::Accepted);
+                ack.observation_id = id;
+            }
+            Err(e) => {
+                let status = match &e {
+                    AppError::Unavailable(_) => AckStatus::Unavailable,
+                    AppError::RateLimited { retry_after_secs } => {
+                        ack.retry_after_secs = *retry_after_secs;
+                        AckStatus::RateLimited
+                    }
+                    AppError::Unauthorized | AppError::Forbidden(_) => AckStatus::Forbidden,
+                    _ => AckStatus::Invalid,
+                };
+                ack.set_status(status);
+                ack.message = e.to_string();
+            }
+        }
+        ack
+    }
+
+    async fn accept(&self, reading: proto::SensorReading) -> Result<String, AppError> {
+        let reading = SensorReading::try_from(reading)?;
+        // Plaintext HTTP/2 carries no client certificate
+        tls::check_device_identity(None, &reading.device_id, &self.tls)?;
+        if let Some(limiter) = &self.limiter {
+            limiter.check(
+                "/ingest",
+                &[(KeyKind::Device, &reading.device_id)],
+                Instant::now(),
+            )?;
+        }
+        let obs = pipeline::admit(&self.state, reading)?;
+        let id = obs.id.to_string();
+        // Waiting for queue room slows the stream down via HTTP/2 flow control
+        match &self.pipeline {
+            Some(pipeline) => pipeline.send(obs).await?,
+            None => {
+                pipeline::process(&self.state, &obs);
+            }
+        }
+        Ok(id)
+    }
+
+    pub fn into_server(self) -> PulseSenseServer<Self> {
+        PulseSenseServer::new(self)
+    }
+}
+
+type AckStream = Pin<Box<dyn Stream<Item = Result<proto::IngestAck, Status>> + Send>>;
+type LiveStream = Pin<Box<dyn Stream<Item = Result<proto::LiveEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl PulseSense for GrpcService {
+    type IngestStream = AckStream;
+
+    async fn ingest(
+        &self,
+        request: Request<Streaming<proto::SensorReading>>,
+    ) -> Result<Response<AckStream>, Status> {
+        let entry = self.audit_entry(&request, Action::Create, "grpc Ingest");
+        if !self.authorized(&request) {
+            self.record(entry, Outcome::Failure, "unauthenticated".into());
+            return Err(Status::unauthenticated("missing or invalid bearer token"));
+        }
+        let call = IngestCall {
+            service: self.clone(),
+            readings: request.into_inner(),
+            entry: Some(entry),
+            patients: BTreeSet::new(),
+            index: 0,
+            accepted: 0,
+        };
+        // Each reading is handled when the client's stream yields it and
+        // answered right away, so acks never pile up on the server
+        let acks = futures_util::stream::unfold(call, |mut call| async move {
+            // Ended by a stream error, which was already passed on
+            call.entry.as_ref()?;
+            let reading = match call.readings.message().await {
+                Ok(Some(reading)) => reading,
+                Ok(None) => {
+                    call.finish(Outcome::Success, None);
+                    return None;
+                }
+                Err(status) => {
+                    let reason = format!(
+                        "stream failed after {} readings: {}",
+                        call.index,
+                        status.message()
+                    );
+                    call.finish(Outcome::Failure, Some(reason));
+                    return Some((Err(status), call));
+                }
+            };
+            call.patients.insert(reading.patient_id.clone());
+            let ack = call.service.ingest_one(call.index, reading).await;
+            if ack.status() == AckStatus::Accepted {
+                call.accepted += 1;
+            }
+            call.index += 1;
+            Some((Ok(ack), call))
+        });
+        Ok(Response::new(Box::pin(acks)))
+    }
+
+    type SubscribeStream = LiveStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<LiveStream>, Status> {
+        let mut entry = self.audit_entry(&request, Action::Read, "grpc Subscribe");
+        let request = request.into_inner();
+        entry
+            .patients
+            .extend(Some(request.patient_id.clone()).filter(|p| !p.is_empty()));
+        let code = match request.code {
+            0 => None,
+            code => match signal_code(code) {
+                Some(code) => Some(code),
+                None => {
+                    self.record(entry, Outcome::Failure, "unknown code".into());
+                    return Err(Status::invalid_argument("unknown code"));
+                }
+            },
+        };
+        self.record(entry, Outcome::Success, "subscribed".into());
+        let filter = LiveFilter {
+            patient_id: Some(request.patient_id).filter(|p| !p.is_empty()),
+            code,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let hub = self.state.ws_hub.clone();
+        let (id, events) = hub.add_event_client(tx);
+        let subscription = Subscription {
+            hub,
+            id,
+            rx,
+            events,
+            filter,
+            closing: false,
+        };
+        let stream = futures_util::stream::unfold(subscription, |mut sub| async move {
+            while !sub.closing {
+                let hub_event = tokio::select! {
+                    // Events published before a shutdown go out ahead of it
+                    biased;
+                    event = sub.events.recv() => {
+                        let event = event?;
+                        let (patient_id, code) = event.scope();
+                        if !sub.filter.matches(patient_id, code) {
+                            continue;
+                        }
+                        let event = live_event(&event);
+                        return Some((Ok(proto::LiveEvent { event: Some(event) }), sub));
+                    }
+                    hub_event = sub.rx.recv() => hub_event?,
+                };
+                match hub_event {
+                    // Event clients get nothing else from the hub
+                    HubEvent::Text(_) => {}
+                    HubEvent::Shutdown {
+                        reason,
+                        reconnect_after_secs,
+                    } => {
+                        sub.closing = true;
+                        let event = proto::live_event::Event::Shutdown(proto::Shutdown {
+                            reason,
+                            reconnect_after_secs,
+                        });
+                        return Some((Ok(proto::LiveEvent { event: Some(event) }), sub));
+                    }
+                }
+            }
+            None
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+// A hub registration that lasts as long as the response stream
+struct Subscription {
+    hub: Hub,
+    id: u64,
+    rx: mpsc::UnboundedReceiver<HubEvent>,
+    events: LiveEvents,
+    filter: LiveFilter,
+    closing: bool,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.remove_client(self.id);
+    }
+}
+
+// One Ingest call, audited once when it ends (or the client goes away)
+struct IngestCall {
+    service: GrpcService,
+    readings: Streaming<proto::SensorReading>,
+    entry: Option<Entry>,
+    patients: BTreeSet<String>,
+    index: u64,
+    accepted: u64,
+}
+
+impl IngestCall {
+    fn finish(&mut self, outcome: Outcome, reason: Option<String>) {
+        let Some(mut entry) = self.entry.take() else {
+            return;
+        };
+        entry.patients = std::mem::take(&mut self.patients).into_iter().collect();
+        let counts = format!(
+            "accepted {}, rejected {}",
+            self.accepted,
+            self.index - self.accepted
+        );
+        let detail = match reason {
+            Some(reason) => format!("{}; {}", reason, counts),
+            None => counts,
+        };
+        self.service.record(entry, outcome, detail);
+    }
+}
+
+impl Drop for IngestCall {
+    fn drop(&mut self) {
+        self.finish(Outcome::Failure, Some("cancelled by the client".into()));
+    }
+}
+
+fn live_event(event: &LiveEvent) -> proto::live_event::Event {
+    match event {
+        LiveEvent::Alert(alert) => proto::live_event::Event::Alert(alert.clone().into()),
+        LiveEvent::Observation(obs) => proto::live_event::Event::Observation(proto::Observation {
+            id: obs.id.to_string(),
+            reading: Some(obs.reading.clone().into()),
+        }),
+    }
+}
+
+/// The gRPC listener, on its own port (HTTP/2 without TLS).
+pub struct GrpcServer {
+    addr: std::net::SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), tonic::transport::Error>>,
+}
+
+impl GrpcServer {
+    pub async fn bind(addr: &str, service: GrpcService) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let incoming =
+            TcpIncoming::from_listener(listener, true, None).map_err(std::io::Error::other)?;
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let task = actix_rt::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = signal.await;
+                }),
+        );
+        Ok(Self {
+            addr,
+            shutdown,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting calls and give open ones a moment to finish.
+    /// Subscribe streams end on their own when the hub closes its clients.
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(());
+        let abort = self.task.abort_handle();
+        match tokio::time::timeout(STOP_TIMEOUT, self.task).await {
+            Ok(Ok(Err(e))) => tracing::error!("gRPC server failed: {}", e),
+            Err(_) => abort.abort(),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::bulk::BulkExports;
+use crate::domain::store::AppState;
+use crate::lifecycle::Lifecycle;
+use crate::pipeline::{Pipeline, QueueStatus};
+use crate::settings::Settings;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// The compactor counts as stalled after missing this many intervals
+const MISSED_COMPACTIONS: i64 = 3;
+// Each directory is probed at most this often; readiness reuses the result
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+// When each directory was last probed and the outcome, or that a probe is
+// running and what the one before it found
+#[derive(Clone)]
+enum Probe {
+    Done(Instant, Result<(), String>),
+    Running(Result<(), String>),
+}
+static PROBES: LazyLock<Mutex<HashMap<PathBuf, Probe>>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Ready,
+    Degraded,
+    Draining,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckStatus {
+    Ok,
+    Degraded,
+}
+
+impl CheckStatus {
+    fn from_ok(ok: bool) -> Self {
+        if ok {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Degraded
+        }
+    }
+}
+
+/// Body of `/readyz`.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: Status,
+    pub checks: Checks,
+    /// Per-device activity; only shown to admins (see `routes::readyz`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceStatus>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == Status::Ready
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Checks {
+    pub store: StoreCheck,
+    pub queues: QueueCheck,
+    pub tasks: BTreeMap<&'static str, TaskCheck>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoreCheck {
+    pub status: CheckStatus,
+    /// False once a panic poisoned a store shard, or when a directory the
+    /// store writes to (snapshot, bulk exports) is not writable
+    pub writable: bool,
+    pub observations: usize,
+    pub capacity: usize,
+    pub last_ingest: Option<DateTime<Utc>>,
+    /// How far the latest reading arrived behind its measurement time
+    pub lag_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueCheck {
+    pub status: CheckStatus,
+    pub ws_clients: usize,
+    pub bulk_exports_in_progress: usize,
+    /// Ingest pipeline stage queues; degraded while the ingest queue is full
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pipeline: Vec<QueueStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskCheck {
+    pub status: CheckStatus,
+    pub last_run: Option<DateTime<Utc>>,
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub device_id: String,
+    pub last_ingest: DateTime<Utc>,
+    pub last_reading_ts: DateTime<Utc>,
+    pub lag_secs: f64,
+    pub stale: bool,
+}
+
+/// Gather the readiness report. Anything degraded makes the whole report
+/// degraded; a shutdown in progress overrides both.
+pub async fn readiness(
+    state: &AppState,
+    settings: &Settings,
+    bulk: Option<&BulkExports>,
+    lifecycle: Option<&Lifecycle>,
+    pipeline: Option<&Pipeline>,
+    now: DateTime<Utc>,
+) -> Readiness {
+    let poisoned = state.is_poisoned();
+
+    let mut dirs: Vec<&Path> = Vec::new();
+    if let Some(path) = &settings.store.snapshot_path {
+        dirs.push(
+            path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+        );
+    }
+    if let Some(bulk) = bulk {
+        dirs.push(bulk.dir());
+    }
+    let mut error = poisoned.then(|| "store shard lock poisoned".to_string());
+    for dir in dirs {
+        if let Err(e) = probe_writable(dir).await {
+            error.get_or_insert(format!("{} not writable: {}", dir.display(), e));
+        }
+    }
+
+    let activity = state.devices();
+    let latest = activity.values().max_by_key(|d| d.last_ingest).copied();
+    let store = StoreCheck {
+        status: CheckStatus::from_ok(error.is_none()),
+        writable: error.is_none(),
+        observations: state.len(),
+        capacity: state.capacity(),
+        last_ingest: latest.map(|d| d.last_ingest),
+        lag_secs: latest.map(|d| secs(d.lag())),
+        error,
+    };
+
+    let queues = QueueCheck {
+        status: CheckStatus::from_ok(!pipeline.is_some_and(|p| p.is_full())),
+        ws_clients: state.ws_hub.client_count(),
+        bulk_exports_in_progress: bulk.map(|b| b.in_progress()).unwrap_or(0),
+        pipeline: pipeline.map(|p| p.queues()).unwrap_or_default(),
+    };
+
+    let interval = settings.store.compaction_interval_secs;
+    let last_run = state.last_compaction();
+    let stalled_after = chrono::Duration::seconds(interval as i64 * MISSED_COMPACTIONS);
+    let mut tasks = BTreeMap::new();
+    tasks.insert(
+        "compactor",
+        TaskCheck {
+            status: CheckStatus::from_ok(
+                now - last_run.unwrap_or(state.created_at()) <= stalled_after,
+            ),
+            last_run,
+            interval_secs: interval,
+        },
+    );
+
+    let stale_after = chrono::Duration::seconds(settings.health.stale_device_secs as i64);
+    let mut devices: Vec<DeviceStatus> = activity
+        .into_iter()
+        .map(|(id, d)| DeviceStatus {
+            device_id: id,
+            last_ingest: d.last_ingest,
+            last_reading_ts: d.last_reading_ts,
+            lag_secs: secs(d.lag()),
+            stale: now - d.last_ingest > stale_after,
+        })
+        .collect();
+    devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+    let degraded = store.status == CheckStatus::Degraded
+        || queues.status == CheckStatus::Degraded
+        || tasks.values().any(|t| t.status == CheckStatus::Degraded);
+    let status = if lifecycle.is_some_and(|l| !l.is_ready()) {
+        Status::Draining
+    } else if degraded {
+        Status::Degraded
+    } else {
+        Status::Ready
+    };
+
+    Readiness {
+        status,
+        checks: Checks {
+            store,
+            queues,
+            tasks,
+        },
+        devices,
+    }
+}
+
+/// Liveness only fails when the process cannot recover on its own: a panic
+/// while writing to the store leaves a shard poisoned for good.
+pub fn is_live(state: &AppState) -> bool {
+    !state.is_poisoned()
+}
+
+// Creates and removes a file in `dir` on a blocking thread, unless that was
+// done recently. While one check probes, concurrent checks report the
+// previous result (or none yet) instead of waiting for it.
+async fn probe_writable(dir: &Path) -> Result<(), String> {
+    {
+        let mut probes = PROBES.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = match probes.get(dir) {
+            Some(Probe::Done(at, result)) if at.elapsed() < PROBE_INTERVAL => {
+                return result.clone()
+            }
+            Some(Probe::Running(result)) => return result.clone(),
+            Some(Probe::Done(_, result)) => result.clone(),
+            None => Ok(()),
+        };
+        probes.insert(dir.to_path_buf(), Probe::Running(previous));
+    }
+    let dir = dir.to_path_buf();
+    // Records its own result, so it counts even if this check is dropped
+    let probe = tokio::task::spawn_blocking(move || {
+        let result = (|| {
+            std::fs::create_dir_all(&dir)?;
+            let probe = dir.join(format!(".probe-{}", uuid::Uuid::new_v4()));
+            std::fs::write(&probe, b"")?;
+            std::fs::remove_file(&probe)
+        })()
+        .map_err(|e: std::io::Error| e.to_string());
+        let done = Probe::Done(Instant::now(), result.clone());
+        PROBES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(dir, done);
+        result
+    });
+    probe.await.unwrap_or_else(|e| Err(e.to_string()))
+}
+
+fn secs(d: chrono::Duration) -> f64 {
+    d.num_milliseconds() as f64 / 1000.0
+}
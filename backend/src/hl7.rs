@@ -0,0 +1,507 @@
+use crate::domain::alerts::{Alert, AlertKind};
+use crate::domain::models::{SensorReading, SignalCode};
+use crate::domain::store::AppState;
+use crate::fhir;
+use crate::metrics::METRICS;
+use crate::settings::{Hl7Settings, SignalRange, SignalRanges};
+use crate::ws::{Hub, LiveEvent, LiveEvents};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// MLLP start block
+pub const START_BLOCK: u8 = 0x0B;
+/// MLLP end block, followed by a carriage return
+pub const END_BLOCK: [u8; 2] = [0x1C, 0x0D];
+// ACKs are a few segments; anything much longer is not an ACK
+const MAX_ACK_BYTES: usize = 64 * 1024;
+// How long `stop` lets queued messages go out
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// MSH sender and receiver fields.
+#[derive(Debug, Clone, Default)]
+pub struct Header {
+    pub sending_application: String,
+    pub sending_facility: String,
+    pub receiving_application: String,
+    pub receiving_facility: String,
+}
+
+impl From<&Hl7Settings> for Header {
+    fn from(s: &Hl7Settings) -> Self {
+        Self {
+            sending_application: s.sending_application.clone(),
+            sending_facility: s.sending_facility.clone(),
+            receiving_application: s.receiving_application.clone(),
+            receiving_facility: s.receiving_facility.clone(),
+        }
+    }
+}
+
+/// Escape the HL7 delimiters (`|^~\&`) and line breaks in a field value.
+pub fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\E\\"),
+            '|' => out.push_str("\\F\\"),
+            '^' => out.push_str("\\S\\"),
+            '&' => out.push_str("\\T\\"),
+            '~' => out.push_str("\\R\\"),
+            '\r' => out.push_str("\\X0D\\"),
+            '\n' => out.push_str("\\X0A\\"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn timestamp(ts: DateTime<Utc>) -> String {
+    ts.format("%Y%m%d%H%M%S%z").to_string()
+}
+
+// Join fields 1..=n of a segment; `fields` maps field number to an already
+// escaped value
+fn segment(name: &str, fields: &[(usize, String)]) -> String {
+    let len = fields.iter().map(|(i, _)| *i).max().unwrap_or(0);
+    let mut values = vec![String::new(); len];
+    for (i, value) in fields {
+        values[i - 1] = value.clone();
+    }
+    let mut out = name.to_string();
+    for value in values {
+        out.push('|');
+        out.push_str(&value);
+    }
+    out
+}
+
+// CWE: LOINC where there is a code, our own code otherwise
+fn observation_identifier(code: SignalCode) -> String {
+    match fhir::loinc_code(code) {
+        Some(loinc) => format!("{}^{}^LN", loinc, fhir::signal_name(code)),
+        None => format!("{}^{}^L", code.as_str(), fhir::signal_name(code)),
+    }
+}
+
+// OBX-6 takes UCUM units; temperatures are accepted as "C" or "°C"
+fn ucum_unit(unit: &str) -> &str {
+    match unit {
+        "C" | "°C" => "Cel",
+        unit => unit,
+    }
+}
+
+// The range outside which readings raise alerts
+fn reference_range(range: &SignalRange) -> String {
+    match (range.alert_below, range.alert_above) {
+        (Some(low), Some(high)) => format!("{}-{}", low, high),
+        (Some(low), None) => format!(">={}", low),
+        (None, Some(high)) => format!("<={}", high),
+        (None, None) => String::new(),
+    }
+}
+
+/// Render an observation, or an alert on one, as an ORU^R01 message with
+/// MSH, PID, OBR and OBX segments separated by carriage returns. An alert
+/// carries the abnormal flag (H/L) and a note naming the crossed threshold.
+pub fn oru_r01(
+    event: &LiveEvent,
+    header: &Header,
+    signals: &SignalRanges,
+    control_id: &str,
+    now: DateTime<Utc>,
+) -> String {
+    let (filler_id, reading, alert): (String, SensorReading, Option<&Alert>) = match event {
+        LiveEvent::Observation(obs) => (obs.id.to_string(), obs.reading.clone(), None),
+        LiveEvent::Alert(alert) => (
+            alert.id.to_string(),
+            SensorReading {
+                device_id: alert.device_id.clone(),
+                patient_id: alert.patient_id.clone(),
+                code: alert.code,
+                value: alert.value,
+                unit: alert.unit.clone(),
+                ts: alert.ts,
+            },
+            Some(alert),
+        ),
+    };
+    let sender = escape(&header.sending_application);
+    let observed_at = timestamp(reading.ts);
+
+    let mut segments = vec![
+        // MSH-1 is the field separator itself, so MSH-n goes at n - 1 here
+        segment(
+            "MSH",
+            &[
+                (1, "^~\\&".to_string()),
+                (2, sender.clone()),
+                (3, escape(&header.sending_facility)),
+                (4, escape(&header.receiving_application)),
+                (5, escape(&header.receiving_facility)),
+                (6, timestamp(now)),
+                (8, "ORU^R01^ORU_R01".to_string()),
+                (9, escape(control_id)),
+                (10, "P".to_string()),
+                (11, "2.5.1".to_string()),
+            ],
+        ),
+        segment(
+            "PID",
+            &[
+                (1, "1".into()),
+                (
+                    3,
+                    format!("{}^^^{}^MR", escape(&reading.patient_id), sender),
+                ),
+            ],
+        ),
+        segment(
+            "OBR",
+            &[
+                (1, "1".into()),
+                (3, format!("{}^{}", escape(&filler_id), sender)),
+                (4, observation_identifier(reading.code)),
+                (7, observed_at.clone()),
+                (25, "F".into()),
+            ],
+        ),
+        segment(
+            "OBX",
+            &[
+                (1, "1".into()),
+                (2, "NM".into()),
+                (3, observation_identifier(reading.code)),
+                (5, reading.value.to_string()),
+                (6, escape(ucum_unit(&reading.unit))),
+                (7, escape(&reference_range(signals.get(reading.code)))),
+                (
+                    8,
+                    match alert.map(|a| a.kind) {
+                        Some(AlertKind::High) => "H".into(),
+                        Some(AlertKind::Low) => "L".into(),
+                        None => String::new(),
+                    },
+                ),
+                (11, "F".into()),
+                (14, observed_at),
+                (18, escape(&reading.device_id)),
+            ],
+        ),
+    ];
+    if let Some(alert) = alert {
+        let note = format!(
+            "{} alert: {} {} is {} the threshold of {}",
+            alert.kind.as_str(),
+            alert.value,
+            alert.unit,
+            match alert.kind {
+                AlertKind::High => "above",
+                AlertKind::Low => "below",
+            },
+            alert.threshold
+        );
+        segments.push(segment("NTE", &[(1, "1".into()), (3, escape(&note))]));
+    }
+    let mut message = segments.join("\r");
+    message.push('\r');
+    message
+}
+
+/// Wrap a message in an MLLP frame.
+pub fn frame(message: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len() + 3);
+    out.push(START_BLOCK);
+    out.extend_from_slice(message.as_bytes());
+    out.extend_from_slice(&END_BLOCK);
+    out
+}
+
+/// A parsed MSA segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ack {
+    /// AA/AE/AR, or CA/CE/CR for enhanced-mode commit ACKs
+    pub code: String,
+    /// MSA-2: the control id of the message being acknowledged
+    pub control_id: String,
+    pub text: String,
+}
+
+impl Ack {
+    pub fn accepted(&self) -> bool {
+        matches!(self.code.as_str(), "AA" | "CA")
+    }
+
+    /// AE/CE: the receiver processed the message and failed; sending it
+    /// again would fail the same way. AR/CR (rejected, often because the
+    /// receiver is busy or down) are worth retrying.
+    pub fn is_permanent_error(&self) -> bool {
+        matches!(self.code.as_str(), "AE" | "CE")
+    }
+}
+
+/// Read the MSA segment out of an ACK message.
+pub fn parse_ack(message: &str) -> Option<Ack> {
+    let msa = message
+        .split(['\r', '\n'])
+        .find(|s| s.starts_with("MSA|"))?;
+    let fields: Vec<&str> = msa.split('|').collect();
+    Some(Ack {
+        code: fields.get(1)?.to_string(),
+        control_id: fields.get(2).unwrap_or(&"").to_string(),
+        text: fields.get(3).unwrap_or(&"").to_string(),
+    })
+}
+
+/// Read one MLLP frame; bytes before the start block are skipped.
+pub async fn read_frame<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<String> {
+    loop {
+        if let Some(start) = buf.iter().position(|b| *b == START_BLOCK) {
+            if let Some(end) = buf[start..].windows(2).position(|w| w == END_BLOCK) {
+                let message = String::from_utf8_lossy(&buf[start + 1..start + end]).into_owned();
+                buf.drain(..start + end + 2);
+                return Ok(message);
+            }
+        }
+        if buf.len() > MAX_ACK_BYTES {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "MLLP frame too long",
+            ));
+        }
+        let mut chunk = [0u8; 4096];
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Why one attempt did not deliver
+#[derive(Debug)]
+enum SendError {
+    // Worth retrying: connection trouble, timeout, AR/CR or a mismatched ACK
+    Retry(String),
+    // AE/CE: give up on this message
+    Permanent(String),
+}
+
+/// Keeps one MLLP connection to the interface engine and delivers messages
+/// one at a time, in order, waiting for each ACK.
+struct MllpClient {
+    addr: String,
+    timeout: Duration,
+    conn: Option<(TcpStream, Vec<u8>)>,
+}
+
+impl MllpClient {
+    async fn send(&mut self, message: &str, control_id: &str) -> Result<(), SendError> {
+        let result = tokio::time::timeout(self.timeout, self.exchange(message)).await;
+        let ack = match result {
+            Ok(Ok(reply)) => {
+                parse_ack(&reply).ok_or_else(|| "reply has no MSA segment".to_string())
+            }
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timed out waiting for ACK".to_string()),
+        };
+        let ack = match ack {
+            Ok(ack) => ack,
+            Err(reason) => {
+                // Start over on a fresh connection; a late ACK would otherwise
+                // be read as the next message's
+                self.conn = None;
+                METRICS
+                    .hl7_send_attempts_total
+                    .with_label_values(&["error"])
+                    .inc();
+                return Err(SendError::Retry(reason));
+            }
+        };
+        METRICS
+            .hl7_send_attempts_total
+            .with_label_values(&[&ack.code])
+            .inc();
+        if ack.control_id != control_id {
+            self.conn = None;
+            return Err(SendError::Retry(format!(
+                "ACK is for {:?}, not {:?}",
+                ack.control_id, control_id
+            )));
+        }
+        if ack.accepted() {
+            Ok(())
+        } else if ack.is_permanent_error() {
+            Err(SendError::Permanent(format!("{}: {}", ack.code, ack.text)))
+        } else {
+            Err(SendError::Retry(format!("{}: {}", ack.code, ack.text)))
+        }
+    }
+
+    async fn exchange(&mut self, message: &str) -> std::io::Result<String> {
+        if self.conn.is_none() {
+            let stream = TcpStream::connect(&self.addr).await?;
+            stream.set_nodelay(true)?;
+            self.conn = Some((stream, Vec::new()));
+        }
+        let (stream, buf) = self.conn.as_mut().expect("connected above");
+        stream.write_all(&frame(message)).await?;
+        read_frame(stream, buf).await
+    }
+}
+
+/// Sends an ORU^R01 over MLLP for every alert and, if configured, every
+/// observation. Events come from the WebSocket hub, like the MQTT alert
+/// publisher, and wait in a queue of `queue_capacity` messages while earlier
+/// ones are delivered; when it is full, new messages are counted as dropped.
+/// A message that cannot be delivered after `max_attempts` is logged and
+/// dropped so the ones behind it are not held up for good.
+pub struct Hl7Forwarder {
+    hub: Hub,
+    id: u64,
+    dispatcher: JoinHandle<()>,
+    sender: JoinHandle<()>,
+}
+
+impl Hl7Forwarder {
+    pub fn start(state: Arc<AppState>, settings: &Hl7Settings) -> Self {
+        // Registered before returning, so no event after `start` is missed
+        let hub = state.ws_hub.clone();
+        let (id, events) = hub.add_listener();
+        let (queue, queued) = mpsc::channel(settings.queue_capacity);
+        let dispatcher = actix_rt::spawn(dispatch(state, events, queue, settings.clone()));
+        let sender = actix_rt::spawn(send_all(queued, settings.clone()));
+        Self {
+            hub,
+            id,
+            dispatcher,
+            sender,
+        }
+    }
+
+    /// Stop taking events and wait for queued messages to go out. Call once
+    /// the pipeline has drained, so alerts raised while draining are sent.
+    pub async fn stop(self) {
+        // Ends the dispatcher once it has read what was already broadcast
+        self.hub.remove_client(self.id);
+        let aborts = [self.dispatcher.abort_handle(), self.sender.abort_handle()];
+        let all = async {
+            let _ = self.dispatcher.await;
+            let _ = self.sender.await;
+        };
+        if tokio::time::timeout(STOP_TIMEOUT, all).await.is_err() {
+            tracing::warn!(
+                "HL7 forwarder did not finish in time; undelivered messages are dropped"
+            );
+            aborts.iter().for_each(|a| a.abort());
+        }
+    }
+}
+
+// A rendered message waiting for delivery
+struct Outgoing {
+    kind: &'static str,
+    control_id: String,
+    message: String,
+}
+
+async fn dispatch(
+    state: Arc<AppState>,
+    mut events: LiveEvents,
+    queue: mpsc::Sender<Outgoing>,
+    settings: Hl7Settings,
+) {
+    let header = Header::from(&settings);
+    while let Some(event) = events.recv().await {
+        let kind = match *event {
+            LiveEvent::Alert(_) => "alert",
+            LiveEvent::Observation(_) if settings.send_observations => "observation",
+            _ => continue,
+        };
+        // MSH-10 is at most 20 characters
+        let control_id = Uuid::new_v4().simple().to_string()[..20].to_string();
+        let message = oru_r01(&event, &header, state.signals(), &control_id, Utc::now());
+        let outgoing = Outgoing {
+            kind,
+            control_id,
+            message,
+        };
+        if let Err(e) = queue.try_send(outgoing) {
+            let outgoing = e.into_inner();
+            tracing::warn!(
+                control_id = outgoing.control_id,
+                "HL7 queue is full; message dropped"
+            );
+            METRICS
+                .hl7_messages_total
+                .with_label_values(&[kind, "dropped"])
+                .inc();
+        }
+    }
+}
+
+// Deliver queued messages one at a time, in order
+async fn send_all(mut queued: mpsc::Receiver<Outgoing>, settings: Hl7Settings) {
+    let mut client = MllpClient {
+        addr: settings.addr(),
+        timeout: Duration::from_secs(settings.ack_timeout_secs),
+        conn: None,
+    };
+    while let Some(outgoing) = queued.recv().await {
+        let result = deliver(
+            &mut client,
+            &settings,
+            &outgoing.message,
+            &outgoing.control_id,
+        )
+        .await;
+        METRICS
+            .hl7_messages_total
+            .with_label_values(&[outgoing.kind, result])
+            .inc();
+    }
+}
+
+async fn deliver(
+    client: &mut MllpClient,
+    settings: &Hl7Settings,
+    message: &str,
+    control_id: &str,
+) -> &'static str {
+    let mut backoff = Duration::from_millis(settings.retry_initial_ms);
+    for attempt in 1..=settings.max_attempts {
+        match client.send(message, control_id).await {
+            Ok(()) => return "acked",
+            Err(SendError::Permanent(reason)) => {
+                tracing::error!(
+                    control_id,
+                    "HL7 message rejected by the receiver: {}",
+                    reason
+                );
+                return "rejected";
+            }
+            Err(SendError::Retry(reason)) => {
+                tracing::warn!(control_id, attempt, "HL7 send failed: {}", reason);
+                if attempt < settings.max_attempts {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_millis(settings.retry_max_ms));
+                }
+            }
+        }
+    }
+    tracing::error!(
+        control_id,
+        attempts = settings.max_attempts,
+        "HL7 message dropped after retries"
+    );
+    "failed"
+}
@@ -1,6 +1,26 @@
+pub mod audit;
+pub mod bulk;
+pub mod coap;
+pub mod cors;
 pub mod domain;
 pub mod errors;
+pub mod export;
 pub mod fhir;
+pub mod gatt;
+pub mod grpc;
+pub mod health;
+pub mod hl7;
+pub mod lifecycle;
+pub mod metrics;
+pub mod mqtt;
+pub mod pipeline;
+pub mod ratelimit;
 pub mod routes;
+pub mod settings;
+pub mod sse;
+pub mod subscriptions;
 pub mod telemetry;
+pub mod tls;
+pub mod upstream;
+pub mod webhooks;
 pub mod ws;
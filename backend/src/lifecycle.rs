@@ -0,0 +1,92 @@
+use crate::domain::store::AppState;
+use crate::settings::ServerSettings;
+use actix_web::dev::ServerHandle;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Close reason sent to WebSocket clients on shutdown.
+pub const SHUTDOWN_REASON: &str = "server restarting";
+/// How long clients are told to wait before reconnecting.
+pub const RECONNECT_AFTER_SECS: u64 = 5;
+// Upper bound on waiting for WebSocket clients to go away
+const WS_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the server should receive new traffic. Flipped to not-ready as
+/// the first step of shutdown so load balancers stop routing here.
+#[derive(Debug)]
+pub struct Lifecycle {
+    ready: AtomicBool,
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self {
+            ready: AtomicBool::new(true),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.ready.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Shut down in order: report not-ready, give load balancers
+/// `drain_delay_secs` to notice, close WebSocket clients with a reconnect
+/// hint, then stop the server once in-flight requests have finished (up to
+/// the server's shutdown timeout). Flushing the store is left to the caller,
+/// after the server future has resolved.
+pub async fn drain(
+    lifecycle: &Lifecycle,
+    state: &AppState,
+    server: ServerHandle,
+    settings: &ServerSettings,
+) {
+    lifecycle.begin_shutdown();
+    tracing::info!(
+        drain_delay_secs = settings.drain_delay_secs,
+        "shutting down: readiness off"
+    );
+    tokio::time::sleep(Duration::from_secs(settings.drain_delay_secs)).await;
+
+    let hub = &state.ws_hub;
+    let clients = hub.client_count();
+    hub.close_all(SHUTDOWN_REASON, RECONNECT_AFTER_SECS);
+    let deadline = Instant::now() + WS_DRAIN_TIMEOUT;
+    while hub.client_count() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    tracing::info!(
+        clients,
+        remaining = hub.client_count(),
+        "websocket clients closed"
+    );
+
+    server.stop(true).await;
+}
@@ -0,0 +1,351 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::HttpResponse;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+/// Process-wide metrics, exposed in Prometheus text format on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub ingest_total: IntCounterVec,
+    pub errors_total: IntCounterVec,
+    pub store_observations: IntGauge,
+    pub store_evictions_total: IntCounter,
+    pub ws_clients: IntGauge,
+    pub ws_send_failures_total: IntCounter,
+    pub live_events_lagged_total: IntCounter,
+    pub http_request_duration: HistogramVec,
+    pub rate_limited_total: IntCounterVec,
+    pub rate_limit_buckets: IntGauge,
+    pub pipeline_queue_depth: IntGaugeVec,
+    pub pipeline_rejected_total: IntCounter,
+    pub pipeline_stage_duration: HistogramVec,
+    pub alerts_total: IntCounterVec,
+    pub mqtt_messages_total: IntCounterVec,
+    pub mqtt_connected: IntGauge,
+    pub coap_requests_total: IntCounterVec,
+    pub hl7_messages_total: IntCounterVec,
+    pub hl7_send_attempts_total: IntCounterVec,
+    pub upstream_outbox_depth: IntGauge,
+    pub upstream_observations_total: IntCounterVec,
+    pub upstream_requests_total: IntCounterVec,
+    pub webhook_deliveries_total: IntCounterVec,
+    pub subscription_notifications_total: IntCounterVec,
+    pub audit_records_total: IntCounterVec,
+    pub audit_write_errors_total: IntCounter,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("pulsesense".into()), None).unwrap();
+
+        let ingest_total = IntCounterVec::new(
+            Opts::new(
+                "ingest_total",
+                "Readings received on ingest, by signal code and result",
+            ),
+            &["code", "result"],
+        )
+        .unwrap();
+        let errors_total = IntCounterVec::new(
+            Opts::new("errors_total", "Error responses, by AppError variant"),
+            &["variant"],
+        )
+        .unwrap();
+        let store_observations =
+            IntGauge::new("store_observations", "Raw observations currently held").unwrap();
+        let store_evictions_total = IntCounter::new(
+            "store_evictions_total",
+            "Raw observations evicted for capacity or retention",
+        )
+        .unwrap();
+        let ws_clients = IntGauge::new("ws_clients", "Connected WebSocket clients").unwrap();
+        let ws_send_failures_total = IntCounter::new(
+            "ws_send_failures_total",
+            "Broadcasts that could not be queued for a client",
+        )
+        .unwrap();
+        let live_events_lagged_total = IntCounter::new(
+            "live_events_lagged_total",
+            "Live events an in-process consumer missed by falling behind",
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency, by route",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+
+        let rate_limited_total = IntCounterVec::new(
+            Opts::new(
+                "rate_limited_total",
+                "Requests rejected by rate limiting, by route and key type",
+            ),
+            &["route", "key"],
+        )
+        .unwrap();
+        let rate_limit_buckets =
+            IntGauge::new("rate_limit_buckets", "Token buckets currently tracked").unwrap();
+
+        let pipeline_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "pipeline_queue_depth",
+                "Readings waiting in front of each ingest pipeline stage",
+            ),
+            &["stage"],
+        )
+        .unwrap();
+        let pipeline_rejected_total = IntCounter::new(
+            "pipeline_rejected_total",
+            "Readings refused with 503 because the ingest queue was full",
+        )
+        .unwrap();
+        let pipeline_stage_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "pipeline_stage_duration_seconds",
+                "Time spent processing one reading, by stage",
+            ),
+            &["stage"],
+        )
+        .unwrap();
+        let alerts_total = IntCounterVec::new(
+            Opts::new(
+                "alerts_total",
+                "Threshold alerts raised, by signal code and kind",
+            ),
+            &["code", "kind"],
+        )
+        .unwrap();
+
+        let mqtt_messages_total = IntCounterVec::new(
+            Opts::new(
+                "mqtt_messages_total",
+                "MQTT messages received by the ingest bridge, by result",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let mqtt_connected = IntGauge::new(
+            "mqtt_connected",
+            "1 while the MQTT bridge is connected to the broker",
+        )
+        .unwrap();
+        let coap_requests_total = IntCounterVec::new(
+            Opts::new(
+                "coap_requests_total",
+                "CoAP requests answered, by response code (e.g. 2.01)",
+            ),
+            &["code"],
+        )
+        .unwrap();
+        let hl7_messages_total = IntCounterVec::new(
+            Opts::new(
+                "hl7_messages_total",
+                "HL7 ORU^R01 messages by kind and final result (acked, rejected, failed, dropped)",
+            ),
+            &["kind", "result"],
+        )
+        .unwrap();
+        let hl7_send_attempts_total = IntCounterVec::new(
+            Opts::new(
+                "hl7_send_attempts_total",
+                "MLLP send attempts by outcome (AA, AE, AR, error, ...)",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let upstream_outbox_depth = IntGauge::new(
+            "upstream_outbox_depth",
+            "Observations waiting to be sent to the upstream FHIR server",
+        )
+        .unwrap();
+        let upstream_observations_total = IntCounterVec::new(
+            Opts::new(
+                "upstream_observations_total",
+                "Observations leaving the FHIR outbox, by result (delivered, dead_lettered)",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let upstream_requests_total = IntCounterVec::new(
+            Opts::new(
+                "upstream_requests_total",
+                "Transaction Bundles POSTed upstream, by HTTP status (or error)",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        let webhook_deliveries_total = IntCounterVec::new(
+            Opts::new(
+                "webhook_deliveries_total",
+                "Webhook deliveries by final result (delivered, failed, dropped)",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let subscription_notifications_total = IntCounterVec::new(
+            Opts::new(
+                "subscription_notifications_total",
+                "FHIR Subscription notifications sent, by channel and type (handshake, heartbeat, event-notification)",
+            ),
+            &["channel", "type"],
+        )
+        .unwrap();
+        let audit_records_total = IntCounterVec::new(
+            Opts::new(
+                "audit_records_total",
+                "Audit records written, by outcome (success, failure, error)",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+        let audit_write_errors_total = IntCounter::new(
+            "audit_write_errors_total",
+            "Audit records that could not be written",
+        )
+        .unwrap();
+
+        registry.register(Box::new(ingest_total.clone())).unwrap();
+        registry.register(Box::new(errors_total.clone())).unwrap();
+        registry
+            .register(Box::new(store_observations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(store_evictions_total.clone()))
+            .unwrap();
+        registry.register(Box::new(ws_clients.clone())).unwrap();
+        registry
+            .register(Box::new(ws_send_failures_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(live_events_lagged_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limited_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limit_buckets.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pipeline_queue_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pipeline_rejected_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pipeline_stage_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(alerts_total.clone())).unwrap();
+        registry
+            .register(Box::new(mqtt_messages_total.clone()))
+            .unwrap();
+        registry.register(Box::new(mqtt_connected.clone())).unwrap();
+        registry
+            .register(Box::new(coap_requests_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(hl7_messages_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(hl7_send_attempts_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_outbox_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_observations_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_requests_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(webhook_deliveries_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(subscription_notifications_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(audit_records_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(audit_write_errors_total.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            ingest_total,
+            errors_total,
+            store_observations,
+            store_evictions_total,
+            ws_clients,
+            ws_send_failures_total,
+            live_events_lagged_total,
+            http_request_duration,
+            rate_limited_total,
+            rate_limit_buckets,
+            pipeline_queue_depth,
+            pipeline_rejected_total,
+            pipeline_stage_duration,
+            alerts_total,
+            mqtt_messages_total,
+            mqtt_connected,
+            coap_requests_total,
+            hl7_messages_total,
+            hl7_send_attempts_total,
+            upstream_outbox_depth,
+            upstream_observations_total,
+            upstream_requests_total,
+            webhook_deliveries_total,
+            subscription_notifications_total,
+            audit_records_total,
+            audit_write_errors_total,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        // Encoding into a Vec cannot fail
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buf);
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
+/// Middleware recording request latency per matched route pattern, so
+/// `/fhir/$export-status/{id}` is one series rather than one per job.
+pub async fn track_latency(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let res = next.call(req).await?;
+
+    METRICS
+        .http_request_duration
+        .with_label_values(&[method.as_str(), route.as_str(), res.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    Ok(res)
+}
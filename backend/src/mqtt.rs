@@ -0,0 +1,493 @@
+use crate::audit::{Action, AuditLog, Entry, Outcome};
+use crate::domain::models::SensorReading;
+use crate::domain::store::AppState;
+use crate::errors::AppError;
+use crate::fhir;
+use crate::metrics::METRICS;
+use crate::pipeline::{self, Pipeline};
+use crate::settings::MqttSettings;
+use crate::ws::{Hub, LiveEvent, LiveEvents};
+use rumqttc::{
+    AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, Publish, QoS, TlsConfiguration,
+    Transport,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+// Pause before the event loop reconnects after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// How long each step of `stop` may take: finishing the reading in hand,
+// publishing the last alerts, and the broker taking the DISCONNECT
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+// Messages read off the connection but not yet taken by ingest; beyond
+// this they are acked and dropped. QoS 1 deliveries are mostly bounded by
+// the broker's in-flight window first, since they are only acked once queued
+const HANDOFF_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    DeviceId,
+    PatientId,
+    Code,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Level {
+    Literal(String),
+    Field(Field),
+}
+
+/// A topic such as `pulsesense/{device_id}/{code}`. Each placeholder fills a
+/// whole level, so it subscribes as `+` and can be read back from a topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicTemplate {
+    levels: Vec<Level>,
+}
+
+/// Values taken from (or written into) a topic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopicFields {
+    pub device_id: Option<String>,
+    pub patient_id: Option<String>,
+    pub code: Option<String>,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if template.is_empty() {
+            return Err("must not be empty".into());
+        }
+        let mut levels = Vec::new();
+        for level in template.split('/') {
+            let level = match level {
+                "{device_id}" => Level::Field(Field::DeviceId),
+                "{patient_id}" => Level::Field(Field::PatientId),
+                "{code}" => Level::Field(Field::Code),
+                _ if level.contains(['{', '}']) => {
+                    return Err(format!(
+                        "has unknown or partial-level placeholder {:?}",
+                        level
+                    ));
+                }
+                _ if level.contains(['+', '#']) => return Err("must not contain wildcards".into()),
+                _ => Level::Literal(level.to_string()),
+            };
+            if let Level::Field(f) = level {
+                if levels.contains(&Level::Field(f)) {
+                    return Err("repeats a placeholder".into());
+                }
+            }
+            levels.push(level);
+        }
+        Ok(Self { levels })
+    }
+
+    /// Subscription filter: every placeholder becomes `+`.
+    pub fn filter(&self) -> String {
+        self.levels
+            .iter()
+            .map(|l| match l {
+                Level::Literal(s) => s.as_str(),
+                Level::Field(_) => "+",
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Read the placeholder values out of `topic`, or None if it does not fit.
+    pub fn capture(&self, topic: &str) -> Option<TopicFields> {
+        let parts: Vec<&str> = topic.split('/').collect();
+        if parts.len() != self.levels.len() {
+            return None;
+        }
+        let mut fields = TopicFields::default();
+        for (level, part) in self.levels.iter().zip(parts) {
+            match level {
+                Level::Literal(s) if s == part => {}
+                Level::Literal(_) => return None,
+                Level::Field(_) if part.is_empty() => return None,
+                Level::Field(Field::DeviceId) => fields.device_id = Some(part.to_string()),
+                Level::Field(Field::PatientId) => fields.patient_id = Some(part.to_string()),
+                Level::Field(Field::Code) => fields.code = Some(part.to_string()),
+            }
+        }
+        Some(fields)
+    }
+
+    pub fn render(&self, fields: &TopicFields) -> String {
+        self.levels
+            .iter()
+            .map(|l| match l {
+                Level::Literal(s) => s.as_str(),
+                Level::Field(Field::DeviceId) => fields.device_id.as_deref().unwrap_or_default(),
+                Level::Field(Field::PatientId) => fields.patient_id.as_deref().unwrap_or_default(),
+                Level::Field(Field::Code) => fields.code.as_deref().unwrap_or_default(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Decode a message payload: a `SensorReading` (fields the topic carries may
+/// be left out) or a FHIR Observation. Topic values must agree with the
+/// payload.
+pub fn decode(fields: &TopicFields, payload: &[u8]) -> Result<SensorReading, AppError> {
+    let mut value: serde_json::Value = serde_json::from_slice(payload)
+        .map_err(|e| AppError::Validation(format!("invalid JSON payload: {}", e)))?;
+
+    let reading = if value.get("resourceType").and_then(|v| v.as_str()) == Some("Observation") {
+        let obs = serde_json::from_value(value)
+            .map_err(|e| AppError::Validation(format!("invalid FHIR Observation: {}", e)))?;
+        fhir::from_fhir_observation(obs, fields.device_id.as_deref())?
+    } else {
+        if let Some(obj) = value.as_object_mut() {
+            for (key, v) in [
+                ("device_id", &fields.device_id),
+                ("patient_id", &fields.patient_id),
+                ("code", &fields.code),
+            ] {
+                if let Some(v) = v {
+                    obj.entry(key).or_insert_with(|| v.clone().into());
+                }
+            }
+        }
+        serde_json::from_value::<SensorReading>(value)
+            .map_err(|e| AppError::Validation(format!("invalid reading: {}", e)))?
+    };
+
+    for (name, topic, payload) in [
+        ("device_id", &fields.device_id, reading.device_id.as_str()),
+        (
+            "patient_id",
+            &fields.patient_id,
+            reading.patient_id.as_str(),
+        ),
+        ("code", &fields.code, reading.code.as_str()),
+    ] {
+        if topic.as_deref().is_some_and(|t| t != payload) {
+            return Err(AppError::Validation(format!(
+                "{} in payload does not match the topic",
+                name
+            )));
+        }
+    }
+    Ok(reading)
+}
+
+/// Subscribes to the configured topics and feeds readings through the same
+/// validation and pipeline as `POST /ingest`; publishes alerts back to
+/// `alert_topic`.
+pub struct MqttBridge {
+    client: AsyncClient,
+    hub: Hub,
+    events: JoinHandle<()>,
+    // Taken by `stop_ingest`
+    ingest: Option<JoinHandle<()>>,
+    stop_ingest: watch::Sender<bool>,
+    // Hub listener and publishing task, when alerts are published
+    alerts: Option<(u64, JoinHandle<()>)>,
+}
+
+impl MqttBridge {
+    /// Connect in the background; the event loop reconnects on its own.
+    /// Topics must already have passed settings validation; fails if the
+    /// TLS files cannot be read.
+    pub fn start(
+        state: Arc<AppState>,
+        pipeline: Option<Arc<Pipeline>>,
+        audit: Option<Arc<AuditLog>>,
+        settings: &MqttSettings,
+    ) -> std::io::Result<Self> {
+        let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+        options.set_keep_alive(Duration::from_secs(settings.keep_alive_secs));
+        // Acked once the reading is queued, so a full pipeline holds back the
+        // broker; the session outlives a restart, so the broker redelivers
+        // whatever was not acked
+        options.set_manual_acks(true);
+        options.set_clean_session(false);
+        if let Some(username) = &settings.username {
+            options.set_credentials(username, settings.password.clone().unwrap_or_default());
+        }
+        if settings.tls {
+            options.set_transport(Transport::tls_with_config(broker_tls(settings)?));
+        }
+        let (client, eventloop) = AsyncClient::new(options, 64);
+
+        let templates: Vec<TopicTemplate> = settings
+            .topics
+            .iter()
+            .filter_map(|t| TopicTemplate::parse(t).ok())
+            .collect();
+        let (handoff, messages) = mpsc::channel(HANDOFF_CAPACITY);
+        let (stop_ingest, stopping) = watch::channel(false);
+        let events = actix_rt::spawn(run(eventloop, client.clone(), handoff, templates.clone()));
+        let ingest = Some(actix_rt::spawn(ingest(
+            client.clone(),
+            state.clone(),
+            pipeline,
+            audit,
+            templates,
+            messages,
+            stopping,
+        )));
+
+        let hub = state.ws_hub.clone();
+        let alerts = TopicTemplate::parse(&settings.alert_topic)
+            .ok()
+            .map(|alert_topic| {
+                let (id, events) = hub.add_listener();
+                (
+                    id,
+                    actix_rt::spawn(publish_alerts(client.clone(), events, alert_topic)),
+                )
+            });
+
+        Ok(Self {
+            client,
+            hub,
+            events,
+            ingest,
+            stop_ingest,
+            alerts,
+        })
+    }
+
+    /// Stop taking readings; call before the pipeline shuts down. Messages
+    /// arriving from now on are left unacked for the broker to redeliver.
+    pub async fn stop_ingest(&mut self) {
+        let _ = self.stop_ingest.send(true);
+        let Some(ingest) = self.ingest.take() else {
+            return;
+        };
+        let abort = ingest.abort_handle();
+        if tokio::time::timeout(STOP_TIMEOUT, ingest).await.is_err() {
+            abort.abort();
+        }
+    }
+
+    /// Publish the alerts the pipeline raised while draining, then
+    /// disconnect. Call once the pipeline has shut down.
+    pub async fn stop(mut self) {
+        self.stop_ingest().await;
+        if let Some((id, publisher)) = self.alerts.take() {
+            self.hub.remove_client(id);
+            let abort = publisher.abort_handle();
+            if tokio::time::timeout(STOP_TIMEOUT, publisher).await.is_err() {
+                tracing::warn!("MQTT alerts not all published before shutdown");
+                abort.abort();
+            }
+        }
+        let _ = self.client.try_disconnect();
+        let abort = self.events.abort_handle();
+        if tokio::time::timeout(STOP_TIMEOUT, self.events)
+            .await
+            .is_err()
+        {
+            abort.abort();
+        }
+        METRICS.mqtt_connected.set(0);
+    }
+}
+
+// Keeps polling the connection, so keep-alive pings go out however long the
+// pipeline holds up ingest, and hands each message to `ingest`
+async fn run(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    handoff: mpsc::Sender<Publish>,
+    templates: Vec<TopicTemplate>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                METRICS.mqtt_connected.set(1);
+                tracing::info!(topics = templates.len(), "MQTT bridge connected");
+                // Subscribe on every connect, in case the broker lost the session
+                for template in &templates {
+                    if let Err(e) = client.try_subscribe(template.filter(), QoS::AtLeastOnce) {
+                        tracing::error!("MQTT subscribe failed: {}", e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => match handoff.try_send(publish) {
+                Ok(()) => {}
+                // Acked anyway: left unacked it would hold one of the broker's
+                // in-flight slots until the next session
+                Err(mpsc::error::TrySendError::Full(publish)) => {
+                    METRICS
+                        .mqtt_messages_total
+                        .with_label_values(&["dropped"])
+                        .inc();
+                    tracing::warn!(topic = %publish.topic, "MQTT message dropped: ingest is behind");
+                    if let Err(e) = client.try_ack(&publish) {
+                        tracing::warn!("MQTT ack failed: {}", e);
+                    }
+                }
+                // Left unacked: the broker redelivers it on the next session
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    METRICS
+                        .mqtt_messages_total
+                        .with_label_values(&["unavailable"])
+                        .inc();
+                    tracing::warn!("MQTT message not taken: ingest has stopped");
+                }
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                METRICS.mqtt_connected.set(0);
+                tracing::warn!("MQTT connection error: {}; reconnecting", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// Takes messages in order until `stop_ingest`; each is acked once it is
+// settled: queued, or rejected for good
+async fn ingest(
+    client: AsyncClient,
+    state: Arc<AppState>,
+    pipeline: Option<Arc<Pipeline>>,
+    audit: Option<Arc<AuditLog>>,
+    templates: Vec<TopicTemplate>,
+    mut messages: mpsc::Receiver<Publish>,
+    mut stopping: watch::Receiver<bool>,
+) {
+    loop {
+        let publish = tokio::select! {
+            biased;
+            _ = stopping.wait_for(|stop| *stop) => break,
+            publish = messages.recv() => match publish {
+                Some(publish) => publish,
+                None => break,
+            },
+        };
+        let span = tracing::info_span!("mqtt_ingest", topic = %publish.topic);
+        let settled = handle(
+            &state,
+            pipeline.as_deref(),
+            audit.as_deref(),
+            &templates,
+            &publish,
+        )
+        .instrument(span)
+        .await;
+        if settled {
+            if let Err(e) = client.ack(&publish).await {
+                tracing::warn!("MQTT ack failed: {}", e);
+            }
+        }
+    }
+}
+
+// False when the reading could not be queued for now and should come again
+async fn handle(
+    state: &AppState,
+    pipeline: Option<&Pipeline>,
+    audit: Option<&AuditLog>,
+    templates: &[TopicTemplate],
+    publish: &Publish,
+) -> bool {
+    let Some(fields) = templates.iter().find_map(|t| t.capture(&publish.topic)) else {
+        METRICS
+            .mqtt_messages_total
+            .with_label_values(&["unmatched"])
+            .inc();
+        return true;
+    };
+    // No device identity check: the broker's own authentication decides
+    // who may publish, and settings refuse MQTT with tls.require_client_cert
+    let reading = decode(&fields, &publish.payload);
+    let patient = reading
+        .as_ref()
+        .ok()
+        .map(|r| r.patient_id.clone())
+        .or(fields.patient_id);
+    let result = match reading.and_then(|r| pipeline::admit(state, r)) {
+        Ok(obs) => match pipeline {
+            Some(pipeline) => pipeline.send(obs).await,
+            None => {
+                pipeline::process(state, &obs);
+                Ok(())
+            }
+        },
+        Err(e) => Err(e),
+    };
+    let outcome = match &result {
+        Ok(()) => "accepted",
+        Err(AppError::Unavailable(_)) => "unavailable",
+        Err(_) => "rejected",
+    };
+    METRICS
+        .mqtt_messages_total
+        .with_label_values(&[outcome])
+        .inc();
+    if let Some(audit) = audit {
+        // The broker does not say who published, only where
+        audit.record(Entry {
+            action: Action::Create,
+            operation: "mqtt publish".to_string(),
+            actor: "mqtt".to_string(),
+            client_ip: None,
+            request_id: None,
+            query: Some(publish.topic.clone()),
+            patients: patient.into_iter().collect(),
+            outcome: match &result {
+                Ok(()) => Outcome::Success,
+                Err(AppError::Unavailable(_)) => Outcome::Error,
+                Err(_) => Outcome::Failure,
+            },
+            detail: Some(outcome.to_string()),
+        });
+    }
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!("MQTT reading not ingested: {}", e);
+            !matches!(e, AppError::Unavailable(_))
+        }
+    }
+}
+
+// The CA the broker is verified against and the client certificate, if
+// any, as PEM; rumqttc parses them when it connects
+fn broker_tls(settings: &MqttSettings) -> std::io::Result<TlsConfiguration> {
+    let read = |path: &Option<PathBuf>| path.as_deref().map(std::fs::read).transpose();
+    Ok(TlsConfiguration::Simple {
+        ca: read(&settings.ca_path)?.unwrap_or_default(),
+        alpn: None,
+        client_auth: read(&settings.client_cert_path)?.zip(read(&settings.client_key_path)?),
+    })
+}
+
+// Republish the alerts the hub publishes until the listener is removed
+async fn publish_alerts(client: AsyncClient, mut events: LiveEvents, topic: TopicTemplate) {
+    while let Some(event) = events.recv().await {
+        let LiveEvent::Alert(alert) = &*event else {
+            continue;
+        };
+        let fields = TopicFields {
+            device_id: Some(alert.device_id.clone()),
+            patient_id: Some(alert.patient_id.clone()),
+            code: Some(alert.code.as_str().to_string()),
+        };
+        let payload = match serde_json::to_vec(&alert) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!("serializing alert failed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = client
+            .publish(topic.render(&fields), QoS::AtLeastOnce, false, payload)
+            .await
+        {
+            tracing::warn!(alert_id = %alert.id, "publishing alert to MQTT failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::domain::alerts::{self, Alert};
+use crate::domain::models::{SensorReading, StoredObservation};
+use crate::domain::store::AppState;
+use crate::errors::AppError;
+use crate::metrics::METRICS;
+use crate::settings::PipelineSettings;
+use crate::upstream::Upstream;
+use crate::ws::LiveEvent;
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Stage names, in processing order; also the `stage` metric label.
+pub const STAGES: [&str; 3] = ["persist", "alerts", "broadcast"];
+
+// Most readings the persist stage takes off its queue at once
+const JOURNAL_BATCH: usize = 256;
+
+struct Envelope<T> {
+    item: T,
+    span: tracing::Span,
+}
+
+/// Depth bookkeeping for the channel in front of one stage.
+struct StageQueue {
+    name: &'static str,
+    capacity: usize,
+    depth: AtomicUsize,
+}
+
+impl StageQueue {
+    fn new(name: &'static str, capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            capacity,
+            depth: AtomicUsize::new(0),
+        })
+    }
+
+    fn pushed(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        METRICS
+            .pipeline_queue_depth
+            .with_label_values(&[self.name])
+            .set(depth as i64);
+    }
+
+    fn popped(&self) {
+        let depth = self.depth.fetch_sub(1, Ordering::SeqCst) - 1;
+        METRICS
+            .pipeline_queue_depth
+            .with_label_values(&[self.name])
+            .set(depth as i64);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStatus {
+    pub stage: &'static str,
+    pub depth: usize,
+    pub capacity: usize,
+}
+
+/// Ingest runs as three stages (persist, evaluate alerts, broadcast) connected
+/// by bounded channels. `/ingest` only validates and enqueues; when the first
+/// queue is full the reading is refused instead of buffered without limit,
+/// and a slow later stage backs up into the earlier ones.
+pub struct Pipeline {
+    // Taken on shutdown so the stages drain and exit
+    tx: RwLock<Option<mpsc::Sender<Envelope<StoredObservation>>>>,
+    queues: [Arc<StageQueue>; 3],
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Pipeline {
+    /// Spawn the stage tasks on the current runtime.
+    pub fn start(state: Arc<AppState>, settings: &PipelineSettings) -> Self {
+        Self::start_with_upstream(state, settings, None)
+    }
+
+    /// Like `start`, with the persist stage also journaling each stored
+    /// observation in the upstream FHIR outbox before passing it on. Readings
+    /// already waiting are stored and journaled together, one fsync for all,
+    /// on a blocking thread so the other stages keep running meanwhile.
+    pub fn start_with_upstream(
+        state: Arc<AppState>,
+        settings: &PipelineSettings,
+        upstream: Option<Arc<Upstream>>,
+    ) -> Self {
+        let queues = [
+            StageQueue::new(STAGES[0], settings.queue_capacity),
+            StageQueue::new(STAGES[1], settings.stage_capacity),
+            StageQueue::new(STAGES[2], settings.stage_capacity),
+        ];
+        let (ingest_tx, mut ingest_rx) =
+            mpsc::channel::<Envelope<StoredObservation>>(queues[0].capacity);
+        let (alerts_tx, mut alerts_rx) =
+            mpsc::channel::<Envelope<StoredObservation>>(queues[1].capacity);
+        let (broadcast_tx, mut broadcast_rx) =
+            mpsc::channel::<Envelope<(StoredObservation, Option<Alert>)>>(queues[2].capacity);
+
+        let persist = {
+            let (state, queue, next) = (state.clone(), queues[0].clone(), queues[1].clone());
+            actix_rt::spawn(async move {
+                while let Some(env) = ingest_rx.recv().await {
+                    let mut batch = vec![env];
+                    while batch.len() < JOURNAL_BATCH {
+                        match ingest_rx.try_recv() {
+                            Ok(env) => batch.push(env),
+                            Err(_) => break,
+                        }
+                    }
+                    for env in &batch {
+                        queue.popped();
+                        env.span.in_scope(|| persist(&state, &env.item));
+                    }
+                    if let Some(upstream) = &upstream {
+                        journal(upstream, &batch).await;
+                    }
+                    for env in batch {
+                        next.pushed();
+                        if alerts_tx.send(env).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        };
+        let evaluate = {
+            let (state, queue, next) = (state.clone(), queues[1].clone(), queues[2].clone());
+            actix_rt::spawn(async move {
+                while let Some(Envelope { item, span }) = alerts_rx.recv().await {
+                    queue.popped();
+                    let alert = span.in_scope(|| evaluate(&state, &item));
+                    next.pushed();
+                    let env = Envelope {
+                        item: (item, alert),
+                        span,
+                    };
+                    if broadcast_tx.send(env).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+        let broadcast = {
+            let queue = queues[2].clone();
+            actix_rt::spawn(async move {
+                while let Some(Envelope {
+                    item: (obs, alert),
+                    span,
+                }) = broadcast_rx.recv().await
+                {
+                    queue.popped();
+                    span.in_scope(|| broadcast(&state, &obs, alert.as_ref()));
+                }
+            })
+        };
+
+        Self {
+            tx: RwLock::new(Some(ingest_tx)),
+            queues,
+            workers: Mutex::new(vec![persist, evaluate, broadcast]),
+        }
+    }
+
+    /// Enqueue a validated reading, waiting for room in the ingest queue.
+    /// For callers that can push back on their source instead of refusing,
+    /// such as the MQTT bridge; fails only once shutdown has begun.
+    pub async fn send(&self, obs: StoredObservation) -> Result<(), AppError> {
+        // Cloned so the lock is not held across the await
+        let tx = self.tx.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let Some(tx) = tx else {
+            return Err(AppError::Unavailable(
+                "ingest pipeline is shutting down".into(),
+            ));
+        };
+        let span = tracing::info_span!("pipeline", observation_id = %obs.id);
+        self.queues[0].pushed();
+        tx.send(Envelope { item: obs, span }).await.map_err(|_| {
+            self.queues[0].popped();
+            AppError::Unavailable("ingest pipeline is shutting down".into())
+        })
+    }
+
+    /// Enqueue a validated reading without waiting. Fails with 503 when the
+    /// ingest queue is full or the pipeline is shutting down.
+    pub fn submit(&self, obs: StoredObservation) -> Result<(), AppError> {
+        self.submit_all(vec![obs])
+    }
+
+    /// Enqueue readings decoded from one payload: either all of them are
+    /// queued or, with the same errors as `submit`, none is. A batch larger
+    /// than the whole queue could never be queued, so it is a validation
+    /// error rather than a retryable 503.
+    pub fn submit_all(&self, batch: Vec<StoredObservation>) -> Result<(), AppError> {
+        let capacity = self.queues[0].capacity;
+        if batch.len() > capacity {
+            return Err(AppError::Validation(format!(
+                "{} readings in one payload are more than the ingest queue holds ({})",
+                batch.len(),
+                capacity
+            )));
+        }
+        let tx = self.tx.read().unwrap_or_else(|e| e.into_inner());
+        let Some(tx) = tx.as_ref() else {
+            return Err(AppError::Unavailable(
+                "ingest pipeline is shutting down".into(),
+            ));
+        };
+        let permits = match tx.try_reserve_many(batch.len()) {
+            Ok(permits) => permits,
+            Err(mpsc::error::TrySendError::Full(())) => {
+                METRICS.pipeline_rejected_total.inc();
+                return Err(AppError::Unavailable("ingest queue is full".into()));
+            }
+            Err(mpsc::error::TrySendError::Closed(())) => {
+                return Err(AppError::Unavailable(
+                    "ingest pipeline is shutting down".into(),
+                ));
+            }
+        };
+        for (permit, obs) in permits.zip(batch) {
+            let span = tracing::info_span!("pipeline", observation_id = %obs.id);
+            // Count before sending so the consumer never decrements below zero
+            self.queues[0].pushed();
+            permit.send(Envelope { item: obs, span });
+        }
+        Ok(())
+    }
+
+    pub fn queues(&self) -> Vec<QueueStatus> {
+        self.queues
+            .iter()
+            .map(|q| QueueStatus {
+                stage: q.name,
+                depth: q.depth.load(Ordering::SeqCst),
+                capacity: q.capacity,
+            })
+            .collect()
+    }
+
+    /// True while `/ingest` would be refused for lack of queue space.
+    pub fn is_full(&self) -> bool {
+        let q = &self.queues[0];
+        q.depth.load(Ordering::SeqCst) >= q.capacity
+    }
+
+    /// Stop taking readings and wait until everything already queued has
+    /// gone through every stage.
+    pub async fn shutdown(&self) {
+        self.tx.write().unwrap_or_else(|e| e.into_inner()).take();
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap_or_else(|e| e.into_inner()));
+        for worker in workers {
+            if let Err(e) = worker.await {
+                tracing::error!("ingest pipeline stage failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Validate a reading and give it its id, counting the outcome in
+/// `ingest_total`. Every ingest protocol goes through here.
+pub fn admit(state: &AppState, reading: SensorReading) -> Result<StoredObservation, AppError> {
+    let result = state.validate(&reading);
+    METRICS
+        .ingest_total
+        .with_label_values(&[
+            reading.code.as_str(),
+            if result.is_ok() {
+                "accepted"
+            } else {
+                "rejected"
+            },
+        ])
+        .inc();
+    result?;
+    Ok(StoredObservation {
+        id: Uuid::new_v4(),
+        reading,
+    })
+}
+
+/// Run every stage inline; used when no `Pipeline` is registered.
+pub fn process(state: &AppState, obs: &StoredObservation) -> Option<Alert> {
+    persist(state, obs);
+    let alert = evaluate(state, obs);
+    broadcast(state, obs, alert.as_ref());
+    alert
+}
+
+fn persist(state: &AppState, obs: &StoredObservation) {
+    timed(STAGES[0], || state.insert(obs.clone()))
+}
+
+async fn journal(upstream: &Arc<Upstream>, batch: &[Envelope<StoredObservation>]) {
+    let observations = batch.iter().map(|env| env.item.clone()).collect();
+    let upstream = upstream.clone();
+    let written =
+        tokio::task::spawn_blocking(move || upstream.outbox().push_all(observations, Utc::now()))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    if let Err(e) = written {
+        tracing::error!(
+            count = batch.len(),
+            "journaling observations for the FHIR upstream failed: {}",
+            e
+        );
+    }
+}
+
+fn evaluate(state: &AppState, obs: &StoredObservation) -> Option<Alert> {
+    timed(STAGES[1], || {
+        let alert = alerts::evaluate(state.signals().get(obs.reading.code), obs)?;
+        METRICS
+            .alerts_total
+            .with_label_values(&[alert.code.as_str(), alert.kind.as_str()])
+            .inc();
+        tracing::info!(alert_id = %alert.id, kind = alert.kind.as_str(), value = alert.value, "threshold alert");
+        Some(alert)
+    })
+}
+
+#[derive(Serialize)]
+struct AlertMessage<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    alert: &'a Alert,
+}
+
+fn broadcast(state: &AppState, obs: &StoredObservation, alert: Option<&Alert>) {
+    timed(STAGES[2], || {
+        state.broadcast(obs);
+        if let Some(alert) = alert {
+            state.ws_hub.broadcast_scoped(
+                &AlertMessage {
+                    kind: "alert",
+                    alert,
+                },
+                &alert.patient_id,
+                alert.code,
+            );
+            state.ws_hub.publish(LiveEvent::Alert(alert.clone()));
+        }
+    })
+}
+
+fn timed<T>(stage: &'static str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let out = f();
+    METRICS
+        .pipeline_stage_duration
+        .with_label_values(&[stage])
+        .observe(started.elapsed().as_secs_f64());
+    out
+}
@@ -0,0 +1,223 @@
+use crate::errors::AppError;
+use crate::metrics::METRICS;
+use crate::settings::{Limit, RateLimitSettings, RouteLimits};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, ResponseError};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Past this many buckets, full (idle) ones are dropped to bound memory.
+const PRUNE_ABOVE: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyKind {
+    Device,
+    Token,
+    Ip,
+}
+
+impl KeyKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyKind::Device => "device",
+            KeyKind::Token => "token",
+            KeyKind::Ip => "ip",
+        }
+    }
+
+    fn limit(&self, limits: &RouteLimits) -> Option<Limit> {
+        match self {
+            KeyKind::Device => limits.per_device,
+            KeyKind::Token => limits.per_token,
+            KeyKind::Ip => limits.per_ip,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+type BucketId = (String, KeyKind, String);
+
+// The buckets the middleware charged for a request, so a handler whose
+// per-device check fails can give the tokens back
+#[derive(Debug, Clone)]
+struct Charged(Vec<BucketId>);
+
+/// Token-bucket limiter keyed by (route, key type, key).
+#[derive(Debug)]
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<HashMap<BucketId, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take one token from each configured bucket for `route`; fails with
+    /// the wait until the emptiest bucket refills if any of them is empty,
+    /// in which case none of them is charged.
+    pub fn check(
+        &self,
+        route: &str,
+        keys: &[(KeyKind, &str)],
+        now: Instant,
+    ) -> Result<(), AppError> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+        let Some(limits) = self.settings.routes.get(route) else {
+            return Ok(());
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut charged = Vec::with_capacity(keys.len());
+        for (kind, key) in keys {
+            let Some(limit) = kind.limit(limits) else {
+                continue;
+            };
+            let burst = limit.burst as f64;
+            let id = bucket_id(route, *kind, key);
+            let bucket = buckets.entry(id.clone()).or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * limit.requests_per_second).min(burst);
+            bucket.updated = now;
+
+            if bucket.tokens < 1.0 {
+                METRICS
+                    .rate_limited_total
+                    .with_label_values(&[route, kind.as_str()])
+                    .inc();
+                let wait = (1.0 - bucket.tokens) / limit.requests_per_second;
+                return Err(AppError::RateLimited {
+                    retry_after_secs: wait.ceil().max(1.0) as u64,
+                });
+            }
+            charged.push(id);
+        }
+        for id in &charged {
+            if let Some(bucket) = buckets.get_mut(id) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        if buckets.len() > PRUNE_ABOVE {
+            let routes = &self.settings.routes;
+            buckets.retain(|(route, kind, _), b| {
+                let Some(limit) = routes.get(route).and_then(|l| kind.limit(l)) else {
+                    return false;
+                };
+                let refilled = b.tokens
+                    + now.saturating_duration_since(b.updated).as_secs_f64()
+                        * limit.requests_per_second;
+                refilled < limit.burst as f64
+            });
+        }
+        METRICS.rate_limit_buckets.set(buckets.len() as i64);
+        Ok(())
+    }
+
+    /// The per-device check for a request `limit_requests` let through. A
+    /// device over its limit also gets the IP and token tokens refunded, so
+    /// the request is charged all or nothing, as by a single `check`.
+    pub fn check_device(
+        &self,
+        req: &HttpRequest,
+        route: &str,
+        device_id: &str,
+        now: Instant,
+    ) -> Result<(), AppError> {
+        let result = self.check(route, &[(KeyKind::Device, device_id)], now);
+        if result.is_err() {
+            if let Some(Charged(ids)) = req.extensions().get::<Charged>() {
+                self.refund(ids);
+            }
+        }
+        result
+    }
+
+    fn refund(&self, ids: &[BucketId]) {
+        let mut buckets = self.buckets.lock().unwrap();
+        for id in ids {
+            let (route, kind, _) = id;
+            let Some(limit) = self.settings.routes.get(route).and_then(|l| kind.limit(l)) else {
+                continue;
+            };
+            if let Some(bucket) = buckets.get_mut(id) {
+                bucket.tokens = (bucket.tokens + 1.0).min(limit.burst as f64);
+            }
+        }
+    }
+}
+
+// Tokens are credentials; only their digest is kept
+fn bucket_id(route: &str, kind: KeyKind, key: &str) -> BucketId {
+    let key = match kind {
+        KeyKind::Token => token_digest(key),
+        _ => key.to_string(),
+    };
+    (route.to_string(), kind, key)
+}
+
+fn token_digest(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Middleware applying the per-IP and per-token limits of the matched
+/// route. Per-device limits need the request body and are checked by the
+/// handler with `RateLimiter::check_device`. Rejections are returned as responses so
+/// outer middleware (logging, latency metrics) still sees them.
+pub async fn limit_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        let route = req.match_pattern().unwrap_or_default();
+        let ip = req.peer_addr().map(|a| a.ip().to_string());
+        let token = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok());
+
+        let mut keys = Vec::with_capacity(2);
+        if let Some(ip) = ip.as_deref() {
+            keys.push((KeyKind::Ip, ip));
+        }
+        if let Some(token) = token {
+            keys.push((KeyKind::Token, token));
+        }
+        if let Err(err) = limiter.check(&route, &keys, Instant::now()) {
+            return Ok(req
+                .into_response(err.error_response())
+                .map_into_right_body());
+        }
+        let charged = keys
+            .iter()
+            .map(|(kind, key)| bucket_id(&route, *kind, key))
+            .collect();
+        req.extensions_mut().insert(Charged(charged));
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
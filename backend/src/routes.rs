@@ -2,30 +2,129 @@ use actix::prelude::*; // IMPORTANT: brings StreamHandler, ActorContext, AsyncCo
 use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
+use crate::audit::{self, AuditLog, AuditQuery};
+use crate::bulk::{BulkExports, ExportRequest, JobStatus, ResourceType};
+use crate::domain::aggregate;
 use crate::domain::models::{SensorReading, SignalCode};
-use crate::domain::store::AppState;
+use crate::domain::retention::Resolution;
+use crate::domain::store::{AppState, ObsFilter};
 use crate::errors::AppError;
+use crate::export::{self, Column, Format};
+use crate::gatt::{self, GattUpload};
+use crate::health;
+use crate::lifecycle::Lifecycle;
+use crate::pipeline::{self, Pipeline};
+use crate::ratelimit::RateLimiter;
+use crate::settings::Settings;
+use crate::sse;
+use crate::subscriptions::{self, Subscription as FhirSubscription, Subscriptions};
+use crate::tls::{self, ClientIdentity};
+use crate::upstream::Upstream;
+use crate::webhooks::{SubscriptionRequest, Webhooks};
+use crate::ws::{Hub, HubEvent, LiveFilter};
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
-    cfg.route("/healthz", web::get().to(healthz))
+    cfg.route("/healthz", web::get().to(livez))
+        .route("/livez", web::get().to(livez))
+        .route("/readyz", web::get().to(readyz))
+        .route("/metrics", web::get().to(crate::metrics::metrics))
         .route("/ingest", web::post().to(ingest))
+        .route("/ingest/gatt", web::post().to(ingest_gatt))
         .route("/fhir/Observation", web::get().to(get_observations))
-        .route("/ws/live", web::get().to(ws_live));
+        .route("/fhir/Observation/{id}", web::get().to(get_observation))
+        .route("/observations/aggregate", web::get().to(get_aggregate))
+        .route("/export/observations", web::get().to(export_observations))
+        .route("/fhir/$export", web::get().to(bulk_kickoff))
+        .route("/fhir/$export-status/{id}", web::get().to(bulk_status))
+        .route("/fhir/$export-status/{id}", web::delete().to(bulk_cancel))
+        .route("/fhir/$export-files/{id}/{file}", web::get().to(bulk_file))
+        .route("/upstream/status", web::get().to(upstream_status))
+        .route("/webhooks", web::post().to(create_webhook))
+        .route("/webhooks", web::get().to(list_webhooks))
+        .route("/webhooks/{id}", web::get().to(get_webhook))
+        .route("/webhooks/{id}", web::put().to(update_webhook))
+        .route("/webhooks/{id}", web::delete().to(delete_webhook))
+        .route(
+            "/webhooks/{id}/deliveries",
+            web::get().to(webhook_deliveries),
+        )
+        .route(
+            "/fhir/SubscriptionTopic",
+            web::get().to(subscription_topics),
+        )
+        .route("/fhir/Subscription", web::post().to(create_subscription))
+        .route("/fhir/Subscription", web::get().to(list_subscriptions))
+        .route("/fhir/Subscription/{id}", web::get().to(get_subscription))
+        .route(
+            "/fhir/Subscription/{id}",
+            web::put().to(update_subscription),
+        )
+        .route(
+            "/fhir/Subscription/{id}",
+            web::delete().to(delete_subscription),
+        )
+        .route(
+            "/fhir/Subscription/{id}/$status",
+            web::get().to(subscription_status),
+        )
+        .route(
+            "/fhir/Subscription/{id}/$get-ws-binding-token",
+            web::get().to(ws_binding_token),
+        )
+        .route(
+            "/fhir/Subscription/{id}/$get-ws-binding-token",
+            web::post().to(ws_binding_token),
+        )
+        .route("/fhir/websocket", web::get().to(ws_subscriptions))
+        .route("/fhir/AuditEvent", web::get().to(search_audit_events))
+        .route("/fhir/AuditEvent/{id}", web::get().to(get_audit_event))
+        .route("/audit/verify", web::get().to(verify_audit))
+        .route("/ws/live", web::get().to(ws_live))
+        .route("/events/live", web::get().to(events_live));
 }
 
-async fn healthz() -> HttpResponse {
-    HttpResponse::Ok().json(serde_json::json!({"status":"ok"}))
+async fn livez(state: web::Data<AppState>) -> HttpResponse {
+    if health::is_live(&state) {
+        HttpResponse::Ok().json(serde_json::json!({"status":"ok"}))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({"status":"failed"}))
+    }
 }
 
-fn check_ingest_token(req: &HttpRequest) -> Result<(), AppError> {
-    let token = std::env::var("INGEST_TOKEN").unwrap_or_default();
-    if token.trim().is_empty() {
-        return Ok(());
+// 503 while degraded or draining, so load balancers stop routing here.
+// Device ids are left out unless the caller has the admin token.
+async fn readyz(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    settings: Option<web::Data<Settings>>,
+    bulk: Option<web::Data<BulkExports>>,
+    lifecycle: Option<web::Data<Lifecycle>>,
+    pipeline: Option<web::Data<Pipeline>>,
+) -> HttpResponse {
+    let settings = settings.map(|s| s.into_inner()).unwrap_or_default();
+    let bulk = bulk.as_ref().map(|b| b.get_ref());
+    let lifecycle = lifecycle.as_ref().map(|l| l.get_ref());
+    let pipeline = pipeline.as_ref().map(|p| p.get_ref());
+    let mut report =
+        health::readiness(&state, &settings, bulk, lifecycle, pipeline, Utc::now()).await;
+    if !is_admin(&settings, &req) {
+        report.devices.clear();
+    }
+    if report.is_ready() {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
     }
+}
+
+fn check_ingest_token(settings: &Settings, req: &HttpRequest) -> Result<(), AppError> {
+    let Some(token) = settings.auth.ingest_token() else {
+        return Ok(());
+    };
 
     let header = req
         .headers()
@@ -41,37 +140,123 @@ fn check_ingest_token(req: &HttpRequest) -> Result<(), AppError> {
     }
 }
 
+// Same scheme as the ingest token, for the admin API
+fn check_admin_token(settings: &Settings, req: &HttpRequest) -> Result<(), AppError> {
+    let Some(token) = settings.auth.admin_token() else {
+        return Ok(());
+    };
+    let header = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if header == format!("Bearer {}", token) {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized)
+    }
+}
+
+// Only callers presenting a configured admin token, unlike `check_admin_token`
+fn is_admin(settings: &Settings, req: &HttpRequest) -> bool {
+    settings.auth.admin_token().is_some() && check_admin_token(settings, req).is_ok()
+}
+
+#[tracing::instrument(name = "ingest", skip_all, fields(device_id, patient_id, code))]
 async fn ingest(
-    state: web::Data<Arc<Mutex<AppState>>>,
+    state: web::Data<AppState>,
+    settings: web::Data<Settings>,
+    limiter: Option<web::Data<RateLimiter>>,
+    pipeline: Option<web::Data<Pipeline>>,
     req: HttpRequest,
     payload: web::Json<SensorReading>,
 ) -> Result<HttpResponse, AppError> {
-    check_ingest_token(&req)?;
-
     let reading = payload.into_inner();
-    let mut s = state.lock().unwrap();
-    s.validate(&reading)?;
-    let stored = s.add_reading(reading);
-    Ok(HttpResponse::Ok().json(stored))
+    audit::touch(&req, [reading.patient_id.as_str()]);
+    check_ingest_token(&settings, &req)?;
+
+    tls::check_device_identity(
+        req.conn_data::<ClientIdentity>(),
+        &reading.device_id,
+        &settings.tls,
+    )?;
+    if let Some(limiter) = limiter {
+        limiter.check_device(&req, "/ingest", &reading.device_id, Instant::now())?;
+    }
+    let span = tracing::Span::current();
+    span.record("device_id", reading.device_id.as_str());
+    span.record("patient_id", reading.patient_id.as_str());
+    span.record("code", reading.code.as_str());
+
+    let stored = pipeline::admit(&state, reading)?;
+    // 202: stored and broadcast shortly, by the pipeline stages
+    match pipeline {
+        Some(pipeline) => {
+            pipeline.submit(stored.clone())?;
+            Ok(HttpResponse::Accepted().json(stored))
+        }
+        None => {
+            pipeline::process(&state, &stored);
+            Ok(HttpResponse::Ok().json(stored))
+        }
+    }
+}
+
+#[tracing::instrument(name = "ingest_gatt", skip_all, fields(device_id, patient_id))]
+async fn ingest_gatt(
+    state: web::Data<AppState>,
+    settings: web::Data<Settings>,
+    limiter: Option<web::Data<RateLimiter>>,
+    pipeline: Option<web::Data<Pipeline>>,
+    req: HttpRequest,
+    payload: web::Json<GattUpload>,
+) -> Result<HttpResponse, AppError> {
+    let upload = payload.into_inner();
+    audit::touch(&req, [upload.patient_id.as_str()]);
+    check_ingest_token(&settings, &req)?;
+
+    tls::check_device_identity(
+        req.conn_data::<ClientIdentity>(),
+        &upload.device_id,
+        &settings.tls,
+    )?;
+    if let Some(limiter) = limiter {
+        limiter.check_device(&req, "/ingest/gatt", &upload.device_id, Instant::now())?;
+    }
+    let span = tracing::Span::current();
+    span.record("device_id", upload.device_id.as_str());
+    span.record("patient_id", upload.patient_id.as_str());
+
+    let (measurement, readings) = gatt::decode_upload(&upload, Utc::now())?;
+    // One bad reading (e.g. an implausible RR interval) rejects the whole payload
+    let stored = readings
+        .into_iter()
+        .map(|r| pipeline::admit(&state, r))
+        .collect::<Result<Vec<_>, _>>()?;
+    let body = serde_json::json!({ "measurement": measurement, "observations": stored });
+    match pipeline {
+        Some(pipeline) => {
+            pipeline.submit_all(stored)?;
+            Ok(HttpResponse::Accepted().json(body))
+        }
+        None => {
+            for obs in &stored {
+                pipeline::process(&state, obs);
+            }
+            Ok(HttpResponse::Ok().json(body))
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct ObsQuery {
+    patient: Option<String>,
     code: Option<String>,
     limit: Option<usize>,
     from: Option<String>,
     to: Option<String>,
 }
 
-fn parse_code(code: &str) -> Option<SignalCode> {
-    match code {
-        "heart-rate" => Some(SignalCode::HeartRate),
-        "body-temperature" => Some(SignalCode::BodyTemperature),
-        "steps-per-minute" => Some(SignalCode::StepsPerMinute),
-        _ => None,
-    }
-}
-
 fn parse_dt(s: &Option<String>) -> Result<Option<DateTime<Utc>>, AppError> {
     if let Some(v) = s {
         let dt = DateTime::parse_from_rfc3339(v)
@@ -83,44 +268,755 @@ fn parse_dt(s: &Option<String>) -> Result<Option<DateTime<Utc>>, AppError> {
     }
 }
 
+fn parse_filter(
+    patient: &Option<String>,
+    code: Option<SignalCode>,
+    from: &Option<String>,
+    to: &Option<String>,
+) -> Result<ObsFilter, AppError> {
+    Ok(ObsFilter {
+        patient_id: patient.clone().filter(|p| !p.trim().is_empty()),
+        code,
+        from: parse_dt(from)?,
+        to: parse_dt(to)?,
+    })
+}
+
+// Every patient a read over `filter` reaches, for its audit record; the
+// scan is skipped when nothing is audited
+fn touch_patients(req: &HttpRequest, state: &AppState, filter: &ObsFilter) {
+    if !audit::is_audited(req) {
+        return;
+    }
+    audit::touch(req, filter.patient_id.as_deref());
+    audit::touch(req, state.patients(filter).iter().map(String::as_str));
+}
+
+// Raw readings older than the raw retention horizon are gone, so a range
+// reaching past it is refused rather than answered empty or partial
+fn check_raw_horizon(state: &AppState, filter: &ObsFilter, what: &str) -> Result<(), AppError> {
+    let Some(horizon) = state.policy().horizon(Resolution::Raw, Utc::now()) else {
+        return Ok(());
+    };
+    if filter.from.or(filter.to).is_some_and(|t| t < horizon) {
+        return Err(AppError::Validation(format!(
+            "{} only go back to the raw retention horizon, {}; \
+             use /observations/aggregate without percentiles for older ranges",
+            what,
+            horizon.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        )));
+    }
+    Ok(())
+}
+
 async fn get_observations(
-    state: web::Data<Arc<Mutex<AppState>>>,
+    state: web::Data<AppState>,
+    req: HttpRequest,
     q: web::Query<ObsQuery>,
 ) -> Result<HttpResponse, AppError> {
-    let code = q.code.as_deref().and_then(parse_code);
+    let code = q.code.as_deref().and_then(SignalCode::parse);
     let limit = q.limit.unwrap_or(200).min(2000);
+    let filter = parse_filter(&q.patient, code, &q.from, &q.to)?;
+    check_raw_horizon(&state, &filter, "observations")?;
 
-    let from = parse_dt(&q.from)?;
-    let to = parse_dt(&q.to)?;
-
-    let s = state.lock().unwrap();
-    let obs = s.query(code, limit, from, to);
+    let obs = state.query(&filter, limit);
+    audit::touch(&req, filter.patient_id.as_deref());
+    audit::touch(&req, obs.iter().map(|o| o.reading.patient_id.as_str()));
     let bundle = crate::fhir::to_bundle(&obs)?;
     Ok(HttpResponse::Ok().json(bundle))
 }
 
+// Read by id, e.g. a Subscription notification's `focus` reference
+async fn get_observation(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let obs = state.get(path.into_inner()).ok_or(AppError::NotFound)?;
+    audit::touch(&req, [obs.reading.patient_id.as_str()]);
+    Ok(HttpResponse::Ok().json(crate::fhir::to_fhir_observation(&obs)?))
+}
+
+#[derive(Debug, Deserialize)]
+struct AggQuery {
+    patient: Option<String>,
+    code: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    bucket: Option<String>,
+    // comma separated, e.g. "50,95"
+    percentiles: Option<String>,
+}
+
+// Wider buckets gain nothing and would overflow the bucket arithmetic
+const MAX_BUCKET_DAYS: i64 = 3650;
+
+// "30s", "1m", "15m", "1h", "1d"
+fn parse_bucket(s: &str) -> Result<chrono::Duration, AppError> {
+    let err = || {
+        AppError::Validation(format!(
+            "bucket must look like 30s, 1m, 1h or 1d, up to {}d",
+            MAX_BUCKET_DAYS
+        ))
+    };
+    let (at, _) = s.char_indices().last().ok_or_else(err)?;
+    let (n, unit) = s.split_at(at);
+    let n: i64 = n.parse().map_err(|_| err())?;
+    if n <= 0 {
+        return Err(err());
+    }
+    let width = match unit {
+        "s" => chrono::Duration::try_seconds(n),
+        "m" => chrono::Duration::try_minutes(n),
+        "h" => chrono::Duration::try_hours(n),
+        "d" => chrono::Duration::try_days(n),
+        _ => None,
+    };
+    width
+        .filter(|w| *w <= chrono::Duration::days(MAX_BUCKET_DAYS))
+        .ok_or_else(err)
+}
+
+fn parse_percentiles(s: &Option<String>) -> Result<Vec<f64>, AppError> {
+    let Some(s) = s else {
+        return Ok(Vec::new());
+    };
+    s.split(',')
+        .map(|p| match p.trim().parse::<f64>() {
+            Ok(v) if (0.0..=100.0).contains(&v) => Ok(v),
+            _ => Err(AppError::Validation(
+                "percentiles must be numbers between 0 and 100".into(),
+            )),
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+struct AggResponse {
+    patient: Option<String>,
+    code: SignalCode,
+    resolution: Resolution,
+    bucket_secs: i64,
+    buckets: Vec<aggregate::Bucket>,
+}
+
+async fn get_aggregate(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    q: web::Query<AggQuery>,
+) -> Result<HttpResponse, AppError> {
+    // Mixing signals in one bucket is meaningless, so code is required here
+    let code = q
+        .code
+        .as_deref()
+        .and_then(SignalCode::parse)
+        .ok_or_else(|| {
+            AppError::Validation(
+                "code is required (heart-rate, body-temperature, steps-per-minute, rr-interval)"
+                    .into(),
+            )
+        })?;
+    let width = parse_bucket(q.bucket.as_deref().unwrap_or("1m"))?;
+    let percentiles = parse_percentiles(&q.percentiles)?;
+    let filter = parse_filter(&q.patient, Some(code), &q.from, &q.to)?;
+    if !percentiles.is_empty() {
+        check_raw_horizon(&state, &filter, "percentiles")?;
+    }
+    touch_patients(&req, &state, &filter);
+
+    // Older ranges are served from rollups once raw data has expired
+    let resolution = Resolution::choose(
+        state.policy(),
+        width,
+        filter.from,
+        !percentiles.is_empty(),
+        Utc::now(),
+    );
+    let width = resolution.fit(width);
+    let buckets = match resolution {
+        Resolution::Raw => {
+            let readings = state.range(&filter);
+            aggregate::aggregate(&readings.iter().collect::<Vec<_>>(), width, &percentiles)
+        }
+        res => {
+            let rollups = state.rollup_range(res, &filter);
+            aggregate::merge_rollups(&rollups.iter().collect::<Vec<_>>(), width)
+        }
+    };
+    Ok(HttpResponse::Ok().json(AggResponse {
+        patient: filter.patient_id,
+        code,
+        resolution,
+        bucket_secs: width.num_seconds(),
+        buckets,
+    }))
+}
+
+// Exports (CSV/Parquet and every step of a bulk export) are admin only, and
+// refused when no admin token is configured: they hold every patient's data
+fn check_export_access(settings: &Settings, req: &HttpRequest) -> Result<(), AppError> {
+    if is_admin(settings, req) {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    patient: Option<String>,
+    code: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    // csv | parquet; falls back to the Accept header, then csv
+    format: Option<String>,
+    // comma separated subset of patient,device,code,value,unit,ts,status
+    columns: Option<String>,
+}
+
+fn export_format(q: &ExportQuery, req: &HttpRequest) -> Result<Format, AppError> {
+    match q.format.as_deref() {
+        Some("csv") => return Ok(Format::Csv),
+        Some("parquet") => return Ok(Format::Parquet),
+        Some(_) => return Err(AppError::Validation("format must be csv or parquet".into())),
+        None => {}
+    }
+    let accept = req
+        .headers()
+        .get("accept")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if accept.contains("parquet") {
+        Ok(Format::Parquet)
+    } else {
+        Ok(Format::Csv)
+    }
+}
+
+fn export_columns(s: &Option<String>) -> Result<Vec<Column>, AppError> {
+    let Some(s) = s else {
+        return Ok(Column::ALL.to_vec());
+    };
+    s.split(',')
+        .map(|c| {
+            Column::parse(c.trim()).ok_or_else(|| {
+                AppError::Validation(
+                    "columns must be among patient,device,code,value,unit,ts,status".into(),
+                )
+            })
+        })
+        .collect()
+}
+
+async fn export_observations(
+    state: web::Data<AppState>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    q: web::Query<ExportQuery>,
+) -> Result<HttpResponse, AppError> {
+    check_export_access(&settings, &req)?;
+    let code = match q.code.as_deref() {
+        Some(c) => Some(
+            SignalCode::parse(c)
+                .ok_or_else(|| AppError::Validation(format!("unknown code '{}'", c)))?,
+        ),
+        None => None,
+    };
+    let filter = parse_filter(&q.patient, code, &q.from, &q.to)?;
+    let format = export_format(&q, &req)?;
+    let columns = export_columns(&q.columns)?;
+    touch_patients(&req, &state, &filter);
+
+    let rx = export::stream(state.into_inner(), filter, columns, format);
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "content-disposition",
+            format!(
+                "attachment; filename=\"observations.{}\"",
+                format.extension()
+            ),
+        ))
+        .streaming(body))
+}
+
+// -------------------------
+// FHIR Bulk Data $export
+// -------------------------
+
+#[derive(Debug, Deserialize)]
+struct BulkQuery {
+    #[serde(rename = "_outputFormat")]
+    output_format: Option<String>,
+    #[serde(rename = "_since")]
+    since: Option<String>,
+    #[serde(rename = "_type")]
+    types: Option<String>,
+}
+
+async fn bulk_kickoff(
+    state: web::Data<AppState>,
+    bulk: web::Data<BulkExports>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    q: web::Query<BulkQuery>,
+) -> Result<HttpResponse, AppError> {
+    check_export_access(&settings, &req)?;
+    let prefer = req
+        .headers()
+        .get("prefer")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !prefer.contains("respond-async") {
+        return Err(AppError::Validation(
+            "$export requires 'Prefer: respond-async'".into(),
+        ));
+    }
+
+    if let Some(f) = q.output_format.as_deref() {
+        if !matches!(
+            f,
+            "application/fhir+ndjson" | "application/ndjson" | "ndjson"
+        ) {
+            return Err(AppError::Validation(
+                "_outputFormat must be application/fhir+ndjson".into(),
+            ));
+        }
+    }
+
+    let types = match q.types.as_deref() {
+        Some(t) => t
+            .split(',')
+            .map(|t| {
+                ResourceType::parse(t.trim()).ok_or_else(|| {
+                    AppError::Validation(format!("unsupported _type '{}'", t.trim()))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => ResourceType::ALL.to_vec(),
+    };
+    let since = parse_dt(&q.since)?;
+    // `_since` is on when readings were taken in, which a filter can't
+    // express, so any patient may be in the export
+    touch_patients(&req, &state, &ObsFilter::default());
+
+    let info = req.connection_info().clone();
+    let base_url = format!("{}://{}/fhir", info.scheme(), info.host());
+    let id = bulk.start(
+        state.into_inner(),
+        ExportRequest {
+            types,
+            since,
+            request_url: format!("{}://{}{}", info.scheme(), info.host(), req.uri()),
+            base_url: base_url.clone(),
+        },
+    );
+
+    Ok(HttpResponse::Accepted()
+        .insert_header((
+            "content-location",
+            format!("{}/$export-status/{}", base_url, id),
+        ))
+        .finish())
+}
+
+fn operation_outcome(msg: &str) -> serde_json::Value {
+    serde_json::json!({
+        "resourceType": "OperationOutcome",
+        "issue": [{ "severity": "error", "code": "exception", "diagnostics": msg }]
+    })
+}
+
+async fn bulk_status(
+    bulk: web::Data<BulkExports>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    check_export_access(&settings, &req)?;
+    match bulk.status(path.into_inner()).ok_or(AppError::NotFound)? {
+        JobStatus::InProgress { progress } => Ok(HttpResponse::Accepted()
+            .insert_header(("x-progress", progress))
+            .insert_header(("retry-after", "2"))
+            .finish()),
+        JobStatus::Complete(manifest) => Ok(HttpResponse::Ok().json(manifest)),
+        JobStatus::Failed(msg) => {
+            Ok(HttpResponse::InternalServerError().json(operation_outcome(&msg)))
+        }
+    }
+}
+
+async fn bulk_cancel(
+    bulk: web::Data<BulkExports>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    check_export_access(&settings, &req)?;
+    bulk.delete(path.into_inner())?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+async fn bulk_file(
+    bulk: web::Data<BulkExports>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, AppError> {
+    check_export_access(&settings, &req)?;
+    let (id, name) = path.into_inner();
+    let file =
+        actix_files::NamedFile::open(bulk.file_path(id, &name)?).map_err(|_| AppError::NotFound)?;
+    Ok(file
+        .set_content_type("application/fhir+ndjson".parse().unwrap())
+        .into_response(&req))
+}
+
+// Outbox depth, lag and last error of the upstream FHIR forwarder
+// Admin only, and refused when no admin token is configured: the status
+// names the upstream server and quotes its error responses
+async fn upstream_status(
+    upstream: Option<web::Data<Upstream>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    if !is_admin(&settings, &req) {
+        return Err(AppError::Unauthorized);
+    }
+    let upstream = upstream.ok_or(AppError::NotFound)?;
+    Ok(HttpResponse::Ok().json(upstream.status(Utc::now())))
+}
+
+// Webhook subscriptions: 404 throughout unless `[webhooks]` is enabled, and
+// refused without the admin token even if none is configured
+fn webhooks_for(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: &Settings,
+    req: &HttpRequest,
+) -> Result<web::Data<Webhooks>, AppError> {
+    if !is_admin(settings, req) {
+        return Err(AppError::Unauthorized);
+    }
+    webhooks.ok_or(AppError::NotFound)
+}
+
+async fn create_webhook(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    body: web::Json<SubscriptionRequest>,
+) -> Result<HttpResponse, AppError> {
+    let webhooks = webhooks_for(webhooks, &settings, &req)?;
+    audit::touch(&req, body.patient.as_deref());
+    let sub = webhooks.create(body.into_inner(), Utc::now()).await?;
+    Ok(HttpResponse::Created()
+        .insert_header(("Location", format!("/webhooks/{}", sub.id)))
+        .json(sub))
+}
+
+async fn list_webhooks(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let webhooks = webhooks_for(webhooks, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(webhooks.list()))
+}
+
+async fn get_webhook(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let webhooks = webhooks_for(webhooks, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(webhooks.get(path.into_inner())?))
+}
+
+async fn update_webhook(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: web::Json<SubscriptionRequest>,
+) -> Result<HttpResponse, AppError> {
+    let webhooks = webhooks_for(webhooks, &settings, &req)?;
+    audit::touch(&req, body.patient.as_deref());
+    let sub = webhooks
+        .update(path.into_inner(), body.into_inner(), Utc::now())
+        .await?;
+    Ok(HttpResponse::Ok().json(sub))
+}
+
+async fn delete_webhook(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let webhooks = webhooks_for(webhooks, &settings, &req)?;
+    webhooks.delete(path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn webhook_deliveries(
+    webhooks: Option<web::Data<Webhooks>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let webhooks = webhooks_for(webhooks, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(webhooks.deliveries(path.into_inner())?))
+}
+
+// -------------------------
+// FHIR Subscriptions
+// -------------------------
+
+// Guarded like the webhooks: admin token (required even if none is
+// configured), then 404 unless `[subscriptions]` is enabled
+fn subscriptions_for(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: &Settings,
+    req: &HttpRequest,
+) -> Result<web::Data<Subscriptions>, AppError> {
+    if !is_admin(settings, req) {
+        return Err(AppError::Unauthorized);
+    }
+    subscriptions.ok_or(AppError::NotFound)
+}
+
+fn searchset<T: Serialize>(resources: &[T]) -> serde_json::Value {
+    let entry: Vec<_> = resources
+        .iter()
+        .map(|r| serde_json::json!({ "resource": r }))
+        .collect();
+    serde_json::json!({ "resourceType": "Bundle", "type": "searchset", "total": entry.len(), "entry": entry })
+}
+
+async fn subscription_topics(
+    subscriptions: Option<web::Data<Subscriptions>>,
+) -> Result<HttpResponse, AppError> {
+    subscriptions.ok_or(AppError::NotFound)?;
+    Ok(HttpResponse::Ok().json(searchset(&[subscriptions::observation_topic()])))
+}
+
+async fn create_subscription(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    body: web::Json<FhirSubscription>,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    let base_url = {
+        let info = req.connection_info();
+        format!("{}://{}/fhir", info.scheme(), info.host())
+    };
+    let sub = subscriptions
+        .create(body.into_inner(), &base_url, Utc::now())
+        .await?;
+    Ok(HttpResponse::Created()
+        .insert_header((
+            "Location",
+            format!(
+                "/fhir/Subscription/{}",
+                sub.id.as_deref().unwrap_or_default()
+            ),
+        ))
+        .json(sub))
+}
+
+async fn list_subscriptions(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(searchset(&subscriptions.list())))
+}
+
+async fn get_subscription(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(subscriptions.get(path.into_inner())?))
+}
+
+async fn update_subscription(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: web::Json<FhirSubscription>,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    let sub = subscriptions
+        .update(path.into_inner(), body.into_inner(), Utc::now())
+        .await?;
+    Ok(HttpResponse::Ok().json(sub))
+}
+
+async fn delete_subscription(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    subscriptions.delete(path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn subscription_status(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(subscriptions.status(path.into_inner(), Utc::now())?))
+}
+
+async fn ws_binding_token(
+    subscriptions: Option<web::Data<Subscriptions>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let subscriptions = subscriptions_for(subscriptions, &settings, &req)?;
+    let url = {
+        let info = req.connection_info();
+        let scheme = if info.scheme() == "https" {
+            "wss"
+        } else {
+            "ws"
+        };
+        format!("{}://{}/fhir/websocket", scheme, info.host())
+    };
+    Ok(
+        HttpResponse::Ok().json(subscriptions.binding_token(
+            path.into_inner(),
+            &url,
+            Utc::now(),
+        )?),
+    )
+}
+
+// The binding token is the credential here, as browsers cannot set headers on websockets
+async fn ws_subscriptions(
+    state: web::Data<AppState>,
+    subscriptions: Option<web::Data<Subscriptions>>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscriptions = subscriptions.ok_or(AppError::NotFound)?;
+    let actor = SubscriptionWs {
+        subscriptions: subscriptions.get_ref().clone(),
+        hub: state.ws_hub.clone(),
+        client_id: None,
+    };
+    ws::start(actor, &req, stream)
+}
+
+// -------------------------
+// Audit trail
+// -------------------------
+
+// Admin only, and 404 unless `[audit]` is enabled
+fn audit_for(
+    audit: Option<web::Data<AuditLog>>,
+    settings: &Settings,
+    req: &HttpRequest,
+) -> Result<web::Data<AuditLog>, AppError> {
+    if !is_admin(settings, req) {
+        return Err(AppError::Unauthorized);
+    }
+    audit.ok_or(AppError::NotFound)
+}
+
+// Reads go to disk, so they run on the blocking pool
+async fn read_audit<T: Send + 'static>(
+    audit: web::Data<AuditLog>,
+    read: impl FnOnce(&AuditLog) -> std::io::Result<T> + Send + 'static,
+) -> Result<T, AppError> {
+    let result = web::block(move || read(&audit))
+        .await
+        .map_err(|_| AppError::Internal)?;
+    result.map_err(|e| {
+        tracing::error!("reading the audit trail failed: {}", e);
+        AppError::Internal
+    })
+}
+
+async fn search_audit_events(
+    audit: Option<web::Data<AuditLog>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    params: web::Query<Vec<(String, String)>>,
+) -> Result<HttpResponse, AppError> {
+    let audit = audit_for(audit, &settings, &req)?;
+    let query = AuditQuery::parse(&params)?;
+    audit::touch(&req, query.patient.as_deref());
+    let records = read_audit(audit, move |audit| audit.search(&query)).await?;
+    let events: Vec<_> = records.iter().map(audit::to_audit_event).collect();
+    Ok(HttpResponse::Ok().json(searchset(&events)))
+}
+
+async fn get_audit_event(
+    audit: Option<web::Data<AuditLog>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+    path: web::Path<u64>,
+) -> Result<HttpResponse, AppError> {
+    let audit = audit_for(audit, &settings, &req)?;
+    let seq = path.into_inner();
+    let record = read_audit(audit, move |audit| audit.get(seq))
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(HttpResponse::Ok().json(audit::to_audit_event(&record)))
+}
+
+// Re-checks the whole hash chain
+async fn verify_audit(
+    audit: Option<web::Data<AuditLog>>,
+    settings: web::Data<Settings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let audit = audit_for(audit, &settings, &req)?;
+    Ok(HttpResponse::Ok().json(read_audit(audit, AuditLog::verify).await?))
+}
+
 // -------------------------
 // WebSocket: actor-based (reliable)
 // -------------------------
 
 struct LiveWs {
-    state: Arc<Mutex<AppState>>,
+    hub: Hub,
+    filter: LiveFilter,
     client_id: Option<u64>,
 }
 
 impl LiveWs {
-    fn new(state: Arc<Mutex<AppState>>) -> Self {
+    fn new(hub: Hub, filter: LiveFilter) -> Self {
         Self {
-            state,
+            hub,
+            filter,
             client_id: None,
         }
     }
 }
 
-// Message used to push hub text into the websocket
-struct PushTxt(String);
+// Message used to push hub events into the websocket
+struct PushEvent(HubEvent);
 
-impl Message for PushTxt {
+impl Message for PushEvent {
     type Result = ();
 }
 
@@ -129,11 +1025,10 @@ impl Actor for LiveWs {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         // Channel for this websocket client to receive broadcasts
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<HubEvent>();
 
         // Register in hub
-        let hub = { self.state.lock().unwrap().ws_hub.clone() };
-        let id = hub.add_client(tx);
+        let id = self.hub.add_filtered_client(tx, self.filter.clone());
         self.client_id = Some(id);
 
         // Hello
@@ -142,8 +1037,8 @@ impl Actor for LiveWs {
         // Forward hub -> websocket (tokio -> actix bridge)
         let addr = ctx.address();
         actix_rt::spawn(async move {
-            while let Some(txt) = rx.recv().await {
-                addr.do_send(PushTxt(txt));
+            while let Some(event) = rx.recv().await {
+                addr.do_send(PushEvent(event));
             }
         });
 
@@ -155,17 +1050,44 @@ impl Actor for LiveWs {
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         if let Some(id) = self.client_id.take() {
-            let hub = { self.state.lock().unwrap().ws_hub.clone() };
-            hub.remove_client(id);
+            self.hub.remove_client(id);
         }
     }
 }
 
-impl Handler<PushTxt> for LiveWs {
+impl Handler<PushEvent> for LiveWs {
     type Result = ();
 
-    fn handle(&mut self, msg: PushTxt, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, msg: PushEvent, ctx: &mut Self::Context) {
+        push_event(msg.0, ctx);
+    }
+}
+
+// Write a hub event to a websocket; shutdown closes it with a reconnect hint
+fn push_event<A>(event: HubEvent, ctx: &mut ws::WebsocketContext<A>)
+where
+    A: Actor<Context = ws::WebsocketContext<A>>,
+{
+    match event {
+        HubEvent::Text(txt) => ctx.text(txt),
+        HubEvent::Shutdown {
+            reason,
+            reconnect_after_secs,
+        } => {
+            // Clients that ignore close reasons still get the hint as JSON
+            ctx.text(
+                serde_json::json!({"type":"shutdown","msg":reason,"reconnect_after_secs":reconnect_after_secs})
+                    .to_string(),
+            );
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Restart,
+                description: Some(format!(
+                    "{}; reconnect in {}s",
+                    reason, reconnect_after_secs
+                )),
+            }));
+            ctx.stop();
+        }
     }
 }
 
@@ -186,10 +1108,138 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LiveWs {
     }
 }
 
+/// The FHIR Subscription websocket channel. Clients send
+/// `bind-with-token: <token>` for each subscription they want notifications of.
+struct SubscriptionWs {
+    subscriptions: Subscriptions,
+    hub: Hub,
+    client_id: Option<u64>,
+}
+
+impl Actor for SubscriptionWs {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // A channel client from the start, so draining closes it even unbound
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<HubEvent>();
+        self.client_id = Some(self.hub.add_channel_client(tx));
+
+        let addr = ctx.address();
+        actix_rt::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                addr.do_send(PushEvent(event));
+            }
+        });
+
+        ctx.run_interval(Duration::from_secs(20), |_actor, ctx| {
+            ctx.ping(b"ping");
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(id) = self.client_id.take() {
+            self.hub.remove_client(id);
+        }
+    }
+}
+
+impl Handler<PushEvent> for SubscriptionWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: PushEvent, ctx: &mut Self::Context) {
+        push_event(msg.0, ctx);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SubscriptionWs {
+    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match item {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Text(text)) => {
+                let Some(client_id) = self.client_id else {
+                    return;
+                };
+                // The handshake, or an OperationOutcome saying why not
+                let reply = match text.trim().strip_prefix("bind-with-token:") {
+                    Some(token) => self
+                        .subscriptions
+                        .bind(token.trim(), client_id, Utc::now())
+                        .unwrap_or_else(|e| operation_outcome(&e.to_string())),
+                    None => operation_outcome("expected 'bind-with-token: <token>'"),
+                };
+                ctx.text(reply.to_string());
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LiveQuery {
+    patient: Option<String>,
+    code: Option<String>,
+}
+
+/// Parse the `patient` / `code` filters shared by the live streams.
+fn live_filter(patient: &Option<String>, code: &Option<String>) -> Result<LiveFilter, AppError> {
+    let code = match code.as_deref() {
+        None => None,
+        Some(c) => Some(
+            SignalCode::parse(c)
+                .ok_or_else(|| AppError::Validation(format!("unknown code '{}'", c)))?,
+        ),
+    };
+    Ok(LiveFilter {
+        patient_id: patient.clone(),
+        code,
+    })
+}
+
 async fn ws_live(
-    state: web::Data<Arc<Mutex<AppState>>>,
+    state: web::Data<AppState>,
+    q: web::Query<LiveQuery>,
     req: HttpRequest,
     stream: web::Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
-    ws::start(LiveWs::new(state.get_ref().clone()), &req, stream)
+    let filter = live_filter(&q.patient, &q.code)?;
+    audit::touch(&req, filter.patient_id.as_deref());
+    ws::start(LiveWs::new(state.ws_hub.clone(), filter), &req, stream)
+}
+
+// Server-Sent Events twin of `/ws/live`, for networks whose proxies break websockets
+async fn events_live(
+    state: web::Data<AppState>,
+    q: web::Query<LiveQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let filter = live_filter(&q.patient, &q.code)?;
+    audit::touch(&req, filter.patient_id.as_deref());
+    // Sent by EventSource when it reconnects
+    let after = match req.headers().get("last-event-id") {
+        Some(v) => Some(
+            v.to_str()
+                .ok()
+                .and_then(sse::parse_event_id)
+                .ok_or_else(|| {
+                    AppError::Validation("Last-Event-ID is not an id from this stream".into())
+                })?,
+        ),
+        None => None,
+    };
+
+    let rx = sse::stream(state.into_inner(), filter, after, sse::HEARTBEAT_INTERVAL);
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        // Stops nginx from buffering the stream
+        .insert_header(("x-accel-buffering", "no"))
+        .streaming(body))
 }
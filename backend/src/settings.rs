@@ -0,0 +1,1128 @@
+use crate::domain::models::SignalCode;
+use crate::domain::retention::RetentionPolicy;
+use crate::mqtt::TopicTemplate;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Config file read when `PULSESENSE_CONFIG` is not set (optional).
+pub const DEFAULT_CONFIG_PATH: &str = "pulsesense.toml";
+// A century; far longer windows overflow timestamp arithmetic
+const MAX_RETENTION_DAYS: i64 = 36_500;
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("cannot read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("cannot parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("environment variable {var}={value:?} is not valid: {reason}")]
+    Env {
+        var: &'static str,
+        value: String,
+        reason: String,
+    },
+
+    #[error("invalid settings:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+/// Server configuration, loaded once at startup and shared via `web::Data`.
+/// Every field has a default, so an empty file (or none) is a valid config.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub store: StoreSettings,
+    pub auth: AuthSettings,
+    pub cors: CorsSettings,
+    pub tls: TlsSettings,
+    pub rate_limit: RateLimitSettings,
+    pub health: HealthSettings,
+    pub pipeline: PipelineSettings,
+    pub mqtt: MqttSettings,
+    pub coap: CoapSettings,
+    pub grpc: GrpcSettings,
+    pub hl7: Hl7Settings,
+    pub upstream: UpstreamSettings,
+    pub webhooks: WebhookSettings,
+    pub subscriptions: SubscriptionSettings,
+    pub audit: AuditSettings,
+    pub signals: SignalRanges,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+    /// How long readiness reports draining before connections are closed
+    pub drain_delay_secs: u64,
+    /// Grace period for in-flight requests once the server stops accepting
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            // Docker-friendly default; override locally with HOST=127.0.0.1
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            drain_delay_secs: 0,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreSettings {
+    /// Raw readings held in memory
+    pub capacity: usize,
+    pub retention_raw_days: i64,
+    pub retention_minute_days: i64,
+    pub retention_hour_days: i64,
+    pub compaction_interval_secs: u64,
+    /// Where FHIR bulk $export jobs write their NDJSON files
+    pub bulk_export_dir: PathBuf,
+    /// How long a finished export's status and files are kept
+    pub bulk_export_ttl_secs: u64,
+    /// NDJSON file the raw readings are flushed to on shutdown and loaded
+    /// from at startup; unset keeps the store in memory only
+    pub snapshot_path: Option<PathBuf>,
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        Self {
+            capacity: 2_000,
+            retention_raw_days: 7,
+            retention_minute_days: 30,
+            retention_hour_days: 365,
+            compaction_interval_secs: 60,
+            bulk_export_dir: PathBuf::from("./bulk-exports"),
+            bulk_export_ttl_secs: 86_400,
+            snapshot_path: None,
+        }
+    }
+}
+
+impl StoreSettings {
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy::days(
+            self.retention_raw_days,
+            self.retention_minute_days,
+            self.retention_hour_days,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    /// If unset or empty, ingest is open; otherwise clients must send
+    /// `Authorization: Bearer <token>`.
+    pub ingest_token: Option<String>,
+    /// Same, for the admin API (webhook subscriptions)
+    pub admin_token: Option<String>,
+}
+
+impl AuthSettings {
+    pub fn ingest_token(&self) -> Option<&str> {
+        self.ingest_token
+            .as_deref()
+            .filter(|t| !t.trim().is_empty())
+    }
+
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref().filter(|t| !t.trim().is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSettings {
+    /// Exact origins such as "http://127.0.0.1:5173", or "*" for any
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![
+                "http://127.0.0.1:5173".to_string(),
+                "http://localhost:5173".to_string(),
+            ],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "DELETE".to_string()],
+            allowed_headers: vec![
+                "authorization".to_string(),
+                "content-type".to_string(),
+                "prefer".to_string(),
+                "x-request-id".to_string(),
+            ],
+            max_age_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    /// CA bundle used to verify device client certificates (mutual TLS)
+    pub client_ca_path: Option<PathBuf>,
+    /// Reject ingest from clients that present no certificate
+    pub require_client_cert: bool,
+    /// Certificate identity (CN or SAN) -> device ids it may submit for.
+    /// Identities not listed may only submit for a device of the same name.
+    pub identity_map: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    /// Limits per route pattern, e.g. "/ingest" or "/fhir/Observation"
+    pub routes: HashMap<String, RouteLimits>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        let ingest = RouteLimits {
+            per_device: Some(Limit::new(5.0, 10)),
+            per_token: Some(Limit::new(50.0, 100)),
+            per_ip: Some(Limit::new(20.0, 40)),
+        };
+        Self {
+            enabled: false,
+            routes: HashMap::from([
+                ("/ingest".to_string(), ingest.clone()),
+                ("/ingest/gatt".to_string(), ingest),
+            ]),
+        }
+    }
+}
+
+/// Token buckets applied to one route; each key type is limited separately.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteLimits {
+    pub per_device: Option<Limit>,
+    pub per_token: Option<Limit>,
+    pub per_ip: Option<Limit>,
+}
+
+/// Refill rate and bucket size of one token bucket.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+impl Limit {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            requests_per_second,
+            burst,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthSettings {
+    /// Devices silent for longer are flagged stale in /readyz (informational)
+    pub stale_device_secs: u64,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            stale_device_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineSettings {
+    /// Validated readings waiting to be stored; /ingest answers 503 when full
+    pub queue_capacity: usize,
+    /// Buffer between the persist, alert and broadcast stages
+    pub stage_capacity: usize,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 1_024,
+            stage_capacity: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive_secs: u64,
+    /// Connect to the broker over TLS, verifying it against `ca_path`
+    pub tls: bool,
+    /// CA bundle (PEM) the broker's certificate must chain to
+    pub ca_path: Option<PathBuf>,
+    /// Client certificate and key (PEM), for brokers that authenticate
+    /// clients by certificate
+    pub client_cert_path: Option<PathBuf>,
+    pub client_key_path: Option<PathBuf>,
+    /// Topics to ingest from; `{device_id}`, `{patient_id}` and `{code}`
+    /// stand for whole levels and are filled into the reading
+    pub topics: Vec<String>,
+    /// Where alerts are published, with the same placeholders; empty disables
+    pub alert_topic: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "pulsesense-backend".to_string(),
+            username: None,
+            password: None,
+            keep_alive_secs: 30,
+            tls: false,
+            ca_path: None,
+            client_cert_path: None,
+            client_key_path: None,
+            topics: vec!["pulsesense/{device_id}/{code}".to_string()],
+            alert_topic: "pulsesense/{patient_id}/alerts/{code}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoapSettings {
+    pub enabled: bool,
+    pub host: String,
+    /// UDP port; 5683 is the CoAP default
+    pub port: u16,
+}
+
+impl Default for CoapSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "0.0.0.0".to_string(),
+            port: 5683,
+        }
+    }
+}
+
+impl CoapSettings {
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcSettings {
+    pub enabled: bool,
+    pub host: String,
+    /// Plaintext HTTP/2 port, separate from the HTTP server
+    pub port: u16,
+}
+
+impl Default for GrpcSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "0.0.0.0".to_string(),
+            port: 50051,
+        }
+    }
+}
+
+impl GrpcSettings {
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hl7Settings {
+    pub enabled: bool,
+    /// Interface engine's MLLP listener
+    pub host: String,
+    pub port: u16,
+    /// MSH-3/4: who we are
+    pub sending_application: String,
+    pub sending_facility: String,
+    /// MSH-5/6: who the messages are for
+    pub receiving_application: String,
+    pub receiving_facility: String,
+    /// Send an ORU^R01 for every observation, not only for alerts
+    pub send_observations: bool,
+    /// How long to wait for the connection and for each ACK
+    pub ack_timeout_secs: u64,
+    /// Tries per message before it is dropped (AE/CE are never retried)
+    pub max_attempts: u32,
+    /// Backoff before the first retry; doubles up to `retry_max_ms`
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+    /// Messages waiting for delivery; beyond it new ones are dropped
+    pub queue_capacity: usize,
+}
+
+impl Default for Hl7Settings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 2575,
+            sending_application: "PULSESENSE".to_string(),
+            sending_facility: String::new(),
+            receiving_application: String::new(),
+            receiving_facility: String::new(),
+            send_observations: true,
+            ack_timeout_secs: 10,
+            max_attempts: 5,
+            retry_initial_ms: 500,
+            retry_max_ms: 30_000,
+            queue_capacity: 1_000,
+        }
+    }
+}
+
+impl Hl7Settings {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamSettings {
+    pub enabled: bool,
+    /// FHIR R4 base URL that transaction Bundles are POSTed to
+    pub base_url: String,
+    /// Sent as `Authorization: Bearer <token>` if set
+    pub bearer_token: Option<String>,
+    /// Holds the outbox journal and the dead-letter file
+    pub outbox_dir: PathBuf,
+    /// Observations waiting beyond this go straight to the dead-letter file
+    pub outbox_capacity: usize,
+    /// Observations per transaction Bundle
+    pub batch_size: usize,
+    pub request_timeout_secs: u64,
+    /// Backoff before the first retry; doubles up to `retry_max_ms`, and
+    /// retries go on until the server takes the batch (4xx responses other
+    /// than 408/429 are dead-lettered instead)
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            base_url: String::new(),
+            bearer_token: None,
+            outbox_dir: PathBuf::from("./fhir-outbox"),
+            outbox_capacity: 100_000,
+            batch_size: 100,
+            request_timeout_secs: 30,
+            retry_initial_ms: 1_000,
+            retry_max_ms: 300_000,
+        }
+    }
+}
+
+impl UpstreamSettings {
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.bearer_token
+            .as_deref()
+            .filter(|t| !t.trim().is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    pub enabled: bool,
+    pub request_timeout_secs: u64,
+    /// Tries per delivery before it is marked failed (3xx and 4xx responses
+    /// other than 408/429 are never retried)
+    pub max_attempts: u32,
+    /// Backoff before the first retry; doubles up to `retry_max_ms`
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+    /// Deliveries waiting per subscription; beyond it new ones are dropped
+    pub queue_capacity: usize,
+    /// Deliveries kept in each subscription's log
+    pub log_size: usize,
+    /// JSON file the subscriptions and their secrets are kept in
+    pub path: PathBuf,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            request_timeout_secs: 10,
+            max_attempts: 6,
+            retry_initial_ms: 1_000,
+            retry_max_ms: 300_000,
+            queue_capacity: 1_000,
+            log_size: 100,
+            path: PathBuf::from("./webhooks/webhooks.json"),
+        }
+    }
+}
+
+/// FHIR topic-based Subscriptions. rest-hook notifications are delivered
+/// with the retry, queue and timeout settings of `[webhooks]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionSettings {
+    pub enabled: bool,
+    /// How long a websocket binding token can be redeemed for
+    pub binding_token_ttl_secs: u64,
+    /// JSON file the subscriptions are kept in, rest-hook headers included
+    pub path: PathBuf,
+}
+
+impl Default for SubscriptionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            binding_token_ttl_secs: 60,
+            path: PathBuf::from("./subscriptions/subscriptions.json"),
+        }
+    }
+}
+
+/// The audit trail of reads and writes (see `audit`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditSettings {
+    pub enabled: bool,
+    /// Append-only NDJSON file of hash-chained records
+    pub path: PathBuf,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("./audit/audit.ndjson"),
+        }
+    }
+}
+
+/// Accepted value range and units for one signal, plus the thresholds that
+/// raise an alert (a reading can be valid and still alarming).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignalRange {
+    pub min: f64,
+    pub max: f64,
+    pub units: Vec<String>,
+    #[serde(default)]
+    pub alert_below: Option<f64>,
+    #[serde(default)]
+    pub alert_above: Option<f64>,
+}
+
+impl SignalRange {
+    fn new(min: f64, max: f64, units: &[&str]) -> Self {
+        Self {
+            min,
+            max,
+            units: units.iter().map(|u| u.to_string()).collect(),
+            alert_below: None,
+            alert_above: None,
+        }
+    }
+
+    fn alerts(mut self, below: Option<f64>, above: Option<f64>) -> Self {
+        self.alert_below = below;
+        self.alert_above = above;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SignalRanges {
+    pub heart_rate: SignalRange,
+    pub body_temperature: SignalRange,
+    pub steps_per_minute: SignalRange,
+    pub rr_interval: SignalRange,
+}
+
+impl Default for SignalRanges {
+    fn default() -> Self {
+        Self {
+            heart_rate: SignalRange::new(20.0, 240.0, &["beats/min", "bpm"])
+                .alerts(Some(40.0), Some(130.0)),
+            body_temperature: SignalRange::new(30.0, 45.0, &["C", "°C"])
+                .alerts(Some(35.0), Some(38.0)),
+            steps_per_minute: SignalRange::new(0.0, 400.0, &["steps/min"]),
+            // Matches the heart-rate range: 240 to 20 beats/min
+            rr_interval: SignalRange::new(250.0, 3000.0, &["ms"]),
+        }
+    }
+}
+
+impl SignalRanges {
+    pub fn get(&self, code: SignalCode) -> &SignalRange {
+        match code {
+            SignalCode::HeartRate => &self.heart_rate,
+            SignalCode::BodyTemperature => &self.body_temperature,
+            SignalCode::StepsPerMinute => &self.steps_per_minute,
+            SignalCode::RrInterval => &self.rr_interval,
+        }
+    }
+}
+
+impl Settings {
+    /// Load from `PULSESENSE_CONFIG` (or `pulsesense.toml` if present),
+    /// apply environment overrides, then validate.
+    pub fn load() -> Result<Self, SettingsError> {
+        let mut settings = match std::env::var("PULSESENSE_CONFIG") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Self::default(),
+        };
+        settings.apply_env(|var| std::env::var(var).ok())?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let text = std::fs::read_to_string(path).map_err(|source| SettingsError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_toml(&text).map_err(|source| SettingsError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Override individual fields from environment variables; `lookup`
+    /// abstracts the environment so tests can supply their own.
+    pub fn apply_env(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), SettingsError> {
+        fn parse<T: std::str::FromStr>(var: &'static str, value: String) -> Result<T, SettingsError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .trim()
+                .parse()
+                .map_err(|e: T::Err| SettingsError::Env {
+                    var,
+                    value,
+                    reason: e.to_string(),
+                })
+        }
+        let list = |v: String| {
+            v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+
+        if let Some(v) = lookup("HOST") {
+            self.server.host = v;
+        }
+        if let Some(v) = lookup("PORT") {
+            self.server.port = parse("PORT", v)?;
+        }
+        if let Some(v) = lookup("INGEST_TOKEN") {
+            self.auth.ingest_token = Some(v);
+        }
+        if let Some(v) = lookup("ADMIN_TOKEN") {
+            self.auth.admin_token = Some(v);
+        }
+        if let Some(v) = lookup("STORE_CAPACITY") {
+            self.store.capacity = parse("STORE_CAPACITY", v)?;
+        }
+        if let Some(v) = lookup("RETENTION_RAW_DAYS") {
+            self.store.retention_raw_days = parse("RETENTION_RAW_DAYS", v)?;
+        }
+        if let Some(v) = lookup("RETENTION_MINUTE_DAYS") {
+            self.store.retention_minute_days = parse("RETENTION_MINUTE_DAYS", v)?;
+        }
+        if let Some(v) = lookup("RETENTION_HOUR_DAYS") {
+            self.store.retention_hour_days = parse("RETENTION_HOUR_DAYS", v)?;
+        }
+        if let Some(v) = lookup("BULK_EXPORT_DIR") {
+            self.store.bulk_export_dir = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("BULK_EXPORT_TTL_SECS") {
+            self.store.bulk_export_ttl_secs = parse("BULK_EXPORT_TTL_SECS", v)?;
+        }
+        if let Some(v) = lookup("STORE_SNAPSHOT_PATH") {
+            self.store.snapshot_path = Some(PathBuf::from(v)).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Some(v) = lookup("DRAIN_DELAY_SECS") {
+            self.server.drain_delay_secs = parse("DRAIN_DELAY_SECS", v)?;
+        }
+        if let Some(v) = lookup("INGEST_QUEUE_CAPACITY") {
+            self.pipeline.queue_capacity = parse("INGEST_QUEUE_CAPACITY", v)?;
+        }
+        if let Some(v) = lookup("MQTT_ENABLED") {
+            self.mqtt.enabled = parse("MQTT_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("MQTT_HOST") {
+            self.mqtt.host = v;
+        }
+        if let Some(v) = lookup("MQTT_PORT") {
+            self.mqtt.port = parse("MQTT_PORT", v)?;
+        }
+        if let Some(v) = lookup("MQTT_USERNAME") {
+            self.mqtt.username = Some(v);
+        }
+        if let Some(v) = lookup("MQTT_PASSWORD") {
+            self.mqtt.password = Some(v);
+        }
+        if let Some(v) = lookup("MQTT_TLS") {
+            self.mqtt.tls = parse("MQTT_TLS", v)?;
+        }
+        if let Some(v) = lookup("MQTT_CA_PATH") {
+            self.mqtt.ca_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = lookup("MQTT_CLIENT_CERT_PATH") {
+            self.mqtt.client_cert_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = lookup("MQTT_CLIENT_KEY_PATH") {
+            self.mqtt.client_key_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = lookup("COAP_ENABLED") {
+            self.coap.enabled = parse("COAP_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("COAP_PORT") {
+            self.coap.port = parse("COAP_PORT", v)?;
+        }
+        if let Some(v) = lookup("GRPC_ENABLED") {
+            self.grpc.enabled = parse("GRPC_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("GRPC_PORT") {
+            self.grpc.port = parse("GRPC_PORT", v)?;
+        }
+        if let Some(v) = lookup("HL7_ENABLED") {
+            self.hl7.enabled = parse("HL7_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("HL7_HOST") {
+            self.hl7.host = v;
+        }
+        if let Some(v) = lookup("HL7_PORT") {
+            self.hl7.port = parse("HL7_PORT", v)?;
+        }
+        if let Some(v) = lookup("UPSTREAM_ENABLED") {
+            self.upstream.enabled = parse("UPSTREAM_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("UPSTREAM_BASE_URL") {
+            self.upstream.base_url = v;
+        }
+        if let Some(v) = lookup("UPSTREAM_TOKEN") {
+            self.upstream.bearer_token = Some(v);
+        }
+        if let Some(v) = lookup("UPSTREAM_OUTBOX_DIR") {
+            self.upstream.outbox_dir = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("WEBHOOKS_ENABLED") {
+            self.webhooks.enabled = parse("WEBHOOKS_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("WEBHOOKS_PATH") {
+            self.webhooks.path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("SUBSCRIPTIONS_ENABLED") {
+            self.subscriptions.enabled = parse("SUBSCRIPTIONS_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("SUBSCRIPTIONS_PATH") {
+            self.subscriptions.path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("AUDIT_ENABLED") {
+            self.audit.enabled = parse("AUDIT_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("AUDIT_PATH") {
+            self.audit.path = PathBuf::from(v);
+        }
+        if let Some(v) = lookup("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = list(v);
+        }
+        if let Some(v) = lookup("TLS_ENABLED") {
+            self.tls.enabled = parse("TLS_ENABLED", v)?;
+        }
+        if let Some(v) = lookup("TLS_CERT_PATH") {
+            self.tls.cert_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = lookup("TLS_KEY_PATH") {
+            self.tls.key_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = lookup("TLS_CLIENT_CA_PATH") {
+            self.tls.client_ca_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = lookup("TLS_REQUIRE_CLIENT_CERT") {
+            self.tls.require_client_cert = parse("TLS_REQUIRE_CLIENT_CERT", v)?;
+        }
+        if let Some(v) = lookup("RATE_LIMIT_ENABLED") {
+            self.rate_limit.enabled = parse("RATE_LIMIT_ENABLED", v)?;
+        }
+        Ok(())
+    }
+
+    /// Check cross-field constraints, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let mut errors = Vec::new();
+
+        if self.server.host.trim().is_empty() {
+            errors.push("server.host must not be empty".to_string());
+        }
+
+        let s = &self.store;
+        if s.capacity == 0 {
+            errors.push("store.capacity must be at least 1".to_string());
+        }
+        if s.retention_raw_days <= 0 || s.retention_minute_days <= 0 || s.retention_hour_days <= 0 {
+            errors.push("store.retention_*_days must be positive".to_string());
+        }
+        if s.retention_raw_days
+            .max(s.retention_minute_days)
+            .max(s.retention_hour_days)
+            > MAX_RETENTION_DAYS
+        {
+            errors.push(format!(
+                "store.retention_*_days must be at most {}",
+                MAX_RETENTION_DAYS
+            ));
+        }
+        if s.retention_raw_days > s.retention_minute_days
+            || s.retention_minute_days > s.retention_hour_days
+        {
+            errors.push("store retention must satisfy raw <= minute <= hour days".to_string());
+        }
+        if s.compaction_interval_secs == 0 {
+            errors.push("store.compaction_interval_secs must be at least 1".to_string());
+        }
+        if s.bulk_export_ttl_secs == 0 {
+            errors.push("store.bulk_export_ttl_secs must be at least 1".to_string());
+        }
+
+        for origin in &self.cors.allowed_origins {
+            let valid = origin == "*"
+                || ((origin.starts_with("http://") || origin.starts_with("https://"))
+                    && !origin.ends_with('/'));
+            if !valid {
+                errors.push(format!(
+                    "cors.allowed_origins: {:?} must be \"*\" or scheme://host[:port]",
+                    origin
+                ));
+            }
+        }
+        for header in &self.cors.allowed_headers {
+            if actix_web::http::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                errors.push(format!(
+                    "cors.allowed_headers: {:?} is not a valid header name",
+                    header
+                ));
+            }
+        }
+        for method in &self.cors.allowed_methods {
+            if actix_web::http::Method::from_bytes(method.as_bytes()).is_err() {
+                errors.push(format!(
+                    "cors.allowed_methods: {:?} is not an HTTP method",
+                    method
+                ));
+            }
+        }
+
+        if self.tls.enabled {
+            for (name, path) in [
+                ("cert_path", &self.tls.cert_path),
+                ("key_path", &self.tls.key_path),
+            ] {
+                match path {
+                    None => {
+                        errors.push(format!("tls.{} is required when tls.enabled = true", name))
+                    }
+                    Some(p) if !p.is_file() => {
+                        errors.push(format!("tls.{} {} does not exist", name, p.display()))
+                    }
+                    Some(_) => {}
+                }
+            }
+            if let Some(p) = &self.tls.client_ca_path {
+                if !p.is_file() {
+                    errors.push(format!("tls.client_ca_path {} does not exist", p.display()));
+                }
+            }
+            if self.tls.require_client_cert && self.tls.client_ca_path.is_none() {
+                errors.push("tls.require_client_cert needs tls.client_ca_path".to_string());
+            }
+        } else if self.tls.client_ca_path.is_some() || self.tls.require_client_cert {
+            errors.push(
+                "tls.client_ca_path and tls.require_client_cert need tls.enabled = true"
+                    .to_string(),
+            );
+        }
+
+        for (route, limits) in &self.rate_limit.routes {
+            for (key, limit) in [
+                ("per_device", limits.per_device),
+                ("per_token", limits.per_token),
+                ("per_ip", limits.per_ip),
+            ] {
+                let Some(limit) = limit else {
+                    continue;
+                };
+                let rps = limit.requests_per_second;
+                if rps.is_nan() || rps <= 0.0 || limit.burst == 0 {
+                    errors.push(format!(
+                        "rate_limit.routes.{:?}.{}: requests_per_second must be positive and burst at least 1",
+                        route, key
+                    ));
+                }
+            }
+        }
+
+        for code in SignalCode::ALL {
+            let r = self.signals.get(code);
+            if r.min.is_nan() || r.max.is_nan() || r.min >= r.max {
+                errors.push(format!("signals.{}: min must be below max", code.as_str()));
+            }
+            if r.units.is_empty() {
+                errors.push(format!(
+                    "signals.{}: at least one unit is required",
+                    code.as_str()
+                ));
+            }
+            for (name, threshold) in [
+                ("alert_below", r.alert_below),
+                ("alert_above", r.alert_above),
+            ] {
+                if threshold.is_some_and(|t| !(r.min..=r.max).contains(&t)) {
+                    errors.push(format!(
+                        "signals.{}.{}: must lie within min..max",
+                        code.as_str(),
+                        name
+                    ));
+                }
+            }
+            if let (Some(below), Some(above)) = (r.alert_below, r.alert_above) {
+                if below >= above {
+                    errors.push(format!(
+                        "signals.{}: alert_below must be below alert_above",
+                        code.as_str()
+                    ));
+                }
+            }
+        }
+
+        if self.pipeline.queue_capacity == 0 || self.pipeline.stage_capacity == 0 {
+            errors
+                .push("pipeline: queue_capacity and stage_capacity must be at least 1".to_string());
+        }
+
+        if self.mqtt.enabled {
+            if self.mqtt.host.trim().is_empty() {
+                errors.push("mqtt.host is required when MQTT is enabled".to_string());
+            }
+            if self.mqtt.topics.is_empty() {
+                errors.push(
+                    "mqtt.topics: at least one topic is required when MQTT is enabled".to_string(),
+                );
+            }
+            for topic in &self.mqtt.topics {
+                if let Err(e) = TopicTemplate::parse(topic) {
+                    errors.push(format!("mqtt.topics: {:?} {}", topic, e));
+                }
+            }
+            if !self.mqtt.alert_topic.is_empty() {
+                if let Err(e) = TopicTemplate::parse(&self.mqtt.alert_topic) {
+                    errors.push(format!(
+                        "mqtt.alert_topic: {:?} {}",
+                        self.mqtt.alert_topic, e
+                    ));
+                }
+            }
+            let paths = [
+                ("ca_path", &self.mqtt.ca_path),
+                ("client_cert_path", &self.mqtt.client_cert_path),
+                ("client_key_path", &self.mqtt.client_key_path),
+            ];
+            if self.mqtt.tls {
+                if self.mqtt.ca_path.is_none() {
+                    errors.push("mqtt.ca_path is required when mqtt.tls = true".to_string());
+                }
+                if self.mqtt.client_cert_path.is_some() != self.mqtt.client_key_path.is_some() {
+                    errors.push(
+                        "mqtt.client_cert_path and mqtt.client_key_path go together".to_string(),
+                    );
+                }
+                for (name, path) in paths {
+                    if let Some(p) = path.as_ref().filter(|p| !p.is_file()) {
+                        errors.push(format!("mqtt.{} {} does not exist", name, p.display()));
+                    }
+                }
+            } else if paths.iter().any(|(_, path)| path.is_some()) {
+                errors.push(
+                    "mqtt.ca_path, mqtt.client_cert_path and mqtt.client_key_path need mqtt.tls = true"
+                        .to_string(),
+                );
+            }
+        }
+
+        // CoAP (plain UDP) and gRPC (plaintext HTTP/2) carry no client
+        // certificate to check; MQTT readings come through the broker, whose
+        // own authentication decides who may publish
+        for (listener, enabled) in [
+            ("coap", self.coap.enabled),
+            ("grpc", self.grpc.enabled),
+            ("mqtt", self.mqtt.enabled),
+        ] {
+            if enabled && self.tls.require_client_cert {
+                errors.push(format!(
+                    "{}.enabled cannot be combined with tls.require_client_cert",
+                    listener
+                ));
+            }
+        }
+
+        if self.hl7.enabled {
+            if self.hl7.host.trim().is_empty() {
+                errors.push("hl7.host is required when HL7 is enabled".to_string());
+            }
+            if self.hl7.sending_application.trim().is_empty() {
+                errors.push("hl7.sending_application is required when HL7 is enabled".to_string());
+            }
+            if self.hl7.max_attempts == 0
+                || self.hl7.ack_timeout_secs == 0
+                || self.hl7.queue_capacity == 0
+            {
+                errors.push(
+                    "hl7: max_attempts, ack_timeout_secs and queue_capacity must be at least 1"
+                        .to_string(),
+                );
+            }
+            if self.hl7.retry_initial_ms > self.hl7.retry_max_ms {
+                errors.push("hl7: retry_initial_ms must not exceed retry_max_ms".to_string());
+            }
+        }
+
+        if self.upstream.enabled {
+            let url = &self.upstream.base_url;
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                errors.push(format!(
+                    "upstream.base_url: {:?} must be an http(s) URL when upstream is enabled",
+                    url
+                ));
+            }
+            if self.upstream.batch_size == 0 || self.upstream.outbox_capacity == 0 {
+                errors.push(
+                    "upstream: batch_size and outbox_capacity must be at least 1".to_string(),
+                );
+            }
+            if self.upstream.request_timeout_secs == 0 {
+                errors.push("upstream: request_timeout_secs must be at least 1".to_string());
+            }
+            if self.upstream.retry_initial_ms > self.upstream.retry_max_ms {
+                errors.push("upstream: retry_initial_ms must not exceed retry_max_ms".to_string());
+            }
+        }
+
+        // FHIR Subscriptions deliver with the webhook settings
+        if self.webhooks.enabled || self.subscriptions.enabled {
+            let w = &self.webhooks;
+            if w.max_attempts == 0 || w.request_timeout_secs == 0 {
+                errors.push(
+                    "webhooks: max_attempts and request_timeout_secs must be at least 1"
+                        .to_string(),
+                );
+            }
+            if w.queue_capacity == 0 || w.log_size == 0 {
+                errors.push("webhooks: queue_capacity and log_size must be at least 1".to_string());
+            }
+            if w.retry_initial_ms > w.retry_max_ms {
+                errors.push("webhooks: retry_initial_ms must not exceed retry_max_ms".to_string());
+            }
+        }
+        if self.webhooks.enabled {
+            // The webhook API hands out delivery secrets
+            if self.auth.admin_token().is_none() {
+                errors.push("webhooks.enabled requires auth.admin_token".to_string());
+            }
+            if self.webhooks.path.as_os_str().is_empty() {
+                errors.push("webhooks.path is required when webhooks.enabled = true".to_string());
+            }
+        }
+        if self.subscriptions.enabled {
+            // rest-hook subscriptions carry the receivers' credentials
+            if self.auth.admin_token().is_none() {
+                errors.push("subscriptions.enabled requires auth.admin_token".to_string());
+            }
+            if self.subscriptions.path.as_os_str().is_empty() {
+                errors.push(
+                    "subscriptions.path is required when subscriptions.enabled = true".to_string(),
+                );
+            }
+            if self.subscriptions.binding_token_ttl_secs == 0 {
+                errors.push("subscriptions.binding_token_ttl_secs must be at least 1".to_string());
+            }
+        }
+        if self.audit.enabled {
+            // Its API is the only way to read the trail back
+            if self.auth.admin_token().is_none() {
+                errors.push("audit.enabled requires auth.admin_token".to_string());
+            }
+            if self.audit.path.as_os_str().is_empty() {
+                errors.push("audit.path is required when audit.enabled = true".to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SettingsError::Invalid(errors))
+        }
+    }
+
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.server.host, self.server.port)
+    }
+}
@@ -0,0 +1,234 @@
+use crate::domain::alerts;
+use crate::domain::models::StoredObservation;
+use crate::domain::store::{AppState, ObsFilter};
+use crate::errors::AppError;
+use crate::fhir;
+use crate::ws::{HubEvent, LiveEvent, LiveEvents, LiveFilter};
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// How often an idle stream gets a comment, so proxies keep it open.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// Observations read from the store per lock when resuming
+const PAGE_SIZE: usize = 1_000;
+
+/// Where a stream resumes: the store's start (µs) and an arrival number
+/// from that store (see `AppState::arrival`).
+pub type EventCursor = (i64, u64);
+
+/// An event's SSE `id`: the observation's arrival in the store (`<store
+/// start micros>_<arrival>`), so a reconnecting client resumes right after
+/// it, late readings included. Alerts share the id of their observation.
+pub fn event_id(state: &AppState, arrival: u64) -> String {
+    format!("{}_{}", store_run(state), arrival)
+}
+
+/// Inverse of `event_id`.
+pub fn parse_event_id(id: &str) -> Option<EventCursor> {
+    let (run, arrival) = id.split_once('_')?;
+    Some((run.parse().ok()?, arrival.parse().ok()?))
+}
+
+// Arrival numbers restart with the store, so ids carry which one they're from
+fn store_run(state: &AppState) -> i64 {
+    state.created_at().timestamp_micros()
+}
+
+fn frame(id: Option<&str>, event: &str, data: &str) -> Bytes {
+    let id = id.map(|id| format!("id: {}\n", id)).unwrap_or_default();
+    Bytes::from(format!("{}event: {}\ndata: {}\n\n", id, event, data))
+}
+
+// An event as `/ws/live` sends it: an observation as its FHIR resource,
+// an alert as `{"type":"alert"}`
+fn event_frame(id: Option<&str>, event: &LiveEvent) -> Option<Bytes> {
+    match event {
+        LiveEvent::Observation(obs) => {
+            let resource = fhir::to_fhir_observation(obs).ok()?;
+            let text = serde_json::to_string(&resource).ok()?;
+            Some(frame(id, "observation", &text))
+        }
+        LiveEvent::Alert(alert) => {
+            let text = serde_json::json!({ "type": "alert", "alert": alert }).to_string();
+            Some(frame(id, "alert", &text))
+        }
+    }
+}
+
+// The observation and its alert (if any) as the hub would have sent them
+fn replay_frames(state: &AppState, arrival: u64, obs: &StoredObservation) -> Vec<Bytes> {
+    let id = event_id(state, arrival);
+    let alert = alerts::evaluate(state.signals().get(obs.reading.code), obs);
+    std::iter::once(LiveEvent::Observation(obs.clone()))
+        .chain(alert.map(LiveEvent::Alert))
+        .filter_map(|event| event_frame(Some(&id), &event))
+        .collect()
+}
+
+/// The `/events/live` stream: the same messages as `/ws/live`, as
+/// `observation` and `alert` events. With `after`, stored observations
+/// that arrived since then are replayed first (alerts re-evaluated from
+/// them); a cursor from an earlier run of the store replays everything it
+/// has taken in since starting. Ends after a `shutdown` event when the hub closes its clients.
+pub fn stream(
+    state: Arc<AppState>,
+    filter: LiveFilter,
+    after: Option<EventCursor>,
+    heartbeat: Duration,
+) -> mpsc::Receiver<Result<Bytes, AppError>> {
+    let (tx, rx) = mpsc::channel(16);
+    // Registered before replaying, so nothing falls between the two
+    let (hub_tx, mut hub_rx) = mpsc::unbounded_channel();
+    let (client, mut events) = state.ws_hub.add_event_client(hub_tx);
+
+    actix_rt::spawn(async move {
+        let _ = run(
+            &state,
+            &tx,
+            &mut hub_rx,
+            &mut events,
+            filter,
+            after,
+            heartbeat,
+        )
+        .await;
+        state.ws_hub.remove_client(client);
+    });
+    rx
+}
+
+// Send the observations matching `filter` that arrived after `cursor`, in
+// arrival order, moving it along. Replaying (with `replayed`) also sends
+// the alerts they raise and notes them, so their live alerts are skipped.
+async fn catch_up(
+    state: &AppState,
+    tx: &mpsc::Sender<Result<Bytes, AppError>>,
+    filter: &ObsFilter,
+    cursor: &mut u64,
+    mut replayed: Option<&mut HashSet<Uuid>>,
+) -> Result<(), mpsc::error::SendError<Result<Bytes, AppError>>> {
+    loop {
+        let page = state.arrived_after(filter, *cursor, PAGE_SIZE);
+        for (arrival, obs) in &page {
+            let frames = match replayed.as_deref_mut() {
+                Some(replayed) => {
+                    replayed.insert(obs.id);
+                    replay_frames(state, *arrival, obs)
+                }
+                None => {
+                    let id = event_id(state, *arrival);
+                    let event = LiveEvent::Observation(obs.clone());
+                    event_frame(Some(&id), &event).into_iter().collect()
+                }
+            };
+            for frame in frames {
+                tx.send(Ok(frame)).await?;
+            }
+            *cursor = *arrival;
+        }
+        if page.len() < PAGE_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+// Err once the client has gone away
+async fn run(
+    state: &AppState,
+    tx: &mpsc::Sender<Result<Bytes, AppError>>,
+    hub_rx: &mut mpsc::UnboundedReceiver<HubEvent>,
+    events: &mut LiveEvents,
+    filter: LiveFilter,
+    after: Option<EventCursor>,
+    heartbeat: Duration,
+) -> Result<(), mpsc::error::SendError<Result<Bytes, AppError>>> {
+    // Sent first so proxies pass the headers on straight away
+    tx.send(Ok(Bytes::from_static(b": connected\n\n"))).await?;
+
+    let obs_filter = ObsFilter {
+        patient_id: filter.patient_id.clone(),
+        code: filter.code,
+        ..ObsFilter::default()
+    };
+    // Every matching observation that arrived up to here has been sent
+    let mut cursor = match after {
+        Some((from_run, arrival)) if from_run == store_run(state) => {
+            arrival.min(state.last_arrival())
+        }
+        Some(_) => 0,
+        None => state.last_arrival(),
+    };
+    let mut replayed = HashSet::new();
+    if after.is_some() {
+        catch_up(state, tx, &obs_filter, &mut cursor, Some(&mut replayed)).await?;
+    }
+
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat, heartbeat);
+    loop {
+        let frame = tokio::select! {
+            // Events published before a shutdown go out ahead of it
+            biased;
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return Ok(());
+                };
+                let (patient_id, code) = event.scope();
+                if !filter.matches(patient_id, code) {
+                    continue;
+                }
+                let (observation_id, ts) = match &*event {
+                    LiveEvent::Observation(obs) => (obs.id, obs.reading.ts),
+                    LiveEvent::Alert(alert) => (alert.observation_id, alert.ts),
+                };
+                let id = match state.arrival(patient_id, code, (ts, observation_id)) {
+                    // Workers can publish out of arrival order; taking
+                    // observations from the store keeps ids in order, so
+                    // resuming after one never skips an earlier one
+                    Some(arrival) => {
+                        if arrival > cursor {
+                            catch_up(state, tx, &obs_filter, &mut cursor, None).await?;
+                        }
+                        let LiveEvent::Alert(_) = &*event else {
+                            continue;
+                        };
+                        // Re-evaluated when replayed
+                        if replayed.contains(&observation_id) {
+                            continue;
+                        }
+                        Some(event_id(state, arrival))
+                    }
+                    // Restored readings have no arrival, so are sent as they
+                    // come, without an id
+                    None => None,
+                };
+                match event_frame(id.as_deref(), &event) {
+                    Some(frame) => frame,
+                    None => continue,
+                }
+            }
+            event = hub_rx.recv() => match event {
+                // Event clients get nothing else from the hub
+                Some(HubEvent::Text(_)) => continue,
+                Some(HubEvent::Shutdown { reason, reconnect_after_secs }) => {
+                    let data = serde_json::json!({
+                        "type": "shutdown",
+                        "msg": reason,
+                        "reconnect_after_secs": reconnect_after_secs,
+                    });
+                    // `retry` makes EventSource wait that long before reconnecting
+                    let retry = format!("retry: {}\n", reconnect_after_secs * 1000);
+                    let shutdown = frame(None, "shutdown", &data.to_string());
+                    tx.send(Ok(Bytes::from([retry.as_bytes(), &shutdown].concat()))).await?;
+                    return Ok(());
+                }
+                None => return Ok(()),
+            },
+            _ = ticks.tick() => Bytes::from_static(b": heartbeat\n\n"),
+        };
+        tx.send(Ok(frame)).await?;
+    }
+}
@@ -0,0 +1,943 @@
+use crate::domain::models::{SignalCode, StoredObservation};
+use crate::domain::store::AppState;
+use crate::errors::AppError;
+use crate::fhir;
+use crate::metrics::METRICS;
+use crate::settings::{SubscriptionSettings, WebhookSettings};
+use crate::webhooks::{self, DeliveryStatus, Endpoint, Target};
+use crate::ws::{Hub, LiveEvent, LiveEvents, LiveFilter};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// The one topic offered: every Observation stored, filterable by patient
+/// and code.
+pub const OBSERVATION_TOPIC: &str = "http://pulsesense.io/fhir/SubscriptionTopic/observation";
+const CHANNEL_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/subscription-channel-type";
+const FHIR_JSON: &str = "application/fhir+json";
+// How often due heartbeats are looked for
+const HEARTBEAT_TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// Waiting for the rest-hook handshake to be accepted
+    Requested,
+    Active,
+    /// The handshake failed; update the subscription to try again
+    Error,
+    Off,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Requested => "requested",
+            Status::Active => "active",
+            Status::Error => "error",
+            Status::Off => "off",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChannelType {
+    RestHook,
+    Websocket,
+}
+
+impl ChannelType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelType::RestHook => "rest-hook",
+            ChannelType::Websocket => "websocket",
+        }
+    }
+}
+
+/// How much of the Observation a notification carries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Content {
+    Empty,
+    #[default]
+    IdOnly,
+    FullResource,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelCoding {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub code: ChannelType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterBy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_type: Option<String>,
+    pub filter_parameter: String,
+    pub value: String,
+}
+
+/// A rest-hook request header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Parameter {
+    pub name: String,
+    pub value: String,
+}
+
+/// Subscription resource in its R5 shape. Elements not listed here are
+/// ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    pub resource_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Kept by the server; clients can only ask for `off`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Shorthand for the topic and its filters, as in
+    /// `Observation?patient=X&code=8867-4`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub criteria: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filter_by: Vec<FilterBy>,
+    pub channel_type: ChannelCoding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// Only shown in the response that sets them, as they usually hold
+    /// credentials
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameter: Vec<Parameter>,
+    /// Seconds without a notification before a heartbeat is sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_period: Option<u64>,
+    #[serde(default)]
+    pub content: Content,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NotificationType {
+    Handshake,
+    Heartbeat,
+    EventNotification,
+    QueryStatus,
+}
+
+impl NotificationType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NotificationType::Handshake => "handshake",
+            NotificationType::Heartbeat => "heartbeat",
+            NotificationType::EventNotification => "event-notification",
+            NotificationType::QueryStatus => "query-status",
+        }
+    }
+}
+
+/// The SubscriptionTopic resource for `OBSERVATION_TOPIC`.
+pub fn observation_topic() -> serde_json::Value {
+    json!({
+        "resourceType": "SubscriptionTopic",
+        "id": "observation",
+        "url": OBSERVATION_TOPIC,
+        "title": "New observations",
+        "status": "active",
+        "resourceTrigger": [{
+            "description": "An Observation is stored",
+            "resource": "Observation",
+            "supportedInteraction": ["create"],
+        }],
+        "canFilterBy": [
+            { "resource": "Observation", "filterParameter": "patient", "description": "Patient id or reference" },
+            { "resource": "Observation", "filterParameter": "code", "description": "LOINC code or signal name" },
+        ],
+        "notificationShape": [{ "resource": "Observation" }],
+    })
+}
+
+struct Entry {
+    resource: Subscription,
+    filter: LiveFilter,
+    // Where Subscription and Observation references point
+    base_url: String,
+    created_at: DateTime<Utc>,
+    events_since_start: u64,
+    last_sent_at: DateTime<Utc>,
+    // rest-hook only; websocket notifications go through the hub
+    endpoint: Option<Endpoint>,
+}
+
+impl Entry {
+    fn status(&self) -> Status {
+        self.resource.status.unwrap_or(Status::Requested)
+    }
+
+    fn channel(&self) -> ChannelType {
+        self.resource.channel_type.code
+    }
+}
+
+struct Binding {
+    subscription: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+// One subscription as kept in the registry file, `parameter` included
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    resource: Subscription,
+    base_url: String,
+    created_at: DateTime<Utc>,
+}
+
+/// FHIR topic-based Subscriptions on new Observations. rest-hook
+/// notifications reuse the webhook delivery queues; websocket ones go to
+/// hub clients bound to the subscription's channel. Subscriptions are saved
+/// to `settings.path` on every change, status included, and reloaded on
+/// start; event counts restart from zero and binding tokens are not kept.
+#[derive(Clone)]
+pub struct Subscriptions {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    settings: SubscriptionSettings,
+    delivery: WebhookSettings,
+    http: reqwest::Client,
+    // The runtime `start` ran on, for rest-hook deliveries and handshakes
+    runtime: Handle,
+    hub: Hub,
+    // The dispatcher's hub listener
+    listener: u64,
+    entries: Mutex<HashMap<Uuid, Entry>>,
+    // Held across each registry change and its save, so changes reach the
+    // file in order while `entries` stays free for publish and heartbeat
+    registry: tokio::sync::Mutex<()>,
+    tokens: Mutex<HashMap<String, Binding>>,
+    dispatcher: Mutex<Option<JoinHandle<()>>>,
+    heartbeat: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Subscriptions {
+    /// Load the saved subscriptions, start taking events from the hub and
+    /// sending heartbeats. rest-hook subscriptions still `requested` are
+    /// sent their handshake again. Call on the main runtime: rest-hook
+    /// deliveries and handshakes run there, so they outlive the HTTP workers
+    /// that create subscriptions.
+    pub fn start(
+        state: Arc<AppState>,
+        settings: &SubscriptionSettings,
+        delivery: &WebhookSettings,
+    ) -> std::io::Result<Self> {
+        let runtime = Handle::current();
+        let http = webhooks::client(delivery);
+        let mut entries = HashMap::new();
+        for saved in webhooks::load_registry::<Saved>(&settings.path)? {
+            let invalid = |e: AppError| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}", settings.path.display(), e),
+                )
+            };
+            let id = saved.resource.id.as_deref().and_then(|id| id.parse().ok());
+            let id = id.ok_or_else(|| {
+                invalid(AppError::Validation("Subscription without an id".into()))
+            })?;
+            let (resource, filter) = normalize(saved.resource).map_err(invalid)?;
+            let endpoint = (resource.channel_type.code == ChannelType::RestHook)
+                .then(|| Endpoint::start(&runtime, http.clone(), delivery, target(&resource)));
+            let entry = Entry {
+                resource,
+                filter,
+                base_url: saved.base_url,
+                created_at: saved.created_at,
+                events_since_start: 0,
+                last_sent_at: Utc::now(),
+                endpoint,
+            };
+            entries.insert(id, entry);
+        }
+        // Registered before returning, so no event after `start` is missed
+        let (listener, events) = state.ws_hub.add_listener();
+        let subscriptions = Self {
+            inner: Arc::new(Inner {
+                settings: settings.clone(),
+                delivery: delivery.clone(),
+                http,
+                runtime,
+                hub: state.ws_hub.clone(),
+                listener,
+                entries: Mutex::new(entries),
+                registry: tokio::sync::Mutex::new(()),
+                tokens: Mutex::new(HashMap::new()),
+                dispatcher: Mutex::new(None),
+                heartbeat: Mutex::new(None),
+            }),
+        };
+        let dispatcher = actix_rt::spawn(dispatch(subscriptions.clone(), events));
+        let heartbeat = actix_rt::spawn(heartbeats(subscriptions.clone()));
+        *subscriptions.inner.dispatcher.lock().unwrap() = Some(dispatcher);
+        *subscriptions.inner.heartbeat.lock().unwrap() = Some(heartbeat);
+        // Only those still `requested` are sent one
+        let ids: Vec<Uuid> = subscriptions
+            .inner
+            .entries
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for id in ids {
+            subscriptions.handshake(id, Utc::now());
+        }
+        Ok(subscriptions)
+    }
+
+    /// Store a new subscription. rest-hook ones start `requested` and turn
+    /// `active` once their endpoint accepts the handshake.
+    pub async fn create(
+        &self,
+        resource: Subscription,
+        base_url: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Subscription, AppError> {
+        let (mut resource, filter) = normalize(resource)?;
+        let id = Uuid::new_v4();
+        resource.id = Some(id.to_string());
+        resource.status = Some(initial_status(&resource));
+        let endpoint = (resource.channel_type.code == ChannelType::RestHook).then(|| {
+            Endpoint::start(
+                &self.inner.runtime,
+                self.inner.http.clone(),
+                &self.inner.delivery,
+                target(&resource),
+            )
+        });
+        let entry = Entry {
+            resource: resource.clone(),
+            filter,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            created_at: now,
+            events_since_start: 0,
+            last_sent_at: now,
+            endpoint,
+        };
+        {
+            let _registry = self.inner.registry.lock().await;
+            let mut saved = saved(&self.inner.entries.lock().unwrap());
+            saved.push(Saved {
+                resource: resource.clone(),
+                base_url: entry.base_url.clone(),
+                created_at: now,
+            });
+            self.save(saved).await?;
+            self.inner.entries.lock().unwrap().insert(id, entry);
+        }
+        self.handshake(id, now);
+        Ok(resource)
+    }
+
+    /// Oldest first.
+    pub fn list(&self) -> Vec<Subscription> {
+        let entries = self.inner.entries.lock().unwrap();
+        let mut list: Vec<&Entry> = entries.values().collect();
+        list.sort_by_key(|e| (e.created_at, e.resource.id.clone()));
+        list.into_iter().map(|e| redacted(&e.resource)).collect()
+    }
+
+    pub fn get(&self, id: Uuid) -> Result<Subscription, AppError> {
+        let entries = self.inner.entries.lock().unwrap();
+        entries
+            .get(&id)
+            .map(|e| redacted(&e.resource))
+            .ok_or(AppError::NotFound)
+    }
+
+    /// Replace a subscription, keeping its event count. A rest-hook one goes
+    /// back to `requested` and is sent a new handshake.
+    pub async fn update(
+        &self,
+        id: Uuid,
+        resource: Subscription,
+        now: DateTime<Utc>,
+    ) -> Result<Subscription, AppError> {
+        let (mut resource, filter) = normalize(resource)?;
+        {
+            let _registry = self.inner.registry.lock().await;
+            let saved = {
+                let entries = self.inner.entries.lock().unwrap();
+                let entry = entries.get(&id).ok_or(AppError::NotFound)?;
+                if entry.channel() != resource.channel_type.code {
+                    return Err(AppError::Validation(
+                        "channelType cannot change; create a new Subscription".into(),
+                    ));
+                }
+                resource.id = Some(id.to_string());
+                resource.status = Some(initial_status(&resource));
+                let (base_url, created_at) = (entry.base_url.clone(), entry.created_at);
+                let mut saved = saved(&entries);
+                saved.retain(|s| s.resource.id.as_deref() != Some(&id.to_string()));
+                saved.push(Saved {
+                    resource: resource.clone(),
+                    base_url,
+                    created_at,
+                });
+                saved
+            };
+            self.save(saved).await?;
+            let mut entries = self.inner.entries.lock().unwrap();
+            let entry = entries.get_mut(&id).ok_or(AppError::NotFound)?;
+            if let Some(endpoint) = &entry.endpoint {
+                let new = target(&resource);
+                endpoint.update_target(|t| *t = new);
+            }
+            entry.resource = resource.clone();
+            entry.filter = filter;
+            entry.last_sent_at = now;
+        }
+        self.handshake(id, now);
+        Ok(resource)
+    }
+
+    /// Remove a subscription; rest-hook notifications still queued are
+    /// abandoned and bound websockets hear nothing more.
+    pub async fn delete(&self, id: Uuid) -> Result<(), AppError> {
+        {
+            let _registry = self.inner.registry.lock().await;
+            let saved = {
+                let entries = self.inner.entries.lock().unwrap();
+                if !entries.contains_key(&id) {
+                    return Err(AppError::NotFound);
+                }
+                let mut saved = saved(&entries);
+                saved.retain(|s| s.resource.id.as_deref() != Some(&id.to_string()));
+                saved
+            };
+            self.save(saved).await?;
+            self.inner.entries.lock().unwrap().remove(&id);
+        }
+        self.inner
+            .tokens
+            .lock()
+            .unwrap()
+            .retain(|_, b| b.subscription != id);
+        Ok(())
+    }
+
+    /// The `$status` operation: a searchset Bundle with one SubscriptionStatus.
+    pub fn status(&self, id: Uuid, now: DateTime<Utc>) -> Result<serde_json::Value, AppError> {
+        let entries = self.inner.entries.lock().unwrap();
+        let entry = entries.get(&id).ok_or(AppError::NotFound)?;
+        Ok(json!({
+            "resourceType": "Bundle",
+            "type": "searchset",
+            "timestamp": now,
+            "total": 1,
+            "entry": [{ "resource": subscription_status(id, entry, NotificationType::QueryStatus) }],
+        }))
+    }
+
+    /// The `$get-ws-binding-token` operation, as a Parameters resource. The
+    /// token binds one websocket connection to the subscription.
+    pub fn binding_token(
+        &self,
+        id: Uuid,
+        websocket_url: &str,
+        now: DateTime<Utc>,
+    ) -> Result<serde_json::Value, AppError> {
+        {
+            let entries = self.inner.entries.lock().unwrap();
+            let entry = entries.get(&id).ok_or(AppError::NotFound)?;
+            if entry.channel() != ChannelType::Websocket {
+                return Err(AppError::Validation(
+                    "Subscription does not use the websocket channel".into(),
+                ));
+            }
+        }
+        let mut bytes = [0u8; 24];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ttl = chrono::Duration::seconds(self.inner.settings.binding_token_ttl_secs as i64);
+        let expires_at = now + ttl;
+        let mut tokens = self.inner.tokens.lock().unwrap();
+        tokens.retain(|_, b| b.expires_at > now);
+        tokens.insert(
+            token.clone(),
+            Binding {
+                subscription: id,
+                expires_at,
+            },
+        );
+        Ok(json!({
+            "resourceType": "Parameters",
+            "parameter": [
+                { "name": "token", "valueString": token },
+                { "name": "expiration", "valueDateTime": expires_at },
+                { "name": "subscription", "valueString": format!("Subscription/{}", id) },
+                { "name": "websocket-url", "valueUrl": websocket_url },
+            ],
+        }))
+    }
+
+    /// Redeem a binding token: hub channel client `client` joins the
+    /// subscription's channel and gets back the handshake to send. Tokens
+    /// work once.
+    pub fn bind(
+        &self,
+        token: &str,
+        client: u64,
+        now: DateTime<Utc>,
+    ) -> Result<serde_json::Value, AppError> {
+        let binding = self
+            .inner
+            .tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|b| b.expires_at > now)
+            .ok_or_else(|| AppError::Forbidden("binding token is unknown or expired".into()))?;
+        // Registered under the lock, so no event falls between handshake and binding
+        let entries = self.inner.entries.lock().unwrap();
+        let entry = entries
+            .get(&binding.subscription)
+            .ok_or(AppError::NotFound)?;
+        if !self
+            .inner
+            .hub
+            .join_channel(client, &binding.subscription.to_string())
+        {
+            return Err(AppError::NotFound);
+        }
+        let kind = NotificationType::Handshake;
+        let handshake = notification(Uuid::new_v4(), binding.subscription, entry, kind, None, now);
+        METRICS
+            .subscription_notifications_total
+            .with_label_values(&[
+                ChannelType::Websocket.as_str(),
+                NotificationType::Handshake.as_str(),
+            ])
+            .inc();
+        Ok(handshake)
+    }
+
+    /// Notify every active subscription whose filters match the observation.
+    pub fn publish(&self, obs: &StoredObservation, now: DateTime<Utc>) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        for (id, entry) in entries.iter_mut() {
+            if entry.status() != Status::Active
+                || !entry
+                    .filter
+                    .matches(&obs.reading.patient_id, obs.reading.code)
+            {
+                continue;
+            }
+            entry.events_since_start += 1;
+            self.notify(
+                *id,
+                entry,
+                NotificationType::EventNotification,
+                Some(obs),
+                now,
+            );
+        }
+    }
+
+    /// Send a heartbeat to active subscriptions that have been quiet for
+    /// their `heartbeatPeriod`.
+    pub fn heartbeat(&self, now: DateTime<Utc>) {
+        let mut entries = self.inner.entries.lock().unwrap();
+        for (id, entry) in entries.iter_mut() {
+            let Some(period) = entry.resource.heartbeat_period else {
+                continue;
+            };
+            let due = now - entry.last_sent_at >= chrono::Duration::seconds(period as i64);
+            if entry.status() == Status::Active && due {
+                self.notify(*id, entry, NotificationType::Heartbeat, None, now);
+            }
+        }
+    }
+
+    /// Stop taking events and let queued rest-hook notifications finish.
+    /// Call once the pipeline has drained, so what it broadcast meanwhile is
+    /// notified too.
+    pub async fn stop(&self) {
+        if let Some(heartbeat) = self.inner.heartbeat.lock().unwrap().take() {
+            heartbeat.abort();
+        }
+        // Ends the dispatcher once it has read what was already broadcast
+        self.inner.hub.remove_client(self.inner.listener);
+        let dispatcher = self.inner.dispatcher.lock().unwrap().take();
+        let workers = || {
+            let mut entries = self.inner.entries.lock().unwrap();
+            entries
+                .values_mut()
+                .filter_map(|e| e.endpoint.as_mut().and_then(|endpoint| endpoint.close()))
+                .collect()
+        };
+        if !webhooks::finish(dispatcher, workers).await {
+            tracing::warn!(
+                "subscription notifications did not finish in time; the rest are dropped"
+            );
+        }
+    }
+
+    // The rewrite fsyncs, so it runs on a blocking thread; callers hold
+    // `registry`, not `entries`
+    async fn save(&self, saved: Vec<Saved>) -> Result<(), AppError> {
+        let path = self.inner.settings.path.clone();
+        let written = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || webhooks::save_registry(&path, &saved))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+        };
+        written.map_err(|e| {
+            tracing::error!(path = %path.display(), "saving FHIR subscriptions failed: {}", e);
+            AppError::Internal
+        })
+    }
+
+    // A rest-hook subscription that is `requested` gets a handshake; its
+    // outcome decides between `active` and `error`
+    fn handshake(&self, id: Uuid, now: DateTime<Utc>) {
+        let result = {
+            let mut entries = self.inner.entries.lock().unwrap();
+            let Some(entry) = entries.get_mut(&id) else {
+                return;
+            };
+            if entry.status() != Status::Requested {
+                return;
+            }
+            self.notify(id, entry, NotificationType::Handshake, None, now)
+        };
+        let Some(result) = result else {
+            return;
+        };
+        let subscriptions = self.clone();
+        self.inner.runtime.spawn(async move {
+            let delivered = result.await == Ok(DeliveryStatus::Delivered);
+            let _registry = subscriptions.inner.registry.lock().await;
+            let saved = {
+                let mut entries = subscriptions.inner.entries.lock().unwrap();
+                let Some(entry) = entries
+                    .get_mut(&id)
+                    .filter(|e| e.status() == Status::Requested)
+                else {
+                    return;
+                };
+                entry.resource.status = Some(if delivered {
+                    Status::Active
+                } else {
+                    Status::Error
+                });
+                tracing::info!(subscription = %id, delivered, "subscription handshake finished");
+                saved(&entries)
+            };
+            // Already logged on failure; the status is right in memory
+            let _ = subscriptions.save(saved).await;
+        });
+    }
+
+    // rest-hook notifications return the delivery's outcome
+    fn notify(
+        &self,
+        id: Uuid,
+        entry: &mut Entry,
+        kind: NotificationType,
+        focus: Option<&StoredObservation>,
+        now: DateTime<Utc>,
+    ) -> Option<tokio::sync::oneshot::Receiver<DeliveryStatus>> {
+        entry.last_sent_at = now;
+        let bundle_id = Uuid::new_v4();
+        let bundle = notification(bundle_id, id, entry, kind, focus, now);
+        let labels = [entry.channel().as_str(), kind.as_str()];
+        match &entry.endpoint {
+            Some(endpoint) => {
+                METRICS
+                    .subscription_notifications_total
+                    .with_label_values(&labels)
+                    .inc();
+                let body = Bytes::from(serde_json::to_vec(&bundle).ok()?);
+                Some(endpoint.enqueue(bundle_id, kind.as_str(), body, now))
+            }
+            None => {
+                if self.inner.hub.send_to_channel(&id.to_string(), &bundle) > 0 {
+                    METRICS
+                        .subscription_notifications_total
+                        .with_label_values(&labels)
+                        .inc();
+                }
+                None
+            }
+        }
+    }
+}
+
+// What the registry file holds, oldest first
+fn saved(entries: &HashMap<Uuid, Entry>) -> Vec<Saved> {
+    let mut saved: Vec<&Entry> = entries.values().collect();
+    saved.sort_by_key(|e| (e.created_at, e.resource.id.clone()));
+    saved
+        .into_iter()
+        .map(|e| Saved {
+            resource: e.resource.clone(),
+            base_url: e.base_url.clone(),
+            created_at: e.created_at,
+        })
+        .collect()
+}
+
+fn initial_status(resource: &Subscription) -> Status {
+    match (resource.status, resource.channel_type.code) {
+        (Some(Status::Off), _) => Status::Off,
+        (_, ChannelType::RestHook) => Status::Requested,
+        (_, ChannelType::Websocket) => Status::Active,
+    }
+}
+
+fn redacted(resource: &Subscription) -> Subscription {
+    Subscription {
+        parameter: Vec::new(),
+        ..resource.clone()
+    }
+}
+
+fn target(resource: &Subscription) -> Target {
+    Target {
+        url: resource.endpoint.clone().unwrap_or_default(),
+        secret: None,
+        content_type: FHIR_JSON,
+        headers: resource
+            .parameter
+            .iter()
+            .map(|p| (p.name.clone(), p.value.clone()))
+            .collect(),
+    }
+}
+
+/// Check a Subscription and read its filters. `criteria` is folded into
+/// `topic` and `filterBy`.
+fn normalize(mut sub: Subscription) -> Result<(Subscription, LiveFilter), AppError> {
+    if sub.resource_type != "Subscription" {
+        return Err(AppError::Validation(
+            "resourceType must be Subscription".into(),
+        ));
+    }
+    if let Some(criteria) = &sub.criteria {
+        // Any base will do; it only makes the query parseable
+        let url = reqwest::Url::parse(&format!("http://localhost/{}", criteria))
+            .map_err(|e| AppError::Validation(format!("criteria: {}", e)))?;
+        if url.path() != "/Observation" {
+            return Err(AppError::Validation(
+                "criteria must be an Observation search".into(),
+            ));
+        }
+        for (name, value) in url.query_pairs() {
+            sub.filter_by.push(FilterBy {
+                resource_type: Some("Observation".into()),
+                filter_parameter: name.into_owned(),
+                value: value.into_owned(),
+            });
+        }
+    }
+    match sub.topic.as_deref() {
+        None if sub.criteria.is_some() => sub.topic = Some(OBSERVATION_TOPIC.to_string()),
+        None => return Err(AppError::Validation("topic or criteria is required".into())),
+        Some(OBSERVATION_TOPIC) => {}
+        Some(other) => return Err(AppError::Validation(format!("unknown topic '{}'", other))),
+    }
+
+    let mut filter = LiveFilter::default();
+    let mut seen = Vec::with_capacity(sub.filter_by.len());
+    for f in &sub.filter_by {
+        if f.resource_type
+            .as_deref()
+            .is_some_and(|r| r != "Observation")
+        {
+            return Err(AppError::Validation(
+                "filterBy.resourceType must be Observation".into(),
+            ));
+        }
+        match f.filter_parameter.as_str() {
+            "patient" => {
+                let patient = f.value.strip_prefix("Patient/").unwrap_or(&f.value);
+                if patient.is_empty() {
+                    return Err(AppError::Validation(
+                        "patient filter must name a patient".into(),
+                    ));
+                }
+                if filter
+                    .patient_id
+                    .replace(patient.to_string())
+                    .is_some_and(|p| p != patient)
+                {
+                    return Err(AppError::Validation("conflicting patient filters".into()));
+                }
+            }
+            "code" => {
+                let code = filter_code(&f.value)?;
+                if filter.code.replace(code).is_some_and(|c| c != code) {
+                    return Err(AppError::Validation("conflicting code filters".into()));
+                }
+            }
+            other => {
+                return Err(AppError::Validation(format!(
+                    "unsupported filter parameter '{}'; use patient or code",
+                    other
+                )))
+            }
+        }
+        // A filter given both in criteria and filterBy is kept once
+        if !seen.contains(f) {
+            seen.push(f.clone());
+        }
+    }
+    sub.filter_by = seen;
+
+    match sub.channel_type.code {
+        ChannelType::RestHook => {
+            let endpoint = sub.endpoint.as_deref().ok_or_else(|| {
+                AppError::Validation("rest-hook subscriptions need an endpoint".into())
+            })?;
+            let url = reqwest::Url::parse(endpoint)
+                .map_err(|e| AppError::Validation(format!("endpoint: {}", e)))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(AppError::Validation(
+                    "endpoint must be http or https".into(),
+                ));
+            }
+            for p in &sub.parameter {
+                let name_ok = reqwest::header::HeaderName::from_bytes(p.name.as_bytes()).is_ok();
+                if !name_ok || reqwest::header::HeaderValue::from_str(&p.value).is_err() {
+                    return Err(AppError::Validation(format!(
+                        "parameter '{}' is not a valid HTTP header",
+                        p.name
+                    )));
+                }
+            }
+        }
+        ChannelType::Websocket => {}
+    }
+    sub.channel_type
+        .system
+        .get_or_insert_with(|| CHANNEL_TYPE_SYSTEM.to_string());
+    match sub.content_type.as_deref() {
+        None => sub.content_type = Some(FHIR_JSON.to_string()),
+        Some(FHIR_JSON) => {}
+        Some(other) => {
+            return Err(AppError::Validation(format!(
+                "unsupported contentType '{}'",
+                other
+            )))
+        }
+    }
+    if sub.heartbeat_period == Some(0) {
+        return Err(AppError::Validation(
+            "heartbeatPeriod must be at least 1 second".into(),
+        ));
+    }
+    Ok((sub, filter))
+}
+
+// `8867-4`, `http://loinc.org|8867-4`, or one of our signal names
+fn filter_code(value: &str) -> Result<SignalCode, AppError> {
+    let code = match value.split_once('|') {
+        Some((system, code)) if system.is_empty() || system == fhir::LOINC => code,
+        Some(_) => {
+            return Err(AppError::Validation(format!(
+                "code filter '{}' is not a LOINC code",
+                value
+            )))
+        }
+        None => value,
+    };
+    fhir::signal_from_loinc(code)
+        .or_else(|| SignalCode::parse(code))
+        .ok_or_else(|| {
+            AppError::Validation(format!("code filter '{}' is not a supported signal", value))
+        })
+}
+
+fn subscription_status(id: Uuid, entry: &Entry, kind: NotificationType) -> serde_json::Value {
+    json!({
+        "resourceType": "SubscriptionStatus",
+        "status": entry.status().as_str(),
+        "type": kind.as_str(),
+        // integer64, which FHIR JSON writes as a string
+        "eventsSinceSubscriptionStart": entry.events_since_start.to_string(),
+        "subscription": { "reference": format!("{}/Subscription/{}", entry.base_url, id) },
+        "topic": OBSERVATION_TOPIC,
+    })
+}
+
+/// A `subscription-notification` Bundle: the SubscriptionStatus first, then
+/// the Observation itself for `full-resource` content.
+fn notification(
+    bundle_id: Uuid,
+    id: Uuid,
+    entry: &Entry,
+    kind: NotificationType,
+    focus: Option<&StoredObservation>,
+    now: DateTime<Utc>,
+) -> serde_json::Value {
+    let mut status = subscription_status(id, entry, kind);
+    let mut entries = Vec::with_capacity(2);
+    let mut resource = None;
+    if let Some(obs) = focus {
+        let full_url = format!("{}/Observation/{}", entry.base_url, obs.id);
+        let mut event =
+            json!({ "eventNumber": entry.events_since_start.to_string(), "timestamp": now });
+        if entry.resource.content != Content::Empty {
+            event["focus"] = json!({ "reference": full_url });
+        }
+        status["notificationEvent"] = json!([event]);
+        if entry.resource.content == Content::FullResource {
+            resource = fhir::to_fhir_observation(obs)
+                .ok()
+                .map(|r| json!({ "fullUrl": full_url, "resource": r }));
+        }
+    }
+    entries.push(json!({ "fullUrl": format!("urn:uuid:{}", Uuid::new_v4()), "resource": status }));
+    entries.extend(resource);
+    json!({
+        "resourceType": "Bundle",
+        "id": bundle_id,
+        "type": "subscription-notification",
+        "timestamp": now,
+        "entry": entries,
+    })
+}
+
+// Runs until `stop` removes the listener
+async fn dispatch(subscriptions: Subscriptions, mut events: LiveEvents) {
+    while let Some(event) = events.recv().await {
+        if let LiveEvent::Observation(obs) = &*event {
+            subscriptions.publish(obs, Utc::now());
+        }
+    }
+}
+
+async fn heartbeats(subscriptions: Subscriptions) {
+    let mut tick = tokio::time::interval(HEARTBEAT_TICK);
+    loop {
+        tick.tick().await;
+        subscriptions.heartbeat(Utc::now());
+    }
+}
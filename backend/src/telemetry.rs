@@ -1,13 +1,125 @@
-use tracing_subscriber::{fmt, EnvFilter};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
 
-pub fn init_tracing() {
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("info,actix_web=info"));
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
 
-    // Use the default human-readable formatter (no .json()).
-    fmt()
-        .with_env_filter(filter)
-        .with_target(true)
-        .with_level(true)
+/// Keeps the OTLP exporter alive; call `shutdown` before exit to flush spans.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("otel shutdown failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Logs go to stdout, human-readable by default or as JSON with
+/// `LOG_FORMAT=json`. Spans are also exported over OTLP/HTTP when
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set (e.g. http://collector:4318).
+pub fn init_tracing() -> Telemetry {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info,actix_web=info"));
+
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|v| v.eq_ignore_ascii_case("json"));
+    let fmt_layer = if json {
+        fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed()
+    } else {
+        fmt::layer().with_target(true).with_level(true).boxed()
+    };
+
+    let service =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "pulsesense-backend".to_string());
+    let provider = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .and_then(|endpoint| match otlp_tracer_provider(&endpoint, &service) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                eprintln!("OTLP export disabled: {}", e);
+                None
+            }
+        });
+    let otel_layer = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("pulsesense")));
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(otel_layer)
+        .with(filter)
         .init();
+
+    Telemetry { provider }
+}
+
+/// Tracer provider batching spans to `{endpoint}/v1/traces` over OTLP/HTTP.
+pub fn otlp_tracer_provider(endpoint: &str, service: &str) -> anyhow::Result<SdkTracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service.to_string())
+                .build(),
+        )
+        .build())
+}
+
+/// Request id for the current request, as accepted or generated by
+/// `request_id`.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Middleware that takes `X-Request-Id` from the client (or generates one),
+/// runs the request inside a span carrying it, and echoes it back.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = tracing::info_span!(
+        "http_request",
+        request_id = %id,
+        method = %req.method(),
+        path = %req.path(),
+    );
+    let mut res = next.call(req).instrument(span).await?;
+
+    if let Ok(v) = HeaderValue::from_str(&id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), v);
+    }
+    Ok(res)
 }
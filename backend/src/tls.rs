@@ -0,0 +1,141 @@
+use crate::errors::AppError;
+use crate::settings::TlsSettings;
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use anyhow::Context;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Identity taken from a verified client certificate: the subject CN plus
+/// DNS and URI subject alternative names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub names: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .collect();
+
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(n) | GeneralName::URI(n) => names.push(n.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        (!names.is_empty()).then_some(Self { names })
+    }
+
+    /// Whether this certificate may submit readings for `device_id`.
+    pub fn allows(&self, device_id: &str, tls: &TlsSettings) -> bool {
+        self.names
+            .iter()
+            .any(|name| match tls.identity_map.get(name) {
+                Some(devices) => devices.iter().any(|d| d == device_id),
+                None => name == device_id,
+            })
+    }
+}
+
+/// Enforce the device identity on ingest: with a client certificate the
+/// reading's `device_id` must map to it; without one, ingest is refused
+/// only when `tls.require_client_cert` is set.
+pub fn check_device_identity(
+    identity: Option<&ClientIdentity>,
+    device_id: &str,
+    tls: &TlsSettings,
+) -> Result<(), AppError> {
+    match identity {
+        Some(id) if id.allows(device_id, tls) => Ok(()),
+        Some(id) => Err(AppError::Forbidden(format!(
+            "client certificate {:?} may not submit readings for device '{}'",
+            id.names, device_id
+        ))),
+        None if tls.require_client_cert => Err(AppError::Unauthorized),
+        None => Ok(()),
+    }
+}
+
+/// `HttpServer::on_connect` hook: stores the peer certificate identity in
+/// the connection data so handlers can read it with `req.conn_data()`.
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = tls.get_ref();
+    if let Some(identity) = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| ClientIdentity::from_der(cert.as_ref()))
+    {
+        ext.insert(identity);
+    }
+}
+
+/// rustls server config from the cert/key files, verifying client
+/// certificates against `client_ca_path` when set. Clients without a
+/// certificate can still connect (e.g. the dashboard); ingest decides
+/// whether one is required.
+pub fn server_config(tls: &TlsSettings) -> anyhow::Result<ServerConfig> {
+    let cert_path = tls
+        .cert_path
+        .as_deref()
+        .context("tls.cert_path is not set")?;
+    let key_path = tls.key_path.as_deref().context("tls.key_path is not set")?;
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match tls.client_ca_path.as_deref() {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in load_certs(ca_path)? {
+                roots.add(ca)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    anyhow::ensure!(
+        !certs.is_empty(),
+        "no certificates found in {}",
+        path.display()
+    );
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .with_context(|| format!("no private key found in {}", path.display()))
+}
@@ -0,0 +1,685 @@
+use crate::domain::models::StoredObservation;
+use crate::fhir;
+use crate::metrics::METRICS;
+use crate::settings::UpstreamSettings;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+const JOURNAL: &str = "outbox.ndjson";
+const CURSOR: &str = "outbox.cursor";
+const DEAD_LETTER: &str = "dead-letter.ndjson";
+const FHIR_JSON: &str = "application/fhir+json";
+// How long `stop` waits for a request in flight
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+// Characters of an error response kept in the reason
+const MAX_REASON_BODY: usize = 500;
+// Delivered bytes at the front of the journal before it is rewritten without them
+const COMPACT_BYTES: u64 = 4 * 1024 * 1024;
+
+/// One observation waiting to be sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub enqueued_at: DateTime<Utc>,
+    pub observation: StoredObservation,
+}
+
+/// A line of `dead-letter.ndjson`: an observation the server would not take.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub failed_at: DateTime<Utc>,
+    pub reason: String,
+    #[serde(flatten)]
+    pub entry: OutboxEntry,
+}
+
+/// Observations the upstream server has not accepted yet, held in memory
+/// and in an NDJSON journal so they survive a restart. The journal is only
+/// appended to, with an fsync per batch of new lines. Delivered lines are not
+/// rewritten: `outbox.cursor` records where the pending ones start, and the
+/// journal is cut back once it empties or its delivered part grows large.
+/// Delivery is at least once: a batch sent just before a crash goes again
+/// after it, which the bundle's PUTs make harmless.
+pub struct Outbox {
+    dir: PathBuf,
+    capacity: usize,
+    inner: Mutex<OutboxInner>,
+    ready: Notify,
+}
+
+struct Pending {
+    entry: OutboxEntry,
+    // Journal offset just past the entry's line
+    end: u64,
+}
+
+struct OutboxInner {
+    pending: VecDeque<Pending>,
+    // Append handle on the journal; replaced whenever the journal is rewritten
+    journal: File,
+    // Bytes in the journal
+    len: u64,
+    dead_lettered: u64,
+}
+
+impl Outbox {
+    /// Open the outbox in `dir`, creating it if needed, and reload whatever
+    /// an earlier run left pending. A line that cannot be read (a write cut
+    /// short by a crash) is skipped.
+    pub fn open(dir: &Path, capacity: usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        // A missing or unreadable cursor means reading from the start, which
+        // at worst sends delivered observations again
+        let cursor: u64 = match std::fs::read_to_string(dir.join(CURSOR)) {
+            Ok(text) => text.trim().parse().unwrap_or(0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        match File::open(dir.join(JOURNAL)) {
+            Ok(mut file) => {
+                let cursor = if cursor > file.metadata()?.len() {
+                    0
+                } else {
+                    cursor
+                };
+                file.seek(SeekFrom::Start(cursor))?;
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => {
+                            tracing::warn!(dir = %dir.display(), "skipping unreadable outbox entry: {}", e)
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let dead_lettered = match File::open(dir.join(DEAD_LETTER)) {
+            Ok(file) => BufReader::new(file).lines().count() as u64,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        // Rewritten straight away so new entries never follow a torn line
+        let (journal, pending, len) = write_journal(dir, entries)?;
+        METRICS.upstream_outbox_depth.set(pending.len() as i64);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            capacity,
+            inner: Mutex::new(OutboxInner {
+                pending,
+                journal,
+                len,
+                dead_lettered,
+            }),
+            ready: Notify::new(),
+        })
+    }
+
+    /// Journal an observation for sending; see `push_all`.
+    pub fn push(&self, observation: StoredObservation, now: DateTime<Utc>) -> std::io::Result<()> {
+        self.push_all(vec![observation], now)
+    }
+
+    /// Journal observations for sending, with one fsync for all of them, so
+    /// call it from a blocking thread. Once `capacity` observations are
+    /// waiting, new ones are dead-lettered instead.
+    pub fn push_all(
+        &self,
+        observations: Vec<StoredObservation>,
+        now: DateTime<Utc>,
+    ) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let room = self.capacity.saturating_sub(inner.pending.len());
+        let mut entries = observations.into_iter().map(|observation| OutboxEntry {
+            enqueued_at: now,
+            observation,
+        });
+        let mut lines = Vec::new();
+        let mut added = Vec::new();
+        for entry in entries.by_ref().take(room) {
+            serde_json::to_writer(&mut lines, &entry)?;
+            lines.push(b'\n');
+            added.push(Pending {
+                entry,
+                end: inner.len + lines.len() as u64,
+            });
+        }
+        let overflow: Vec<OutboxEntry> = entries.collect();
+
+        if !added.is_empty() {
+            let written = inner
+                .journal
+                .write_all(&lines)
+                .and_then(|()| inner.journal.sync_data());
+            if let Err(e) = written {
+                // Cut off whatever part made it, so the next line starts clean
+                let _ = inner.journal.set_len(inner.len);
+                return Err(e);
+            }
+            inner.len += lines.len() as u64;
+            inner.pending.extend(added);
+            METRICS
+                .upstream_outbox_depth
+                .set(inner.pending.len() as i64);
+        }
+        if !overflow.is_empty() {
+            self.append_dead_letters(&mut inner, overflow, "outbox full", now)?;
+        }
+        drop(inner);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// When the oldest pending observation was journaled.
+    pub fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .front()
+            .map(|p| p.entry.enqueued_at)
+    }
+
+    /// Lines in the dead-letter file, including earlier runs.
+    pub fn dead_lettered(&self) -> u64 {
+        self.inner.lock().unwrap().dead_lettered
+    }
+
+    pub fn dead_letter_path(&self) -> PathBuf {
+        self.dir.join(DEAD_LETTER)
+    }
+
+    /// Size of the journal file, delivered lines included.
+    pub fn journal_len(&self) -> u64 {
+        self.inner.lock().unwrap().len
+    }
+
+    /// Up to `n` entries from the front, oldest first.
+    pub fn peek(&self, n: usize) -> Vec<OutboxEntry> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .pending
+            .iter()
+            .take(n)
+            .map(|p| p.entry.clone())
+            .collect()
+    }
+
+    /// Drop the first `n` entries, which the server has accepted.
+    pub fn complete(&self, n: usize) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        self.advance(&mut inner, n)
+    }
+
+    /// Move the first `n` entries to the dead-letter file.
+    pub fn dead_letter(&self, n: usize, reason: &str, now: DateTime<Utc>) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let entries: Vec<OutboxEntry> = inner
+            .pending
+            .iter()
+            .take(n)
+            .map(|p| p.entry.clone())
+            .collect();
+        self.append_dead_letters(&mut inner, entries, reason, now)?;
+        self.advance(&mut inner, n)
+    }
+
+    // Move the cursor past the first `n` entries. The cursor is always saved
+    // before the journal changes, so after a crash it can only lag behind
+    // (sending some observations twice), never skip pending ones.
+    fn advance(&self, inner: &mut OutboxInner, n: usize) -> std::io::Result<()> {
+        let n = n.min(inner.pending.len());
+        if n == 0 {
+            return Ok(());
+        }
+        let cursor = inner.pending[n - 1].end;
+        inner.pending.drain(..n);
+        METRICS
+            .upstream_outbox_depth
+            .set(inner.pending.len() as i64);
+        if inner.pending.is_empty() {
+            // Nothing left to keep: start the journal over
+            write_cursor(&self.dir, 0)?;
+            inner.journal.set_len(0)?;
+            inner.len = 0;
+        } else if cursor >= COMPACT_BYTES {
+            let entries: Vec<OutboxEntry> = inner.pending.drain(..).map(|p| p.entry).collect();
+            let (journal, pending, len) = write_journal(&self.dir, entries)?;
+            *inner = OutboxInner {
+                pending,
+                journal,
+                len,
+                dead_lettered: inner.dead_lettered,
+            };
+        } else {
+            write_cursor(&self.dir, cursor)?;
+        }
+        Ok(())
+    }
+
+    fn append_dead_letters(
+        &self,
+        inner: &mut OutboxInner,
+        entries: Vec<OutboxEntry>,
+        reason: &str,
+        now: DateTime<Utc>,
+    ) -> std::io::Result<()> {
+        let count = entries.len() as u64;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dead_letter_path())?;
+        let mut out = BufWriter::new(file);
+        for entry in entries {
+            let letter = DeadLetter {
+                failed_at: now,
+                reason: reason.to_string(),
+                entry,
+            };
+            serde_json::to_writer(&mut out, &letter)?;
+            out.write_all(b"\n")?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_data()?;
+        inner.dead_lettered += count;
+        METRICS
+            .upstream_observations_total
+            .with_label_values(&["dead_lettered"])
+            .inc_by(count);
+        Ok(())
+    }
+}
+
+// Write `entries` next to the journal and rename it into place, like the
+// store snapshot, resetting the cursor first; returns an append handle on
+// the new journal, the entries with their offsets, and its length
+fn write_journal(
+    dir: &Path,
+    entries: Vec<OutboxEntry>,
+) -> std::io::Result<(File, VecDeque<Pending>, u64)> {
+    let path = dir.join(JOURNAL);
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    let mut pending = VecDeque::with_capacity(entries.len());
+    let mut len = 0;
+    for entry in entries {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        out.write_all(&line)?;
+        len += line.len() as u64;
+        pending.push_back(Pending { entry, end: len });
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    // A crash between these two rereads the old journal from its start
+    write_cursor(dir, 0)?;
+    std::fs::rename(&tmp, &path)?;
+    let journal = OpenOptions::new().append(true).open(&path)?;
+    Ok((journal, pending, len))
+}
+
+fn write_cursor(dir: &Path, offset: u64) -> std::io::Result<()> {
+    let path = dir.join(CURSOR);
+    let tmp = path.with_extension("cursor.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(offset.to_string().as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, &path)
+}
+
+/// Body of `GET /upstream/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStatus {
+    pub base_url: String,
+    pub pending: usize,
+    pub oldest_pending_at: Option<DateTime<Utc>>,
+    /// How long the oldest pending observation has been waiting
+    pub lag_secs: f64,
+    /// Observations accepted by the server since startup
+    pub delivered: u64,
+    pub dead_lettered: u64,
+    pub last_delivery_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    /// Failed tries at the batch currently at the front
+    pub failed_attempts: u32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    delivered: u64,
+    last_delivery_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    last_error_at: Option<DateTime<Utc>>,
+    failed_attempts: u32,
+    next_attempt_at: Option<DateTime<Utc>>,
+}
+
+/// The outbox plus delivery progress, shared by the forwarder's tasks and
+/// the status endpoint.
+pub struct Upstream {
+    base_url: String,
+    outbox: Outbox,
+    progress: Mutex<Progress>,
+}
+
+impl Upstream {
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> UpstreamStatus {
+        let oldest = self.outbox.oldest();
+        let p = self.progress.lock().unwrap();
+        UpstreamStatus {
+            base_url: self.base_url.clone(),
+            pending: self.outbox.len(),
+            oldest_pending_at: oldest,
+            lag_secs: oldest.map_or(0.0, |t| (now - t).num_milliseconds().max(0) as f64 / 1000.0),
+            delivered: p.delivered,
+            dead_lettered: self.outbox.dead_lettered(),
+            last_delivery_at: p.last_delivery_at,
+            last_error: p.last_error.clone(),
+            last_error_at: p.last_error_at,
+            failed_attempts: p.failed_attempts,
+            next_attempt_at: p.next_attempt_at,
+        }
+    }
+
+    fn delivered(&self, count: usize, now: DateTime<Utc>) {
+        let mut p = self.progress.lock().unwrap();
+        p.delivered += count as u64;
+        p.last_delivery_at = Some(now);
+        p.failed_attempts = 0;
+        p.next_attempt_at = None;
+        METRICS
+            .upstream_observations_total
+            .with_label_values(&["delivered"])
+            .inc_by(count as u64);
+    }
+
+    fn failed(
+        &self,
+        reason: &str,
+        attempts: u32,
+        next_attempt_at: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) {
+        let mut p = self.progress.lock().unwrap();
+        p.last_error = Some(reason.to_string());
+        p.last_error_at = Some(now);
+        p.failed_attempts = attempts;
+        p.next_attempt_at = next_attempt_at;
+    }
+}
+
+// Why one request did not deliver its batch
+#[derive(Debug)]
+enum SendError {
+    // Connection trouble, timeout, 408, 429 or 5xx, retried until it goes
+    // through; also 401/403 (an expired or rotated token) and 3xx (a
+    // misconfigured `base_url`), which need an operator rather than a
+    // different bundle. `after` from Retry-After
+    Retry {
+        reason: String,
+        after: Option<Duration>,
+    },
+    // Any other 4xx: the server will refuse the same bundle again
+    Permanent(String),
+}
+
+struct FhirClient {
+    http: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl FhirClient {
+    fn new(settings: &UpstreamSettings) -> std::io::Result<Self> {
+        // Redirects are not followed: reqwest would resend the POST as a
+        // bodyless GET, and a 2xx to that would look like a delivery
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.request_timeout_secs))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(std::io::Error::other)?;
+        Ok(Self {
+            http,
+            url: settings.base_url.trim_end_matches('/').to_string(),
+            token: settings.bearer_token().map(str::to_string),
+        })
+    }
+
+    async fn send(&self, batch: &[OutboxEntry]) -> Result<(), SendError> {
+        let observations: Vec<StoredObservation> =
+            batch.iter().map(|e| e.observation.clone()).collect();
+        let bundle =
+            fhir::to_transaction(&observations).map_err(|e| SendError::Permanent(e.to_string()))?;
+        let body = serde_json::to_vec(&bundle).map_err(|e| SendError::Permanent(e.to_string()))?;
+        let mut request = self
+            .http
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, FHIR_JSON)
+            .header(reqwest::header::ACCEPT, FHIR_JSON)
+            .body(body);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                METRICS
+                    .upstream_requests_total
+                    .with_label_values(&["error"])
+                    .inc();
+                return Err(SendError::Retry {
+                    reason: e.to_string(),
+                    after: None,
+                });
+            }
+        };
+        let status = response.status();
+        METRICS
+            .upstream_requests_total
+            .with_label_values(&[status.as_str()])
+            .inc();
+        if status.is_success() {
+            return Ok(());
+        }
+        let after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs);
+        let text = response.text().await.unwrap_or_default();
+        let reason = format!(
+            "{}: {}",
+            status,
+            text.chars().take(MAX_REASON_BODY).collect::<String>()
+        );
+        let retry = status.is_server_error()
+            || status.is_redirection()
+            || matches!(status.as_u16(), 401 | 403 | 408 | 429);
+        if retry {
+            Err(SendError::Retry { reason, after })
+        } else {
+            Err(SendError::Permanent(reason))
+        }
+    }
+}
+
+/// Pushes every stored observation to an upstream FHIR server as
+/// transaction Bundles. The pipeline's persist stage journals observations
+/// in the outbox (see `Pipeline::start_with_upstream`); this sends them in
+/// order, a batch at a time.
+pub struct UpstreamForwarder {
+    upstream: Arc<Upstream>,
+    sender: JoinHandle<()>,
+    stop: watch::Sender<bool>,
+}
+
+impl UpstreamForwarder {
+    pub fn start(settings: &UpstreamSettings) -> std::io::Result<Self> {
+        let outbox = Outbox::open(&settings.outbox_dir, settings.outbox_capacity)?;
+        if !outbox.is_empty() {
+            tracing::info!(pending = outbox.len(), "resuming FHIR upstream outbox");
+        }
+        let upstream = Arc::new(Upstream {
+            base_url: settings.base_url.clone(),
+            outbox,
+            progress: Mutex::new(Progress::default()),
+        });
+        let client = FhirClient::new(settings)?;
+        let (stop, stopping) = watch::channel(false);
+        let sender = actix_rt::spawn(send_loop(
+            upstream.clone(),
+            client,
+            settings.clone(),
+            stopping,
+        ));
+        Ok(Self {
+            upstream,
+            sender,
+            stop,
+        })
+    }
+
+    pub fn upstream(&self) -> Arc<Upstream> {
+        self.upstream.clone()
+    }
+
+    /// Call once the pipeline has drained, so every stored observation is
+    /// journaled; a request in flight gets a moment to finish, and whatever
+    /// is still pending goes out after the next start.
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+        let abort = self.sender.abort_handle();
+        if tokio::time::timeout(STOP_TIMEOUT, self.sender)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "FHIR upstream request did not finish in time; it is resent after restart"
+            );
+            abort.abort();
+        }
+    }
+}
+
+async fn send_loop(
+    upstream: Arc<Upstream>,
+    client: FhirClient,
+    settings: UpstreamSettings,
+    mut stopping: watch::Receiver<bool>,
+) {
+    let initial = Duration::from_millis(settings.retry_initial_ms);
+    let max = Duration::from_millis(settings.retry_max_ms);
+    let mut backoff = initial;
+    let mut attempts = 0;
+    // After a batch is refused outright its observations go one per request,
+    // so only the ones the server objects to are dead-lettered
+    let mut isolate = 0;
+
+    while !*stopping.borrow() {
+        let n = if isolate > 0 { 1 } else { settings.batch_size };
+        let batch = match on_outbox(&upstream, move |outbox| Ok(outbox.peek(n))).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                tracing::error!("reading the FHIR upstream outbox failed: {}", e);
+                Vec::new()
+            }
+        };
+        if batch.is_empty() {
+            tokio::select! {
+                _ = upstream.outbox.ready.notified() => {}
+                _ = stopping.changed() => {}
+            }
+            continue;
+        }
+
+        let result = client.send(&batch).await;
+        let now = Utc::now();
+        let count = batch.len();
+        let outcome = match result {
+            Ok(()) => {
+                upstream.delivered(count, now);
+                on_outbox(&upstream, move |outbox| outbox.complete(count)).await
+            }
+            Err(SendError::Permanent(reason)) if batch.len() > 1 => {
+                tracing::warn!(
+                    batch = batch.len(),
+                    "FHIR upstream refused a batch, retrying one by one: {}",
+                    reason
+                );
+                upstream.failed(&reason, 0, None, now);
+                isolate = batch.len();
+                continue;
+            }
+            Err(SendError::Permanent(reason)) => {
+                let id = batch[0].observation.id;
+                tracing::error!(observation_id = %id, "FHIR upstream refused observation, dead-lettered: {}", reason);
+                upstream.failed(&reason, 0, None, now);
+                on_outbox(&upstream, move |outbox| outbox.dead_letter(1, &reason, now)).await
+            }
+            // The server may come back at any time, so these are never
+            // dead-lettered; the outbox holds them meanwhile
+            Err(SendError::Retry { reason, after }) => {
+                attempts += 1;
+                let delay = after.unwrap_or(backoff).min(max);
+                tracing::warn!(
+                    attempt = attempts,
+                    batch = count,
+                    "FHIR upstream send failed: {}",
+                    reason
+                );
+                let next = now + chrono::Duration::from_std(delay).unwrap_or_default();
+                upstream.failed(&reason, attempts, Some(next), now);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = stopping.changed() => {}
+                }
+                backoff = (backoff * 2).min(max);
+                continue;
+            }
+        };
+        if let Err(e) = outcome {
+            tracing::error!("updating the FHIR upstream outbox failed: {}", e);
+        }
+        attempts = 0;
+        backoff = initial;
+        isolate = isolate.saturating_sub(count);
+    }
+}
+
+// Outbox calls lock it and may fsync, so they run on a blocking thread
+// rather than stall the runtime the forwarder shares with the pipeline
+async fn on_outbox<T: Send + 'static>(
+    upstream: &Arc<Upstream>,
+    f: impl FnOnce(&Outbox) -> std::io::Result<T> + Send + 'static,
+) -> std::io::Result<T> {
+    let upstream = upstream.clone();
+    tokio::task::spawn_blocking(move || f(&upstream.outbox))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
@@ -0,0 +1,827 @@
+use crate::domain::models::SignalCode;
+use crate::domain::store::AppState;
+use crate::errors::AppError;
+use crate::fhir;
+use crate::metrics::METRICS;
+use crate::settings::WebhookSettings;
+use crate::ws::{Hub, LiveEvent, LiveEvents, LiveFilter};
+use actix_web::web;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+pub const SIGNATURE_HEADER: &str = "X-PulseSense-Signature";
+pub const EVENT_HEADER: &str = "X-PulseSense-Event";
+pub const DELIVERY_HEADER: &str = "X-PulseSense-Delivery";
+// How long `stop` waits for queued deliveries
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+// Characters of an error response kept in the delivery log
+const MAX_ERROR_BODY: usize = 200;
+
+/// What a subscription can listen to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventType {
+    Observation,
+    Alert,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Observation => "observation",
+            EventType::Alert => "alert",
+        }
+    }
+}
+
+/// Body of `POST /webhooks` and `PUT /webhooks/{id}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionRequest {
+    pub url: String,
+    pub events: Vec<EventType>,
+    #[serde(default)]
+    pub patient: Option<String>,
+    #[serde(default)]
+    pub code: Option<SignalCode>,
+    /// HMAC key; generated on create and kept on update when omitted
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "active_by_default")]
+    pub active: bool,
+}
+
+fn active_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: Uuid,
+    pub url: String,
+    pub events: Vec<EventType>,
+    pub patient: Option<String>,
+    pub code: Option<SignalCode>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Only shown in the response that sets it (and kept in the registry file)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl Subscription {
+    fn wants(&self, event_type: EventType, patient_id: &str, code: SignalCode) -> bool {
+        let filter = LiveFilter {
+            patient_id: self.patient.clone(),
+            code: self.code,
+        };
+        self.active && self.events.contains(&event_type) && filter.matches(patient_id, code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Out of attempts, or refused with a 3xx or a 4xx other than 408/429
+    Failed,
+    /// The subscription's queue was full
+    Dropped,
+}
+
+/// One entry of a subscription's delivery log.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    /// Also sent as `X-PulseSense-Delivery`, the same on every attempt
+    pub id: Uuid,
+    pub event_id: Uuid,
+    /// `observation` or `alert`; FHIR subscriptions use their notification type
+    pub event_type: String,
+    pub created_at: DateTime<Utc>,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub response_status: Option<u16>,
+    pub error: Option<String>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+/// The JSON body POSTed to subscribers.
+#[derive(Debug, Serialize)]
+pub struct WebhookEvent {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    pub created_at: DateTime<Utc>,
+    /// A FHIR Observation, or the alert as on `/ws/live`
+    pub data: serde_json::Value,
+}
+
+/// `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`. Receivers
+/// recompute it with their secret and reject stale timestamps.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let mut ctx = ring::hmac::Context::with_key(&key);
+    ctx.update(timestamp.to_string().as_bytes());
+    ctx.update(b".");
+    ctx.update(body);
+    format!("t={},v1={}", timestamp, hex(ctx.sign().as_ref()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("whsec_{}", hex(&bytes))
+}
+
+struct Job {
+    delivery_id: Uuid,
+    event_type: String,
+    body: Bytes,
+    // Told the final status, if anyone is waiting for it
+    done: oneshot::Sender<DeliveryStatus>,
+}
+
+/// Where an endpoint delivers to. Read on every attempt, so a change applies
+/// to deliveries already queued.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub url: String,
+    /// Requests are signed with `X-PulseSense-Signature` when set
+    pub secret: Option<String>,
+    pub content_type: &'static str,
+    /// Sent with every request, e.g. the receiver's own authorization
+    pub headers: Vec<(String, String)>,
+}
+
+// Shared between an endpoint and its delivery task
+struct Shared {
+    target: Mutex<Target>,
+    log: Mutex<VecDeque<Delivery>>,
+    log_size: usize,
+}
+
+impl Shared {
+    fn record(&self, delivery: Delivery) {
+        let mut log = self.log.lock().unwrap();
+        log.push_back(delivery);
+        while log.len() > self.log_size {
+            log.pop_front();
+        }
+    }
+
+    fn update(&self, id: Uuid, f: impl FnOnce(&mut Delivery)) {
+        let mut log = self.log.lock().unwrap();
+        if let Some(delivery) = log.iter_mut().rev().find(|d| d.id == id) {
+            f(delivery);
+        }
+    }
+}
+
+/// HTTP client with the configured request timeout, for `Endpoint::start`.
+/// Redirects are not followed: subscriber URLs are whatever an admin
+/// entered, and a redirect could point deliveries anywhere.
+pub fn client(settings: &WebhookSettings) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(settings.request_timeout_secs))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap_or_default()
+}
+
+/// One receiver's bounded queue, delivery task and delivery log. Deliveries
+/// go out in order, each retried with backoff; dropping the endpoint
+/// abandons whatever is still queued. The task runs on `runtime`, which
+/// should be the main one: an HTTP worker's runtime stops with the server,
+/// before the queue has been delivered.
+pub struct Endpoint {
+    shared: Arc<Shared>,
+    // None once closed
+    queue: Option<mpsc::Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Endpoint {
+    pub fn start(
+        runtime: &Handle,
+        http: reqwest::Client,
+        settings: &WebhookSettings,
+        target: Target,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            target: Mutex::new(target),
+            log: Mutex::new(VecDeque::new()),
+            log_size: settings.log_size,
+        });
+        let (queue, rx) = mpsc::channel(settings.queue_capacity);
+        let worker = runtime.spawn(deliver_all(shared.clone(), rx, http, settings.clone()));
+        Self {
+            shared,
+            queue: Some(queue),
+            worker: Some(worker),
+        }
+    }
+
+    pub fn update_target(&self, f: impl FnOnce(&mut Target)) {
+        f(&mut self.shared.target.lock().unwrap());
+    }
+
+    pub fn target(&self) -> Target {
+        self.shared.target.lock().unwrap().clone()
+    }
+
+    /// Queue a body for delivery. The receiver resolves to the final status:
+    /// delivered, failed, or dropped when the queue is full or closed.
+    pub fn enqueue(
+        &self,
+        event_id: Uuid,
+        event_type: &str,
+        body: Bytes,
+        now: DateTime<Utc>,
+    ) -> oneshot::Receiver<DeliveryStatus> {
+        let (done, result) = oneshot::channel();
+        let delivery = Delivery {
+            id: Uuid::new_v4(),
+            event_id,
+            event_type: event_type.to_string(),
+            created_at: now,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            response_status: None,
+            error: None,
+            last_attempt_at: None,
+            next_attempt_at: None,
+        };
+        let job = Job {
+            delivery_id: delivery.id,
+            event_type: event_type.to_string(),
+            body,
+            done,
+        };
+        // Logged first, so the delivery task always finds the entry to update
+        self.shared.record(delivery);
+        let sent = match &self.queue {
+            Some(queue) => queue.try_send(job).map_err(|e| e.into_inner()),
+            None => Err(job),
+        };
+        if let Err(job) = sent {
+            self.shared.update(job.delivery_id, |d| {
+                d.status = DeliveryStatus::Dropped;
+                d.error = Some("delivery queue full".to_string());
+            });
+            METRICS
+                .webhook_deliveries_total
+                .with_label_values(&["dropped"])
+                .inc();
+            let _ = job.done.send(DeliveryStatus::Dropped);
+        }
+        result
+    }
+
+    /// The delivery log, newest first.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.shared
+            .log
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Stop taking deliveries; the returned task ends once the queued ones are done.
+    pub fn close(&mut self) -> Option<JoinHandle<()>> {
+        self.queue = None;
+        self.worker.take()
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.abort();
+        }
+    }
+}
+
+struct Registered {
+    subscription: Subscription,
+    endpoint: Endpoint,
+}
+
+/// Webhook subscriptions and their deliveries. Events come from the
+/// WebSocket hub; each subscription has its own queue and delivery task, so
+/// a slow or failing endpoint only delays its own deliveries. Subscriptions
+/// and their secrets are saved to `settings.path` on every change and
+/// reloaded on start; delivery logs and queued deliveries are not.
+#[derive(Clone)]
+pub struct Webhooks {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    settings: WebhookSettings,
+    http: reqwest::Client,
+    // The runtime `start` ran on, for delivery tasks of subscriptions created later
+    runtime: Handle,
+    hub: Hub,
+    // The dispatcher's hub listener
+    listener: u64,
+    subscriptions: Mutex<HashMap<Uuid, Registered>>,
+    // Held across each registry change and its save, so changes reach the
+    // file in order while `subscriptions` stays free for the dispatcher
+    registry: tokio::sync::Mutex<()>,
+    dispatcher: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Webhooks {
+    /// Load the saved subscriptions and start taking events from the hub.
+    /// Call on the main runtime: delivery tasks run there, including those
+    /// of subscriptions created through the API, so they outlive the HTTP
+    /// workers and can finish in `stop`.
+    pub fn start(state: Arc<AppState>, settings: &WebhookSettings) -> std::io::Result<Self> {
+        let runtime = Handle::current();
+        let http = client(settings);
+        let mut subscriptions = HashMap::new();
+        for mut subscription in load_registry::<Subscription>(&settings.path)? {
+            let target = Target {
+                url: subscription.url.clone(),
+                secret: subscription.secret.take(),
+                content_type: "application/json",
+                headers: Vec::new(),
+            };
+            let endpoint = Endpoint::start(&runtime, http.clone(), settings, target);
+            subscriptions.insert(
+                subscription.id,
+                Registered {
+                    subscription,
+                    endpoint,
+                },
+            );
+        }
+        // Registered before returning, so no event after `start` is missed
+        let (listener, events) = state.ws_hub.add_listener();
+        let webhooks = Self {
+            inner: Arc::new(Inner {
+                settings: settings.clone(),
+                http,
+                runtime,
+                hub: state.ws_hub.clone(),
+                listener,
+                subscriptions: Mutex::new(subscriptions),
+                registry: tokio::sync::Mutex::new(()),
+                dispatcher: Mutex::new(None),
+            }),
+        };
+        let task = actix_rt::spawn(dispatch(webhooks.clone(), events));
+        *webhooks.inner.dispatcher.lock().unwrap() = Some(task);
+        Ok(webhooks)
+    }
+
+    pub async fn create(
+        &self,
+        req: SubscriptionRequest,
+        now: DateTime<Utc>,
+    ) -> Result<Subscription, AppError> {
+        validate(&req)?;
+        let secret = req.secret.clone().unwrap_or_else(generate_secret);
+        let subscription = Subscription {
+            id: Uuid::new_v4(),
+            url: req.url,
+            events: dedup(req.events),
+            patient: req.patient,
+            code: req.code,
+            active: req.active,
+            created_at: now,
+            updated_at: now,
+            secret: None,
+        };
+        let target = Target {
+            url: subscription.url.clone(),
+            secret: Some(secret.clone()),
+            content_type: "application/json",
+            headers: Vec::new(),
+        };
+        let _registry = self.inner.registry.lock().await;
+        let mut saved = self.saved(&self.inner.subscriptions.lock().unwrap());
+        saved.push(Subscription {
+            secret: Some(secret.clone()),
+            ..subscription.clone()
+        });
+        self.save(saved).await?;
+        let endpoint = Endpoint::start(
+            &self.inner.runtime,
+            self.inner.http.clone(),
+            &self.inner.settings,
+            target,
+        );
+        self.inner.subscriptions.lock().unwrap().insert(
+            subscription.id,
+            Registered {
+                subscription: subscription.clone(),
+                endpoint,
+            },
+        );
+        Ok(Subscription {
+            secret: Some(secret),
+            ..subscription
+        })
+    }
+
+    /// Oldest first.
+    pub fn list(&self) -> Vec<Subscription> {
+        let subs = self.inner.subscriptions.lock().unwrap();
+        let mut list: Vec<Subscription> = subs.values().map(|r| r.subscription.clone()).collect();
+        list.sort_by_key(|s| (s.created_at, s.id));
+        list
+    }
+
+    pub fn get(&self, id: Uuid) -> Result<Subscription, AppError> {
+        let subs = self.inner.subscriptions.lock().unwrap();
+        subs.get(&id)
+            .map(|r| r.subscription.clone())
+            .ok_or(AppError::NotFound)
+    }
+
+    /// Replace a subscription's settings; queued deliveries go to the new URL.
+    pub async fn update(
+        &self,
+        id: Uuid,
+        req: SubscriptionRequest,
+        now: DateTime<Utc>,
+    ) -> Result<Subscription, AppError> {
+        validate(&req)?;
+        let _registry = self.inner.registry.lock().await;
+        let shown = req.secret.clone();
+        let (updated, saved) = {
+            let subs = self.inner.subscriptions.lock().unwrap();
+            let registered = subs.get(&id).ok_or(AppError::NotFound)?;
+            let updated = Subscription {
+                url: req.url,
+                events: dedup(req.events),
+                patient: req.patient,
+                code: req.code,
+                active: req.active,
+                updated_at: now,
+                secret: req.secret.or(registered.endpoint.target().secret),
+                ..registered.subscription.clone()
+            };
+            let mut saved = self.saved(&subs);
+            saved.retain(|s| s.id != id);
+            saved.push(updated.clone());
+            (updated, saved)
+        };
+        self.save(saved).await?;
+
+        let mut subs = self.inner.subscriptions.lock().unwrap();
+        let registered = subs.get_mut(&id).ok_or(AppError::NotFound)?;
+        registered.endpoint.update_target(|target| {
+            target.url = updated.url.clone();
+            target.secret = updated.secret.clone();
+        });
+        registered.subscription = Subscription {
+            secret: None,
+            ..updated.clone()
+        };
+        Ok(Subscription {
+            secret: shown,
+            ..updated
+        })
+    }
+
+    /// Remove a subscription; deliveries still queued are abandoned.
+    pub async fn delete(&self, id: Uuid) -> Result<(), AppError> {
+        let _registry = self.inner.registry.lock().await;
+        let saved = {
+            let subs = self.inner.subscriptions.lock().unwrap();
+            if !subs.contains_key(&id) {
+                return Err(AppError::NotFound);
+            }
+            let mut saved = self.saved(&subs);
+            saved.retain(|s| s.id != id);
+            saved
+        };
+        self.save(saved).await?;
+        self.inner.subscriptions.lock().unwrap().remove(&id);
+        Ok(())
+    }
+
+    // What the registry file holds: every subscription with its secret
+    fn saved(&self, subs: &HashMap<Uuid, Registered>) -> Vec<Subscription> {
+        let mut saved: Vec<Subscription> = subs
+            .values()
+            .map(|r| Subscription {
+                secret: r.endpoint.target().secret,
+                ..r.subscription.clone()
+            })
+            .collect();
+        saved.sort_by_key(|s| (s.created_at, s.id));
+        saved
+    }
+
+    // The rewrite fsyncs, so it runs off the worker; callers hold
+    // `registry`, not `subscriptions`
+    async fn save(&self, saved: Vec<Subscription>) -> Result<(), AppError> {
+        let path = self.inner.settings.path.clone();
+        let written = {
+            let path = path.clone();
+            web::block(move || save_registry(&path, &saved)).await
+        };
+        written
+            .map_err(std::io::Error::other)
+            .and_then(|r| r)
+            .map_err(|e| {
+                tracing::error!(path = %path.display(), "saving webhook subscriptions failed: {}", e);
+                AppError::Internal
+            })
+    }
+
+    /// The subscription's delivery log, newest first.
+    pub fn deliveries(&self, id: Uuid) -> Result<Vec<Delivery>, AppError> {
+        let subs = self.inner.subscriptions.lock().unwrap();
+        subs.get(&id)
+            .map(|r| r.endpoint.deliveries())
+            .ok_or(AppError::NotFound)
+    }
+
+    /// Queue an event for every subscription that wants it.
+    pub fn publish(&self, event: &LiveEvent, now: DateTime<Utc>) {
+        let (event_type, patient_id, code, data) = match event {
+            LiveEvent::Observation(obs) => {
+                let Ok(resource) = fhir::to_fhir_observation(obs) else {
+                    return;
+                };
+                let data = serde_json::to_value(resource).unwrap_or_default();
+                (
+                    EventType::Observation,
+                    obs.reading.patient_id.as_str(),
+                    obs.reading.code,
+                    data,
+                )
+            }
+            LiveEvent::Alert(alert) => {
+                let data = serde_json::to_value(alert).unwrap_or_default();
+                (
+                    EventType::Alert,
+                    alert.patient_id.as_str(),
+                    alert.code,
+                    data,
+                )
+            }
+        };
+        let event = WebhookEvent {
+            id: Uuid::new_v4(),
+            event_type,
+            created_at: now,
+            data,
+        };
+        let Ok(body) = serde_json::to_vec(&event) else {
+            return;
+        };
+        let body = Bytes::from(body);
+
+        let subs = self.inner.subscriptions.lock().unwrap();
+        for registered in subs.values() {
+            if registered.subscription.wants(event_type, patient_id, code) {
+                registered
+                    .endpoint
+                    .enqueue(event.id, event_type.as_str(), body.clone(), now);
+            }
+        }
+    }
+
+    /// Stop taking events and let queued deliveries finish. Call once the
+    /// pipeline has drained, so what it broadcast meanwhile is delivered too.
+    pub async fn stop(&self) {
+        // Ends the dispatcher once it has read what was already broadcast
+        self.inner.hub.remove_client(self.inner.listener);
+        let dispatcher = self.inner.dispatcher.lock().unwrap().take();
+        let workers = || {
+            let mut subs = self.inner.subscriptions.lock().unwrap();
+            subs.values_mut()
+                .filter_map(|r| r.endpoint.close())
+                .collect()
+        };
+        if !finish(dispatcher, workers).await {
+            tracing::warn!("webhook deliveries did not finish in time; the rest are dropped");
+        }
+    }
+}
+
+fn validate(req: &SubscriptionRequest) -> Result<(), AppError> {
+    let url =
+        reqwest::Url::parse(&req.url).map_err(|e| AppError::Validation(format!("url: {}", e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::Validation("url must be http or https".into()));
+    }
+    if req.events.is_empty() {
+        return Err(AppError::Validation(
+            "events must name at least one of observation, alert".into(),
+        ));
+    }
+    if req.secret.as_deref().is_some_and(|s| s.trim().is_empty()) {
+        return Err(AppError::Validation("secret must not be empty".into()));
+    }
+    Ok(())
+}
+
+/// Wait up to `STOP_TIMEOUT` for a dispatcher to end, then for the delivery
+/// tasks `close` returns once it has; whatever is left is aborted. False if
+/// anything was.
+pub(crate) async fn finish(
+    dispatcher: Option<JoinHandle<()>>,
+    close: impl FnOnce() -> Vec<JoinHandle<()>>,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + STOP_TIMEOUT;
+    let mut finished = true;
+    if let Some(dispatcher) = dispatcher {
+        let abort = dispatcher.abort_handle();
+        if tokio::time::timeout_at(deadline, dispatcher).await.is_err() {
+            abort.abort();
+            finished = false;
+        }
+    }
+    let workers = close();
+    let aborts: Vec<_> = workers.iter().map(|w| w.abort_handle()).collect();
+    let all = async {
+        for worker in workers {
+            let _ = worker.await;
+        }
+    };
+    if tokio::time::timeout_at(deadline, all).await.is_err() {
+        aborts.iter().for_each(|a| a.abort());
+        finished = false;
+    }
+    finished
+}
+
+/// Read a registry file written by [`save_registry`]; a missing file is an
+/// empty registry.
+pub(crate) fn load_registry<T: DeserializeOwned>(path: &Path) -> std::io::Result<Vec<T>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&text).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Write `items` to `path` as a JSON array, next to it first and renamed
+/// into place like the store snapshot. The file holds delivery secrets, so
+/// on Unix only the owner can read it.
+pub(crate) fn save_registry<T: Serialize>(path: &Path, items: &[T]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut out = std::io::BufWriter::new(options.open(&tmp)?);
+    serde_json::to_writer_pretty(&mut out, items)?;
+    out.write_all(b"\n")?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+fn dedup(mut events: Vec<EventType>) -> Vec<EventType> {
+    let mut seen = Vec::with_capacity(events.len());
+    events.retain(|e| {
+        let new = !seen.contains(e);
+        seen.push(*e);
+        new
+    });
+    events
+}
+
+// Runs until `stop` removes the listener
+async fn dispatch(webhooks: Webhooks, mut events: LiveEvents) {
+    while let Some(event) = events.recv().await {
+        webhooks.publish(&event, Utc::now());
+    }
+}
+
+// One subscription's deliveries, in order, each retried with backoff
+async fn deliver_all(
+    shared: Arc<Shared>,
+    mut rx: mpsc::Receiver<Job>,
+    http: reqwest::Client,
+    settings: WebhookSettings,
+) {
+    while let Some(job) = rx.recv().await {
+        let mut backoff = Duration::from_millis(settings.retry_initial_ms);
+        for attempt in 1..=settings.max_attempts {
+            let target = shared.target.lock().unwrap().clone();
+            let now = Utc::now();
+            let result = send(&http, &target, &job, now).await;
+            let done = result.is_ok() || attempt == settings.max_attempts || is_permanent(&result);
+            let next_attempt_at =
+                (!done).then(|| now + chrono::Duration::from_std(backoff).unwrap_or_default());
+            shared.update(job.delivery_id, |d| {
+                d.attempts = attempt;
+                d.last_attempt_at = Some(now);
+                d.next_attempt_at = next_attempt_at;
+                match &result {
+                    Ok(status) => {
+                        d.status = DeliveryStatus::Delivered;
+                        d.response_status = Some(*status);
+                        d.error = None;
+                    }
+                    Err((status, error)) => {
+                        d.response_status = *status;
+                        d.error = Some(error.clone());
+                        if done {
+                            d.status = DeliveryStatus::Failed;
+                        }
+                    }
+                }
+            });
+            if done {
+                let (label, status) = match result {
+                    Ok(_) => ("delivered", DeliveryStatus::Delivered),
+                    Err(_) => ("failed", DeliveryStatus::Failed),
+                };
+                METRICS
+                    .webhook_deliveries_total
+                    .with_label_values(&[label])
+                    .inc();
+                let _ = job.done.send(status);
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_millis(settings.retry_max_ms));
+        }
+    }
+}
+
+// A 4xx other than 408/429 would only be refused again, as for the FHIR
+// upstream; so is a 3xx, since redirects are not followed
+fn is_permanent(result: &Result<u16, (Option<u16>, String)>) -> bool {
+    matches!(result, Err((Some(status), _)) if (300..500).contains(status) && !matches!(status, 408 | 429))
+}
+
+// Any 2xx delivers
+async fn send(
+    http: &reqwest::Client,
+    target: &Target,
+    job: &Job,
+    now: DateTime<Utc>,
+) -> Result<u16, (Option<u16>, String)> {
+    let mut request = http
+        .post(&target.url)
+        .header(reqwest::header::CONTENT_TYPE, target.content_type)
+        .header(EVENT_HEADER, job.event_type.as_str())
+        .header(DELIVERY_HEADER, job.delivery_id.to_string());
+    if let Some(secret) = &target.secret {
+        request = request.header(
+            SIGNATURE_HEADER,
+            signature(secret, now.timestamp(), &job.body),
+        );
+    }
+    for (name, value) in &target.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let response = request
+        .body(job.body.clone())
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(status.as_u16());
+    }
+    let text = response.text().await.unwrap_or_default();
+    Err((
+        Some(status.as_u16()),
+        format!(
+            "{}: {}",
+            status,
+            text.chars().take(MAX_ERROR_BODY).collect::<String>()
+        ),
+    ))
+}
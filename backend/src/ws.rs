@@ -1,49 +1,310 @@
+use crate::domain::alerts::Alert;
+use crate::domain::models::{SignalCode, StoredObservation};
+use crate::metrics::METRICS;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use std::sync::{Arc, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// Typed events held for in-process consumers; one that falls further
+/// behind than this skips what it missed (see `LiveEvents::recv`).
+pub const LIVE_EVENTS_CAPACITY: usize = 4096;
+
+/// What the hub pushes to each connected client.
+#[derive(Debug, Clone)]
+pub enum HubEvent {
+    /// A JSON message to forward as-is
+    Text(String),
+    /// The server is going away; close with this reason and let the client
+    /// reconnect after `reconnect_after_secs`
+    Shutdown {
+        reason: String,
+        reconnect_after_secs: u64,
+    },
+}
+
+/// A stored reading or a raised alert, as published to consumers that are
+/// not WebSocket clients.
+#[derive(Debug, Clone)]
+pub enum LiveEvent {
+    Observation(StoredObservation),
+    Alert(Alert),
+}
+
+impl LiveEvent {
+    /// The patient and signal it is about.
+    pub fn scope(&self) -> (&str, SignalCode) {
+        match self {
+            LiveEvent::Observation(obs) => (&obs.reading.patient_id, obs.reading.code),
+            LiveEvent::Alert(alert) => (&alert.patient_id, alert.code),
+        }
+    }
+}
+
+/// A subscription to the hub's typed events.
+pub struct LiveEvents {
+    rx: broadcast::Receiver<Arc<LiveEvent>>,
+    // Listeners only: fires (closes) when the hub removes the listener
+    stop: Option<oneshot::Receiver<()>>,
+    stopped: bool,
+}
+
+impl LiveEvents {
+    /// The next event; None once a listener has been removed and has read
+    /// everything published before that. Events missed by lagging are
+    /// counted and skipped.
+    pub async fn recv(&mut self) -> Option<Arc<LiveEvent>> {
+        loop {
+            let received = match (&mut self.stop, self.stopped) {
+                (Some(_), true) => match self.rx.try_recv() {
+                    Ok(event) => Ok(event),
+                    Err(broadcast::error::TryRecvError::Lagged(n)) => Err(n),
+                    Err(_) => return None,
+                },
+                (Some(stop), false) => tokio::select! {
+                    // Drain first, so nothing published before removal is lost
+                    biased;
+                    received = self.rx.recv() => match received {
+                        Ok(event) => Ok(event),
+                        Err(broadcast::error::RecvError::Lagged(n)) => Err(n),
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = stop => {
+                        self.stopped = true;
+                        continue;
+                    }
+                },
+                (None, _) => match self.rx.recv().await {
+                    Ok(event) => Ok(event),
+                    Err(broadcast::error::RecvError::Lagged(n)) => Err(n),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            };
+            match received {
+                Ok(event) => return Some(event),
+                Err(missed) => {
+                    METRICS.live_events_lagged_total.inc_by(missed);
+                    tracing::warn!(missed, "live event consumer fell behind; events skipped");
+                }
+            }
+        }
+    }
+}
+
+/// Narrows the per-reading messages a client receives to one patient and/or
+/// signal; messages not about a reading always go through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveFilter {
+    pub patient_id: Option<String>,
+    pub code: Option<SignalCode>,
+}
+
+impl LiveFilter {
+    pub fn matches(&self, patient_id: &str, code: SignalCode) -> bool {
+        self.patient_id.as_deref().is_none_or(|p| p == patient_id)
+            && self.code.is_none_or(|c| c == code)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Hub {
-    inner: Arc<Mutex<HubInner>>,
+    // Broadcasts share a read lock; only (un)registering takes the write lock
+    inner: Arc<RwLock<HubInner>>,
+    events: broadcast::Sender<Arc<LiveEvent>>,
 }
 
 #[derive(Debug)]
 struct HubInner {
     next_id: u64,
-    clients: HashMap<u64, mpsc::UnboundedSender<String>>,
+    clients: HashMap<u64, Client>,
+    // Dropping a listener's sender ends its `LiveEvents`
+    listeners: HashMap<u64, oneshot::Sender<()>>,
+}
+
+#[derive(Debug)]
+struct Client {
+    tx: mpsc::UnboundedSender<HubEvent>,
+    filter: LiveFilter,
+    // Set for channel clients, which get `send_to_channel` messages instead
+    // of broadcasts
+    channels: Option<Vec<String>>,
 }
 
 impl Hub {
     pub fn new() -> Self {
         Self {
-            inner: Arc::new(Mutex::new(HubInner {
+            inner: Arc::new(RwLock::new(HubInner {
                 next_id: 1,
                 clients: HashMap::new(),
+                listeners: HashMap::new(),
             })),
+            events: broadcast::channel(LIVE_EVENTS_CAPACITY).0,
+        }
+    }
+
+    pub fn add_client(&self, tx: mpsc::UnboundedSender<HubEvent>) -> u64 {
+        self.add_filtered_client(tx, LiveFilter::default())
+    }
+
+    pub fn add_filtered_client(
+        &self,
+        tx: mpsc::UnboundedSender<HubEvent>,
+        filter: LiveFilter,
+    ) -> u64 {
+        self.insert(Client {
+            tx,
+            filter,
+            channels: None,
+        })
+    }
+
+    /// Register an in-process consumer of published events, such as a
+    /// bridge or a forwarder. It is not counted as a client and gets no
+    /// `Shutdown` from `close_all`: it keeps receiving until `remove_client`,
+    /// so what the pipeline publishes while draining still reaches it. Its
+    /// events end on removal, once those already published are read.
+    pub fn add_listener(&self) -> (u64, LiveEvents) {
+        let (stop_tx, stop) = oneshot::channel();
+        let events = LiveEvents {
+            rx: self.events.subscribe(),
+            stop: Some(stop),
+            stopped: false,
+        };
+        let mut inner = self.inner.write().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.listeners.insert(id, stop_tx);
+        (id, events)
+    }
+
+    /// Register a connection that takes typed events instead of JSON text:
+    /// the events come on the returned `LiveEvents`, the client registered
+    /// on `tx` gets only `close_all`. It counts as a client.
+    pub fn add_event_client(&self, tx: mpsc::UnboundedSender<HubEvent>) -> (u64, LiveEvents) {
+        let events = LiveEvents {
+            rx: self.events.subscribe(),
+            stop: None,
+            stopped: false,
+        };
+        let id = self.insert(Client {
+            tx,
+            filter: LiveFilter::default(),
+            channels: Some(Vec::new()),
+        });
+        (id, events)
+    }
+
+    /// Register a client that gets no broadcasts, only messages sent to the
+    /// channels it joins. It still gets `close_all`, so draining covers it.
+    pub fn add_channel_client(&self, tx: mpsc::UnboundedSender<HubEvent>) -> u64 {
+        self.insert(Client {
+            tx,
+            filter: LiveFilter::default(),
+            channels: Some(Vec::new()),
+        })
+    }
+
+    /// Add a channel to a channel client; false if there is no such client.
+    pub fn join_channel(&self, id: u64, channel: &str) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        match inner.clients.get_mut(&id).and_then(|c| c.channels.as_mut()) {
+            Some(channels) => {
+                channels.push(channel.to_string());
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn add_client(&self, tx: mpsc::UnboundedSender<String>) -> u64 {
-        let mut inner = self.inner.lock().unwrap();
+    fn insert(&self, client: Client) -> u64 {
+        let mut inner = self.inner.write().unwrap();
         let id = inner.next_id;
         inner.next_id += 1;
-        inner.clients.insert(id, tx);
+        inner.clients.insert(id, client);
+        METRICS.ws_clients.set(inner.clients.len() as i64);
         id
     }
 
     pub fn remove_client(&self, id: u64) {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = self.inner.write().unwrap();
         inner.clients.remove(&id);
+        inner.listeners.remove(&id);
+        METRICS.ws_clients.set(inner.clients.len() as i64);
+    }
+
+    /// Hand a stored reading or a raised alert to listeners and event
+    /// clients. WebSocket clients get it through `broadcast_scoped`.
+    pub fn publish(&self, event: LiveEvent) {
+        // No receivers is fine: nothing is listening
+        let _ = self.events.send(Arc::new(event));
     }
 
     pub fn broadcast_json<T: Serialize>(&self, msg: &T) {
+        self.send_json(msg, |_| true);
+    }
+
+    /// Broadcast a message about one reading, skipping clients whose filter
+    /// excludes its patient or signal.
+    pub fn broadcast_scoped<T: Serialize>(&self, msg: &T, patient_id: &str, code: SignalCode) {
+        self.send_json(msg, |filter| filter.matches(patient_id, code));
+    }
+
+    fn send_json<T: Serialize>(&self, msg: &T, wanted: impl Fn(&LiveFilter) -> bool) {
         let Ok(text) = serde_json::to_string(msg) else {
             return;
         };
-        let inner = self.inner.lock().unwrap();
-        for (_id, tx) in inner.clients.iter() {
-            let _ = tx.send(text.clone());
+        let inner = self.inner.read().unwrap();
+        for client in inner
+            .clients
+            .values()
+            .filter(|c| c.channels.is_none() && wanted(&c.filter))
+        {
+            if client.tx.send(HubEvent::Text(text.clone())).is_err() {
+                METRICS.ws_send_failures_total.inc();
+            }
+        }
+    }
+
+    /// Send to the clients registered on `channel`; returns how many there are.
+    pub fn send_to_channel<T: Serialize>(&self, channel: &str, msg: &T) -> usize {
+        let Ok(text) = serde_json::to_string(msg) else {
+            return 0;
+        };
+        let inner = self.inner.read().unwrap();
+        let mut sent = 0;
+        let joined = |c: &&Client| {
+            c.channels
+                .as_ref()
+                .is_some_and(|chs| chs.iter().any(|ch| ch == channel))
+        };
+        for client in inner.clients.values().filter(joined) {
+            if client.tx.send(HubEvent::Text(text.clone())).is_err() {
+                METRICS.ws_send_failures_total.inc();
+            }
+            sent += 1;
         }
+        sent
+    }
+
+    /// Ask every client to close; they unregister as their connections end.
+    pub fn close_all(&self, reason: &str, reconnect_after_secs: u64) {
+        let inner = self.inner.read().unwrap();
+        for client in inner.clients.values() {
+            let _ = client.tx.send(HubEvent::Shutdown {
+                reason: reason.to_string(),
+                reconnect_after_secs,
+            });
+        }
+    }
+
+    /// Connected clients, not counting listeners.
+    pub fn client_count(&self) -> usize {
+        self.inner.read().unwrap().clients.len()
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
     }
 }
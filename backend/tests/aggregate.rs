@@ -0,0 +1,141 @@
+use actix_web::{test, web, App};
+use chrono::{Duration, TimeZone, Utc};
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::retention::RetentionPolicy;
+use pulsesense_backend::{domain::store::AppState, routes};
+
+#[actix_rt::test]
+async fn aggregates_heart_rate_per_minute() {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+    let s = AppState::with_limits(1_000, RetentionPolicy::default());
+    for (secs, value, patient) in [
+        (0, 60.0, "p1"),
+        (30, 80.0, "p1"),
+        (45, 70.0, "p1"),
+        (70, 90.0, "p1"),
+        (10, 150.0, "p2"),
+    ] {
+        s.add_reading(SensorReading {
+            device_id: "d1".into(),
+            patient_id: patient.into(),
+            code: SignalCode::HeartRate,
+            value,
+            unit: "bpm".into(),
+            ts: start + Duration::seconds(secs),
+        });
+    }
+
+    let state = web::Data::new(s);
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+
+    let req = test::TestRequest::get()
+        .uri("/observations/aggregate?patient=p1&code=heart-rate&bucket=1m&percentiles=50")
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+    let buckets = body["buckets"].as_array().unwrap();
+    assert_eq!(body["bucket_secs"], 60);
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[0]["count"], 3);
+    assert_eq!(buckets[0]["min"], 60.0);
+    assert_eq!(buckets[0]["max"], 80.0);
+    assert_eq!(buckets[0]["mean"], 70.0);
+    assert_eq!(buckets[0]["last"], 70.0);
+    assert_eq!(buckets[0]["percentiles"]["p50"], 70.0);
+    assert_eq!(buckets[1]["count"], 1);
+}
+
+#[actix_rt::test]
+async fn aggregate_requires_code_and_valid_bucket() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+
+    for uri in [
+        "/observations/aggregate?bucket=1m",
+        "/observations/aggregate?code=heart-rate&bucket=5x",
+        // Multibyte unit, and a width chrono cannot represent
+        "/observations/aggregate?code=heart-rate&bucket=1%C3%A9",
+        "/observations/aggregate?code=heart-rate&bucket=9223372036854775807d",
+        "/observations/aggregate?code=heart-rate&bucket=3651d",
+        "/observations/aggregate?code=heart-rate&bucket=",
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "{}", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn percentiles_of_single_samples_and_sparse_buckets() {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+    let s = AppState::with_limits(1_000, RetentionPolicy::default());
+    // One reading at 12:00, none at 12:01, three at 12:02
+    for (secs, value) in [(5, 64.0), (130, 90.0), (125, 70.0), (150, 80.0)] {
+        s.add_reading(SensorReading {
+            device_id: "d1".into(),
+            patient_id: "p1".into(),
+            code: SignalCode::HeartRate,
+            value,
+            unit: "bpm".into(),
+            ts: start + Duration::seconds(secs),
+        });
+    }
+    let state = web::Data::new(s);
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+
+    let req = test::TestRequest::get()
+        .uri("/observations/aggregate?patient=p1&code=heart-rate&bucket=1m&percentiles=0,50,99.9,100")
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let buckets = body["buckets"].as_array().unwrap();
+
+    // Empty minutes get no bucket rather than one with no values
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[0]["count"], 1);
+    for p in ["p0", "p50", "p99.9", "p100"] {
+        assert_eq!(buckets[0]["percentiles"][p], 64.0, "{}", p);
+    }
+    assert_eq!(buckets[1]["count"], 3);
+    assert_eq!(buckets[1]["percentiles"]["p0"], 70.0);
+    assert_eq!(buckets[1]["percentiles"]["p50"], 80.0);
+    assert_eq!(buckets[1]["percentiles"]["p99.9"], 90.0);
+    assert_eq!(buckets[1]["percentiles"]["p100"], 90.0);
+
+    // A range with no readings at all
+    let req = test::TestRequest::get()
+        .uri("/observations/aggregate?patient=p2&code=heart-rate&bucket=1m&percentiles=50")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["buckets"], serde_json::json!([]));
+}
+
+#[actix_rt::test]
+async fn bucket_width_is_capped_at_max_bucket_days() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+
+    for (bucket, status) in [
+        ("3650d", 200),
+        ("87600h", 200),
+        ("3651d", 400),
+        // Over the cap by less than a whole day
+        ("87601h", 400),
+        ("315360001s", 400),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/observations/aggregate?code=heart-rate&bucket={}",
+                bucket
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status, "{}", bucket);
+        if status == 400 {
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert!(body.to_string().contains("up to 3650d"), "{}", body);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use actix_web::{middleware, web, App};
+use chrono::{TimeZone, Utc};
+use serde_json::json;
+use std::io::Write;
+use std::path::PathBuf;
+
+use pulsesense_backend::audit::{self, Action, AuditLog, AuditQuery, Entry, Outcome};
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::telemetry;
+
+fn trail_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("pulsesense-audit-{}", uuid::Uuid::new_v4()))
+        .join("audit.ndjson")
+}
+
+fn entry(patient: &str, outcome: Outcome) -> Entry {
+    Entry {
+        action: Action::Read,
+        operation: "GET /fhir/Observation".into(),
+        actor: "admin".into(),
+        client_ip: Some("10.0.0.7".into()),
+        request_id: None,
+        query: Some(format!("/fhir/Observation?patient={}", patient)),
+        patients: vec![patient.into()],
+        outcome,
+        detail: None,
+    }
+}
+
+#[test]
+fn trail_is_hash_chained_and_tampering_is_detected() {
+    let path = trail_path();
+    let at = |minute| Utc.with_ymd_and_hms(2026, 10, 18, 12, minute, 0).unwrap();
+    let log = AuditLog::open(&path).unwrap();
+    let first = log.append(entry("p1", Outcome::Success), at(0)).unwrap();
+    let second = log.append(entry("p2", Outcome::Failure), at(1)).unwrap();
+    assert_eq!(
+        (first.seq, first.prev_hash.as_str()),
+        (1, audit::GENESIS_HASH)
+    );
+    assert_eq!((second.seq, &second.prev_hash), (2, &first.hash));
+    assert_eq!(second.hash, second.compute_hash());
+    log.sync().unwrap();
+    // Nothing appended since, so nothing to sync
+    log.sync().unwrap();
+    drop(log);
+
+    // A crash mid-write leaves a torn line, which reopening cuts off
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(br#"{"seq":3,"recorded""#).unwrap();
+    drop(file);
+    let log = AuditLog::open(&path).unwrap();
+    let third = log.append(entry("p1", Outcome::Success), at(2)).unwrap();
+    assert_eq!((third.seq, &third.prev_hash), (3, &second.hash));
+    let verification = log.verify().unwrap();
+    assert!(verification.valid, "{:?}", verification);
+    assert_eq!(
+        (verification.records, verification.head.as_str()),
+        (3, third.hash.as_str())
+    );
+
+    let query = AuditQuery {
+        patient: Some("p1".into()),
+        ..AuditQuery::default()
+    };
+    let found: Vec<u64> = log.search(&query).unwrap().iter().map(|r| r.seq).collect();
+    assert_eq!(found, [3, 1]);
+    assert_eq!(log.get(2).unwrap().unwrap(), second);
+
+    // Rewriting history breaks the chain at the edited record
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(
+        &path,
+        text.replacen(r#""patients":["p2"]"#, r#""patients":["p9"]"#, 1),
+    )
+    .unwrap();
+    let verification = log.verify().unwrap();
+    assert!(!verification.valid);
+    assert_eq!(verification.broken_at, Some(2));
+    assert_eq!(
+        verification.reason.as_deref(),
+        Some("record 2 does not match its hash")
+    );
+
+    // So does dropping a record, even with the rest of the file intact
+    let lines: Vec<&str> = text.lines().collect();
+    std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+    let verification = log.verify().unwrap();
+    assert_eq!(
+        (verification.valid, verification.broken_at),
+        (false, Some(2))
+    );
+    assert_eq!(
+        verification.reason.as_deref(),
+        Some("seq 3 where 2 was expected")
+    );
+}
+
+#[test]
+fn searches_read_back_from_the_newest_records_through_the_index() {
+    let path = trail_path();
+    let at = |n: i64| {
+        Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap() + chrono::Duration::seconds(n)
+    };
+    let log = AuditLog::open(&path).unwrap();
+    // Enough for several read batches, with one patient in every seventh
+    for n in 1..=1_000 {
+        let patient = if n % 7 == 0 { "rare" } else { "common" };
+        log.append(entry(patient, Outcome::Success), at(n)).unwrap();
+    }
+    drop(log);
+
+    // The index is rebuilt from the file
+    let log = AuditLog::open(&path).unwrap();
+    let query = AuditQuery {
+        patient: Some("rare".into()),
+        count: 3,
+        ..AuditQuery::default()
+    };
+    let found: Vec<u64> = log.search(&query).unwrap().iter().map(|r| r.seq).collect();
+    assert_eq!(found, [994, 987, 980]);
+    let query = AuditQuery {
+        patient: Some("rare".into()),
+        count: 1_000,
+        ..AuditQuery::default()
+    };
+    assert_eq!(log.search(&query).unwrap().len(), 142);
+
+    let query = AuditQuery {
+        to: std::ops::Bound::Excluded(at(2)),
+        count: 1_000,
+        ..AuditQuery::default()
+    };
+    let found: Vec<u64> = log.search(&query).unwrap().iter().map(|r| r.seq).collect();
+    assert_eq!(found, [1]);
+    let newest = log.search(&AuditQuery::default()).unwrap();
+    assert_eq!(
+        (newest.len(), newest[0].seq, newest[99].seq),
+        (100, 1_000, 901)
+    );
+
+    let appended = log
+        .append(entry("rare", Outcome::Failure), at(1_001))
+        .unwrap();
+    assert_eq!(log.get(1_001).unwrap(), Some(appended));
+    assert_eq!(
+        log.get(500).unwrap().map(|r| r.entry.patients),
+        Some(vec!["common".to_string()])
+    );
+    assert_eq!(log.get(1_002).unwrap(), None);
+    assert_eq!(log.verify().unwrap().records, 1_001);
+}
+
+#[actix_rt::test]
+async fn audit_api_is_closed_without_an_admin_token() {
+    let log = web::Data::new(AuditLog::open(&trail_path()).unwrap());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(AppState::new_demo()))
+            .app_data(web::Data::new(Settings::default()))
+            .app_data(log)
+            .configure(routes::configure),
+    )
+    .await;
+    for uri in ["/fhir/AuditEvent", "/fhir/AuditEvent/1", "/audit/verify"] {
+        let req = actix_web::test::TestRequest::get().uri(uri).to_request();
+        assert_eq!(
+            actix_web::test::call_service(&app, req).await.status(),
+            401,
+            "{}",
+            uri
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn api_access_is_recorded_and_searchable_as_audit_events() {
+    let path = trail_path();
+    let log = web::Data::new(AuditLog::open(&path).unwrap());
+    let state = web::Data::new(AppState::new_demo());
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    settings.auth.ingest_token = Some("ingest-secret".into());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(settings))
+            .app_data(log.clone())
+            .wrap(middleware::from_fn(audit::record_requests))
+            .wrap(middleware::from_fn(telemetry::request_id))
+            .configure(routes::configure),
+    )
+    .await;
+    let peer = "10.1.2.3:40000".parse().unwrap();
+    let reading = json!({
+        "device_id": "audit-dev", "patient_id": "audit-p1", "code": "heart-rate",
+        "value": 72.0, "unit": "bpm", "ts": "2026-10-18T12:00:00Z",
+    });
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest")
+        .peer_addr(peer)
+        .insert_header(("Authorization", "Bearer ingest-secret"))
+        .set_json(&reading)
+        .to_request();
+    assert!(actix_web::test::call_service(&app, req)
+        .await
+        .status()
+        .is_success());
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(&reading)
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+    let req = actix_web::test::TestRequest::get()
+        .uri("/fhir/Observation?patient=audit-p1")
+        .insert_header(("X-Request-Id", "req-42"))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 200);
+    let req = actix_web::test::TestRequest::get()
+        .uri("/export/observations?code=heart-rate")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 200);
+    let req = actix_web::test::TestRequest::get()
+        .uri("/healthz")
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 200);
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/fhir/AuditEvent")
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+    let admin = |uri: &str| {
+        actix_web::test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request()
+    };
+    let req = admin("/fhir/AuditEvent?date=2026-10-18");
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+
+    let bundle: serde_json::Value = actix_web::test::call_and_read_body_json(
+        &app,
+        admin("/fhir/AuditEvent?patient=Patient/audit-p1"),
+    )
+    .await;
+    assert_eq!(bundle["type"], "searchset");
+    let events: Vec<&serde_json::Value> = bundle["entry"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| &e["resource"])
+        .collect();
+    let summary: Vec<(&str, &str, &str, &str)> = events
+        .iter()
+        .map(|e| {
+            (
+                e["subtype"][0]["code"].as_str().unwrap(),
+                e["action"].as_str().unwrap(),
+                e["agent"][0]["name"].as_str().unwrap(),
+                e["outcome"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    // Newest first; the probe and the refused audit search touch no patient
+    assert_eq!(
+        summary,
+        [
+            ("GET /export/observations", "R", "admin", "0"),
+            ("GET /fhir/Observation", "R", "anonymous", "0"),
+            ("POST /ingest", "C", "anonymous", "4"),
+            ("POST /ingest", "C", "ingest", "0"),
+        ]
+    );
+    let ingest = events[3];
+    assert_eq!(ingest["resourceType"], "AuditEvent");
+    assert_eq!(ingest["id"], "1");
+    assert_eq!(ingest["agent"][0]["network"]["address"], "10.1.2.3");
+    assert_eq!(ingest["entity"][0]["what"]["reference"], "Patient/audit-p1");
+    assert_eq!(events[2]["outcomeDesc"], "HTTP 401");
+    let read = events[1];
+    assert!(read["extension"]
+        .as_array()
+        .unwrap()
+        .contains(&json!({ "url": audit::REQUEST_ID_EXTENSION, "valueString": "req-42" })));
+
+    let all: serde_json::Value =
+        actix_web::test::call_and_read_body_json(&app, admin("/fhir/AuditEvent?_count=100")).await;
+    let operations: Vec<&str> = all["entry"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["resource"]["subtype"][0]["code"].as_str().unwrap())
+        .collect();
+    assert!(!operations.contains(&"GET /healthz"));
+    assert_eq!(
+        operations[0], "GET /fhir/AuditEvent",
+        "audit searches are audited too"
+    );
+
+    let failures: serde_json::Value = actix_web::test::call_and_read_body_json(
+        &app,
+        admin("/fhir/AuditEvent?outcome=4&agent-name=anonymous"),
+    )
+    .await;
+    assert_eq!(failures["total"], 2);
+
+    let event: serde_json::Value =
+        actix_web::test::call_and_read_body_json(&app, admin("/fhir/AuditEvent/1")).await;
+    assert_eq!(event, *ingest);
+    let verification: serde_json::Value =
+        actix_web::test::call_and_read_body_json(&app, admin("/audit/verify")).await;
+    assert_eq!(verification["valid"], true);
+    // The verify call itself is recorded once it has answered
+    assert_eq!(verification["records"], log.verify().unwrap().records - 1);
+    state.ws_hub.close_all("server shutting down", 5);
+}
@@ -0,0 +1,317 @@
+use actix_web::{test, web, App};
+use chrono::{Duration, TimeZone, Utc};
+
+use pulsesense_backend::bulk::{BulkExports, ExportRequest, JobStatus, Manifest, ResourceType};
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::retention::RetentionPolicy;
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::{domain::store::AppState, routes};
+
+fn state() -> web::Data<AppState> {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let s = AppState::with_limits(100, RetentionPolicy::default());
+    for (i, (patient, device)) in [("p1", "d1"), ("p1", "d1"), ("p2", "d2"), ("p2", "d3")]
+        .into_iter()
+        .enumerate()
+    {
+        s.add_reading(SensorReading {
+            device_id: device.into(),
+            patient_id: patient.into(),
+            code: SignalCode::HeartRate,
+            value: 70.0,
+            unit: "bpm".into(),
+            ts: start + Duration::hours(i as i64),
+        });
+    }
+    web::Data::new(s)
+}
+
+fn exports() -> BulkExports {
+    BulkExports::new(
+        std::env::temp_dir().join(format!("pulsesense-bulk-{}", uuid::Uuid::new_v4())),
+        std::time::Duration::from_secs(60),
+    )
+}
+
+fn bulk() -> web::Data<BulkExports> {
+    web::Data::new(exports())
+}
+
+async fn finished(bulk: &BulkExports, id: uuid::Uuid) -> Manifest {
+    for _ in 0..100 {
+        match bulk.status(id) {
+            Some(JobStatus::InProgress { .. }) => {
+                actix_rt::time::sleep(std::time::Duration::from_millis(20)).await
+            }
+            Some(JobStatus::Complete(manifest)) => return manifest,
+            other => panic!("export did not complete: {:?}", other),
+        }
+    }
+    panic!("export did not complete in time")
+}
+
+fn observations(types: &[ResourceType], since: Option<chrono::DateTime<Utc>>) -> ExportRequest {
+    ExportRequest {
+        types: types.to_vec(),
+        since,
+        request_url: "http://localhost/fhir/$export".into(),
+        base_url: "http://localhost/fhir".into(),
+    }
+}
+
+fn admin_settings() -> web::Data<Settings> {
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    web::Data::new(settings)
+}
+
+fn admin(req: test::TestRequest) -> test::TestRequest {
+    req.insert_header(("Authorization", "Bearer admin-secret"))
+}
+
+// Path part of an absolute URL handed out by the server
+fn path_of(url: &str) -> &str {
+    let rest = url.split_once("://").unwrap().1;
+    &rest[rest.find('/').unwrap()..]
+}
+
+#[actix_rt::test]
+async fn export_runs_in_background_and_serves_ndjson() {
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(bulk())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = admin(
+        test::TestRequest::get()
+            .uri("/fhir/$export?_type=Observation,Device")
+            .insert_header(("prefer", "respond-async")),
+    )
+    .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+    let status_url = resp
+        .headers()
+        .get("content-location")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Poll until the job completes
+    let mut manifest = serde_json::Value::Null;
+    for _ in 0..100 {
+        let req = admin(test::TestRequest::get().uri(path_of(&status_url))).to_request();
+        let resp = test::call_service(&app, req).await;
+        if resp.status() == 200 {
+            manifest = test::read_body_json(resp).await;
+            break;
+        }
+        assert_eq!(resp.status(), 202);
+        actix_rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    let output = manifest["output"]
+        .as_array()
+        .expect("export did not complete");
+    assert_eq!(manifest["requiresAccessToken"], true);
+    let counts: Vec<(&str, u64)> = output
+        .iter()
+        .map(|o| (o["type"].as_str().unwrap(), o["count"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(counts, vec![("Observation", 4), ("Device", 3)]);
+
+    let file_path = path_of(output[1]["url"].as_str().unwrap());
+    let req = test::TestRequest::get().uri(file_path).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let req = admin(test::TestRequest::get().uri(file_path)).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/fhir+ndjson"
+    );
+    let body = test::read_body(resp).await;
+    let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines[0]["resourceType"], "Device");
+    assert_eq!(lines[0]["patient"]["reference"], "Patient/p1");
+
+    // Only admins see or cancel the job; deleting it removes its files
+    let req = test::TestRequest::get()
+        .uri(path_of(&status_url))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let req = test::TestRequest::delete()
+        .uri(path_of(&status_url))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let req = admin(test::TestRequest::delete().uri(path_of(&status_url))).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 202);
+    let req = admin(test::TestRequest::get().uri(path_of(output[0]["url"].as_str().unwrap())))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+}
+
+#[actix_rt::test]
+async fn kickoff_requires_respond_async_and_known_types() {
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(bulk())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = admin(test::TestRequest::get().uri("/fhir/$export")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    let req = admin(
+        test::TestRequest::get()
+            .uri("/fhir/$export?_type=Encounter")
+            .insert_header(("prefer", "respond-async")),
+    )
+    .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_rt::test]
+async fn kickoff_needs_a_configured_admin_token() {
+    let kickoff = || {
+        test::TestRequest::get()
+            .uri("/fhir/$export")
+            .insert_header(("prefer", "respond-async"))
+    };
+
+    // Without an admin token configured, nobody can export
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(bulk())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+    assert_eq!(
+        test::call_service(&app, kickoff().to_request())
+            .await
+            .status(),
+        401
+    );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(bulk())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+    assert_eq!(
+        test::call_service(&app, kickoff().to_request())
+            .await
+            .status(),
+        401
+    );
+    let req = kickoff()
+        .insert_header(("Authorization", "Bearer ingest-secret"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    assert_eq!(
+        test::call_service(&app, admin(kickoff()).to_request())
+            .await
+            .status(),
+        202
+    );
+}
+
+#[actix_rt::test]
+async fn since_selects_readings_by_when_they_were_taken_in() {
+    let state = state().into_inner();
+    let bulk = exports();
+    // From when the store started: everything
+    let id = bulk.start(
+        state.clone(),
+        observations(&[ResourceType::Observation], Some(state.created_at())),
+    );
+    assert_eq!(finished(&bulk, id).await.output[0].count, 4);
+
+    actix_rt::time::sleep(std::time::Duration::from_millis(5)).await;
+    let since = Utc::now();
+    actix_rt::time::sleep(std::time::Duration::from_millis(5)).await;
+    // Measured before everything else, but taken in after `since`
+    let late = state.add_reading(SensorReading {
+        device_id: "d4".into(),
+        patient_id: "p3".into(),
+        code: SignalCode::HeartRate,
+        value: 70.0,
+        unit: "bpm".into(),
+        ts: Utc.with_ymd_and_hms(2023, 12, 31, 0, 0, 0).unwrap(),
+    });
+
+    let id = bulk.start(
+        state.clone(),
+        observations(
+            &[ResourceType::Observation, ResourceType::Patient],
+            Some(since),
+        ),
+    );
+    let manifest = finished(&bulk, id).await;
+    let counts: Vec<usize> = manifest.output.iter().map(|o| o.count).collect();
+    assert_eq!(counts, vec![1, 1]);
+    let file = bulk.file_path(id, "Observation.ndjson").unwrap();
+    let body = std::fs::read_to_string(file).unwrap();
+    assert!(body.contains(&late.id.to_string()), "{}", body);
+}
+
+#[actix_rt::test]
+async fn finished_jobs_expire_with_their_files() {
+    let state = state().into_inner();
+    let bulk = exports();
+    let id = bulk.start(state, observations(&[ResourceType::Observation], None));
+    finished(&bulk, id).await;
+    let file = bulk.file_path(id, "Observation.ndjson").unwrap();
+    assert!(file.exists());
+
+    let now = std::time::Instant::now();
+    assert_eq!(bulk.expire(now), 0);
+    assert!(bulk.status(id).is_some());
+    assert_eq!(bulk.expire(now + std::time::Duration::from_secs(60)), 1);
+    assert!(bulk.status(id).is_none());
+    assert!(!file.parent().unwrap().exists());
+}
+
+#[actix_rt::test]
+async fn startup_removes_job_dirs_left_by_an_earlier_run() {
+    let bulk = exports();
+    // Nothing exported yet, not even the directory
+    assert_eq!(bulk.remove_stale().unwrap(), 0);
+
+    let stale = bulk.dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&stale).unwrap();
+    std::fs::write(stale.join("Observation.ndjson"), "{}\n").unwrap();
+    // Anything else in the directory is not ours to remove
+    let other = bulk.dir().join("notes");
+    std::fs::create_dir_all(&other).unwrap();
+
+    let running = bulk.start(
+        state().into_inner(),
+        observations(&[ResourceType::Observation], None),
+    );
+    finished(&bulk, running).await;
+    assert_eq!(bulk.remove_stale().unwrap(), 1);
+    assert!(!stale.exists());
+    assert!(other.exists());
+    assert!(bulk
+        .file_path(running, "Observation.ndjson")
+        .unwrap()
+        .exists());
+    std::fs::remove_dir_all(bulk.dir()).unwrap();
+}
@@ -0,0 +1,252 @@
+use coap_lite::{
+    CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType, ResponseType,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use pulsesense_backend::coap::{CoapIngest, CoapServer};
+use pulsesense_backend::domain::models::StoredObservation;
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::pipeline::Pipeline;
+use pulsesense_backend::ratelimit::RateLimiter;
+use pulsesense_backend::settings::{
+    Limit, PipelineSettings, RateLimitSettings, RouteLimits, Settings,
+};
+
+mod common;
+use common::reading;
+
+fn request(kind: MessageType, method: RequestType, path: &str, message_id: u16) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_type(kind);
+    packet.header.code = MessageClass::Request(method);
+    packet.header.message_id = message_id;
+    packet.set_token(message_id.to_be_bytes().to_vec());
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        packet.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+    }
+    packet
+}
+
+fn cbor(value: f64, message_id: u16) -> Packet {
+    let mut packet = request(
+        MessageType::Confirmable,
+        RequestType::Post,
+        "/ingest",
+        message_id,
+    );
+    packet.set_content_format(ContentFormat::ApplicationCBOR);
+    ciborium::into_writer(&reading(value), &mut packet.payload).unwrap();
+    packet
+}
+
+async fn exchange(client: &UdpSocket, packet: &Packet) -> Packet {
+    client.send(&packet.to_bytes().unwrap()).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let len = tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+        .await
+        .expect("no CoAP response")
+        .unwrap();
+    Packet::from_bytes(&buf[..len]).unwrap()
+}
+
+#[actix_rt::test]
+async fn confirmable_readings_are_acked_with_their_result() {
+    let state = Arc::new(AppState::new_demo());
+    let ingest = CoapIngest::new(state.clone(), None, None, None, &Settings::default());
+    let server = CoapServer::bind("127.0.0.1:0", ingest).await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(server.local_addr()).await.unwrap();
+    let before = state.len();
+
+    let ack = exchange(&client, &cbor(72.0, 1)).await;
+    assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+    assert_eq!(ack.header.message_id, 1);
+    assert_eq!(ack.get_token(), &1u16.to_be_bytes());
+    assert_eq!(
+        ack.header.code,
+        MessageClass::Response(ResponseType::Created)
+    );
+    assert_eq!(state.len(), before + 1);
+
+    // A retransmission gets the same answer and is not stored again
+    let again = exchange(&client, &cbor(72.0, 1)).await;
+    assert_eq!(again.payload, ack.payload);
+    assert_eq!(state.len(), before + 1);
+
+    let mut json = request(MessageType::Confirmable, RequestType::Post, "/ingest", 2);
+    json.set_content_format(ContentFormat::ApplicationJSON);
+    json.payload = serde_json::to_vec(&reading(900.0)).unwrap();
+    let ack = exchange(&client, &json).await;
+    assert_eq!(
+        ack.header.code,
+        MessageClass::Response(ResponseType::BadRequest)
+    );
+    assert!(String::from_utf8(ack.payload)
+        .unwrap()
+        .contains("heart-rate"));
+
+    let mut non = request(MessageType::NonConfirmable, RequestType::Post, "/ingest", 3);
+    non.payload = serde_json::to_vec(&reading(70.0)).unwrap();
+    let reply = exchange(&client, &non).await;
+    assert_eq!(reply.header.get_type(), MessageType::NonConfirmable);
+    assert_eq!(
+        reply.header.code,
+        MessageClass::Response(ResponseType::Created)
+    );
+    assert_eq!(state.len(), before + 2);
+
+    let get = request(MessageType::Confirmable, RequestType::Get, "/ingest", 4);
+    assert_eq!(
+        exchange(&client, &get).await.header.code,
+        MessageClass::Response(ResponseType::MethodNotAllowed)
+    );
+    let other = request(MessageType::Confirmable, RequestType::Post, "/other", 5);
+    assert_eq!(
+        exchange(&client, &other).await.header.code,
+        MessageClass::Response(ResponseType::NotFound)
+    );
+    let mut xml = cbor(70.0, 6);
+    xml.clear_option(CoapOption::ContentFormat);
+    xml.set_content_format(ContentFormat::ApplicationXML);
+    assert_eq!(
+        exchange(&client, &xml).await.header.code,
+        MessageClass::Response(ResponseType::UnsupportedContentFormat)
+    );
+
+    let mut ping = Packet::new();
+    ping.header.set_type(MessageType::Confirmable);
+    ping.header.code = MessageClass::Empty;
+    ping.header.message_id = 7;
+    assert_eq!(
+        exchange(&client, &ping).await.header.get_type(),
+        MessageType::Reset
+    );
+
+    server.stop();
+}
+
+#[actix_rt::test]
+async fn full_ingest_queue_answers_service_unavailable() {
+    let state = Arc::new(AppState::new_demo());
+    let pipeline = Arc::new(Pipeline::start(
+        state.clone(),
+        &PipelineSettings {
+            queue_capacity: 1,
+            stage_capacity: 1,
+        },
+    ));
+    // Stages only run once this task yields, so the queue stays full
+    pipeline
+        .submit(StoredObservation {
+            id: uuid::Uuid::new_v4(),
+            reading: reading(70.0),
+        })
+        .unwrap();
+
+    let queued = CoapIngest::new(
+        state.clone(),
+        Some(pipeline.clone()),
+        None,
+        None,
+        &Settings::default(),
+    );
+    let response = queued.respond(&cbor(72.0, 9)).unwrap();
+    assert_eq!(
+        response.header.code,
+        MessageClass::Response(ResponseType::ServiceUnavailable)
+    );
+    assert_eq!(
+        response.get_first_option(CoapOption::MaxAge),
+        Some(&vec![1])
+    );
+
+    pipeline.shutdown().await;
+    let direct = CoapIngest::new(state, None, None, None, &Settings::default());
+    let response = direct.respond(&cbor(72.0, 10)).unwrap();
+    assert_eq!(
+        response.header.code,
+        MessageClass::Response(ResponseType::Created)
+    );
+}
+
+#[actix_rt::test]
+async fn readings_need_the_ingest_token_and_stay_within_the_device_limit() {
+    let state = Arc::new(AppState::new_demo());
+    let mut settings = Settings::default();
+    settings.auth.ingest_token = Some("ingest-secret".into());
+    let limits = RouteLimits {
+        per_device: Some(Limit::new(0.001, 1)),
+        ..RouteLimits::default()
+    };
+    let limiter = RateLimiter::new(RateLimitSettings {
+        enabled: true,
+        routes: HashMap::from([("/ingest".to_string(), limits)]),
+    });
+    let ingest = CoapIngest::new(
+        state.clone(),
+        None,
+        None,
+        Some(Arc::new(limiter)),
+        &settings,
+    );
+    let with_query = |query: &str, message_id| {
+        let mut packet = cbor(72.0, message_id);
+        packet.add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+        packet
+    };
+    let code = |packet: &Packet| ingest.respond(packet).unwrap().header.code;
+    let before = state.len();
+
+    assert_eq!(
+        code(&cbor(72.0, 1)),
+        MessageClass::Response(ResponseType::Unauthorized)
+    );
+    assert_eq!(
+        code(&with_query("token=wrong", 2)),
+        MessageClass::Response(ResponseType::Unauthorized)
+    );
+    assert_eq!(
+        code(&with_query("token=ingest-secret", 3)),
+        MessageClass::Response(ResponseType::Created)
+    );
+    assert_eq!(state.len(), before + 1);
+
+    let limited = ingest
+        .respond(&with_query("token=ingest-secret", 4))
+        .unwrap();
+    assert_eq!(
+        limited.header.code,
+        MessageClass::Response(ResponseType::TooManyRequests)
+    );
+    assert!(limited.get_first_option(CoapOption::MaxAge).is_some());
+    assert_eq!(state.len(), before + 1);
+}
+
+#[actix_rt::test]
+async fn a_full_exchange_cache_drops_its_oldest_entries() {
+    let state = Arc::new(AppState::new_demo());
+    let ingest = CoapIngest::new(state.clone(), None, None, None, &Settings::default());
+    let server = CoapServer::bind("127.0.0.1:0", ingest).await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(server.local_addr()).await.unwrap();
+
+    // More exchanges than the cache holds, then a reading
+    for message_id in 0..5_000 {
+        let get = request(
+            MessageType::Confirmable,
+            RequestType::Get,
+            "/ingest",
+            message_id,
+        );
+        exchange(&client, &get).await;
+    }
+    let before = state.len();
+    exchange(&client, &cbor(72.0, 5_000)).await;
+    exchange(&client, &cbor(72.0, 5_000)).await;
+    assert_eq!(state.len(), before + 1, "the newest exchange is remembered");
+
+    server.stop();
+}
@@ -0,0 +1,23 @@
+// Shared by several test crates, each using only some of it
+#![allow(dead_code)]
+
+use chrono::{DateTime, Utc};
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+
+/// A heart rate reading from device d1 for patient p1, taken now.
+pub fn reading(value: f64) -> SensorReading {
+    reading_at(value, Utc::now())
+}
+
+/// Like `reading`, taken at `ts`.
+pub fn reading_at(value: f64, ts: DateTime<Utc>) -> SensorReading {
+    SensorReading {
+        device_id: "d1".into(),
+        patient_id: "p1".into(),
+        code: SignalCode::HeartRate,
+        value,
+        unit: "bpm".into(),
+        ts,
+    }
+}
@@ -0,0 +1,104 @@
+use actix_web::{test, web, App};
+
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::{cors, domain::store::AppState, routes};
+
+macro_rules! app {
+    ($settings:expr) => {{
+        let settings: Settings = $settings;
+        let state = web::Data::new(AppState::from_settings(&settings));
+        test::init_service(
+            App::new()
+                .app_data(state)
+                .wrap(cors::cors(&settings.cors))
+                .app_data(web::Data::new(settings))
+                .configure(routes::configure),
+        )
+        .await
+    }};
+}
+
+fn preflight(uri: &str, origin: &str, method: &str) -> test::TestRequest {
+    test::TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri(uri)
+        .insert_header(("origin", origin))
+        .insert_header(("access-control-request-method", method))
+        .insert_header((
+            "access-control-request-headers",
+            "authorization, content-type",
+        ))
+}
+
+#[actix_rt::test]
+async fn preflight_is_answered_for_allowed_origins() {
+    let app = app!(Settings::default());
+
+    for (uri, method) in [("/ingest", "POST"), ("/fhir/Observation", "GET")] {
+        let resp = test::call_service(
+            &app,
+            preflight(uri, "http://127.0.0.1:5173", method).to_request(),
+        )
+        .await;
+        assert!(resp.status().is_success(), "{} {}", uri, resp.status());
+        let headers = resp.headers();
+        assert_eq!(
+            headers.get("access-control-allow-origin").unwrap(),
+            "http://127.0.0.1:5173"
+        );
+        let methods = headers
+            .get("access-control-allow-methods")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(methods.contains(method));
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/healthz")
+        .insert_header(("origin", "http://localhost:5173"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get("access-control-allow-origin").unwrap(),
+        "http://localhost:5173"
+    );
+}
+
+#[actix_rt::test]
+async fn other_origins_are_rejected() {
+    let app = app!(Settings::default());
+
+    let resp = test::call_service(
+        &app,
+        preflight("/ingest", "http://evil.example", "POST").to_request(),
+    )
+    .await;
+    assert!(resp.status().is_client_error());
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+
+    let req = test::TestRequest::get()
+        .uri("/healthz")
+        .insert_header(("origin", "http://evil.example"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+}
+
+#[actix_rt::test]
+async fn wildcard_origin_allows_any() {
+    let mut settings = Settings::default();
+    settings.cors.allowed_origins = vec!["*".into()];
+    let app = app!(settings);
+
+    let resp = test::call_service(
+        &app,
+        preflight("/ingest", "http://anything.example", "POST").to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    assert_eq!(
+        resp.headers().get("access-control-allow-origin").unwrap(),
+        "*"
+    );
+}
@@ -0,0 +1,145 @@
+use actix_web::{test, web, App};
+use chrono::{Duration, TimeZone, Utc};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::retention::RetentionPolicy;
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::{domain::store::AppState, routes};
+
+// More than one export page, so paging across the store is exercised
+const ROWS: usize = 12_001;
+
+fn state() -> web::Data<AppState> {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let s = AppState::with_limits(ROWS * 2, RetentionPolicy::default());
+    for i in 0..ROWS {
+        for (patient, code, value, unit) in [
+            ("p1", SignalCode::HeartRate, 70.0, "bpm"),
+            ("p2", SignalCode::BodyTemperature, 36.6, "C"),
+        ] {
+            s.add_reading(SensorReading {
+                device_id: "d1".into(),
+                patient_id: patient.into(),
+                code,
+                value,
+                unit: unit.into(),
+                ts: start + Duration::seconds(i as i64),
+            });
+        }
+    }
+    web::Data::new(s)
+}
+
+fn admin_settings() -> web::Data<Settings> {
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    web::Data::new(settings)
+}
+
+fn admin(req: test::TestRequest) -> test::TestRequest {
+    req.insert_header(("Authorization", "Bearer admin-secret"))
+}
+
+#[actix_rt::test]
+async fn exports_filtered_csv_with_selected_columns() {
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = admin(test::TestRequest::get())
+        .uri("/export/observations?patient=p1&format=csv&columns=patient,value,ts")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("content-type").unwrap(), "text/csv");
+
+    let body = test::read_body(resp).await;
+    let text = std::str::from_utf8(&body).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), ROWS + 1);
+    assert_eq!(lines[0], "patient,value,ts");
+    assert_eq!(lines[1], "p1,70,2024-01-01T00:00:00+00:00");
+    assert!(lines[ROWS].ends_with("2024-01-01T03:20:00+00:00"));
+}
+
+#[actix_rt::test]
+async fn exports_parquet_via_accept_header() {
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = admin(test::TestRequest::get())
+        .uri("/export/observations?code=body-temperature")
+        .insert_header(("accept", "application/vnd.apache.parquet"))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(body)
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut rows = 0;
+    for batch in reader {
+        let batch = batch.unwrap();
+        assert_eq!(batch.num_columns(), 7);
+        rows += batch.num_rows();
+    }
+    assert_eq!(rows, ROWS);
+}
+
+#[actix_rt::test]
+async fn rejects_unknown_columns() {
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = admin(test::TestRequest::get())
+        .uri("/export/observations?columns=patient,heartbeat")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_rt::test]
+async fn exports_need_the_admin_token() {
+    let export = || test::TestRequest::get().uri("/export/observations?patient=p1");
+
+    // Without an admin token configured, nobody can export
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+    let resp = test::call_service(&app, export().to_request()).await;
+    assert_eq!(resp.status(), 401);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(state())
+            .app_data(admin_settings())
+            .configure(routes::configure),
+    )
+    .await;
+    let resp = test::call_service(&app, export().to_request()).await;
+    assert_eq!(resp.status(), 401);
+    let req = export()
+        .insert_header(("Authorization", "Bearer wrong"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let resp = test::call_service(&app, admin(export()).to_request()).await;
+    assert_eq!(resp.status(), 200);
+}
@@ -0,0 +1,276 @@
+use actix_web::{web, App};
+use base64::Engine;
+use chrono::{TimeZone, Utc};
+
+use pulsesense_backend::domain::models::SignalCode;
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+use pulsesense_backend::errors::AppError;
+use pulsesense_backend::gatt::{self, Measurement, TemperatureUnit};
+use pulsesense_backend::pipeline::Pipeline;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{PipelineSettings, Settings};
+
+// Flags 0x1F: 16-bit rate, contact supported and detected, energy expended
+// and RR intervals present. 72 bpm, 272 kJ, RR 1024/1024 s and 768/1024 s.
+const HR_FULL: [u8; 9] = [0x1F, 0x48, 0x00, 0x10, 0x01, 0x00, 0x04, 0x00, 0x03];
+// 36.6 °C: mantissa 366, exponent -1
+const TEMP_C: [u8; 5] = [0x00, 0x6E, 0x01, 0x00, 0xFF];
+// 98.6 °F with time stamp 2026-10-18 08:30:00 and temperature type 2 (body)
+const TEMP_F_FULL: [u8; 13] = [
+    0x07, 0xDA, 0x03, 0x00, 0xFF, 0xEA, 0x07, 10, 18, 8, 30, 0, 2,
+];
+
+fn invalid<T: std::fmt::Debug>(result: Result<T, AppError>) -> String {
+    match result {
+        Err(AppError::Validation(msg)) => msg,
+        other => panic!("expected a validation error, got {:?}", other),
+    }
+}
+
+#[test]
+fn heart_rate_measurement_fixtures() {
+    let hr = gatt::decode_heart_rate(&[0x00, 0x48]).unwrap();
+    assert_eq!(hr.heart_rate, 72);
+    assert_eq!(hr.sensor_contact, None);
+    assert_eq!(hr.energy_expended_kj, None);
+    assert!(hr.rr_intervals_ms.is_empty());
+
+    let hr = gatt::decode_heart_rate(&HR_FULL).unwrap();
+    assert_eq!(hr.heart_rate, 72);
+    assert_eq!(hr.sensor_contact, Some(true));
+    assert_eq!(hr.energy_expended_kj, Some(272));
+    assert_eq!(hr.rr_intervals_ms, [1000.0, 750.0]);
+
+    // Contact supported but not detected; 16-bit rate above 255
+    let hr = gatt::decode_heart_rate(&[0x05, 0x2C, 0x01]).unwrap();
+    assert_eq!((hr.heart_rate, hr.sensor_contact), (300, Some(false)));
+
+    assert!(invalid(gatt::decode_heart_rate(&[])).contains("flags"));
+    assert!(invalid(gatt::decode_heart_rate(&[0x01, 0x48])).contains("heart rate"));
+    assert!(invalid(gatt::decode_heart_rate(&[0x08, 0x48, 0x10])).contains("energy expended"));
+    assert!(invalid(gatt::decode_heart_rate(&[0x10, 0x48, 0x00])).contains("RR interval"));
+    assert!(invalid(gatt::decode_heart_rate(&[0x10, 0x48])).contains("RR interval"));
+
+    let mut most = vec![0x10, 0x48];
+    most.extend([0x00, 0x04].repeat(gatt::MAX_RR_INTERVALS));
+    assert_eq!(
+        gatt::decode_heart_rate(&most)
+            .unwrap()
+            .rr_intervals_ms
+            .len(),
+        gatt::MAX_RR_INTERVALS
+    );
+    most.extend([0x00, 0x04]);
+    assert!(invalid(gatt::decode_heart_rate(&most)).contains("RR intervals, more than"));
+}
+
+#[test]
+fn temperature_measurement_fixtures() {
+    let t = gatt::decode_temperature(&TEMP_C).unwrap();
+    assert_eq!(t.unit, TemperatureUnit::Celsius);
+    assert!((t.value - 36.6).abs() < 1e-9);
+    assert_eq!((t.ts, t.temperature_type), (None, None));
+
+    let t = gatt::decode_temperature(&TEMP_F_FULL).unwrap();
+    assert_eq!(t.unit, TemperatureUnit::Fahrenheit);
+    assert!((t.value - 98.6).abs() < 1e-9);
+    assert!((t.celsius() - 37.0).abs() < 1e-9);
+    assert_eq!(
+        t.ts,
+        Some(Utc.with_ymd_and_hms(2026, 10, 18, 8, 30, 0).unwrap())
+    );
+    assert_eq!(t.temperature_type, Some(2));
+
+    // NaN, and a month of 13
+    assert!(
+        invalid(gatt::decode_temperature(&[0x00, 0xFF, 0xFF, 0x7F, 0x00])).contains("no value")
+    );
+    let mut bad_month = TEMP_F_FULL;
+    bad_month[7] = 13;
+    assert!(invalid(gatt::decode_temperature(&bad_month)).contains("time stamp"));
+    assert!(invalid(gatt::decode_temperature(&TEMP_F_FULL[..9])).contains("time stamp"));
+    assert!(invalid(gatt::decode_temperature(&TEMP_C[..3])).contains("temperature"));
+}
+
+#[test]
+fn ieee_11073_float_and_uuids() {
+    assert!((gatt::decode_float([0x6E, 0x01, 0x00, 0xFF]).unwrap() - 36.6).abs() < 1e-9);
+    assert_eq!(gatt::decode_float([0xFB, 0xFF, 0xFF, 0x00]), Some(-5.0));
+    assert_eq!(gatt::decode_float([0x02, 0x00, 0x00, 0x02]), Some(200.0));
+    for special in [
+        0x007F_FFFFu32,
+        0x0080_0000,
+        0x007F_FFFE,
+        0x0080_0002,
+        0x0080_0001,
+    ] {
+        assert_eq!(gatt::decode_float(special.to_le_bytes()), None);
+    }
+
+    assert_eq!(gatt::parse_uuid("2A37"), Some(gatt::HEART_RATE_MEASUREMENT));
+    assert_eq!(
+        gatt::parse_uuid("0x2a1c"),
+        Some(gatt::TEMPERATURE_MEASUREMENT)
+    );
+    assert_eq!(
+        gatt::parse_uuid("00002A37-0000-1000-8000-00805F9B34FB"),
+        Some(0x2A37)
+    );
+    assert_eq!(
+        gatt::parse_uuid("12345678-0000-1000-8000-00805F9B34FB"),
+        None
+    );
+    assert_eq!(gatt::parse_uuid("2A3"), None);
+    assert!(invalid(gatt::decode(0x2A19, &[0x64])).contains("0x2A19"));
+}
+
+#[test]
+fn rr_intervals_become_their_own_series() {
+    let ts = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+    let measurement = gatt::decode(gatt::HEART_RATE_MEASUREMENT, &HR_FULL).unwrap();
+    let readings = gatt::to_readings("band-1", "p1", &measurement, ts);
+    let summary: Vec<_> = readings
+        .iter()
+        .map(|r| (r.code, r.value, r.unit.as_str(), r.ts))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (SignalCode::HeartRate, 72.0, "bpm", ts),
+            (
+                SignalCode::RrInterval,
+                1000.0,
+                "ms",
+                ts - chrono::Duration::milliseconds(750)
+            ),
+            (SignalCode::RrInterval, 750.0, "ms", ts),
+        ]
+    );
+
+    let measurement = gatt::decode(gatt::TEMPERATURE_MEASUREMENT, &TEMP_F_FULL).unwrap();
+    let Measurement::Temperature(t) = &measurement else {
+        panic!("expected a temperature");
+    };
+    let readings = gatt::to_readings("thermo-1", "p1", &measurement, ts);
+    assert_eq!(readings.len(), 1);
+    assert_eq!(
+        (readings[0].code, readings[0].unit.as_str()),
+        (SignalCode::BodyTemperature, "C")
+    );
+    assert_eq!(Some(readings[0].ts), t.ts);
+}
+
+fn upload(characteristic: &str, bytes: &[u8]) -> serde_json::Value {
+    serde_json::json!({
+        "device_id": "band-1",
+        "patient_id": "gatt-p1",
+        "characteristic": characteristic,
+        "payload": base64::engine::general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+#[actix_rt::test]
+async fn gatt_endpoint_stores_decoded_readings() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+    let filter = ObsFilter {
+        patient_id: Some("gatt-p1".into()),
+        ..ObsFilter::default()
+    };
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest/gatt")
+        .set_json(upload("0x2A37", &HR_FULL))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(body["measurement"]["characteristic"], "heart-rate");
+    assert_eq!(body["measurement"]["energy_expended_kj"], 272);
+    assert_eq!(body["observations"].as_array().unwrap().len(), 3);
+    assert_eq!(state.range(&filter).len(), 3);
+
+    // An RR interval of 100 ms is implausible: nothing from the payload is stored
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest/gatt")
+        .set_json(upload("2A37", &[0x10, 0x48, 0x66, 0x00]))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+    assert_eq!(state.range(&filter).len(), 3);
+
+    let mut oversized = vec![0x00, 0x48];
+    oversized.resize(gatt::MAX_PAYLOAD_BYTES + 1, 0);
+    for bad in [
+        upload("2A19", &[0x64]),
+        upload("2A1C", &[0x00, 0x6E]),
+        upload("2A37", &oversized),
+        serde_json::json!({ "device_id": "band-1", "patient_id": "gatt-p1", "characteristic": "2A37",
+            "payload": "A".repeat(100_000) }),
+        serde_json::json!({ "device_id": "band-1", "patient_id": "gatt-p1", "characteristic": "2A37",
+            "payload": "not base64!" }),
+    ] {
+        let req = actix_web::test::TestRequest::post()
+            .uri("/ingest/gatt")
+            .set_json(bad)
+            .to_request();
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+    }
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest/gatt")
+        .set_json(upload("2A1C", &TEMP_C))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 200);
+    let codes: Vec<_> = state
+        .range(&filter)
+        .iter()
+        .map(|o| o.reading.code)
+        .collect();
+    assert_eq!(
+        codes
+            .iter()
+            .filter(|c| **c == SignalCode::BodyTemperature)
+            .count(),
+        1
+    );
+}
+
+#[actix_rt::test]
+async fn gatt_payload_is_queued_all_or_nothing() {
+    let state = web::Data::new(AppState::new_demo());
+    let settings = PipelineSettings {
+        queue_capacity: 2,
+        stage_capacity: 4,
+    };
+    let pipeline = web::Data::new(Pipeline::start(state.clone().into_inner(), &settings));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(pipeline.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+
+    // Three readings never fit a queue of two, so retrying would not help
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest/gatt")
+        .set_json(upload("2A37", &HR_FULL))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+    assert_eq!(pipeline.queues()[0].depth, 0);
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest/gatt")
+        .set_json(upload("2A37", &[0x00, 0x48]))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 202);
+    pipeline.shutdown().await;
+    assert_eq!(state.len(), AppState::new_demo().len() + 1);
+}
@@ -0,0 +1,263 @@
+use actix_web::{test, web, App};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Request;
+
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+use pulsesense_backend::grpc::proto::pulse_sense_client::PulseSenseClient;
+use pulsesense_backend::grpc::proto::{self, ingest_ack, live_event};
+use pulsesense_backend::grpc::{GrpcServer, GrpcService};
+use pulsesense_backend::ratelimit::RateLimiter;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{AuthSettings, Limit, RateLimitSettings, RouteLimits, Settings};
+
+fn reading(
+    patient_id: &str,
+    code: proto::SignalCode,
+    value: f64,
+    unit: &str,
+) -> proto::SensorReading {
+    let now = chrono::Utc::now();
+    proto::SensorReading {
+        device_id: "grpc-d1".into(),
+        patient_id: patient_id.into(),
+        code: code.into(),
+        value,
+        unit: unit.into(),
+        ts: Some(prost_types::Timestamp {
+            seconds: now.timestamp(),
+            nanos: 0,
+        }),
+    }
+}
+
+async fn start(
+    state: Arc<AppState>,
+    settings: &Settings,
+) -> (GrpcServer, PulseSenseClient<tonic::transport::Channel>) {
+    let limiter = Arc::new(RateLimiter::new(settings.rate_limit.clone()));
+    let service = GrpcService::new(state, None, None, Some(limiter), settings);
+    let server = GrpcServer::bind("127.0.0.1:0", service).await.unwrap();
+    let client = PulseSenseClient::connect(format!("http://{}", server.local_addr()))
+        .await
+        .unwrap();
+    (server, client)
+}
+
+async fn all_acks(mut acks: tonic::Streaming<proto::IngestAck>) -> Vec<proto::IngestAck> {
+    let mut all = Vec::new();
+    while let Some(ack) = acks.message().await.unwrap() {
+        all.push(ack);
+    }
+    all
+}
+
+async fn next_ack(acks: &mut tonic::Streaming<proto::IngestAck>) -> Option<proto::IngestAck> {
+    tokio::time::timeout(Duration::from_secs(2), acks.message())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+async fn next(live: &mut tonic::Streaming<proto::LiveEvent>) -> Option<proto::LiveEvent> {
+    tokio::time::timeout(Duration::from_secs(2), live.message())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn ingest_stream_acks_each_reading_in_order() {
+    let state = Arc::new(AppState::new_demo());
+    let settings = Settings {
+        auth: AuthSettings {
+            ingest_token: Some("s3cret".into()),
+            ..AuthSettings::default()
+        },
+        ..Settings::default()
+    };
+    let (server, mut client) = start(state.clone(), &settings).await;
+    let before = state.len();
+
+    let mut no_ts = reading("grpc-p1", proto::SignalCode::HeartRate, 70.0, "bpm");
+    no_ts.ts = None;
+    let batch = vec![
+        reading("grpc-p1", proto::SignalCode::HeartRate, 72.0, "bpm"),
+        reading("grpc-p1", proto::SignalCode::HeartRate, 900.0, "bpm"),
+        reading("grpc-p1", proto::SignalCode::Unspecified, 72.0, "bpm"),
+        no_ts,
+        reading("grpc-p1", proto::SignalCode::BodyTemperature, 36.9, "C"),
+    ];
+
+    let err = client
+        .ingest(futures_util::stream::iter(batch.clone()))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    assert_eq!(state.len(), before);
+
+    let mut request = Request::new(futures_util::stream::iter(batch));
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer s3cret".parse().unwrap());
+    let acks = all_acks(client.ingest(request).await.unwrap().into_inner()).await;
+    let statuses: Vec<_> = acks.iter().map(|a| (a.index, a.status())).collect();
+    assert_eq!(
+        statuses,
+        [
+            (0, ingest_ack::Status::Accepted),
+            (1, ingest_ack::Status::Invalid),
+            (2, ingest_ack::Status::Invalid),
+            (3, ingest_ack::Status::Invalid),
+            (4, ingest_ack::Status::Accepted),
+        ]
+    );
+    assert!(acks[1].message.contains("heart-rate"));
+    assert!(acks[1].observation_id.is_empty());
+    assert_eq!(state.len(), before + 2);
+    let filter = ObsFilter {
+        patient_id: Some("grpc-p1".into()),
+        ..ObsFilter::default()
+    };
+    let stored: Vec<String> = state
+        .range(&filter)
+        .iter()
+        .map(|o| o.id.to_string())
+        .collect();
+    assert!(stored.contains(&acks[0].observation_id));
+
+    server.stop().await;
+}
+
+#[actix_rt::test]
+async fn acks_arrive_while_the_stream_is_open_and_devices_are_rate_limited() {
+    let state = Arc::new(AppState::new_demo());
+    let limits = RouteLimits {
+        per_device: Some(Limit::new(0.001, 1)),
+        ..RouteLimits::default()
+    };
+    let settings = Settings {
+        rate_limit: RateLimitSettings {
+            enabled: true,
+            routes: HashMap::from([("/ingest".to_string(), limits)]),
+        },
+        ..Settings::default()
+    };
+    let (server, mut client) = start(state.clone(), &settings).await;
+    let before = state.len();
+
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let readings =
+        futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|r| (r, rx)) });
+    let mut acks = client.ingest(readings).await.unwrap().into_inner();
+
+    tx.send(reading(
+        "grpc-p3",
+        proto::SignalCode::HeartRate,
+        72.0,
+        "bpm",
+    ))
+    .await
+    .unwrap();
+    let first = next_ack(&mut acks)
+        .await
+        .expect("acked before the stream closes");
+    assert_eq!(
+        (first.index, first.status()),
+        (0, ingest_ack::Status::Accepted)
+    );
+
+    tx.send(reading(
+        "grpc-p3",
+        proto::SignalCode::HeartRate,
+        73.0,
+        "bpm",
+    ))
+    .await
+    .unwrap();
+    let second = next_ack(&mut acks).await.unwrap();
+    assert_eq!(
+        (second.index, second.status()),
+        (1, ingest_ack::Status::RateLimited)
+    );
+    assert!(second.retry_after_secs > 0);
+    assert_eq!(state.len(), before + 1);
+
+    drop(tx);
+    assert!(next_ack(&mut acks).await.is_none());
+    server.stop().await;
+}
+
+#[actix_rt::test]
+async fn subscribe_streams_filtered_observations_alerts_and_shutdown() {
+    let state = Arc::new(AppState::new_demo());
+    let (server, mut client) = start(state.clone(), &Settings::default()).await;
+
+    let request = proto::SubscribeRequest {
+        patient_id: "grpc-p2".into(),
+        code: proto::SignalCode::HeartRate.into(),
+    };
+    let mut live = client.subscribe(request).await.unwrap().into_inner();
+    assert_eq!(state.ws_hub.client_count(), 1);
+
+    let readings = vec![
+        reading("someone-else", proto::SignalCode::HeartRate, 72.0, "bpm"),
+        reading("grpc-p2", proto::SignalCode::BodyTemperature, 36.9, "C"),
+        reading("grpc-p2", proto::SignalCode::HeartRate, 150.0, "bpm"),
+    ];
+    let acks = all_acks(
+        client
+            .ingest(futures_util::stream::iter(readings))
+            .await
+            .unwrap()
+            .into_inner(),
+    )
+    .await;
+
+    let Some(live_event::Event::Observation(obs)) = next(&mut live).await.unwrap().event else {
+        panic!("expected an observation");
+    };
+    assert_eq!(obs.id, acks[2].observation_id);
+    let reading = obs.reading.unwrap();
+    assert_eq!(
+        (reading.patient_id.as_str(), reading.code()),
+        ("grpc-p2", proto::SignalCode::HeartRate)
+    );
+    assert_eq!(reading.device_id, "grpc-d1");
+    assert_eq!(reading.value, 150.0);
+
+    let Some(live_event::Event::Alert(alert)) = next(&mut live).await.unwrap().event else {
+        panic!("expected an alert");
+    };
+    assert_eq!(alert.kind(), proto::alert::Kind::High);
+    assert_eq!(alert.observation_id, acks[2].observation_id);
+
+    state.ws_hub.close_all("server shutting down", 5);
+    let Some(live_event::Event::Shutdown(shutdown)) = next(&mut live).await.unwrap().event else {
+        panic!("expected shutdown");
+    };
+    assert_eq!(shutdown.reconnect_after_secs, 5);
+    assert!(next(&mut live).await.is_none());
+
+    let bad = proto::SubscribeRequest {
+        patient_id: String::new(),
+        code: 42,
+    };
+    assert_eq!(
+        client.subscribe(bad).await.unwrap_err().code(),
+        tonic::Code::InvalidArgument
+    );
+
+    server.stop().await;
+}
+
+#[actix_rt::test]
+async fn ws_live_rejects_unknown_code_filter() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+    let req = test::TestRequest::get()
+        .uri("/ws/live?code=blood-pressure")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
@@ -0,0 +1,140 @@
+use actix_web::{test, web, App};
+
+use pulsesense_backend::bulk::BulkExports;
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::health::{self, CheckStatus, Status};
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::Settings;
+
+fn reading(device_id: &str, secs_ago: i64) -> serde_json::Value {
+    serde_json::json!({
+        "device_id": device_id,
+        "patient_id": "p1",
+        "code": "heart-rate",
+        "value": 72.0,
+        "unit": "bpm",
+        "ts": chrono::Utc::now() - chrono::Duration::seconds(secs_ago),
+    })
+}
+
+#[actix_rt::test]
+async fn readyz_reports_checks_and_devices() {
+    let dir = std::env::temp_dir().join(format!("pulsesense-health-{}", uuid::Uuid::new_v4()));
+    let state = web::Data::new(AppState::new_demo());
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(web::Data::new(settings))
+            .app_data(web::Data::new(BulkExports::new(
+                dir.clone(),
+                std::time::Duration::from_secs(60),
+            )))
+            .configure(routes::configure),
+    )
+    .await;
+
+    for (device, lag) in [("d1", 2), ("d2", 30)] {
+        let req = test::TestRequest::post()
+            .uri("/ingest")
+            .set_json(reading(device, lag))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/readyz")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["checks"]["store"]["writable"], true);
+    assert_eq!(body["checks"]["store"]["observations"], 2);
+    assert!(body["checks"]["store"]["lag_secs"].as_f64().unwrap() >= 30.0);
+    assert_eq!(body["checks"]["queues"]["ws_clients"], 0);
+    assert_eq!(body["checks"]["tasks"]["compactor"]["status"], "ok");
+    let devices = body["devices"].as_array().unwrap();
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0]["device_id"], "d1");
+    assert_eq!(devices[1]["stale"], false);
+
+    // Anyone else gets the checks without device ids
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["checks"]["store"]["observations"], 2);
+    assert!(body.get("devices").is_none());
+
+    let req = test::TestRequest::get().uri("/livez").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[actix_rt::test]
+async fn stalled_compactor_and_silent_devices() {
+    let state = AppState::new_demo();
+    state.add_reading(serde_json::from_value(reading("d1", 0)).unwrap());
+    let settings = Settings::default();
+
+    let later = chrono::Utc::now() + chrono::Duration::minutes(10);
+    let report = health::readiness(&state, &settings, None, None, None, later).await;
+    assert_eq!(report.status, Status::Degraded);
+    assert_eq!(
+        report.checks.tasks["compactor"].status,
+        CheckStatus::Degraded
+    );
+    assert!(report.devices[0].stale);
+
+    // A compaction run brings it back
+    state.compact(later);
+    let report = health::readiness(&state, &settings, None, None, None, later).await;
+    assert_eq!(report.status, Status::Ready);
+}
+
+#[actix_rt::test]
+async fn unwritable_export_dir_degrades_readiness() {
+    // A directory can't be created under a regular file
+    let file = std::env::temp_dir().join(format!("pulsesense-health-{}", uuid::Uuid::new_v4()));
+    std::fs::write(&file, b"").unwrap();
+
+    let state = web::Data::new(AppState::new_demo());
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(web::Data::new(BulkExports::new(
+                file.join("exports"),
+                std::time::Duration::from_secs(60),
+            )))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "degraded");
+    assert_eq!(body["checks"]["store"]["writable"], false);
+    assert!(body["checks"]["store"]["error"]
+        .as_str()
+        .unwrap()
+        .contains("not writable"));
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[actix_rt::test]
+async fn poisoned_store_fails_liveness() {
+    let state = web::Data::new(AppState::new_demo());
+    state.poison_shard();
+    assert!(state.is_poisoned());
+
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+    for uri in ["/livez", "/healthz", "/readyz"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 503, "{}", uri);
+    }
+}
@@ -1,11 +1,10 @@
 use actix_web::{test, web, App};
-use std::sync::{Arc, Mutex};
 
 use pulsesense_backend::{domain::store::AppState, routes};
 
 #[actix_rt::test]
 async fn healthz_works() {
-    let state = web::Data::new(Arc::new(Mutex::new(AppState::new_demo())));
+    let state = web::Data::new(AppState::new_demo());
     let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
 
     let req = test::TestRequest::get().uri("/healthz").to_request();
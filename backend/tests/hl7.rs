@@ -0,0 +1,283 @@
+use chrono::{TimeZone, Utc};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+use pulsesense_backend::domain::alerts::{self, Alert};
+use pulsesense_backend::domain::models::{SensorReading, SignalCode, StoredObservation};
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::hl7::{self, Header, Hl7Forwarder};
+use pulsesense_backend::metrics::METRICS;
+use pulsesense_backend::pipeline;
+use pulsesense_backend::settings::{Hl7Settings, SignalRanges};
+use pulsesense_backend::ws::LiveEvent;
+
+fn observation(patient_id: &str, value: f64) -> StoredObservation {
+    StoredObservation {
+        id: uuid::Uuid::new_v4(),
+        reading: SensorReading {
+            device_id: "monitor|7".into(),
+            patient_id: patient_id.into(),
+            code: SignalCode::HeartRate,
+            value,
+            unit: "bpm".into(),
+            ts: Utc.with_ymd_and_hms(2026, 10, 18, 9, 15, 0).unwrap(),
+        },
+    }
+}
+
+fn header() -> Header {
+    Header {
+        sending_application: "PULSESENSE".into(),
+        sending_facility: "WARD-3".into(),
+        receiving_application: "ENGINE".into(),
+        receiving_facility: "HOSP".into(),
+    }
+}
+
+fn fields<'a>(message: &'a str, segment: &str) -> Vec<&'a str> {
+    message
+        .split('\r')
+        .find(|s| s.starts_with(segment))
+        .unwrap_or_else(|| panic!("no {} segment in {:?}", segment, message))
+        .split('|')
+        .collect()
+}
+
+#[test]
+fn observations_and_alerts_render_as_oru_r01() {
+    let signals = SignalRanges::default();
+    let now = Utc.with_ymd_and_hms(2026, 10, 18, 9, 15, 2).unwrap();
+    let obs = observation("p^1", 72.0);
+    let message = hl7::oru_r01(
+        &LiveEvent::Observation(obs.clone()),
+        &header(),
+        &signals,
+        "CTL1",
+        now,
+    );
+
+    assert!(message.ends_with('\r'));
+    let segments: Vec<&str> = message.trim_end().split('\r').map(|s| &s[..3]).collect();
+    assert_eq!(segments, ["MSH", "PID", "OBR", "OBX"]);
+    let msh = "MSH|^~\\&|PULSESENSE|WARD-3|ENGINE|HOSP|20261018091502+0000||ORU^R01^ORU_R01|CTL1|P|2.5.1\r";
+    assert!(message.starts_with(msh));
+    assert_eq!(fields(&message, "PID")[3], "p\\S\\1^^^PULSESENSE^MR");
+    let obr = fields(&message, "OBR");
+    assert_eq!(obr[3], format!("{}^PULSESENSE", obs.id));
+    assert_eq!(obr[4], "8867-4^Heart Rate^LN");
+    assert_eq!((obr[7], obr[25]), ("20261018091500+0000", "F"));
+    let obx = fields(&message, "OBX");
+    assert_eq!(
+        (obx[2], obx[3], obx[5], obx[6]),
+        ("NM", "8867-4^Heart Rate^LN", "72", "bpm")
+    );
+    assert_eq!((obx[7], obx[8], obx[11]), ("40-130", "", "F"));
+    assert_eq!((obx[14], obx[18]), ("20261018091500+0000", "monitor\\F\\7"));
+
+    let high = observation("p1", 150.0);
+    let alert: Alert = alerts::evaluate(signals.get(SignalCode::HeartRate), &high).unwrap();
+    let message = hl7::oru_r01(
+        &LiveEvent::Alert(alert.clone()),
+        &header(),
+        &signals,
+        "CTL2",
+        now,
+    );
+    assert_eq!(
+        fields(&message, "OBR")[3],
+        format!("{}^PULSESENSE", alert.id)
+    );
+    let obx = fields(&message, "OBX");
+    assert_eq!((obx[5], obx[8]), ("150", "H"));
+    assert_eq!(
+        fields(&message, "NTE")[3],
+        "high alert: 150 bpm is above the threshold of 130"
+    );
+
+    let mut rr = observation("p1", 812.5);
+    rr.reading.code = SignalCode::RrInterval;
+    let message = hl7::oru_r01(
+        &LiveEvent::Observation(rr),
+        &header(),
+        &signals,
+        "CTL3",
+        now,
+    );
+    assert_eq!(fields(&message, "OBX")[3], "rr-interval^RR Interval^L");
+    assert_eq!(fields(&message, "OBX")[7], "");
+
+    let mut temperature = observation("p1", 36.9);
+    temperature.reading.code = SignalCode::BodyTemperature;
+    temperature.reading.unit = "°C".into();
+    let message = hl7::oru_r01(
+        &LiveEvent::Observation(temperature),
+        &header(),
+        &signals,
+        "CTL4",
+        now,
+    );
+    assert_eq!(fields(&message, "OBX")[6], "Cel");
+}
+
+#[actix_rt::test]
+async fn acks_are_parsed_from_mllp_frames() {
+    let ack =
+        "MSH|^~\\&|ENGINE||PULSESENSE||20261018||ACK^R01^ACK|9|P|2.5.1\rMSA|AE|CTL1|bad OBX\r";
+    let mut bytes = b"noise".to_vec();
+    bytes.extend(hl7::frame(ack));
+    bytes.extend(hl7::frame("MSH|^~\\&\rMSA|AA|CTL2\r"));
+    let mut reader = bytes.as_slice();
+    let mut buf = Vec::new();
+
+    let parsed = hl7::parse_ack(&hl7::read_frame(&mut reader, &mut buf).await.unwrap()).unwrap();
+    assert_eq!(
+        (
+            parsed.code.as_str(),
+            parsed.control_id.as_str(),
+            parsed.text.as_str()
+        ),
+        ("AE", "CTL1", "bad OBX")
+    );
+    assert!(!parsed.accepted() && parsed.is_permanent_error());
+    let parsed = hl7::parse_ack(&hl7::read_frame(&mut reader, &mut buf).await.unwrap()).unwrap();
+    assert!(parsed.accepted());
+    assert!(hl7::read_frame(&mut reader, &mut buf).await.is_err());
+    assert_eq!(hl7::parse_ack("MSH|^~\\&\r"), None);
+}
+
+#[derive(Clone, Copy)]
+enum Reply {
+    Hangup,
+    Ack(&'static str),
+}
+
+/// Stand-in interface engine: records each message and answers from the
+/// script, in order.
+async fn start_listener(script: Vec<Reply>) -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    tokio::spawn(async move {
+        let mut script = script.into_iter();
+        while let Ok((mut sock, _)) = listener.accept().await {
+            let mut buf = Vec::new();
+            while let Ok(message) = hl7::read_frame(&mut sock, &mut buf).await {
+                let control_id = fields(&message, "MSH")[9].to_string();
+                log.lock().unwrap().push(message);
+                match script.next().unwrap_or(Reply::Ack("AA")) {
+                    Reply::Hangup => break,
+                    Reply::Ack(code) => {
+                        let ack = format!(
+                            "MSH|^~\\&|ENGINE||PULSESENSE||20261018||ACK^R01^ACK|1|P|2.5.1\rMSA|{}|{}\r",
+                            code, control_id
+                        );
+                        sock.write_all(&hl7::frame(&ack)).await.unwrap();
+                    }
+                }
+            }
+        }
+    });
+    (port, received)
+}
+
+#[actix_rt::test]
+async fn forwarder_retries_until_acked_and_drops_on_application_error() {
+    // The observation is hung up on, then rejected (AR), then accepted;
+    // the alert gets an application error (AE) and is not resent
+    let script = vec![
+        Reply::Hangup,
+        Reply::Ack("AR"),
+        Reply::Ack("AA"),
+        Reply::Ack("AE"),
+    ];
+    let (port, received) = start_listener(script).await;
+    let state = Arc::new(AppState::new_demo());
+    let settings = Hl7Settings {
+        enabled: true,
+        host: "127.0.0.1".into(),
+        port,
+        retry_initial_ms: 10,
+        ..Hl7Settings::default()
+    };
+    let forwarder = Hl7Forwarder::start(state.clone(), &settings);
+
+    let obs = pipeline::admit(&state, observation("hl7-p1", 150.0).reading).unwrap();
+    assert!(pipeline::process(&state, &obs).is_some());
+
+    for _ in 0..300 {
+        if received.lock().unwrap().len() >= 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Raised while the pipeline drains, after the hub closed its clients
+    state.ws_hub.close_all("server shutting down", 5);
+    let late = pipeline::admit(&state, observation("hl7-p1", 80.0).reading).unwrap();
+    pipeline::process(&state, &late);
+    forwarder.stop().await;
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 5);
+    let control_ids: Vec<&str> = received.iter().map(|m| fields(m, "MSH")[9]).collect();
+    assert_eq!(control_ids[0], control_ids[1]);
+    assert_eq!(control_ids[1], control_ids[2]);
+    assert_ne!(control_ids[2], control_ids[3]);
+    assert_eq!(
+        fields(&received[0], "OBR")[3],
+        format!("{}^PULSESENSE", obs.id)
+    );
+    assert_eq!(fields(&received[3], "OBX")[8], "H");
+    assert_eq!(
+        fields(&received[4], "OBR")[3],
+        format!("{}^PULSESENSE", late.id)
+    );
+    assert_eq!(state.ws_hub.client_count(), 0);
+}
+
+#[actix_rt::test]
+async fn messages_beyond_the_queue_are_dropped_and_counted() {
+    // Takes connections but never answers, so the first message holds the line
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((sock, _)) = listener.accept().await {
+            held.push(sock);
+        }
+    });
+    let state = Arc::new(AppState::new_demo());
+    let settings = Hl7Settings {
+        enabled: true,
+        host: "127.0.0.1".into(),
+        port,
+        ack_timeout_secs: 1,
+        max_attempts: 1,
+        queue_capacity: 1,
+        ..Hl7Settings::default()
+    };
+    let dropped = || {
+        METRICS
+            .hl7_messages_total
+            .with_label_values(&["observation", "dropped"])
+            .get()
+    };
+    let before = dropped();
+    let forwarder = Hl7Forwarder::start(state.clone(), &settings);
+
+    for value in [70.0, 71.0, 72.0, 73.0, 74.0] {
+        let obs = pipeline::admit(&state, observation("hl7-p2", value).reading).unwrap();
+        pipeline::process(&state, &obs);
+    }
+    // At most one is being sent and one is queued
+    for _ in 0..200 {
+        if dropped() - before >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(dropped() - before >= 3);
+    forwarder.stop().await;
+}
@@ -0,0 +1,136 @@
+use actix_web::{middleware, test, web, App};
+
+use pulsesense_backend::domain::models::StoredObservation;
+use pulsesense_backend::metrics::METRICS;
+use pulsesense_backend::pipeline::Pipeline;
+use pulsesense_backend::settings::{PipelineSettings, Settings};
+use pulsesense_backend::{domain::store::AppState, metrics, routes};
+
+mod common;
+use common::reading;
+
+fn request_count(method: &str, route: &str, status: &str) -> u64 {
+    METRICS
+        .http_request_duration
+        .with_label_values(&[method, route, status])
+        .get_sample_count()
+}
+
+#[actix_rt::test]
+async fn metrics_reflect_ingest_and_errors() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(web::Data::new(Settings::default()))
+            .wrap(middleware::from_fn(metrics::track_latency))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let accepted = METRICS
+        .ingest_total
+        .with_label_values(&["heart-rate", "accepted"]);
+    let rejected = METRICS
+        .ingest_total
+        .with_label_values(&["heart-rate", "rejected"]);
+    let validation = METRICS.errors_total.with_label_values(&["validation"]);
+    let before = (accepted.get(), rejected.get(), validation.get());
+    let (ok_before, bad_before) = (
+        request_count("POST", "/ingest", "200"),
+        request_count("POST", "/ingest", "400"),
+    );
+
+    for value in [72.0, 400.0, 75.0] {
+        let req = test::TestRequest::post()
+            .uri("/ingest")
+            .set_json(reading(value))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    assert_eq!(
+        (accepted.get(), rejected.get(), validation.get()),
+        (before.0 + 2, before.1 + 1, before.2 + 1)
+    );
+    assert_eq!(request_count("POST", "/ingest", "200"), ok_before + 2);
+    assert_eq!(request_count("POST", "/ingest", "400"), bad_before + 1);
+
+    // Labelled by route pattern, not by path; unknown paths share one series
+    let req = test::TestRequest::get()
+        .uri(&format!("/fhir/$export-status/{}", uuid::Uuid::new_v4()))
+        .to_request();
+    let status = test::call_service(&app, req).await.status();
+    let req = test::TestRequest::get().uri("/no/such/path").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let text = std::str::from_utf8(&body).unwrap();
+
+    for line in [
+        format!(r#"pulsesense_ingest_total{{code="heart-rate",result="accepted"}} {}"#, accepted.get()),
+        format!(r#"pulsesense_ingest_total{{code="heart-rate",result="rejected"}} {}"#, rejected.get()),
+        format!(r#"pulsesense_errors_total{{variant="validation"}} {}"#, validation.get()),
+        "pulsesense_store_observations".to_string(),
+        "pulsesense_ws_clients".to_string(),
+        r#"pulsesense_http_request_duration_seconds_count{method="POST",route="/ingest",status="200"}"#.to_string(),
+        r#"pulsesense_http_request_duration_seconds_count{method="POST",route="/ingest",status="400"}"#.to_string(),
+        format!(
+            r#"pulsesense_http_request_duration_seconds_count{{method="GET",route="/fhir/$export-status/{{id}}",status="{}"}}"#,
+            status.as_str()
+        ),
+        r#"pulsesense_http_request_duration_seconds_count{method="GET",route="unmatched",status="404"}"#.to_string(),
+    ] {
+        assert!(text.contains(&line), "missing {} in:\n{}", line, text);
+    }
+    assert!(!text.contains("/no/such/path"));
+}
+
+#[actix_rt::test]
+async fn pipeline_gauges_follow_backpressure() {
+    let state = web::Data::new(AppState::new_demo());
+    let settings = PipelineSettings {
+        queue_capacity: 2,
+        stage_capacity: 4,
+    };
+    let pipeline = Pipeline::start(state.clone().into_inner(), &settings);
+    let depth = METRICS.pipeline_queue_depth.with_label_values(&["persist"]);
+    let rejected_before = METRICS.pipeline_rejected_total.get();
+    let stored = |value| StoredObservation {
+        id: uuid::Uuid::new_v4(),
+        reading: reading(value),
+    };
+
+    // The stages only run once this task yields, so the queue stays full
+    pipeline.submit(stored(70.0)).unwrap();
+    pipeline.submit(stored(71.0)).unwrap();
+    assert_eq!(depth.get(), 2);
+    assert!(pipeline.submit(stored(72.0)).is_err());
+    assert!(pipeline.submit_all(vec![stored(73.0)]).is_err());
+    assert_eq!(METRICS.pipeline_rejected_total.get(), rejected_before + 2);
+    assert_eq!(depth.get(), 2, "refused readings are not counted as queued");
+
+    pipeline.shutdown().await;
+    for stage in pulsesense_backend::pipeline::STAGES {
+        assert_eq!(
+            METRICS
+                .pipeline_queue_depth
+                .with_label_values(&[stage])
+                .get(),
+            0,
+            "{}",
+            stage
+        );
+        assert!(
+            METRICS
+                .pipeline_stage_duration
+                .with_label_values(&[stage])
+                .get_sample_count()
+                >= 2,
+            "{}",
+            stage
+        );
+    }
+    assert_eq!(state.len(), 2);
+}
@@ -0,0 +1,345 @@
+use bytes::BytesMut;
+use rumqttc::{
+    matches, read, AsyncClient, ConnAck, ConnectReturnCode, Event, MqttOptions, Packet, PingResp,
+    PubAck, Publish, QoS, SubAck, SubscribeReasonCode,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+use pulsesense_backend::errors::AppError;
+use pulsesense_backend::mqtt::{self, MqttBridge, TopicFields, TopicTemplate};
+use pulsesense_backend::pipeline::{self, Pipeline};
+use pulsesense_backend::settings::{MqttSettings, PipelineSettings};
+
+type Subscriptions = Arc<Mutex<Vec<(String, mpsc::UnboundedSender<Publish>)>>>;
+
+/// Just enough of an MQTT 3.1.1 broker for the bridge: connect, subscribe,
+/// publish (QoS 0 fan-out, QoS 1 acked) and ping.
+async fn start_broker() -> (u16, Subscriptions) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let subs = Subscriptions::default();
+    let shared = subs.clone();
+    tokio::spawn(async move {
+        while let Ok((sock, _)) = listener.accept().await {
+            tokio::spawn(serve(sock, shared.clone()));
+        }
+    });
+    (port, subs)
+}
+
+async fn serve(mut sock: TcpStream, subs: Subscriptions) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Publish>();
+    let mut buf = BytesMut::new();
+    loop {
+        let mut out = BytesMut::new();
+        tokio::select! {
+            n = sock.read_buf(&mut buf) => {
+                if !matches!(n, Ok(n) if n > 0) {
+                    return;
+                }
+                while let Ok(packet) = read(&mut buf, 1 << 20) {
+                    match packet {
+                        Packet::Connect(_) => ConnAck::new(ConnectReturnCode::Success, false).write(&mut out).unwrap(),
+                        Packet::Subscribe(s) => {
+                            let codes = vec![SubscribeReasonCode::Success(QoS::AtMostOnce); s.filters.len()];
+                            subs.lock().unwrap().extend(s.filters.into_iter().map(|f| (f.path, tx.clone())));
+                            SubAck::new(s.pkid, codes).write(&mut out).unwrap()
+                        }
+                        Packet::Publish(p) => {
+                            for (filter, sub) in subs.lock().unwrap().iter() {
+                                if matches(&p.topic, filter) {
+                                    let _ = sub.send(p.clone());
+                                }
+                            }
+                            if p.qos == QoS::AtLeastOnce {
+                                PubAck::new(p.pkid).write(&mut out).unwrap();
+                            }
+                            0
+                        }
+                        Packet::PingReq => PingResp.write(&mut out).unwrap(),
+                        Packet::Disconnect => return,
+                        _ => 0,
+                    };
+                }
+            }
+            Some(p) = rx.recv() => {
+                Publish::new(p.topic, QoS::AtMostOnce, p.payload.to_vec()).write(&mut out).unwrap();
+            }
+        }
+        if sock.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn settings(port: u16) -> MqttSettings {
+    MqttSettings {
+        enabled: true,
+        host: "127.0.0.1".into(),
+        port,
+        ..MqttSettings::default()
+    }
+}
+
+async fn eventually(what: &str, mut done: impl FnMut() -> bool) {
+    for _ in 0..300 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
+
+/// A gateway that also listens for alerts.
+async fn gateway(
+    port: u16,
+    subs: &Subscriptions,
+) -> (AsyncClient, mpsc::UnboundedReceiver<Publish>) {
+    let (gateway, mut eventloop) =
+        AsyncClient::new(MqttOptions::new("gateway", "127.0.0.1", port), 16);
+    let (alerts_tx, alerts) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(event) = eventloop.poll().await {
+            if let Event::Incoming(Packet::Publish(p)) = event {
+                let _ = alerts_tx.send(p);
+            }
+        }
+    });
+    gateway
+        .subscribe("pulsesense/+/alerts/+", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    eventually("subscriptions", || subs.lock().unwrap().len() == 2).await;
+    (gateway, alerts)
+}
+
+#[actix_rt::test]
+async fn readings_are_ingested_and_alerts_published() {
+    let (port, subs) = start_broker().await;
+    let state = Arc::new(AppState::new_demo());
+    let pipeline = Arc::new(Pipeline::start(state.clone(), &PipelineSettings::default()));
+    let bridge =
+        MqttBridge::start(state.clone(), Some(pipeline.clone()), None, &settings(port)).unwrap();
+    let (gateway, mut alerts) = gateway(port, &subs).await;
+    // Listening for alerts does not make the bridge a WebSocket client
+    assert_eq!(state.ws_hub.client_count(), 0);
+
+    let reading = serde_json::json!({
+        "patient_id": "mqtt-p1",
+        "value": 150.0,
+        "unit": "bpm",
+        "ts": chrono::Utc::now(),
+    });
+    gateway
+        .publish(
+            "pulsesense/dev-1/heart-rate",
+            QoS::AtLeastOnce,
+            false,
+            reading.to_string(),
+        )
+        .await
+        .unwrap();
+    let fhir = serde_json::json!({
+        "resourceType": "Observation",
+        "status": "final",
+        "code": { "coding": [{ "system": "http://loinc.org", "code": "8310-5" }] },
+        "subject": { "reference": "Patient/mqtt-p1" },
+        "effectiveDateTime": chrono::Utc::now(),
+        "valueQuantity": { "value": 36.8, "unit": "C" },
+    });
+    gateway
+        .publish(
+            "pulsesense/dev-2/body-temperature",
+            QoS::AtLeastOnce,
+            false,
+            fhir.to_string(),
+        )
+        .await
+        .unwrap();
+    // Topic and payload disagree on the signal: rejected
+    let mismatched = serde_json::json!({ "code": "steps-per-minute", "patient_id": "mqtt-p1", "value": 10.0,
+        "unit": "steps/min", "ts": chrono::Utc::now() });
+    gateway
+        .publish(
+            "pulsesense/dev-1/heart-rate",
+            QoS::AtLeastOnce,
+            false,
+            mismatched.to_string(),
+        )
+        .await
+        .unwrap();
+
+    let filter = ObsFilter {
+        patient_id: Some("mqtt-p1".into()),
+        ..ObsFilter::default()
+    };
+    eventually("stored readings", || state.range(&filter).len() == 2).await;
+    let stored = state.range(&filter);
+    let hr = stored
+        .iter()
+        .find(|o| o.reading.code == SignalCode::HeartRate)
+        .unwrap();
+    assert_eq!(hr.reading.device_id, "dev-1");
+    let temp = stored
+        .iter()
+        .find(|o| o.reading.code == SignalCode::BodyTemperature)
+        .unwrap();
+    assert_eq!(temp.reading.device_id, "dev-2");
+
+    let alert = tokio::time::timeout(Duration::from_secs(3), alerts.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alert.topic, "pulsesense/mqtt-p1/alerts/heart-rate");
+    let body: serde_json::Value = serde_json::from_slice(&alert.payload).unwrap();
+    assert_eq!(body["kind"], "high");
+    assert_eq!(body["observation_id"], hr.id.to_string());
+
+    bridge.stop().await;
+}
+
+#[actix_rt::test]
+async fn alerts_raised_while_the_pipeline_drains_are_published() {
+    let (port, subs) = start_broker().await;
+    let state = Arc::new(AppState::new_demo());
+    let pipeline = Arc::new(Pipeline::start(state.clone(), &PipelineSettings::default()));
+    let mut bridge =
+        MqttBridge::start(state.clone(), Some(pipeline.clone()), None, &settings(port)).unwrap();
+    let (gateway, mut alerts) = gateway(port, &subs).await;
+
+    // Shutdown: ingest stops first, and WebSocket clients are told to go
+    bridge.stop_ingest().await;
+    state.ws_hub.close_all("server shutting down", 5);
+    let late = serde_json::json!({ "patient_id": "mqtt-late", "value": 150.0, "unit": "bpm",
+        "ts": chrono::Utc::now() });
+    gateway
+        .publish(
+            "pulsesense/dev-1/heart-rate",
+            QoS::AtLeastOnce,
+            false,
+            late.to_string(),
+        )
+        .await
+        .unwrap();
+
+    // A reading still in the pipeline raises its alert while it drains
+    let queued = pipeline::admit(
+        &state,
+        SensorReading {
+            device_id: "dev-1".into(),
+            patient_id: "mqtt-p2".into(),
+            code: SignalCode::HeartRate,
+            value: 150.0,
+            unit: "bpm".into(),
+            ts: chrono::Utc::now(),
+        },
+    )
+    .unwrap();
+    pipeline.send(queued).await.unwrap();
+    pipeline.shutdown().await;
+    bridge.stop().await;
+
+    let alert = tokio::time::timeout(Duration::from_secs(3), alerts.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alert.topic, "pulsesense/mqtt-p2/alerts/heart-rate");
+    // Published after ingest stopped, so never taken
+    let late = ObsFilter {
+        patient_id: Some("mqtt-late".into()),
+        ..ObsFilter::default()
+    };
+    assert!(state.range(&late).is_empty());
+}
+
+#[test]
+fn topic_templates_and_payload_decoding() {
+    let t = TopicTemplate::parse("site/{patient_id}/{device_id}/{code}").unwrap();
+    assert_eq!(t.filter(), "site/+/+/+");
+    let fields = t.capture("site/p1/d1/heart-rate").unwrap();
+    assert_eq!(fields.device_id.as_deref(), Some("d1"));
+    assert_eq!(fields.patient_id.as_deref(), Some("p1"));
+    assert!(t.capture("site/p1/d1").is_none());
+    assert!(t.capture("other/p1/d1/heart-rate").is_none());
+    assert_eq!(t.render(&fields), "site/p1/d1/heart-rate");
+
+    assert!(TopicTemplate::parse("site/dev-{device_id}").is_err());
+    assert!(TopicTemplate::parse("site/+/{code}").is_err());
+    assert!(TopicTemplate::parse("{code}/{code}").is_err());
+
+    let fields = TopicFields {
+        device_id: Some("d1".into()),
+        ..TopicFields::default()
+    };
+    let full = serde_json::json!({ "device_id": "d1", "patient_id": "p1", "code": "heart-rate", "value": 70.0,
+        "unit": "bpm", "ts": "2026-01-01T00:00:00Z" });
+    assert_eq!(
+        mqtt::decode(&fields, full.to_string().as_bytes())
+            .unwrap()
+            .patient_id,
+        "p1"
+    );
+
+    let other_device = full.to_string().replace("\"d1\"", "\"d9\"");
+    assert!(matches!(
+        mqtt::decode(&fields, other_device.as_bytes()),
+        Err(AppError::Validation(_))
+    ));
+    assert!(matches!(
+        mqtt::decode(&fields, b"not json"),
+        Err(AppError::Validation(_))
+    ));
+
+    let fhir = serde_json::json!({ "resourceType": "Observation", "code": { "text": "Heart Rate" },
+        "subject": { "reference": "Patient/p1" }, "effectiveDateTime": "2026-01-01T00:00:00Z",
+        "valueQuantity": { "value": 70.0, "unit": "bpm" } });
+    let reading = mqtt::decode(&fields, fhir.to_string().as_bytes()).unwrap();
+    assert_eq!(reading.device_id, "d1");
+    assert_eq!(reading.code, SignalCode::HeartRate);
+}
+
+#[actix_rt::test]
+async fn tls_connects_with_a_client_hello() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let key = rcgen::KeyPair::generate().unwrap();
+    let ca = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+    let ca_path =
+        std::env::temp_dir().join(format!("pulsesense-mqtt-ca-{}.pem", uuid::Uuid::new_v4()));
+    std::fs::write(&ca_path, ca.pem()).unwrap();
+    let state = Arc::new(AppState::new_demo());
+
+    let mut missing = settings(port);
+    missing.tls = true;
+    missing.ca_path = Some(ca_path.with_extension("missing"));
+    assert!(MqttBridge::start(state.clone(), None, None, &missing).is_err());
+
+    let tls = MqttSettings {
+        tls: true,
+        ca_path: Some(ca_path.clone()),
+        ..settings(port)
+    };
+    let bridge = MqttBridge::start(state, None, None, &tls).unwrap();
+    let (mut sock, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    // A TLS handshake record rather than an MQTT CONNECT (0x10)
+    let mut first = [0u8; 1];
+    sock.read_exact(&mut first).await.unwrap();
+    assert_eq!(first[0], 0x16);
+
+    drop(sock);
+    bridge.stop().await;
+    std::fs::remove_file(&ca_path).unwrap();
+}
@@ -0,0 +1,74 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use opentelemetry::trace::TracerProvider;
+use pulsesense_backend::telemetry::otlp_tracer_provider;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Minimal OTLP/HTTP collector: accepts one request and reports its
+/// request line, content type and body size.
+fn collector() -> (String, mpsc::Receiver<(String, String, usize)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let (mut content_type, mut length) = (String::new(), 0usize);
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            match name.to_ascii_lowercase().as_str() {
+                "content-type" => content_type = value.trim().to_string(),
+                "content-length" => length = value.trim().parse().unwrap(),
+                _ => {}
+            }
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut stream = stream;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+        tx.send((
+            request_line.trim_end().to_string(),
+            content_type,
+            body.len(),
+        ))
+        .unwrap();
+    });
+
+    (endpoint, rx)
+}
+
+#[test]
+fn spans_are_exported_to_the_collector() {
+    let (endpoint, rx) = collector();
+    let provider = otlp_tracer_provider(&endpoint, "pulsesense-test").unwrap();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("pulsesense")));
+
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("ingest", device_id = "d1").entered();
+        tracing::info_span!("validate").in_scope(|| {});
+    });
+    provider.force_flush().unwrap();
+
+    let (request_line, content_type, len) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+    assert_eq!(content_type, "application/x-protobuf");
+    assert!(len > 0);
+    provider.shutdown().unwrap();
+}
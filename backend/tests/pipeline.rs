@@ -0,0 +1,189 @@
+use actix_web::{test, web, App};
+use std::time::Duration;
+
+use pulsesense_backend::domain::models::StoredObservation;
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::errors::AppError;
+use pulsesense_backend::pipeline::Pipeline;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{PipelineSettings, Settings};
+use pulsesense_backend::ws::{HubEvent, LiveEvent};
+
+mod common;
+use common::reading;
+
+fn stored(value: f64) -> StoredObservation {
+    StoredObservation {
+        id: uuid::Uuid::new_v4(),
+        reading: reading(value),
+    }
+}
+
+fn settings(queue_capacity: usize) -> PipelineSettings {
+    PipelineSettings {
+        queue_capacity,
+        stage_capacity: 4,
+    }
+}
+
+async fn wait_for_len(state: &AppState, len: usize) {
+    for _ in 0..200 {
+        if state.len() == len {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("store has {} readings, expected {}", state.len(), len);
+}
+
+#[actix_rt::test]
+async fn ingest_is_accepted_then_stored() {
+    let state = web::Data::new(AppState::new_demo());
+    let pipeline = web::Data::new(Pipeline::start(state.clone().into_inner(), &settings(16)));
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(pipeline.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading(72.0))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert!(body["id"].is_string());
+
+    wait_for_len(&state, 1).await;
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["checks"]["queues"]["pipeline"][0]["stage"], "persist");
+    assert_eq!(body["checks"]["queues"]["pipeline"][0]["capacity"], 16);
+
+    // Validation still happens before anything is queued
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading(900.0))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_rt::test]
+async fn full_queue_is_refused_with_503() {
+    let state = web::Data::new(AppState::new_demo());
+    let pipeline = web::Data::new(Pipeline::start(state.clone().into_inner(), &settings(2)));
+    let app = test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(pipeline.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+
+    // The stages only run once this task yields, so the queue stays full
+    pipeline.submit(stored(70.0)).unwrap();
+    pipeline.submit(stored(71.0)).unwrap();
+    assert!(matches!(
+        pipeline.submit(stored(72.0)),
+        Err(AppError::Unavailable(_))
+    ));
+    assert!(pipeline.is_full());
+
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading(73.0))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+
+    wait_for_len(&state, 2).await;
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading(74.0))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 202);
+}
+
+#[actix_rt::test]
+async fn threshold_alerts_follow_the_observation() {
+    let state = web::Data::new(AppState::new_demo());
+    let pipeline = Pipeline::start(state.clone().into_inner(), &settings(16));
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    state.ws_hub.add_client(tx);
+
+    pipeline.submit(stored(72.0)).unwrap();
+    pipeline.submit(stored(150.0)).unwrap();
+
+    let mut messages = Vec::new();
+    while messages.len() < 3 {
+        let event = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let HubEvent::Text(text) = event else {
+            panic!("unexpected hub event");
+        };
+        messages.push(serde_json::from_str::<serde_json::Value>(&text).unwrap());
+    }
+    assert_eq!(messages[0]["resourceType"], "Observation");
+    assert_eq!(messages[1]["resourceType"], "Observation");
+    assert_eq!(messages[2]["type"], "alert");
+    assert_eq!(messages[2]["alert"]["kind"], "high");
+    assert_eq!(messages[2]["alert"]["threshold"], 130.0);
+    assert_eq!(messages[2]["alert"]["patient_id"], "p1");
+}
+
+#[actix_rt::test]
+async fn listeners_get_typed_events_until_removed() {
+    let state = web::Data::new(AppState::new_demo());
+    let pipeline = Pipeline::start(state.clone().into_inner(), &settings(16));
+    let (id, mut events) = state.ws_hub.add_listener();
+    assert_eq!(state.ws_hub.client_count(), 0);
+
+    let high = stored(150.0);
+    pipeline.submit(high.clone()).unwrap();
+    pipeline.shutdown().await;
+    // Published before removal, so still read afterwards
+    state.ws_hub.remove_client(id);
+
+    let Some(event) = events.recv().await else {
+        panic!("observation not published");
+    };
+    let LiveEvent::Observation(obs) = &*event else {
+        panic!("expected the observation first");
+    };
+    assert_eq!(obs.id, high.id);
+    let Some(event) = events.recv().await else {
+        panic!("alert not published");
+    };
+    let LiveEvent::Alert(alert) = &*event else {
+        panic!("expected an alert");
+    };
+    assert_eq!(alert.observation_id, high.id);
+    assert!(events.recv().await.is_none());
+}
+
+#[actix_rt::test]
+async fn shutdown_drains_queued_readings() {
+    let state = web::Data::new(AppState::new_demo());
+    let pipeline = Pipeline::start(state.clone().into_inner(), &settings(64));
+    let before = state.len();
+
+    for i in 0..50 {
+        pipeline.submit(stored(60.0 + i as f64)).unwrap();
+    }
+    pipeline.shutdown().await;
+
+    assert_eq!(state.len(), before + 50);
+    assert!(pipeline.queues().iter().all(|q| q.depth == 0));
+    assert!(matches!(
+        pipeline.submit(stored(70.0)),
+        Err(AppError::Unavailable(_))
+    ));
+}
@@ -0,0 +1,187 @@
+use actix_web::{middleware, test, web, App};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use pulsesense_backend::ratelimit::{self, KeyKind, RateLimiter};
+use pulsesense_backend::settings::{Limit, RateLimitSettings, RouteLimits, Settings};
+use pulsesense_backend::{domain::store::AppState, routes};
+
+fn limits(per_device: Option<Limit>, per_ip: Option<Limit>) -> RateLimitSettings {
+    let route = RouteLimits {
+        per_device,
+        per_token: None,
+        per_ip,
+    };
+    RateLimitSettings {
+        enabled: true,
+        routes: HashMap::from([("/ingest".to_string(), route)]),
+    }
+}
+
+fn reading(device_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "device_id": device_id,
+        "patient_id": "p1",
+        "code": "heart-rate",
+        "value": 72.0,
+        "unit": "bpm",
+        "ts": chrono::Utc::now(),
+    })
+}
+
+#[actix_rt::test]
+async fn device_limit_returns_429_with_retry_after() {
+    let state = web::Data::new(AppState::new_demo());
+    let limiter = web::Data::new(RateLimiter::new(limits(Some(Limit::new(0.5, 2)), None)));
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(web::Data::new(Settings::default()))
+            .app_data(limiter)
+            .wrap(middleware::from_fn(ratelimit::limit_requests))
+            .configure(routes::configure),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::post()
+            .uri("/ingest")
+            .set_json(reading("d1"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading("d1"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "2");
+
+    // Other devices have their own bucket.
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading("d2"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains(r#"pulsesense_rate_limited_total{key="device",route="/ingest"}"#));
+    assert!(text.contains(r#"pulsesense_errors_total{variant="rate_limited"}"#));
+}
+
+#[actix_rt::test]
+async fn ip_limit_applies_before_the_handler() {
+    let state = web::Data::new(AppState::new_demo());
+    let limiter = web::Data::new(RateLimiter::new(limits(None, Some(Limit::new(1.0, 1)))));
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(web::Data::new(Settings::default()))
+            .app_data(limiter)
+            .wrap(middleware::from_fn(ratelimit::limit_requests))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let peer = "10.0.0.7:4000".parse().unwrap();
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .peer_addr(peer)
+        .set_json(reading("d1"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    // Rejected even with an invalid body: the handler never runs.
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .peer_addr(peer)
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 429);
+
+    let other = "10.0.0.8:4000".parse().unwrap();
+    let req = test::TestRequest::post()
+        .uri("/ingest")
+        .peer_addr(other)
+        .set_json(reading("d1"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    // Unlimited routes pass through.
+    let req = test::TestRequest::get()
+        .uri("/healthz")
+        .peer_addr(peer)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
+
+#[actix_rt::test]
+async fn buckets_refill_over_time() {
+    let limiter = RateLimiter::new(limits(Some(Limit::new(2.0, 1)), None));
+    let t0 = Instant::now();
+    let keys = [(KeyKind::Device, "d1")];
+
+    assert!(limiter.check("/ingest", &keys, t0).is_ok());
+    assert!(limiter.check("/ingest", &keys, t0).is_err());
+    assert!(limiter
+        .check("/ingest", &keys, t0 + Duration::from_millis(600))
+        .is_ok());
+}
+
+#[actix_rt::test]
+async fn rejected_requests_do_not_use_up_other_buckets() {
+    let limiter = RateLimiter::new(limits(Some(Limit::new(1.0, 1)), Some(Limit::new(1.0, 2))));
+    let t0 = Instant::now();
+    let keys = |device| [(KeyKind::Ip, "10.0.0.7"), (KeyKind::Device, device)];
+
+    assert!(limiter.check("/ingest", &keys("d1"), t0).is_ok());
+    // d1 is empty, so this IP keeps its second token
+    assert!(limiter.check("/ingest", &keys("d1"), t0).is_err());
+    assert!(limiter.check("/ingest", &keys("d2"), t0).is_ok());
+    assert!(limiter.check("/ingest", &keys("d3"), t0).is_err());
+}
+
+#[actix_rt::test]
+async fn device_rejections_refund_the_ip_token() {
+    let limits = limits(Some(Limit::new(0.001, 1)), Some(Limit::new(0.001, 2)));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(AppState::new_demo()))
+            .app_data(web::Data::new(Settings::default()))
+            .app_data(web::Data::new(RateLimiter::new(limits)))
+            .wrap(middleware::from_fn(ratelimit::limit_requests))
+            .configure(routes::configure),
+    )
+    .await;
+    let peer = "10.0.0.7:4000".parse().unwrap();
+    let ingest = |device| {
+        test::TestRequest::post()
+            .uri("/ingest")
+            .peer_addr(peer)
+            .set_json(reading(device))
+            .to_request()
+    };
+
+    assert_eq!(test::call_service(&app, ingest("d1")).await.status(), 200);
+    // d1 is empty, so this IP keeps its second token
+    assert_eq!(test::call_service(&app, ingest("d1")).await.status(), 429);
+    assert_eq!(test::call_service(&app, ingest("d2")).await.status(), 200);
+    assert_eq!(test::call_service(&app, ingest("d3")).await.status(), 429);
+}
+
+#[actix_rt::test]
+async fn disabled_limiter_allows_everything() {
+    let mut settings = limits(Some(Limit::new(1.0, 1)), None);
+    settings.enabled = false;
+    let limiter = RateLimiter::new(settings);
+    let t0 = Instant::now();
+    for _ in 0..5 {
+        assert!(limiter
+            .check("/ingest", &[(KeyKind::Device, "d1")], t0)
+            .is_ok());
+    }
+}
@@ -0,0 +1,32 @@
+use actix_web::{middleware, test, web, App};
+
+use pulsesense_backend::{domain::store::AppState, routes, telemetry};
+
+#[actix_rt::test]
+async fn request_id_is_echoed_or_generated() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .wrap(middleware::from_fn(telemetry::request_id))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/healthz")
+        .insert_header(("x-request-id", "abc-123"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), "abc-123");
+
+    let req = test::TestRequest::get().uri("/healthz").to_request();
+    let resp = test::call_service(&app, req).await;
+    let generated = resp
+        .headers()
+        .get("x-request-id")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(uuid::Uuid::parse_str(generated).is_ok());
+}
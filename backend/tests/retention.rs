@@ -0,0 +1,201 @@
+use actix_web::{test, web, App};
+use chrono::{Duration, TimeZone, Utc};
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode, StoredObservation};
+use pulsesense_backend::domain::retention::{Resolution, RetentionPolicy, Rollups};
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+use pulsesense_backend::routes;
+
+fn hr(value: f64, ts: chrono::DateTime<Utc>) -> SensorReading {
+    SensorReading {
+        device_id: "d1".into(),
+        patient_id: "p1".into(),
+        code: SignalCode::HeartRate,
+        value,
+        unit: "bpm".into(),
+        ts,
+    }
+}
+
+#[actix_rt::test]
+async fn rollups_outlive_evicted_raw_readings() {
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+    let s = AppState::with_limits(1, RetentionPolicy::default());
+    for (secs, value) in [(0, 60.0), (30, 80.0), (90, 100.0)] {
+        s.add_reading(hr(value, start + Duration::seconds(secs)));
+    }
+    assert_eq!(s.len(), 1);
+
+    let state = web::Data::new(s);
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+
+    let req = test::TestRequest::get()
+        .uri("/observations/aggregate?patient=p1&code=heart-rate&bucket=1m")
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["resolution"], "minute");
+    let buckets = body["buckets"].as_array().unwrap();
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[0]["count"], 2);
+    assert_eq!(buckets[0]["mean"], 70.0);
+    assert_eq!(buckets[1]["last"], 100.0);
+}
+
+#[actix_rt::test]
+async fn raw_reads_past_the_raw_horizon_are_refused() {
+    let s = AppState::with_limits(100, RetentionPolicy::days(1, 7, 30));
+    s.add_reading(hr(70.0, Utc::now() - Duration::minutes(5)));
+    let state = web::Data::new(s);
+    let app = test::init_service(App::new().app_data(state).configure(routes::configure)).await;
+    let ts = |ago: Duration| (Utc::now() - ago).format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let old = ts(Duration::days(2));
+    let recent = ts(Duration::hours(1));
+
+    for uri in [
+        format!("/observations/aggregate?patient=p1&code=heart-rate&percentiles=50&from={old}"),
+        format!("/fhir/Observation?patient=p1&from={old}"),
+        format!("/fhir/Observation?patient=p1&to={old}"),
+    ] {
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400, "{}", uri);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(
+            body.to_string().contains("raw retention horizon"),
+            "{}",
+            body
+        );
+    }
+
+    // Rollups still cover the older range without percentiles
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/observations/aggregate?patient=p1&code=heart-rate&from={old}"
+        ))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["resolution"], "minute");
+    assert_eq!(body["buckets"].as_array().unwrap().len(), 1);
+
+    for uri in [
+        format!("/observations/aggregate?patient=p1&code=heart-rate&percentiles=50&from={recent}"),
+        format!("/fhir/Observation?patient=p1&from={recent}"),
+    ] {
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200, "{}", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn rollups_expire_by_bucket_start_at_each_horizon() {
+    let policy = RetentionPolicy {
+        raw: None,
+        minute: Some(Duration::minutes(10)),
+        hour: Some(Duration::hours(2)),
+    };
+    let at = |h, m, s| Utc.with_ymd_and_hms(2024, 1, 1, h, m, s).unwrap();
+    let mut rollups = Rollups::default();
+    for ts in [
+        at(10, 0, 0),
+        at(10, 59, 59),
+        at(11, 59, 59),
+        at(12, 0, 0),
+        at(12, 0, 30),
+    ] {
+        rollups.record(&StoredObservation {
+            id: uuid::Uuid::new_v4(),
+            reading: hr(70.0, ts),
+        });
+    }
+    let starts = |rollups: &Rollups, res| {
+        let mut starts: Vec<_> = rollups
+            .iter()
+            .filter(|(r, _, _)| *r == res)
+            .map(|(_, _, r)| r.start)
+            .collect();
+        starts.sort();
+        starts
+    };
+
+    // The hour horizon lands exactly on the 10:00 bucket, which is kept
+    assert_eq!(rollups.expire(&policy, at(12, 0, 0)), 2);
+    assert_eq!(
+        starts(&rollups, Resolution::Minute),
+        [at(11, 59, 0), at(12, 0, 0)]
+    );
+    assert_eq!(
+        starts(&rollups, Resolution::Hour),
+        [at(10, 0, 0), at(11, 0, 0), at(12, 0, 0)]
+    );
+
+    // A second later the whole bucket goes, though 10:59:59 is within the horizon
+    assert_eq!(rollups.expire(&policy, at(12, 0, 1)), 1);
+    assert_eq!(
+        starts(&rollups, Resolution::Hour),
+        [at(11, 0, 0), at(12, 0, 0)]
+    );
+
+    // Same at minute resolution
+    assert_eq!(rollups.expire(&policy, at(12, 10, 0)), 1);
+    assert_eq!(starts(&rollups, Resolution::Minute), [at(12, 0, 0)]);
+    assert_eq!(rollups.expire(&policy, at(12, 10, 1)), 1);
+    assert!(starts(&rollups, Resolution::Minute).is_empty());
+    assert_eq!(
+        starts(&rollups, Resolution::Hour),
+        [at(11, 0, 0), at(12, 0, 0)]
+    );
+}
+
+#[actix_rt::test]
+async fn compaction_racing_inserts_keeps_counts_consistent() {
+    let now = Utc::now();
+    let s = AppState::with_limits(100_000, RetentionPolicy::days(1, 1, 1));
+    std::thread::scope(|scope| {
+        for w in 0..4 {
+            let s = &s;
+            scope.spawn(move || {
+                for i in 0..250 {
+                    // Alternately fresh and already past every horizon
+                    let age = if i % 2 == 0 {
+                        Duration::seconds(w * 250 + i)
+                    } else {
+                        Duration::days(2)
+                    };
+                    s.add_reading(hr(70.0, now - age));
+                }
+            });
+        }
+        scope.spawn(|| {
+            for _ in 0..50 {
+                s.compact(now);
+            }
+        });
+    });
+    s.compact(now);
+
+    assert_eq!(s.len(), 500);
+    let kept = s.query(&ObsFilter::default(), 1_000);
+    assert_eq!(kept.len(), 500);
+    assert!(kept.iter().all(|o| o.reading.ts > now - Duration::days(1)));
+    for res in [Resolution::Minute, Resolution::Hour] {
+        let rollups: Vec<_> = s
+            .rollups()
+            .into_iter()
+            .filter(|(r, _, _)| *r == res)
+            .collect();
+        assert_eq!(
+            rollups.iter().map(|(_, _, r)| r.count).sum::<usize>(),
+            500,
+            "{:?}",
+            res
+        );
+        assert!(
+            rollups
+                .iter()
+                .all(|(_, _, r)| r.start >= now - Duration::days(1)),
+            "{:?}",
+            res
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use actix_web::{web, App};
+use std::collections::HashMap;
+
+use pulsesense_backend::settings::{Settings, SettingsError};
+use pulsesense_backend::{domain::store::AppState, routes};
+
+#[test]
+fn toml_and_env_overrides_are_merged() {
+    let mut settings = Settings::from_toml(
+        r#"
+        [server]
+        port = 9000
+
+        [store]
+        capacity = 500
+
+        [signals.heart-rate]
+        min = 30
+        max = 200
+        units = ["bpm"]
+        "#,
+    )
+    .unwrap();
+
+    let env: HashMap<&str, &str> = [("PORT", "9100"), ("INGEST_TOKEN", "secret")].into();
+    settings
+        .apply_env(|var| env.get(var).map(|v| v.to_string()))
+        .unwrap();
+    settings.validate().unwrap();
+
+    assert_eq!(settings.bind_addr(), "0.0.0.0:9100");
+    assert_eq!(settings.store.capacity, 500);
+    assert_eq!(settings.auth.ingest_token(), Some("secret"));
+    assert_eq!(settings.signals.heart_rate.max, 200.0);
+    assert_eq!(settings.signals.body_temperature.max, 45.0);
+}
+
+#[test]
+fn invalid_settings_report_every_problem() {
+    assert!(Settings::from_toml("[server]\nprot = 1").is_err());
+
+    let mut settings = Settings::from_toml(
+        r#"
+        [store]
+        capacity = 0
+        retention_hour_days = 9223372036854775807
+
+        [tls]
+        enabled = true
+        require_client_cert = true
+
+        [coap]
+        enabled = true
+
+        [grpc]
+        enabled = true
+
+        [mqtt]
+        enabled = true
+        tls = true
+        client_key_path = "/nonexistent/client.key"
+
+        [webhooks]
+        enabled = true
+
+        [subscriptions]
+        enabled = true
+
+        [audit]
+        enabled = true
+
+        [pipeline]
+        queue_capacity = 0
+
+        [signals.heart-rate]
+        min = 20.0
+        max = 240.0
+        units = ["bpm"]
+        alert_above = 300.0
+        "#,
+    )
+    .unwrap();
+    let err = settings.apply_env(|var| (var == "PORT").then(|| "eighty".to_string()));
+    assert!(matches!(err, Err(SettingsError::Env { var: "PORT", .. })));
+
+    let Err(SettingsError::Invalid(errors)) = settings.validate() else {
+        panic!("expected validation errors");
+    };
+    assert!(errors.iter().any(|e| e.contains("store.capacity")));
+    assert!(errors
+        .iter()
+        .any(|e| e == "store.retention_*_days must be at most 36500"));
+    assert!(errors.iter().any(|e| e.contains("tls.cert_path")));
+    assert!(errors.iter().any(|e| e.contains("tls.key_path")));
+    assert!(errors.iter().any(|e| e.contains("pipeline")));
+    assert!(errors
+        .iter()
+        .any(|e| e == "coap.enabled cannot be combined with tls.require_client_cert"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "grpc.enabled cannot be combined with tls.require_client_cert"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "mqtt.enabled cannot be combined with tls.require_client_cert"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "mqtt.ca_path is required when mqtt.tls = true"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "mqtt.client_cert_path and mqtt.client_key_path go together"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "mqtt.client_key_path /nonexistent/client.key does not exist"));
+    assert!(errors
+        .iter()
+        .any(|e| e.contains("signals.heart-rate.alert_above")));
+    assert!(errors
+        .iter()
+        .any(|e| e == "webhooks.enabled requires auth.admin_token"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "subscriptions.enabled requires auth.admin_token"));
+    assert!(errors
+        .iter()
+        .any(|e| e == "audit.enabled requires auth.admin_token"));
+}
+
+#[actix_rt::test]
+async fn ingest_uses_injected_token_and_signal_ranges() {
+    let mut settings =
+        Settings::from_toml("[signals.heart-rate]\nmin = 40\nmax = 180\nunits = [\"bpm\"]")
+            .unwrap();
+    settings.auth.ingest_token = Some("secret".into());
+
+    let state = web::Data::new(AppState::from_settings(&settings));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(web::Data::new(settings))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let reading = |value: f64| {
+        serde_json::json!({
+            "device_id": "d1",
+            "patient_id": "p1",
+            "code": "heart-rate",
+            "value": value,
+            "unit": "bpm",
+            "ts": chrono::Utc::now(),
+        })
+    };
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest")
+        .set_json(reading(72.0))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest")
+        .insert_header(("authorization", "Bearer secret"))
+        .set_json(reading(30.0))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(
+        body["error"],
+        "validation error: heart-rate out of range (40..180)"
+    );
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/ingest")
+        .insert_header(("authorization", "Bearer secret"))
+        .set_json(reading(72.0))
+        .to_request();
+    assert!(actix_web::test::call_service(&app, req)
+        .await
+        .status()
+        .is_success());
+}
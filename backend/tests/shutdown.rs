@@ -0,0 +1,186 @@
+use actix_web::{test, web, App, HttpServer};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+
+use awc::ws::{CloseCode, Frame, Message};
+use pulsesense_backend::domain::retention::Resolution;
+use pulsesense_backend::domain::snapshot;
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+use pulsesense_backend::lifecycle::{self, Lifecycle};
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{ServerSettings, Settings};
+
+mod common;
+use common::reading_at;
+
+#[actix_rt::test]
+async fn readyz_reports_draining_after_shutdown_begins() {
+    let state = web::Data::new(AppState::new_demo());
+    let lifecycle = web::Data::new(Lifecycle::new());
+    let app = test::init_service(
+        App::new()
+            .app_data(state)
+            .app_data(lifecycle.clone())
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    lifecycle.begin_shutdown();
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "draining");
+
+    // Liveness is unaffected
+    let req = test::TestRequest::get().uri("/healthz").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
+
+#[actix_rt::test]
+async fn snapshot_round_trips_the_store() {
+    let dir = std::env::temp_dir().join(format!("pulsesense-snapshot-{}", uuid::Uuid::new_v4()));
+    // `save` creates the directory
+    let path = dir.join("data").join("store.ndjson");
+
+    let before = AppState::new_demo();
+    for (i, value) in [70.0, 72.0, 75.0].into_iter().enumerate() {
+        before.add_reading(reading_at(
+            value,
+            chrono::Utc::now() - chrono::Duration::seconds(60 - i as i64),
+        ));
+    }
+    assert_eq!(snapshot::save(&before, &path).unwrap(), 3);
+    assert!(!path.with_extension("tmp").exists());
+
+    let after = AppState::new_demo();
+    assert_eq!(snapshot::load(&after, &path).unwrap(), 3);
+    let filter = ObsFilter::default();
+    let ids = |s: &AppState| {
+        s.query(&filter, 10)
+            .into_iter()
+            .map(|o| o.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&before), ids(&after));
+    let minutes = |s: &AppState| {
+        let rollups = s.rollup_range(Resolution::Minute, &filter);
+        rollups
+            .iter()
+            .map(|r| (r.start, r.count))
+            .collect::<Vec<_>>()
+    };
+    assert!(!minutes(&after).is_empty());
+    assert_eq!(minutes(&before), minutes(&after));
+
+    // Rollups outlive raw retention, and come back from the snapshot as is
+    let long_ago = AppState::new_demo();
+    long_ago.add_reading(reading_at(
+        80.0,
+        chrono::Utc::now() - chrono::Duration::days(30),
+    ));
+    assert!(long_ago.is_empty());
+    snapshot::save(&long_ago, &path).unwrap();
+    let restored = AppState::new_demo();
+    assert_eq!(snapshot::load(&restored, &path).unwrap(), 0);
+    let hours = restored.rollup_range(Resolution::Hour, &filter);
+    assert_eq!((hours.len(), hours[0].count, hours[0].max), (1, 1, 80.0));
+
+    // Older snapshots hold only readings; their rollups are rebuilt
+    let readings = before.query(&filter, 10);
+    let lines: Vec<String> = readings
+        .iter()
+        .map(|o| serde_json::to_string(o).unwrap())
+        .collect();
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    let rebuilt = AppState::new_demo();
+    assert_eq!(snapshot::load(&rebuilt, &path).unwrap(), 3);
+    assert_eq!(minutes(&before), minutes(&rebuilt));
+
+    // No snapshot yet is an empty store, not an error
+    let empty = AppState::new_demo();
+    assert_eq!(
+        snapshot::load(&empty, &dir.join("missing.ndjson")).unwrap(),
+        0
+    );
+
+    std::fs::write(&path, "{not json}\n").unwrap();
+    let err = snapshot::load(&AppState::new_demo(), &path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn drain_closes_websockets_and_stops_the_server() {
+    let state = web::Data::new(AppState::new_demo());
+    let lifecycle = web::Data::new(Lifecycle::new());
+
+    let app_state = state.clone();
+    let app_lifecycle = lifecycle.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .app_data(app_lifecycle.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    let running = actix_rt::spawn(server);
+
+    let (_resp, mut ws) = awc::Client::new()
+        .ws(format!("ws://{}/ws/live", addr))
+        .connect()
+        .await
+        .unwrap();
+    match ws.next().await.unwrap().unwrap() {
+        Frame::Text(t) => assert!(String::from_utf8_lossy(&t).contains("hello")),
+        other => panic!("unexpected frame {:?}", other),
+    }
+
+    let settings = ServerSettings::default();
+    let drain =
+        actix_rt::spawn(
+            async move { lifecycle::drain(&lifecycle, &state, handle, &settings).await },
+        );
+
+    match ws.next().await.unwrap().unwrap() {
+        Frame::Text(t) => {
+            let msg: serde_json::Value = serde_json::from_slice(&t).unwrap();
+            assert_eq!(msg["type"], "shutdown");
+            assert_eq!(msg["msg"], lifecycle::SHUTDOWN_REASON);
+            assert_eq!(msg["reconnect_after_secs"], lifecycle::RECONNECT_AFTER_SECS);
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+    match ws.next().await.unwrap().unwrap() {
+        Frame::Close(Some(reason)) => {
+            assert_eq!(reason.code, CloseCode::Restart);
+            assert!(reason
+                .description
+                .unwrap()
+                .starts_with(lifecycle::SHUTDOWN_REASON));
+        }
+        other => panic!("unexpected frame {:?}", other),
+    }
+    ws.send(Message::Close(None)).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(10), drain)
+        .await
+        .unwrap()
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), running)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
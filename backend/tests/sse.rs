@@ -0,0 +1,329 @@
+use actix_web::{web, App};
+use chrono::{DateTime, TimeZone, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode, StoredObservation};
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::errors::AppError;
+use pulsesense_backend::pipeline;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::sse;
+use pulsesense_backend::ws::LiveFilter;
+
+fn ingest(state: &AppState, patient_id: &str, value: f64, ts: DateTime<Utc>) -> StoredObservation {
+    let obs = pipeline::admit(
+        state,
+        SensorReading {
+            device_id: "sse-dev".into(),
+            patient_id: patient_id.into(),
+            code: SignalCode::HeartRate,
+            value,
+            unit: "bpm".into(),
+            ts,
+        },
+    )
+    .unwrap();
+    pipeline::process(state, &obs);
+    obs
+}
+
+async fn next_frame(rx: &mut mpsc::Receiver<Result<bytes::Bytes, AppError>>) -> Option<String> {
+    let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("no frame in time")?;
+    Some(String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+}
+
+#[actix_rt::test]
+async fn live_events_are_filtered_with_heartbeats_and_shutdown() {
+    let state = Arc::new(AppState::new_demo());
+    let filter = LiveFilter {
+        patient_id: Some("sse-p1".into()),
+        code: None,
+    };
+    let mut rx = sse::stream(state.clone(), filter, None, Duration::from_millis(100));
+    assert_eq!(next_frame(&mut rx).await.unwrap(), ": connected\n\n");
+
+    let ts = Utc.with_ymd_and_hms(2026, 10, 18, 9, 0, 0).unwrap();
+    ingest(&state, "sse-other", 150.0, ts);
+    let high = ingest(&state, "sse-p1", 150.0, ts);
+    // Second reading the store has taken in
+    let id = sse::event_id(&state, 2);
+
+    let observation = next_frame(&mut rx).await.unwrap();
+    assert!(
+        observation.starts_with(&format!("id: {}\nevent: observation\ndata: {{", id)),
+        "{}",
+        observation
+    );
+    assert!(observation.ends_with("}\n\n"));
+    let data = observation
+        .lines()
+        .nth(2)
+        .unwrap()
+        .strip_prefix("data: ")
+        .unwrap();
+    let data: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(
+        (data["resourceType"].as_str(), data["id"].as_str()),
+        (Some("Observation"), Some(&*high.id.to_string()))
+    );
+
+    let alert = next_frame(&mut rx).await.unwrap();
+    assert!(
+        alert.starts_with(&format!("id: {}\nevent: alert\ndata: ", id)),
+        "{}",
+        alert
+    );
+    assert!(alert.contains(r#""kind":"high""#));
+    assert_eq!(
+        sse::parse_event_id(&id),
+        Some((state.created_at().timestamp_micros(), 2))
+    );
+    assert_eq!(
+        state.arrival("sse-p1", SignalCode::HeartRate, (ts, high.id)),
+        Some(2)
+    );
+
+    assert_eq!(next_frame(&mut rx).await.unwrap(), ": heartbeat\n\n");
+
+    state.ws_hub.close_all("server shutting down", 5);
+    let shutdown = next_frame(&mut rx).await.unwrap();
+    assert!(
+        shutdown.starts_with("retry: 5000\nevent: shutdown\ndata: "),
+        "{}",
+        shutdown
+    );
+    assert!(shutdown.contains(r#""reconnect_after_secs":5"#));
+    assert_eq!(next_frame(&mut rx).await, None);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(state.ws_hub.client_count(), 0);
+}
+
+#[actix_rt::test]
+async fn last_event_id_replays_from_the_store_without_duplicates() {
+    let state = Arc::new(AppState::new_demo());
+    let at = |minute| Utc.with_ymd_and_hms(2026, 10, 18, 10, minute, 0).unwrap();
+    let first = ingest(&state, "sse-p2", 70.0, at(0));
+    let high = ingest(&state, "sse-p2", 150.0, at(1));
+    let last = ingest(&state, "sse-p2", 72.0, at(2));
+    ingest(&state, "sse-p3", 71.0, at(3));
+    // Arrives last but was measured before everything else
+    let late = ingest(
+        &state,
+        "sse-p2",
+        69.0,
+        Utc.with_ymd_and_hms(2026, 10, 18, 9, 59, 0).unwrap(),
+    );
+
+    let filter = LiveFilter {
+        patient_id: Some("sse-p2".into()),
+        code: None,
+    };
+    let arrival = |obs: &StoredObservation| {
+        state.arrival("sse-p2", SignalCode::HeartRate, (obs.reading.ts, obs.id))
+    };
+    let after = sse::parse_event_id(&sse::event_id(&state, arrival(&first).unwrap()));
+    let mut rx = sse::stream(state.clone(), filter, after, Duration::from_secs(60));
+    // Re-broadcast of a replayed observation, e.g. from a retried ingest
+    pipeline::process(&state, &last);
+    let next = ingest(&state, "sse-p2", 73.0, at(4));
+
+    let mut frames = Vec::new();
+    for _ in 0..6 {
+        frames.push(next_frame(&mut rx).await.unwrap());
+    }
+    let heads: Vec<&str> = frames
+        .iter()
+        .map(|f| f.split("\ndata: ").next().unwrap())
+        .collect();
+    assert_eq!(
+        heads,
+        [
+            ": connected\n\n".to_string(),
+            format!(
+                "id: {}\nevent: observation",
+                sse::event_id(&state, arrival(&high).unwrap())
+            ),
+            format!(
+                "id: {}\nevent: alert",
+                sse::event_id(&state, arrival(&high).unwrap())
+            ),
+            format!(
+                "id: {}\nevent: observation",
+                sse::event_id(&state, arrival(&last).unwrap())
+            ),
+            format!(
+                "id: {}\nevent: observation",
+                sse::event_id(&state, arrival(&late).unwrap())
+            ),
+            format!(
+                "id: {}\nevent: observation",
+                sse::event_id(&state, arrival(&next).unwrap())
+            ),
+        ]
+    );
+
+    state.ws_hub.close_all("server shutting down", 5);
+    assert!(next_frame(&mut rx)
+        .await
+        .unwrap()
+        .contains("event: shutdown"));
+    assert_eq!(next_frame(&mut rx).await, None);
+}
+
+#[actix_rt::test]
+async fn ids_stay_in_arrival_order_when_published_out_of_order() {
+    let state = Arc::new(AppState::new_demo());
+    let filter = LiveFilter {
+        patient_id: Some("sse-p5".into()),
+        code: None,
+    };
+    let mut rx = sse::stream(state.clone(), filter, None, Duration::from_secs(60));
+    assert_eq!(next_frame(&mut rx).await.unwrap(), ": connected\n\n");
+
+    let ts = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+    let reading = |value| SensorReading {
+        device_id: "sse-dev".into(),
+        patient_id: "sse-p5".into(),
+        code: SignalCode::HeartRate,
+        value,
+        unit: "bpm".into(),
+        ts,
+    };
+    let first = pipeline::admit(&state, reading(70.0)).unwrap();
+    let second = pipeline::admit(&state, reading(71.0)).unwrap();
+    state.insert(first.clone());
+    state.insert(second.clone());
+    let arrival = state.last_arrival();
+    // A faster worker publishes the later arrival first
+    state.broadcast(&second);
+
+    let frames = [
+        next_frame(&mut rx).await.unwrap(),
+        next_frame(&mut rx).await.unwrap(),
+    ];
+    for (frame, (n, obs)) in frames
+        .iter()
+        .zip([(arrival - 1, &first), (arrival, &second)])
+    {
+        let head = format!("id: {}\nevent: observation\n", sse::event_id(&state, n));
+        assert!(frame.starts_with(&head), "{}", frame);
+        assert!(frame.contains(&obs.id.to_string()), "{}", frame);
+    }
+
+    // Already sent, so the late publish adds nothing
+    state.broadcast(&first);
+    let next = ingest(&state, "sse-p5", 72.0, ts);
+    let frame = next_frame(&mut rx).await.unwrap();
+    assert!(frame.contains(&next.id.to_string()), "{}", frame);
+
+    state.ws_hub.close_all("server shutting down", 5);
+    assert!(next_frame(&mut rx)
+        .await
+        .unwrap()
+        .contains("event: shutdown"));
+}
+
+#[actix_rt::test]
+async fn id_from_an_earlier_run_replays_everything_stored_since_startup() {
+    let state = Arc::new(AppState::new_demo());
+    let ts = Utc.with_ymd_and_hms(2026, 10, 18, 11, 0, 0).unwrap();
+    let restored = StoredObservation {
+        id: uuid::Uuid::new_v4(),
+        reading: SensorReading {
+            device_id: "sse-dev".into(),
+            patient_id: "sse-p4".into(),
+            code: SignalCode::HeartRate,
+            value: 68.0,
+            unit: "bpm".into(),
+            ts,
+        },
+    };
+    state.restore(restored.clone());
+    let stored = ingest(&state, "sse-p4", 70.0, ts + chrono::Duration::minutes(1));
+    assert_eq!(
+        state.arrival("sse-p4", SignalCode::HeartRate, (ts, restored.id)),
+        None
+    );
+
+    let filter = LiveFilter {
+        patient_id: Some("sse-p4".into()),
+        code: None,
+    };
+    let earlier_run = state.created_at().timestamp_micros() - 1;
+    let mut rx = sse::stream(
+        state.clone(),
+        filter,
+        Some((earlier_run, 7)),
+        Duration::from_secs(60),
+    );
+    assert_eq!(next_frame(&mut rx).await.unwrap(), ": connected\n\n");
+    let replayed = next_frame(&mut rx).await.unwrap();
+    assert!(
+        replayed.starts_with(&format!(
+            "id: {}\nevent: observation",
+            sse::event_id(&state, 1)
+        )),
+        "{}",
+        replayed
+    );
+    assert!(replayed.contains(&stored.id.to_string()));
+
+    // Restored readings have no arrival, so live frames for them carry no id
+    state.broadcast(&restored);
+    let live = next_frame(&mut rx).await.unwrap();
+    assert!(live.starts_with("event: observation\ndata: "), "{}", live);
+
+    state.ws_hub.close_all("server shutting down", 5);
+    assert!(next_frame(&mut rx)
+        .await
+        .unwrap()
+        .contains("event: shutdown"));
+    assert_eq!(next_frame(&mut rx).await, None);
+}
+
+#[actix_rt::test]
+async fn route_checks_filters_and_last_event_id() {
+    let state = web::Data::new(AppState::new_demo());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/events/live?code=bogus")
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+    let req = actix_web::test::TestRequest::get()
+        .uri("/events/live")
+        .insert_header(("Last-Event-ID", "not-an-id"))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/events/live?patient=p1&code=heart-rate")
+        .insert_header(("Last-Event-ID", sse::event_id(&state, 1)))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "no-cache");
+
+    // Ids from before a restart are still accepted
+    let req = actix_web::test::TestRequest::get()
+        .uri("/events/live")
+        .insert_header(("Last-Event-ID", "1_1"))
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 200);
+    state.ws_hub.close_all("server shutting down", 5);
+}
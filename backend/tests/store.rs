@@ -0,0 +1,221 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::retention::{Resolution, RetentionPolicy};
+use pulsesense_backend::domain::store::{AppState, ObsFilter};
+
+fn hr(patient: &str, ts: DateTime<Utc>) -> SensorReading {
+    SensorReading {
+        device_id: "device-1".into(),
+        patient_id: patient.into(),
+        code: SignalCode::HeartRate,
+        value: 72.0,
+        unit: "bpm".into(),
+        ts,
+    }
+}
+
+fn t(secs: i64) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + Duration::seconds(secs)
+}
+
+#[test]
+fn late_readings_are_returned_in_time_order() {
+    let s = AppState::with_limits(100, RetentionPolicy::default());
+    for secs in [10, 30, 20, 0] {
+        s.add_reading(hr("p1", t(secs)));
+    }
+
+    let ts: Vec<_> = s
+        .query(&ObsFilter::default(), 10)
+        .iter()
+        .map(|o| o.reading.ts)
+        .collect();
+    assert_eq!(ts, vec![t(0), t(10), t(20), t(30)]);
+
+    let latest: Vec<_> = s
+        .query(&ObsFilter::default(), 2)
+        .iter()
+        .map(|o| o.reading.ts)
+        .collect();
+    assert_eq!(latest, vec![t(20), t(30)]);
+
+    let ranged: Vec<_> = s
+        .query(
+            &ObsFilter {
+                code: Some(SignalCode::HeartRate),
+                from: Some(t(10)),
+                to: Some(t(20)),
+                ..Default::default()
+            },
+            10,
+        )
+        .iter()
+        .map(|o| o.reading.ts)
+        .collect();
+    assert_eq!(ranged, vec![t(10), t(20)]);
+}
+
+#[test]
+fn eviction_drops_oldest_timestamp_not_oldest_arrival() {
+    let s = AppState::with_limits(2, RetentionPolicy::default());
+    s.add_reading(hr("p1", t(20)));
+    s.add_reading(hr("p2", t(0)));
+    s.add_reading(hr("p1", t(10)));
+
+    assert_eq!(s.len(), 2);
+    let ts: Vec<_> = s
+        .query(&ObsFilter::default(), 10)
+        .iter()
+        .map(|o| o.reading.ts)
+        .collect();
+    assert_eq!(ts, vec![t(10), t(20)]);
+}
+
+#[test]
+fn retention_drops_readings_past_the_window() {
+    let s = AppState::with_limits(
+        100,
+        RetentionPolicy {
+            raw: Some(Duration::hours(1)),
+            ..Default::default()
+        },
+    );
+    let now = Utc::now();
+    s.add_reading(hr("p1", now - Duration::hours(2)));
+    s.add_reading(hr("p1", now));
+
+    assert_eq!(s.len(), 1);
+    assert_eq!(s.evict(now + Duration::hours(2)), 1);
+    assert!(s.is_empty());
+}
+
+#[test]
+fn ingest_expires_readings_in_every_shard() {
+    let s = AppState::with_limits(
+        100,
+        RetentionPolicy {
+            raw: Some(Duration::milliseconds(200)),
+            ..Default::default()
+        },
+    );
+    // Enough series to spread over every shard
+    for i in 0..50 {
+        s.add_reading(hr(&format!("p{}", i), Utc::now()));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    s.add_reading(hr("fresh", Utc::now()));
+
+    assert_eq!(s.len(), 1);
+    assert_eq!(
+        s.query(&ObsFilter::default(), 100)[0].reading.patient_id,
+        "fresh"
+    );
+}
+
+#[test]
+fn compaction_expires_each_resolution_separately() {
+    let now = Utc::now();
+    let s = AppState::with_limits(100, RetentionPolicy::days(1, 2, 3));
+    s.add_reading(hr("p1", now));
+
+    // Raw data goes first, then minute rollups
+    assert_eq!(s.compact(now + Duration::days(1) + Duration::hours(1)), 1);
+    assert!(s.is_empty());
+    assert_eq!(s.compact(now + Duration::days(2) + Duration::hours(1)), 1);
+    assert_eq!(s.compact(now + Duration::days(4)), 1);
+}
+
+#[test]
+fn resolution_follows_bucket_width_and_range_age() {
+    let now = Utc::now();
+    let policy = RetentionPolicy::days(1, 7, 365);
+    let choose = |width, from_days_ago: i64, percentiles| {
+        Resolution::choose(
+            &policy,
+            width,
+            Some(now - Duration::days(from_days_ago)),
+            percentiles,
+            now,
+        )
+    };
+
+    assert_eq!(choose(Duration::seconds(10), 0, false), Resolution::Raw);
+    assert_eq!(choose(Duration::minutes(5), 0, false), Resolution::Minute);
+    assert_eq!(choose(Duration::hours(2), 0, false), Resolution::Hour);
+    assert_eq!(choose(Duration::minutes(5), 0, true), Resolution::Raw);
+    // Raw data has expired for a 3-day-old range, so minute rollups serve it
+    assert_eq!(choose(Duration::seconds(10), 3, false), Resolution::Minute);
+    assert_eq!(choose(Duration::minutes(5), 30, false), Resolution::Hour);
+    assert_eq!(
+        Resolution::Hour.fit(Duration::minutes(5)),
+        Duration::hours(1)
+    );
+}
+
+#[test]
+fn concurrent_ingest_respects_capacity() {
+    let s = AppState::with_limits(1_000, RetentionPolicy::default());
+    std::thread::scope(|scope| {
+        for w in 0..4 {
+            let s = &s;
+            scope.spawn(move || {
+                for i in 0..500 {
+                    s.add_reading(hr(&format!("p{}", i % 10), t(w * 500 + i)));
+                }
+            });
+        }
+        // Readers run alongside the writers
+        scope.spawn(|| {
+            for _ in 0..100 {
+                assert!(s.query(&ObsFilter::default(), 50).len() <= 50);
+            }
+        });
+    });
+
+    assert_eq!(s.len(), 1_000);
+    let all = s.query(&ObsFilter::default(), 2_000);
+    assert_eq!(all.len(), 1_000);
+    assert_eq!(all.last().unwrap().reading.ts, t(1_999));
+}
+
+#[test]
+fn inverted_range_matches_nothing() {
+    let s = AppState::with_limits(100, RetentionPolicy::days(1, 7, 365));
+    let now = Utc::now();
+    s.add_reading(hr("p1", now));
+    let inverted = ObsFilter {
+        from: Some(now + Duration::hours(1)),
+        to: Some(now - Duration::hours(1)),
+        ..Default::default()
+    };
+
+    assert!(s.query(&inverted, 10).is_empty());
+    assert!(s.page(&inverted, None, 10).is_empty());
+    assert!(s.range(&inverted).is_empty());
+    assert!(s.patients(&inverted).is_empty());
+    assert!(s.rollup_range(Resolution::Minute, &inverted).is_empty());
+    // Nothing panicked while a shard lock was held, so the store still serves
+    assert!(!s.is_poisoned());
+    assert_eq!(s.query(&ObsFilter::default(), 10).len(), 1);
+}
+
+#[test]
+fn storing_a_reading_again_counts_it_once() {
+    let s = AppState::with_limits(2, RetentionPolicy::default());
+    let obs = s.add_reading(hr("p1", t(0)));
+    s.insert(obs.clone());
+    s.restore(obs);
+    s.add_reading(hr("p1", t(10)));
+
+    // Neither the first reading nor a rollup was counted twice
+    assert_eq!(s.len(), 2);
+    assert_eq!(s.query(&ObsFilter::default(), 10).len(), 2);
+    let filter = ObsFilter::default();
+    let counts: Vec<_> = [Resolution::Minute, Resolution::Hour]
+        .into_iter()
+        .flat_map(|res| s.rollup_range(res, &filter))
+        .map(|r| r.count)
+        .collect();
+    assert_eq!(counts, vec![2, 2]);
+}
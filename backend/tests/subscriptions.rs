@@ -0,0 +1,674 @@
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use chrono::{TimeZone, Utc};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use awc::ws::{Frame, Message};
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::pipeline;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{Settings, SubscriptionSettings, WebhookSettings};
+use pulsesense_backend::subscriptions::{Status, Subscription, Subscriptions, OBSERVATION_TOPIC};
+
+fn reading(patient_id: &str, code: SignalCode, value: f64) -> SensorReading {
+    SensorReading {
+        device_id: "fs-dev".into(),
+        patient_id: patient_id.into(),
+        code,
+        value,
+        unit: if code == SignalCode::BodyTemperature {
+            "C"
+        } else {
+            "bpm"
+        }
+        .into(),
+        ts: Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap(),
+    }
+}
+
+fn sub_settings() -> SubscriptionSettings {
+    let dir =
+        std::env::temp_dir().join(format!("pulsesense-subscriptions-{}", uuid::Uuid::new_v4()));
+    SubscriptionSettings {
+        path: dir.join("subscriptions.json"),
+        ..SubscriptionSettings::default()
+    }
+}
+
+fn admin_settings() -> Settings {
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    settings
+}
+
+#[actix_rt::test]
+async fn subscriptions_are_validated_and_managed_by_admins() {
+    let state = web::Data::new(AppState::new_demo());
+    let delivery = WebhookSettings::default();
+    let sub_settings = sub_settings();
+    let subs = Subscriptions::start(state.clone().into_inner(), &sub_settings, &delivery).unwrap();
+
+    // Without a configured admin token the API stays closed
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .app_data(web::Data::new(subs.clone()))
+            .configure(routes::configure),
+    )
+    .await;
+    let req = actix_web::test::TestRequest::get()
+        .uri("/fhir/Subscription")
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+
+    let settings = admin_settings();
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(settings))
+            .app_data(web::Data::new(subs.clone()))
+            .configure(routes::configure),
+    )
+    .await;
+    let request = |req: actix_web::test::TestRequest| {
+        req.insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request()
+    };
+    let body = json!({
+        "resourceType": "Subscription",
+        "criteria": "Observation?patient=Patient/fs-p1&code=http://loinc.org%7C8867-4",
+        "channelType": { "code": "websocket" },
+        "heartbeatPeriod": 30,
+    });
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/fhir/Subscription")
+        .set_json(&body)
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+
+    let req = request(
+        actix_web::test::TestRequest::post()
+            .uri("/fhir/Subscription")
+            .set_json(&body),
+    );
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+    let location = resp
+        .headers()
+        .get("location")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let created: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(
+        location,
+        format!("/fhir/Subscription/{}", created["id"].as_str().unwrap())
+    );
+    assert_eq!(
+        (created["status"].as_str(), created["topic"].as_str()),
+        (Some("active"), Some(OBSERVATION_TOPIC))
+    );
+    assert_eq!(
+        created["filterBy"],
+        json!([
+            { "resourceType": "Observation", "filterParameter": "patient", "value": "Patient/fs-p1" },
+            { "resourceType": "Observation", "filterParameter": "code", "value": "http://loinc.org|8867-4" },
+        ])
+    );
+    assert_eq!(created["contentType"], "application/fhir+json");
+
+    let rest_hook = |extra: serde_json::Value| {
+        let mut sub = json!({
+            "resourceType": "Subscription",
+            "topic": OBSERVATION_TOPIC,
+            "channelType": { "code": "rest-hook" },
+            "endpoint": "http://127.0.0.1:9/hook",
+        });
+        sub.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        sub
+    };
+    for bad in [
+        rest_hook(json!({ "topic": "http://example.org/SubscriptionTopic/encounter" })),
+        rest_hook(json!({ "topic": null })),
+        rest_hook(json!({ "endpoint": null })),
+        rest_hook(json!({ "endpoint": "ftp://example.org" })),
+        rest_hook(json!({ "criteria": "Patient?name=x" })),
+        rest_hook(json!({ "criteria": "Observation?code=blood-pressure" })),
+        rest_hook(json!({ "criteria": "Observation?status=final" })),
+        rest_hook(json!({ "filterBy": [
+            { "filterParameter": "patient", "value": "a" },
+            { "filterParameter": "patient", "value": "b" },
+        ] })),
+        rest_hook(json!({ "parameter": [{ "name": "Bad Header", "value": "x" }] })),
+        rest_hook(json!({ "contentType": "application/xml" })),
+        rest_hook(json!({ "heartbeatPeriod": 0 })),
+    ] {
+        let req = request(
+            actix_web::test::TestRequest::post()
+                .uri("/fhir/Subscription")
+                .set_json(&bad),
+        );
+        assert_eq!(
+            actix_web::test::call_service(&app, req).await.status(),
+            400,
+            "{}",
+            bad
+        );
+    }
+
+    // Switching channels is not an update
+    let req = request(
+        actix_web::test::TestRequest::put()
+            .uri(&location)
+            .set_json(rest_hook(json!({}))),
+    );
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+    // Reading it back and sending it again keeps the filters once
+    let mut update = created.clone();
+    update["status"] = json!("off");
+    let req = request(
+        actix_web::test::TestRequest::put()
+            .uri(&location)
+            .set_json(&update),
+    );
+    let updated: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        (
+            updated["status"].as_str(),
+            updated["filterBy"].as_array().unwrap().len()
+        ),
+        (Some("off"), 2)
+    );
+
+    let listed: serde_json::Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(actix_web::test::TestRequest::get().uri("/fhir/Subscription")),
+    )
+    .await;
+    assert_eq!(
+        (listed["type"].as_str(), listed["total"].as_u64()),
+        (Some("searchset"), Some(1))
+    );
+    let status = request(actix_web::test::TestRequest::get().uri(&format!("{}/$status", location)));
+    let status: serde_json::Value = actix_web::test::call_and_read_body_json(&app, status).await;
+    let status = &status["entry"][0]["resource"];
+    assert_eq!(
+        (status["type"].as_str(), status["status"].as_str()),
+        (Some("query-status"), Some("off"))
+    );
+    assert_eq!(status["eventsSinceSubscriptionStart"], "0");
+
+    // Saved with its status, so a restart does not switch it back on
+    let reloaded =
+        Subscriptions::start(state.clone().into_inner(), &sub_settings, &delivery).unwrap();
+    let restored = reloaded
+        .get(created["id"].as_str().unwrap().parse().unwrap())
+        .unwrap();
+    assert_eq!(
+        (restored.status, restored.filter_by.len()),
+        (Some(Status::Off), 2)
+    );
+    reloaded.stop().await;
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/fhir/SubscriptionTopic")
+        .to_request();
+    let topics: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(topics["entry"][0]["resource"]["url"], OBSERVATION_TOPIC);
+
+    let req = request(actix_web::test::TestRequest::delete().uri(&location));
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 204);
+    let req = request(actix_web::test::TestRequest::get().uri(&location));
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 404);
+    assert_eq!(
+        std::fs::read_to_string(&sub_settings.path).unwrap().trim(),
+        "[]"
+    );
+    state.ws_hub.close_all("server shutting down", 5);
+    subs.stop().await;
+    let _ = std::fs::remove_dir_all(sub_settings.path.parent().unwrap());
+}
+
+struct Received {
+    path: String,
+    headers: HashMap<String, String>,
+    bundle: serde_json::Value,
+}
+
+/// Stand-in rest-hook receiver: answers with the statuses scripted for each
+/// path, then 200, and records every notification.
+#[derive(Default)]
+struct Receiver {
+    script: Mutex<HashMap<String, VecDeque<u16>>>,
+    received: Mutex<Vec<Received>>,
+}
+
+async fn receive(
+    receiver: web::Data<Receiver>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    let path = req.path().to_string();
+    let status = receiver
+        .script
+        .lock()
+        .unwrap()
+        .get_mut(&path)
+        .and_then(|s| s.pop_front())
+        .unwrap_or(200);
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+        .collect();
+    let bundle = serde_json::from_slice(&body).unwrap();
+    receiver.received.lock().unwrap().push(Received {
+        path,
+        headers,
+        bundle,
+    });
+    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish()
+}
+
+fn notification_type(bundle: &serde_json::Value) -> &str {
+    bundle["entry"][0]["resource"]["type"].as_str().unwrap()
+}
+
+async fn wait_until(mut done: impl FnMut() -> bool) {
+    for _ in 0..500 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+}
+
+#[actix_rt::test]
+async fn rest_hooks_get_a_handshake_events_and_heartbeats() {
+    let receiver = web::Data::new(Receiver::default());
+    receiver
+        .script
+        .lock()
+        .unwrap()
+        .insert("/broken".into(), [500, 500].into());
+    let app_receiver = receiver.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_receiver.clone())
+            .default_service(web::to(receive))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let state = Arc::new(AppState::new_demo());
+    let delivery = WebhookSettings {
+        max_attempts: 2,
+        retry_initial_ms: 10,
+        ..WebhookSettings::default()
+    };
+    let sub_settings = sub_settings();
+    let subs = Subscriptions::start(state.clone(), &sub_settings, &delivery).unwrap();
+    let subscribe = |body: serde_json::Value| {
+        let subs = subs.clone();
+        async move {
+            let sub: Subscription = serde_json::from_value(body).unwrap();
+            let sub = subs
+                .create(sub, "http://pulsesense.test/fhir", Utc::now())
+                .await
+                .unwrap();
+            assert_eq!(sub.status, Some(Status::Requested));
+            sub.id.unwrap().parse::<uuid::Uuid>().unwrap()
+        }
+    };
+    let hook_id = subscribe(json!({
+        "resourceType": "Subscription",
+        "criteria": "Observation?patient=fs-p1&code=8867-4",
+        "channelType": { "code": "rest-hook" },
+        "endpoint": format!("{}/hook", base),
+        "parameter": [{ "name": "Authorization", "value": "Bearer hook-secret" }],
+        "heartbeatPeriod": 1,
+        "content": "full-resource",
+    }))
+    .await;
+    let broken = subscribe(json!({
+        "resourceType": "Subscription",
+        "criteria": "Observation?patient=fs-p1",
+        "channelType": { "code": "rest-hook" },
+        "endpoint": format!("{}/broken", base),
+    }))
+    .await;
+    assert!(subs.get(hook_id).unwrap().parameter.is_empty());
+    wait_until(|| {
+        let status = |id| subs.get(id).unwrap().status.unwrap();
+        status(hook_id) == Status::Active && status(broken) == Status::Error
+    })
+    .await;
+
+    let matching = pipeline::admit(&state, reading("fs-p1", SignalCode::HeartRate, 88.0)).unwrap();
+    pipeline::process(&state, &matching);
+    for other in [
+        reading("fs-p1", SignalCode::BodyTemperature, 37.0),
+        reading("fs-p2", SignalCode::HeartRate, 90.0),
+    ] {
+        let other = pipeline::admit(&state, other).unwrap();
+        pipeline::process(&state, &other);
+    }
+    let heartbeat_after_event = || {
+        let received = receiver.received.lock().unwrap();
+        let hook = received.iter().filter(|r| r.path == "/hook");
+        let types: Vec<&str> = hook.map(|r| notification_type(&r.bundle)).collect();
+        types
+            .iter()
+            .skip_while(|t| **t != "event-notification")
+            .any(|t| *t == "heartbeat")
+    };
+    wait_until(heartbeat_after_event).await;
+    // Broadcast while the pipeline drains, after the hub has closed its clients
+    state.ws_hub.close_all("server shutting down", 5);
+    let late = pipeline::admit(&state, reading("fs-p1", SignalCode::HeartRate, 89.0)).unwrap();
+    pipeline::process(&state, &late);
+    subs.stop().await;
+    handle.stop(false).await;
+
+    // The rest-hook headers are kept for the next start
+    let saved = std::fs::read_to_string(&sub_settings.path).unwrap();
+    assert!(saved.contains("Bearer hook-secret"));
+
+    let received = receiver.received.lock().unwrap();
+    let types = |path: &str| -> Vec<&str> {
+        received
+            .iter()
+            .filter(|r| r.path == path)
+            .map(|r| notification_type(&r.bundle))
+            .collect()
+    };
+    assert_eq!(types("/broken"), ["handshake", "handshake"]);
+    let hook: Vec<&Received> = received.iter().filter(|r| r.path == "/hook").collect();
+
+    let (headers, handshake) = (&hook[0].headers, &hook[0].bundle);
+    assert_eq!(headers["authorization"], "Bearer hook-secret");
+    assert_eq!(headers["content-type"], "application/fhir+json");
+    assert_eq!(handshake["resourceType"], "Bundle");
+    assert_eq!(handshake["type"], "subscription-notification");
+    let status = &handshake["entry"][0]["resource"];
+    assert_eq!(
+        (status["type"].as_str(), status["status"].as_str()),
+        (Some("handshake"), Some("requested"))
+    );
+    assert_eq!(
+        status["subscription"]["reference"],
+        format!("http://pulsesense.test/fhir/Subscription/{}", hook_id)
+    );
+
+    let events: Vec<_> = hook
+        .iter()
+        .filter(|r| notification_type(&r.bundle) == "event-notification")
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(
+        events[1].bundle["entry"][1]["resource"]["id"],
+        late.id.to_string()
+    );
+    let bundle = &events[0].bundle;
+    let status = &bundle["entry"][0]["resource"];
+    assert_eq!(status["status"], "active");
+    assert_eq!(status["eventsSinceSubscriptionStart"], "1");
+    let event = &status["notificationEvent"][0];
+    let full_url = format!("http://pulsesense.test/fhir/Observation/{}", matching.id);
+    assert_eq!(event["eventNumber"], "1");
+    assert_eq!(event["focus"]["reference"], full_url);
+    assert_eq!(bundle["entry"][1]["fullUrl"], full_url);
+    assert_eq!(
+        bundle["entry"][1]["resource"]["id"],
+        matching.id.to_string()
+    );
+
+    let heartbeat = hook
+        .iter()
+        .find(|r| notification_type(&r.bundle) == "heartbeat")
+        .unwrap();
+    let status = &heartbeat.bundle["entry"][0]["resource"];
+    assert!(status.get("notificationEvent").is_none());
+    assert_eq!(status["eventsSinceSubscriptionStart"], "1");
+    assert_eq!(state.ws_hub.client_count(), 0);
+}
+
+async fn next_json<S>(ws: &mut S) -> serde_json::Value
+where
+    S: futures_util::Stream<Item = Result<Frame, awc::error::WsProtocolError>> + Unpin,
+{
+    match ws.next().await.unwrap().unwrap() {
+        Frame::Text(t) => serde_json::from_slice(&t).unwrap(),
+        other => panic!("unexpected frame {:?}", other),
+    }
+}
+
+#[actix_rt::test]
+async fn websockets_bind_with_a_token_and_get_notifications() {
+    let state = web::Data::new(AppState::new_demo());
+    let delivery = WebhookSettings::default();
+    let subs =
+        Subscriptions::start(state.clone().into_inner(), &sub_settings(), &delivery).unwrap();
+    let (app_state, app_subs) = (state.clone(), web::Data::new(subs.clone()));
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .app_data(app_subs.clone())
+            .app_data(web::Data::new(admin_settings()))
+            .configure(routes::configure)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let client = awc::Client::new();
+    let body = json!({
+        "resourceType": "Subscription",
+        "criteria": "Observation?code=heart-rate",
+        "channelType": { "code": "websocket" },
+    });
+    let mut resp = client
+        .post(format!("http://{}/fhir/Subscription", addr))
+        .bearer_auth("admin-secret")
+        .send_json(&body)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let created: serde_json::Value = resp.json().await.unwrap();
+    let id = created["id"].as_str().unwrap().to_string();
+    let token_url = format!(
+        "http://{}/fhir/Subscription/{}/$get-ws-binding-token",
+        addr, id
+    );
+    let mut resp = client
+        .post(token_url)
+        .bearer_auth("admin-secret")
+        .send()
+        .await
+        .unwrap();
+    let params: serde_json::Value = resp.json().await.unwrap();
+    let param = |name: &str| {
+        let params = params["parameter"].as_array().unwrap();
+        let found = params.iter().find(|p| p["name"] == name).unwrap();
+        found
+            .as_object()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k.starts_with("value"))
+            .unwrap()
+            .1
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(
+        param("websocket-url"),
+        format!("ws://{}/fhir/websocket", addr)
+    );
+
+    let (_resp, mut ws) = client.ws(param("websocket-url")).connect().await.unwrap();
+    ws.send(Message::Text("bind-with-token: not-a-token".into()))
+        .await
+        .unwrap();
+    let outcome = next_json(&mut ws).await;
+    assert_eq!(outcome["resourceType"], "OperationOutcome");
+
+    ws.send(Message::Text(
+        format!("bind-with-token: {}", param("token")).into(),
+    ))
+    .await
+    .unwrap();
+    let handshake = next_json(&mut ws).await;
+    let status = &handshake["entry"][0]["resource"];
+    assert_eq!(
+        (status["type"].as_str(), status["status"].as_str()),
+        (Some("handshake"), Some("active"))
+    );
+
+    // Tokens are single use
+    ws.send(Message::Text(
+        format!("bind-with-token: {}", param("token")).into(),
+    ))
+    .await
+    .unwrap();
+    assert_eq!(next_json(&mut ws).await["resourceType"], "OperationOutcome");
+
+    let temp =
+        pipeline::admit(&state, reading("fs-p3", SignalCode::BodyTemperature, 37.0)).unwrap();
+    pipeline::process(&state, &temp);
+    let obs = pipeline::admit(&state, reading("fs-p3", SignalCode::HeartRate, 70.0)).unwrap();
+    pipeline::process(&state, &obs);
+    let bundle = next_json(&mut ws).await;
+    let status = &bundle["entry"][0]["resource"];
+    assert_eq!(status["type"], "event-notification");
+    assert_eq!(status["eventsSinceSubscriptionStart"], "1");
+    let focus = status["notificationEvent"][0]["focus"]["reference"]
+        .as_str()
+        .unwrap();
+    assert!(focus.ends_with(&format!("/fhir/Observation/{}", obs.id)));
+    let mut resp = client.get(focus).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let resource: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(
+        (resource["resourceType"].as_str(), resource["id"].as_str()),
+        (Some("Observation"), Some(obs.id.to_string().as_str()))
+    );
+    let gone = format!("http://{}/fhir/Observation/{}", addr, uuid::Uuid::new_v4());
+    assert_eq!(client.get(gone).send().await.unwrap().status(), 404);
+    // id-only by default
+    assert_eq!(bundle["entry"].as_array().unwrap().len(), 1);
+
+    // Draining reaches bound sockets like any other hub client
+    state.ws_hub.close_all("server shutting down", 5);
+    assert_eq!(next_json(&mut ws).await["type"], "shutdown");
+    subs.stop().await;
+    handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn rest_hooks_created_through_the_api_finish_after_the_server_stops() {
+    let receiver = web::Data::new(Receiver::default());
+    // The handshake is retried after the API server has gone
+    receiver
+        .script
+        .lock()
+        .unwrap()
+        .insert("/late".into(), [500].into());
+    let app_receiver = receiver.clone();
+    let sink = HttpServer::new(move || {
+        App::new()
+            .app_data(app_receiver.clone())
+            .default_service(web::to(receive))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let base = format!("http://{}", sink.addrs()[0]);
+    let sink = sink.run();
+    let sink_handle = sink.handle();
+    actix_rt::spawn(sink);
+
+    let state = web::Data::new(AppState::new_demo());
+    let delivery = WebhookSettings {
+        retry_initial_ms: 200,
+        ..WebhookSettings::default()
+    };
+    let subs = web::Data::new(
+        Subscriptions::start(state.clone().into_inner(), &sub_settings(), &delivery).unwrap(),
+    );
+    let settings = web::Data::new(admin_settings());
+    let (app_state, app_subs) = (state.clone(), subs.clone());
+    let api = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .app_data(settings.clone())
+            .app_data(app_subs.clone())
+            .configure(routes::configure)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr = api.addrs()[0];
+    let api = api.run();
+    let api_handle = api.handle();
+    actix_rt::spawn(api);
+
+    // Created on the HTTP worker, whose runtime goes away with the server
+    let resp = awc::Client::new()
+        .post(format!("http://{}/fhir/Subscription", addr))
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .send_json(&json!({
+            "resourceType": "Subscription",
+            "criteria": "Observation?patient=fs-p9",
+            "channelType": { "code": "rest-hook" },
+            "endpoint": format!("{}/late", base),
+        }))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    api_handle.stop(true).await;
+
+    let id = subs.list()[0]
+        .id
+        .clone()
+        .unwrap()
+        .parse::<uuid::Uuid>()
+        .unwrap();
+    wait_until(|| subs.get(id).unwrap().status == Some(Status::Active)).await;
+    // Broadcast while the pipeline drains
+    let obs = pipeline::admit(&state, reading("fs-p9", SignalCode::HeartRate, 70.0)).unwrap();
+    pipeline::process(&state, &obs);
+    state.ws_hub.close_all("server shutting down", 5);
+    subs.stop().await;
+    sink_handle.stop(false).await;
+
+    let received = receiver.received.lock().unwrap();
+    let kinds: Vec<&str> = received
+        .iter()
+        .map(|r| notification_type(&r.bundle))
+        .collect();
+    assert_eq!(kinds, ["handshake", "handshake", "event-notification"]);
+}
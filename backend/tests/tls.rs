@@ -0,0 +1,168 @@
+use actix_web::{web, App, HttpServer};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use std::path::{Path, PathBuf};
+
+use pulsesense_backend::settings::Settings;
+use pulsesense_backend::{domain::store::AppState, routes, tls};
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+fn ca() -> Ca {
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "PulseSense Test CA");
+    let cert = params.self_signed(&key).unwrap();
+    Ca { cert, key }
+}
+
+/// PEM certificate and key signed by `ca`.
+fn leaf(ca: &Ca, cn: &str, sans: &[&str], client: bool) -> (String, String) {
+    let key = KeyPair::generate().unwrap();
+    let sans: Vec<String> = sans.iter().map(|s| s.to_string()).collect();
+    let mut params = CertificateParams::new(sans).unwrap();
+    params.distinguished_name.push(DnType::CommonName, cn);
+    if client {
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    }
+    let cert = params.signed_by(&key, &ca.cert, &ca.key).unwrap();
+    (cert.pem(), key.serialize_pem())
+}
+
+fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Start an HTTPS server requiring device certificates; returns its base URL.
+fn start_server(ca: &Ca) -> String {
+    let dir = std::env::temp_dir().join(format!("pulsesense-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (server_cert, server_key) = leaf(ca, "localhost", &["localhost"], false);
+
+    let mut settings = Settings::default();
+    settings.tls.enabled = true;
+    settings.tls.cert_path = Some(write(&dir, "server.pem", &server_cert));
+    settings.tls.key_path = Some(write(&dir, "server.key", &server_key));
+    settings.tls.client_ca_path = Some(write(&dir, "ca.pem", &ca.cert.pem()));
+    settings.tls.require_client_cert = true;
+    settings
+        .tls
+        .identity_map
+        .insert("gateway-7".into(), vec!["device-002".into()]);
+    settings.validate().unwrap();
+
+    let config = tls::server_config(&settings.tls).unwrap();
+    let state = web::Data::new(AppState::from_settings(&settings));
+    let settings = web::Data::new(settings);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .app_data(settings.clone())
+            .configure(routes::configure)
+    })
+    .on_connect(tls::on_connect)
+    .workers(1)
+    .listen_rustls_0_23(listener, config)
+    .unwrap()
+    .run();
+    actix_rt::spawn(server);
+
+    format!("https://localhost:{}", port)
+}
+
+fn client(ca: &Ca, identity: Option<(String, String)>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_pem(ca.cert.pem().as_bytes()).unwrap());
+    if let Some((cert, key)) = identity {
+        builder = builder
+            .identity(reqwest::Identity::from_pem(format!("{}{}", cert, key).as_bytes()).unwrap());
+    }
+    builder.build().unwrap()
+}
+
+async fn ingest(client: &reqwest::Client, base: &str, device_id: &str) -> u16 {
+    client
+        .post(format!("{}/ingest", base))
+        .json(&serde_json::json!({
+            "device_id": device_id,
+            "patient_id": "p1",
+            "code": "heart-rate",
+            "value": 72.0,
+            "unit": "bpm",
+            "ts": chrono::Utc::now(),
+        }))
+        .send()
+        .await
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+#[actix_rt::test]
+async fn client_certificate_identity_is_enforced_on_ingest() {
+    let ca = ca();
+    let base = start_server(&ca);
+
+    // CN maps directly to the device id
+    let device = client(&ca, Some(leaf(&ca, "device-001", &[], true)));
+    assert_eq!(ingest(&device, &base, "device-001").await, 200);
+    assert_eq!(ingest(&device, &base, "device-002").await, 403);
+
+    // Gateways are mapped to the devices they relay for
+    let gateway = client(&ca, Some(leaf(&ca, "gateway-7", &[], true)));
+    assert_eq!(ingest(&gateway, &base, "device-002").await, 200);
+    assert_eq!(ingest(&gateway, &base, "device-001").await, 403);
+
+    // TLS without a client certificate still serves reads, but not ingest
+    let anonymous = client(&ca, None);
+    assert_eq!(ingest(&anonymous, &base, "device-001").await, 401);
+    let health = anonymous
+        .get(format!("{}/healthz", base))
+        .send()
+        .await
+        .unwrap();
+    assert!(health.status().is_success());
+}
+
+#[actix_rt::test]
+async fn certificates_from_another_ca_are_rejected() {
+    let ca = ca();
+    let base = start_server(&ca);
+
+    let rogue_ca = self::ca();
+    let rogue = client(&ca, Some(leaf(&rogue_ca, "device-001", &[], true)));
+    let result = rogue.get(format!("{}/healthz", base)).send().await;
+    assert!(result.is_err());
+}
+
+#[test]
+fn identity_includes_cn_and_sans() {
+    let ca = ca();
+    let (pem, _) = leaf(
+        &ca,
+        "device-001",
+        &["urn:pulsesense:device:device-001", "dev1.example"],
+        true,
+    );
+    let der = rustls_pemfile::certs(&mut pem.as_bytes())
+        .next()
+        .unwrap()
+        .unwrap();
+    let id = tls::ClientIdentity::from_der(der.as_ref()).unwrap();
+    assert!(id.names.contains(&"device-001".to_string()));
+    assert!(id.names.contains(&"dev1.example".to_string()));
+}
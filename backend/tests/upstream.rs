@@ -0,0 +1,375 @@
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use chrono::{TimeZone, Utc};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode, StoredObservation};
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::pipeline::{self, Pipeline};
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{PipelineSettings, Settings, UpstreamSettings};
+use pulsesense_backend::upstream::{DeadLetter, Outbox, Upstream, UpstreamForwarder};
+
+fn observation(patient_id: &str, value: f64) -> StoredObservation {
+    StoredObservation {
+        id: uuid::Uuid::new_v4(),
+        reading: SensorReading {
+            device_id: "dev-1".into(),
+            patient_id: patient_id.into(),
+            code: SignalCode::HeartRate,
+            value,
+            unit: "bpm".into(),
+            ts: Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap(),
+        },
+    }
+}
+
+fn outbox_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("pulsesense-outbox-{}", uuid::Uuid::new_v4()))
+}
+
+#[test]
+fn outbox_survives_restart_and_overflows_to_dead_letter() {
+    let dir = outbox_dir();
+    let (a, b, c) = (
+        observation("p1", 60.0),
+        observation("p1", 61.0),
+        observation("p1", 62.0),
+    );
+    let outbox = Outbox::open(&dir, 2).unwrap();
+    for obs in [&a, &b, &c] {
+        outbox.push(obs.clone(), Utc::now()).unwrap();
+    }
+    assert_eq!((outbox.len(), outbox.dead_lettered()), (2, 1));
+    // Delivered entries move the cursor; the journal is not rewritten
+    let journal_len = outbox.journal_len();
+    outbox.complete(1).unwrap();
+    assert_eq!(outbox.journal_len(), journal_len);
+    let first_line = std::fs::read_to_string(dir.join("outbox.ndjson"))
+        .unwrap()
+        .find('\n')
+        .unwrap()
+        + 1;
+    assert_eq!(
+        std::fs::read_to_string(dir.join("outbox.cursor")).unwrap(),
+        first_line.to_string()
+    );
+    drop(outbox);
+
+    // A write cut short by a crash is skipped on reload
+    let mut journal = std::fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join("outbox.ndjson"))
+        .unwrap();
+    journal.write_all(b"{\"enqueued_at\":\"2026-10").unwrap();
+    let outbox = Outbox::open(&dir, 2).unwrap();
+    assert_eq!(outbox.dead_lettered(), 1);
+    let pending: Vec<_> = outbox.peek(10).iter().map(|e| e.observation.id).collect();
+    assert_eq!(pending, [b.id]);
+    outbox.push(c.clone(), Utc::now()).unwrap();
+    drop(outbox);
+    let outbox = Outbox::open(&dir, 2).unwrap();
+    assert_eq!(outbox.len(), 2);
+
+    // Once everything is delivered the journal starts over
+    outbox.complete(2).unwrap();
+    assert_eq!(outbox.journal_len(), 0);
+    drop(outbox);
+    assert!(Outbox::open(&dir, 2).unwrap().is_empty());
+
+    let letters = dead_letters(&dir);
+    assert_eq!(
+        (letters[0].entry.observation.id, letters[0].reason.as_str()),
+        (c.id, "outbox full")
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+struct Received {
+    authorization: String,
+    content_type: String,
+    status: u16,
+    bundle: serde_json::Value,
+}
+
+impl Received {
+    fn ids(&self) -> Vec<String> {
+        let entries = self.bundle["entry"].as_array().unwrap();
+        entries
+            .iter()
+            .map(|e| e["resource"]["id"].as_str().unwrap().to_string())
+            .collect()
+    }
+}
+
+/// Stand-in FHIR server: answers transactions with the scripted statuses,
+/// then 200, and records every request. Redirects point back at itself,
+/// where a GET would be accepted.
+#[derive(Default)]
+struct MockFhir {
+    script: Mutex<VecDeque<u16>>,
+    received: Mutex<Vec<Received>>,
+    gets: Mutex<usize>,
+}
+
+async fn transaction(
+    mock: web::Data<MockFhir>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    let status = mock.script.lock().unwrap().pop_front().unwrap_or(200);
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string()
+    };
+    mock.received.lock().unwrap().push(Received {
+        authorization: header("authorization"),
+        content_type: header("content-type"),
+        status,
+        bundle: serde_json::from_slice(&body).unwrap(),
+    });
+    let body = match status {
+        200 => serde_json::json!({ "resourceType": "Bundle", "type": "transaction-response" }),
+        _ => {
+            serde_json::json!({ "resourceType": "OperationOutcome", "issue": [{ "severity": "error" }] })
+        }
+    };
+    let mut response = HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap());
+    if (300..400).contains(&status) {
+        response.insert_header(("Location", req.path().to_string()));
+    }
+    response.json(body)
+}
+
+async fn followed_redirect(mock: web::Data<MockFhir>) -> HttpResponse {
+    *mock.gets.lock().unwrap() += 1;
+    HttpResponse::Ok().json(serde_json::json!({ "resourceType": "Bundle" }))
+}
+
+async fn start_mock(
+    script: Vec<u16>,
+) -> (String, web::Data<MockFhir>, actix_web::dev::ServerHandle) {
+    let mock = web::Data::new(MockFhir {
+        script: Mutex::new(script.into()),
+        ..MockFhir::default()
+    });
+    let app_mock = mock.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_mock.clone())
+            .route("/fhir", web::post().to(transaction))
+            .route("/fhir", web::get().to(followed_redirect))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let base_url = format!("http://{}/fhir/", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+    (base_url, mock, handle)
+}
+
+fn settings(base_url: &str, dir: &Path) -> UpstreamSettings {
+    UpstreamSettings {
+        enabled: true,
+        base_url: base_url.to_string(),
+        bearer_token: Some("upstream-secret".into()),
+        outbox_dir: dir.to_path_buf(),
+        retry_initial_ms: 10,
+        ..UpstreamSettings::default()
+    }
+}
+
+async fn wait_for(upstream: &Upstream, done: impl Fn(u64, u64) -> bool) {
+    for _ in 0..500 {
+        let status = upstream.status(Utc::now());
+        if status.pending == 0 && done(status.delivered, status.dead_lettered) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!(
+        "upstream did not catch up: {:?}",
+        upstream.status(Utc::now())
+    );
+}
+
+fn dead_letters(dir: &Path) -> Vec<DeadLetter> {
+    let text = std::fs::read_to_string(dir.join("dead-letter.ndjson")).unwrap();
+    text.lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+#[actix_rt::test]
+async fn forwards_transaction_bundles_and_retries_server_errors() {
+    let (base_url, mock, server) = start_mock(vec![503]).await;
+    let dir = outbox_dir();
+    // Left over from an earlier run
+    let (a, b) = (observation("up-p1", 70.0), observation("up-p1", 71.0));
+    let outbox = Outbox::open(&dir, 100).unwrap();
+    outbox.push(a.clone(), Utc::now()).unwrap();
+    outbox.push(b.clone(), Utc::now()).unwrap();
+    drop(outbox);
+
+    let state = Arc::new(AppState::new_demo());
+    let forwarder = UpstreamForwarder::start(&settings(&base_url, &dir)).unwrap();
+    let upstream = forwarder.upstream();
+    wait_for(&upstream, |delivered, _| delivered == 2).await;
+
+    // The persist stage journals what it stores
+    let pipeline = Pipeline::start_with_upstream(
+        state.clone(),
+        &PipelineSettings::default(),
+        Some(upstream.clone()),
+    );
+    let c = pipeline::admit(&state, observation("up-p2", 72.0).reading).unwrap();
+    pipeline.submit(c.clone()).unwrap();
+    pipeline.shutdown().await;
+    wait_for(&upstream, |delivered, _| delivered == 3).await;
+
+    {
+        let received = mock.received.lock().unwrap();
+        let summary: Vec<_> = received.iter().map(|r| (r.status, r.ids())).collect();
+        let (a, b, c) = (a.id.to_string(), b.id.to_string(), c.id.to_string());
+        assert_eq!(
+            summary,
+            [
+                (503, vec![a.clone(), b.clone()]),
+                (200, vec![a.clone(), b]),
+                (200, vec![c.clone()])
+            ]
+        );
+
+        let request = &received[2];
+        assert_eq!(request.authorization, "Bearer upstream-secret");
+        assert_eq!(request.content_type, "application/fhir+json");
+        let bundle = &request.bundle;
+        assert_eq!(
+            (bundle["resourceType"].as_str(), bundle["type"].as_str()),
+            (Some("Bundle"), Some("transaction"))
+        );
+        let entry = &bundle["entry"][0];
+        assert_eq!(entry["request"]["method"], "PUT");
+        assert_eq!(entry["request"]["url"], format!("Observation/{}", c));
+        assert_eq!(entry["resource"]["resourceType"], "Observation");
+        assert_eq!(entry["resource"]["subject"]["reference"], "Patient/up-p2");
+        assert_eq!(entry["resource"]["valueQuantity"]["value"], 72.0);
+    }
+
+    let mut app_settings = Settings::default();
+    app_settings.auth.admin_token = Some("admin-secret".into());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_settings))
+            .app_data(web::Data::from(upstream.clone()))
+            .configure(routes::configure),
+    )
+    .await;
+    let req = actix_web::test::TestRequest::get()
+        .uri("/upstream/status")
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+    let req = actix_web::test::TestRequest::get()
+        .uri("/upstream/status")
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .to_request();
+    let status: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        (status["pending"].as_u64(), status["lag_secs"].as_f64()),
+        (Some(0), Some(0.0))
+    );
+    assert_eq!(
+        (
+            status["delivered"].as_u64(),
+            status["dead_lettered"].as_u64()
+        ),
+        (Some(3), Some(0))
+    );
+    assert!(status["last_error"].as_str().unwrap().starts_with("503"));
+    assert!(status["last_delivery_at"].is_string());
+
+    forwarder.stop().await;
+    assert!(Outbox::open(&dir, 100).unwrap().is_empty());
+    server.stop(false).await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[actix_rt::test]
+async fn refused_observations_are_isolated_and_dead_lettered() {
+    // The batch of three is refused, so each goes alone and only the middle
+    // one is rejected; then a lone observation outlasts three server errors
+    let (base_url, mock, server) = start_mock(vec![400, 200, 422, 200, 500, 503, 500]).await;
+    let dir = outbox_dir();
+    let observations: Vec<_> = (0..3)
+        .map(|i| observation("up-p3", 80.0 + i as f64))
+        .collect();
+    let outbox = Outbox::open(&dir, 100).unwrap();
+    for obs in &observations {
+        outbox.push(obs.clone(), Utc::now()).unwrap();
+    }
+    drop(outbox);
+
+    let forwarder = UpstreamForwarder::start(&settings(&base_url, &dir)).unwrap();
+    let upstream = forwarder.upstream();
+    wait_for(&upstream, |delivered, dead| delivered == 2 && dead == 1).await;
+
+    let last = observation("up-p3", 90.0);
+    upstream.outbox().push(last.clone(), Utc::now()).unwrap();
+    wait_for(&upstream, |delivered, dead| delivered == 3 && dead == 1).await;
+    forwarder.stop().await;
+
+    {
+        let received = mock.received.lock().unwrap();
+        let sizes: Vec<usize> = received.iter().map(|r| r.ids().len()).collect();
+        assert_eq!(sizes, [3, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(received[7].ids(), [last.id.to_string()]);
+    }
+    let letters = dead_letters(&dir);
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].entry.observation.id, observations[1].id);
+    assert!(letters[0].reason.starts_with("422"));
+    server.stop(false).await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[actix_rt::test]
+async fn rejected_tokens_and_redirects_are_retried_not_dead_lettered() {
+    // A token the server no longer accepts, then a moved endpoint: both
+    // need an operator, and the outbox keeps the batch until then
+    let (base_url, mock, server) = start_mock(vec![401, 403, 301]).await;
+    let dir = outbox_dir();
+    let observations: Vec<_> = (0..2)
+        .map(|i| observation("up-p4", 60.0 + i as f64))
+        .collect();
+    let outbox = Outbox::open(&dir, 100).unwrap();
+    for obs in &observations {
+        outbox.push(obs.clone(), Utc::now()).unwrap();
+    }
+    drop(outbox);
+
+    let forwarder = UpstreamForwarder::start(&settings(&base_url, &dir)).unwrap();
+    let upstream = forwarder.upstream();
+    wait_for(&upstream, |delivered, dead| delivered == 2 && dead == 0).await;
+    forwarder.stop().await;
+
+    {
+        let received = mock.received.lock().unwrap();
+        let summary: Vec<_> = received.iter().map(|r| (r.status, r.ids().len())).collect();
+        assert_eq!(summary, [(401, 2), (403, 2), (301, 2), (200, 2)]);
+    }
+    assert_eq!(*mock.gets.lock().unwrap(), 0, "the redirect was followed");
+    assert!(!dir.join("dead-letter.ndjson").exists());
+    let status = upstream.status(Utc::now());
+    assert!(status.last_error.unwrap().starts_with("301"));
+    server.stop(false).await;
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,458 @@
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use chrono::{TimeZone, Utc};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pulsesense_backend::domain::models::{SensorReading, SignalCode};
+use pulsesense_backend::domain::store::AppState;
+use pulsesense_backend::pipeline;
+use pulsesense_backend::routes;
+use pulsesense_backend::settings::{Settings, WebhookSettings};
+use pulsesense_backend::webhooks::{self, DeliveryStatus, SubscriptionRequest, Webhooks};
+
+#[test]
+fn signature_is_hmac_sha256_of_timestamp_and_body() {
+    assert_eq!(
+        webhooks::signature("whsec_test", 1_760_000_000, br#"{"a":1}"#),
+        "t=1760000000,v1=f495e119a46eb6023c06ab057f70eee20b42a99ccba7a692b6af681055bfadd9"
+    );
+}
+
+fn hook_settings() -> WebhookSettings {
+    let dir = std::env::temp_dir().join(format!("pulsesense-webhooks-{}", uuid::Uuid::new_v4()));
+    WebhookSettings {
+        path: dir.join("webhooks.json"),
+        ..WebhookSettings::default()
+    }
+}
+
+#[actix_rt::test]
+async fn subscription_crud_needs_the_admin_token() {
+    let state = web::Data::new(AppState::new_demo());
+    let hook_settings = hook_settings();
+    let hooks = Webhooks::start(state.clone().into_inner(), &hook_settings).unwrap();
+
+    // Without a configured admin token the API stays closed
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(Settings::default()))
+            .app_data(web::Data::new(hooks.clone()))
+            .configure(routes::configure),
+    )
+    .await;
+    let req = actix_web::test::TestRequest::get()
+        .uri("/webhooks")
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(state.clone())
+            .app_data(web::Data::new(settings))
+            .app_data(web::Data::new(hooks.clone()))
+            .configure(routes::configure),
+    )
+    .await;
+    let request = |req: actix_web::test::TestRequest| {
+        req.insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request()
+    };
+    let body = json!({ "url": "http://127.0.0.1:9/hook", "events": ["alert"], "patient": "p1" });
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/webhooks")
+        .set_json(&body)
+        .to_request();
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 401);
+
+    let resp = actix_web::test::call_service(
+        &app,
+        request(
+            actix_web::test::TestRequest::post()
+                .uri("/webhooks")
+                .set_json(&body),
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), 201);
+    let location = resp
+        .headers()
+        .get("location")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let created: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert!(created["secret"].as_str().unwrap().starts_with("whsec_"));
+    assert_eq!(
+        location,
+        format!("/webhooks/{}", created["id"].as_str().unwrap())
+    );
+    assert_eq!(
+        (created["active"].as_bool(), created["code"].is_null()),
+        (Some(true), true)
+    );
+
+    for bad in [
+        json!({ "url": "ftp://example.org", "events": ["alert"] }),
+        json!({ "url": "http://example.org", "events": [] }),
+        json!({ "url": "http://example.org", "events": ["reading"] }),
+        json!({ "url": "http://example.org", "events": ["alert"], "code": "blood-pressure" }),
+    ] {
+        let req = request(
+            actix_web::test::TestRequest::post()
+                .uri("/webhooks")
+                .set_json(bad),
+        );
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), 400);
+    }
+
+    let update = json!({ "url": "https://example.org/hook", "events": ["observation", "alert"], "code": "heart-rate" });
+    let req = request(
+        actix_web::test::TestRequest::put()
+            .uri(&location)
+            .set_json(update),
+    );
+    let updated: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(updated["url"], "https://example.org/hook");
+    assert_eq!(
+        (updated["code"].as_str(), updated["patient"].is_null()),
+        (Some("heart-rate"), true)
+    );
+    assert!(updated.get("secret").is_none());
+
+    let listed: serde_json::Value = actix_web::test::call_and_read_body_json(
+        &app,
+        request(actix_web::test::TestRequest::get().uri("/webhooks")),
+    )
+    .await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert!(listed[0].get("secret").is_none());
+
+    // Subscriptions and their secrets survive a restart
+    let saved = std::fs::read_to_string(&hook_settings.path).unwrap();
+    assert!(saved.contains(created["secret"].as_str().unwrap()));
+    let reloaded = Webhooks::start(state.clone().into_inner(), &hook_settings).unwrap();
+    let id = created["id"].as_str().unwrap().parse().unwrap();
+    let restored = reloaded.get(id).unwrap();
+    assert_eq!(
+        (restored.url.as_str(), restored.secret.is_none()),
+        ("https://example.org/hook", true)
+    );
+    let deliveries =
+        request(actix_web::test::TestRequest::get().uri(&format!("{}/deliveries", location)));
+    let deliveries: serde_json::Value =
+        actix_web::test::call_and_read_body_json(&app, deliveries).await;
+    assert_eq!(deliveries, json!([]));
+
+    let req = request(actix_web::test::TestRequest::delete().uri(&location));
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 204);
+    let req = request(actix_web::test::TestRequest::get().uri(&location));
+    assert_eq!(actix_web::test::call_service(&app, req).await.status(), 404);
+    assert_eq!(
+        std::fs::read_to_string(&hook_settings.path).unwrap().trim(),
+        "[]"
+    );
+    state.ws_hub.close_all("server shutting down", 5);
+    hooks.stop().await;
+    reloaded.stop().await;
+    let _ = std::fs::remove_dir_all(hook_settings.path.parent().unwrap());
+}
+
+struct Received {
+    path: String,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+/// Stand-in receiver: answers with the statuses scripted for each path,
+/// then 204, and records every request. Redirects point at `/heart`.
+#[derive(Default)]
+struct Receiver {
+    script: Mutex<HashMap<String, VecDeque<u16>>>,
+    received: Mutex<Vec<Received>>,
+}
+
+async fn receive(receiver: web::Data<Receiver>, req: HttpRequest, body: String) -> HttpResponse {
+    let path = req.path().to_string();
+    let status = receiver
+        .script
+        .lock()
+        .unwrap()
+        .get_mut(&path)
+        .and_then(|s| s.pop_front())
+        .unwrap_or(204);
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+        .collect();
+    receiver.received.lock().unwrap().push(Received {
+        path,
+        headers,
+        body,
+    });
+    let mut response = HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap());
+    if (300..400).contains(&status) {
+        response.insert_header(("Location", "/heart"));
+    }
+    response.body("nope")
+}
+
+async fn subscribe(hooks: &Webhooks, url: String, body: serde_json::Value) -> (uuid::Uuid, String) {
+    let mut req: SubscriptionRequest = serde_json::from_value(body).unwrap();
+    req.url = url;
+    let sub = hooks.create(req, Utc::now()).await.unwrap();
+    (sub.id, sub.secret.unwrap())
+}
+
+#[actix_rt::test]
+async fn events_are_filtered_signed_and_retried() {
+    let receiver = web::Data::new(Receiver::default());
+    {
+        let mut script = receiver.script.lock().unwrap();
+        script.insert("/alerts".into(), [500].into());
+        script.insert("/down".into(), [503, 503].into());
+        script.insert("/gone".into(), [410].into());
+        script.insert("/moved".into(), [307].into());
+    }
+    let app_receiver = receiver.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_receiver.clone())
+            .default_service(web::to(receive))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let state = Arc::new(AppState::new_demo());
+    let settings = WebhookSettings {
+        max_attempts: 2,
+        retry_initial_ms: 10,
+        ..hook_settings()
+    };
+    let hooks = Webhooks::start(state.clone(), &settings).unwrap();
+    let (alerts, alert_secret) = subscribe(
+        &hooks,
+        format!("{}/alerts", base),
+        json!({ "url": "", "events": ["alert"], "patient": "wh-p1" }),
+    )
+    .await;
+    let (heart, _) = subscribe(
+        &hooks,
+        format!("{}/heart", base),
+        json!({ "url": "", "events": ["observation"], "code": "heart-rate" }),
+    )
+    .await;
+    let (down, _) = subscribe(
+        &hooks,
+        format!("{}/down", base),
+        json!({ "url": "", "events": ["alert"] }),
+    )
+    .await;
+    let (gone, _) = subscribe(
+        &hooks,
+        format!("{}/gone", base),
+        json!({ "url": "", "events": ["alert"] }),
+    )
+    .await;
+    let (moved, _) = subscribe(
+        &hooks,
+        format!("{}/moved", base),
+        json!({ "url": "", "events": ["alert"] }),
+    )
+    .await;
+
+    let reading = |patient_id: &str, code, value, unit: &str| SensorReading {
+        device_id: "wh-dev".into(),
+        patient_id: patient_id.into(),
+        code,
+        value,
+        unit: unit.into(),
+        ts: Utc.with_ymd_and_hms(2026, 10, 18, 11, 0, 0).unwrap(),
+    };
+    let high = pipeline::admit(
+        &state,
+        reading("wh-p1", SignalCode::HeartRate, 150.0, "bpm"),
+    )
+    .unwrap();
+    pipeline::process(&state, &high).unwrap();
+    let temp = pipeline::admit(
+        &state,
+        reading("wh-p2", SignalCode::BodyTemperature, 37.0, "C"),
+    )
+    .unwrap();
+    assert!(pipeline::process(&state, &temp).is_none());
+
+    // alerts: 500 then 204; heart: one; down: 503 twice; gone and moved: once
+    for _ in 0..300 {
+        if receiver.received.lock().unwrap().len() >= 7 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // Broadcast while the pipeline drains, after the hub has closed its clients
+    state.ws_hub.close_all("server shutting down", 5);
+    let late =
+        pipeline::admit(&state, reading("wh-p3", SignalCode::HeartRate, 80.0, "bpm")).unwrap();
+    pipeline::process(&state, &late);
+    hooks.stop().await;
+    handle.stop(false).await;
+
+    let received = receiver.received.lock().unwrap();
+    let count = |path: &str| received.iter().filter(|r| r.path == path).count();
+    assert_eq!(
+        (count("/alerts"), count("/heart"), count("/down")),
+        (2, 2, 2)
+    );
+    // Neither retried, and the redirect not followed
+    assert_eq!((count("/gone"), count("/moved")), (1, 1));
+
+    let attempts: Vec<&Received> = received.iter().filter(|r| r.path == "/alerts").collect();
+    let (first, second) = (attempts[0], attempts[1]);
+    assert_eq!(
+        first.headers["x-pulsesense-delivery"],
+        second.headers["x-pulsesense-delivery"]
+    );
+    assert_eq!(second.headers["x-pulsesense-event"], "alert");
+    assert_eq!(second.headers["content-type"], "application/json");
+    let signature = &second.headers["x-pulsesense-signature"];
+    let t: i64 = signature
+        .strip_prefix("t=")
+        .unwrap()
+        .split(',')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(
+        signature,
+        &webhooks::signature(&alert_secret, t, second.body.as_bytes())
+    );
+    let event: serde_json::Value = serde_json::from_str(&second.body).unwrap();
+    assert_eq!(
+        (event["type"].as_str(), event["data"]["kind"].as_str()),
+        (Some("alert"), Some("high"))
+    );
+    assert_eq!(event["data"]["observation_id"], high.id.to_string());
+
+    let heart_event = received.iter().find(|r| r.path == "/heart").unwrap();
+    let event: serde_json::Value = serde_json::from_str(&heart_event.body).unwrap();
+    assert_eq!(
+        (
+            event["type"].as_str(),
+            event["data"]["resourceType"].as_str()
+        ),
+        (Some("observation"), Some("Observation"))
+    );
+    assert_eq!(event["data"]["id"], high.id.to_string());
+
+    let log = hooks.deliveries(alerts).unwrap();
+    assert_eq!(log.len(), 1);
+    assert_eq!(
+        (log[0].status, log[0].attempts, log[0].response_status),
+        (DeliveryStatus::Delivered, 2, Some(204))
+    );
+    let log = hooks.deliveries(heart).unwrap();
+    assert_eq!(log.len(), 2);
+    assert!(log.iter().all(|d| d.status == DeliveryStatus::Delivered));
+    let log = hooks.deliveries(down).unwrap();
+    assert_eq!(
+        (log[0].status, log[0].attempts, log[0].response_status),
+        (DeliveryStatus::Failed, 2, Some(503))
+    );
+    assert!(log[0].error.as_deref().unwrap().contains("nope"));
+    for (id, status) in [(gone, 410), (moved, 307)] {
+        let log = hooks.deliveries(id).unwrap();
+        assert_eq!(
+            (log[0].status, log[0].attempts, log[0].response_status),
+            (DeliveryStatus::Failed, 1, Some(status))
+        );
+    }
+    assert_eq!(state.ws_hub.client_count(), 0);
+}
+
+#[actix_rt::test]
+async fn subscriptions_created_through_the_api_deliver_after_the_server_stops() {
+    let receiver = web::Data::new(Receiver::default());
+    let app_receiver = receiver.clone();
+    let sink = HttpServer::new(move || {
+        App::new()
+            .app_data(app_receiver.clone())
+            .default_service(web::to(receive))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let base = format!("http://{}", sink.addrs()[0]);
+    let sink = sink.run();
+    let sink_handle = sink.handle();
+    actix_rt::spawn(sink);
+
+    let state = web::Data::new(AppState::new_demo());
+    let hooks =
+        web::Data::new(Webhooks::start(state.clone().into_inner(), &hook_settings()).unwrap());
+    let mut settings = Settings::default();
+    settings.auth.admin_token = Some("admin-secret".into());
+    let settings = web::Data::new(settings);
+    let (app_state, app_hooks) = (state.clone(), hooks.clone());
+    let api = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .app_data(settings.clone())
+            .app_data(app_hooks.clone())
+            .configure(routes::configure)
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr = api.addrs()[0];
+    let api = api.run();
+    let api_handle = api.handle();
+    actix_rt::spawn(api);
+
+    // Created on the HTTP worker, whose runtime goes away with the server
+    let resp = awc::Client::new()
+        .post(format!("http://{}/webhooks", addr))
+        .insert_header(("Authorization", "Bearer admin-secret"))
+        .send_json(&json!({ "url": format!("{}/late", base), "events": ["observation"] }))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    api_handle.stop(true).await;
+
+    // Broadcast while the pipeline drains
+    let reading = SensorReading {
+        device_id: "wh-dev".into(),
+        patient_id: "wh-p4".into(),
+        code: SignalCode::HeartRate,
+        value: 72.0,
+        unit: "bpm".into(),
+        ts: Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap(),
+    };
+    let obs = pipeline::admit(&state, reading).unwrap();
+    pipeline::process(&state, &obs);
+    state.ws_hub.close_all("server shutting down", 5);
+    hooks.stop().await;
+    sink_handle.stop(false).await;
+
+    let received = receiver.received.lock().unwrap();
+    assert_eq!(received.iter().filter(|r| r.path == "/late").count(), 1);
+    let id = hooks.list()[0].id;
+    let log = hooks.deliveries(id).unwrap();
+    assert_eq!((log.len(), log[0].status), (1, DeliveryStatus::Delivered));
+}